@@ -0,0 +1,179 @@
+//
+// dc4 benchmarks
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+#![deny(rust_2018_idioms)]
+
+//! Simple timing benchmarks, runnable on stable Rust with `cargo bench`. Pass a substring as an
+//! argument to run only the benchmarks whose names contain it.
+
+use std::hint::black_box;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use dc4::{Dc4, DcValue, OutputSink, Program};
+use dc4::parser::Flavor;
+
+fn bench(filter: &Option<String>, name: &str, iterations: u32, mut f: impl FnMut()) {
+    if let Some(filter) = filter {
+        if !name.contains(filter.as_str()) {
+            return;
+        }
+    }
+    f(); // warm up
+    let start = Instant::now();
+    for _ in 0 .. iterations {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let per_iter = elapsed / iterations;
+    println!("{name:<40} {per_iter:>12?}/iter ({iterations} iterations, {:?} total)",
+        Duration::from_millis(elapsed.as_millis() as u64));
+}
+
+fn main() {
+    // cargo passes "--bench"; anything else is a filter.
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+
+    let mut dc = Dc4::new("bench".to_owned());
+    let program = b"[2 3*p 7 5/p [hello]p c]x";
+
+    bench(&filter, "text, new output Vec each run", 100_000, || {
+        let mut out = Vec::new();
+        dc.text(program.to_vec(), &mut out);
+        black_box(out);
+    });
+
+    let mut sink = OutputSink::new();
+    bench(&filter, "text, reused OutputSink", 100_000, || {
+        sink.reset();
+        dc.text(program.to_vec(), &mut sink);
+        black_box(sink.output());
+    });
+
+    bench(&filter, "text_to_sink, reused, with an error each run", 100_000, || {
+        sink.reset();
+        dc.text_to_sink(&b"2 3*p +"[..], &mut sink);
+        black_box(sink.diagnostics());
+    });
+
+    // The overhead of timing, on a program where there's nothing expensive to time.
+    let mut timed = Dc4::new("bench".to_owned());
+    timed.set_timings(true);
+    bench(&filter, "text, reused OutputSink, with timings", 100_000, || {
+        sink.reset();
+        timed.text(program.to_vec(), &mut sink);
+        black_box(sink.output());
+    });
+
+    // The same formula run against many inputs, parsing it each time or only once.
+    let formula = "4k d 3.25 * r d * 2 / + 1234.5678 - [sign]s. 0>. [result: ]n p c";
+    bench(&filter, "formula, text", 100_000, || {
+        sink.reset();
+        dc.push_scaled_int(1234, 1);
+        dc.text(formula, &mut sink);
+        black_box(sink.output());
+    });
+    let compiled = Program::compile(formula, Flavor::Gnu);
+    bench(&filter, "formula, run_program", 100_000, || {
+        sink.reset();
+        dc.push_scaled_int(1234, 1);
+        dc.run_program(&compiled, &mut sink);
+        black_box(sink.output());
+    });
+
+    // Typical loop bookkeeping: small integers, incremented and compared.
+    let counting = b"[1+ d 100000>x]sx 0 lxx s.";
+    bench(&filter, "counting loop to 100k", 20, || {
+        let mut out = Vec::new();
+        dc.text(counting.to_vec(), &mut out);
+        black_box(out);
+    });
+
+    // Comparing against zero, with the counter at a different scale from the literal 0.
+    let countdown = b"[1- d0<x]sx 10000000.0 lxx s.";
+    bench(&filter, "countdown loop from 10M, d0<x", 1, || {
+        let mut out = Vec::new();
+        dc.text(countdown.to_vec(), &mut out);
+        black_box(out);
+    });
+
+    // Large strings are shared, not copied, when loaded, duplicated, and sliced.
+    let blob = vec![b'x'; 10 * 1024 * 1024];
+    dc.push_string(blob);
+    dc.text(b"sb".to_vec(), &mut Vec::new());
+    bench(&filter, "10 MB string: load, dup, first char", 100_000, || {
+        let mut out = Vec::new();
+        dc.text(b"lb d a r s. s.".to_vec(), &mut out);
+        black_box(out);
+    });
+
+    // Division results carry `k` fractional digits; auto-simplify trims off the trailing zeros.
+    let divisions = format!("1000k 1 {} s.", "3* 4/ 4* 3/ ".repeat(250));
+    for simplify in [false, true] {
+        let mut dc = Dc4::new("bench".to_owned());
+        dc.set_auto_simplify(simplify);
+        let name = format!("1000 divisions at scale 1000{}", if simplify { ", simplified" } else { "" });
+        bench(&filter, &name, 20, || {
+            let mut out = Vec::new();
+            dc.text(divisions.as_bytes().to_vec(), &mut out);
+            black_box(out);
+        });
+    }
+
+    // Adding and comparing numbers of very different scales, which lines them up by multiplying
+    // one by a power of ten.
+    dc.text(b"10000k 2v sa 1.5 sb 0k".to_vec(), &mut Vec::new());
+    bench(&filter, "add and compare at scales 10000 and 1", 1000, || {
+        let mut out = Vec::new();
+        dc.text(b"la lb + s. la lb - s. la lb (s.".to_vec(), &mut out);
+        black_box(out);
+    });
+
+    // Loading a big lookup table in one go, rather than with `:` for each entry.
+    bench(&filter, "import 1M-entry array", 10, || {
+        let mut dc = Dc4::new("bench".to_owned());
+        dc.import_array(b't', (0 .. 1_000_000u64).map(|i| (i, DcValue::Num((i * 7).into())))).unwrap();
+        let mut out = Vec::new();
+        dc.text(b"999999;tp".to_vec(), &mut out);
+        black_box(out);
+    });
+
+    let literal = format!("{} s.", "1234567890".repeat(10_000));
+    bench(&filter, "100k-digit decimal literal", 100, || {
+        let mut out = Vec::new();
+        dc.text(literal.as_bytes().to_vec(), &mut out);
+        black_box(out);
+    });
+
+    dc.text(format!("{} sz", "1234567890".repeat(10_000)).into_bytes(), &mut Vec::new());
+    bench(&filter, "Z of a 100k-digit number", 1000, || {
+        let mut out = Vec::new();
+        dc.text(b"lzZ s.".to_vec(), &mut out);
+        black_box(out);
+    });
+
+    // A big generated table of `:` stores, as from a memory-mapped file: run in place, or copied
+    // into a string first.
+    let mut table = Vec::with_capacity(200 << 20);
+    for i in 0 .. {
+        if table.len() >= 200 << 20 {
+            break;
+        }
+        writeln!(table, "{} {}:t", i * 7, i % 100_000).unwrap();
+    }
+    bench(&filter, "200 MB table of stores, run_slice", 1, || {
+        let mut dc = Dc4::new("bench".to_owned());
+        let mut out = Vec::new();
+        dc.run_slice(&table, &mut out);
+        black_box(out);
+    });
+    bench(&filter, "200 MB table of stores, text", 1, || {
+        let mut dc = Dc4::new("bench".to_owned());
+        let mut out = Vec::new();
+        dc.text(table.clone(), &mut out);
+        black_box(out);
+    });
+}
@@ -0,0 +1,132 @@
+//
+// parse :: Show how dc4 parses a program into actions.
+//
+// Copyright (c) 2019-2024 by William R. Fraser
+//
+
+#![deny(rust_2018_idioms)]
+
+//! This is an example of using the dc4 parser on its own, without running anything. It prints
+//! the sequence of actions a program parses into, with number and string characters gathered up
+//! into whole literals. Strings are often macros, so by default each string is also parsed and
+//! printed (indented) underneath it; see the usage text for ways to limit this.
+
+use dc4::parser::{check_program, literal_text, Action, ParseIssueKind, SliceParser};
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Recurse {
+    Never,
+    Always,
+    /// Only recurse into strings which parse without any unimplemented commands. Strings of prose
+    /// are usually data, not macros.
+    Heuristic,
+}
+
+struct Options {
+    recurse: Recurse,
+    max_depth: usize,
+    programs: Vec<Vec<u8>>,
+}
+
+fn usage(arg0: &str) -> String {
+    // 79:    ###############################################################################
+    [
+        format!("usage: {arg0} [options] [PROGRAM ...]"),
+        "options:".to_owned(),
+        "  -f FILE | --file=FILE           parse the contents of FILE".to_owned(),
+        "  --no-recurse                    don't parse strings as macros".to_owned(),
+        "  --max-depth=N                   don't parse strings nested deeper than N levels".to_owned(),
+        "                                  (default: 16)".to_owned(),
+        "  --heuristic                     only parse strings as macros if they contain no".to_owned(),
+        "                                  unimplemented commands".to_owned(),
+        "  -h | --help                     display this help and exit".to_owned(),
+    ].join("\n")
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item=String>) -> Result<Self, String> {
+        let arg0 = args.next().unwrap_or_else(|| "parse".to_owned());
+        let mut opts = Options {
+            recurse: Recurse::Always,
+            max_depth: 16,
+            programs: vec![],
+        };
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Err(usage(&arg0));
+            } else if arg == "--no-recurse" {
+                opts.recurse = Recurse::Never;
+            } else if arg == "--heuristic" {
+                opts.recurse = Recurse::Heuristic;
+            } else if let Some(n) = arg.strip_prefix("--max-depth=") {
+                opts.max_depth = n.parse()
+                    .map_err(|e| format!("invalid argument to --max-depth: {e}"))?;
+            } else if arg == "-f" || arg.starts_with("--file=") {
+                let path = match arg.strip_prefix("--file=") {
+                    Some(path) => path.to_owned(),
+                    None => args.next().ok_or_else(|| "\"-f\" must be followed by an argument".to_owned())?,
+                };
+                let text = std::fs::read(&path)
+                    .map_err(|e| format!("failed to read {path:?}: {e}"))?;
+                opts.programs.push(text);
+            } else {
+                opts.programs.push(arg.into_bytes());
+            }
+        }
+        if opts.programs.is_empty() {
+            return Err(usage(&arg0));
+        }
+        Ok(opts)
+    }
+}
+
+fn should_recurse(opts: &Options, text: &[u8], depth: usize) -> bool {
+    if depth >= opts.max_depth {
+        return false;
+    }
+    match opts.recurse {
+        Recurse::Never => false,
+        Recurse::Always => true,
+        Recurse::Heuristic => !check_program(text)
+            .iter()
+            .any(|issue| matches!(issue.kind, ParseIssueKind::Unimplemented(_))),
+    }
+}
+
+fn print_program(opts: &Options, text: &[u8], depth: usize, w: &mut impl Write) -> io::Result<()> {
+    let indent = "  ".repeat(depth);
+    let mut parser = SliceParser::new(text);
+    parser.set_coalesce_literals(true);
+    for (_offset, action) in parser {
+        match action {
+            Action::NumberLiteral(_) =>
+                writeln!(w, "{indent}Number({})", String::from_utf8_lossy(&literal_text([&action])))?,
+            Action::StringLiteral(text) => {
+                writeln!(w, "{indent}String({:?})", String::from_utf8_lossy(&text))?;
+                if should_recurse(opts, &text, depth) {
+                    print_program(opts, &text, depth + 1, w)?;
+                }
+            }
+            other => writeln!(w, "{indent}{other:?}")?,
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let opts = Options::parse(std::env::args())
+        .unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(2);
+        });
+
+    let stdout = io::stdout();
+    let mut w = stdout.lock();
+    for text in &opts.programs {
+        if let Err(e) = print_program(&opts, text, 0, &mut w) {
+            eprintln!("error: {e}");
+            std::process::exit(2);
+        }
+    }
+}
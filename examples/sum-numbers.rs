@@ -6,10 +6,10 @@
 
 #![deny(rust_2018_idioms)]
 
-/// This is an example of how Dc4 can be used as a library for doing useful numeric operations.
-/// The program reads numbers from input, delimited by whitespace, and uses Dc4 to add them up as
-/// it reads them. When it reaches EOF, it prints the resulting sum. Because it uses Dc4, it
-/// supports arbitrary precision.
+//! This is an example of how Dc4 can be used as a library for doing useful numeric operations.
+//! The program reads numbers from input, delimited by whitespace, and uses Dc4 to add them up as
+//! it reads them. When it reaches EOF, it prints the resulting sum. Because it uses Dc4, it
+//! supports arbitrary precision.
 
 use dc4::{Dc4, DcError};
 use dc4::parser::Action;
@@ -111,20 +111,20 @@ fn run(r: impl BufRead, mut w: impl Write) -> Result<(), Error> {
         action(&mut dc, Action::SetInputRadix, &mut w)?;
     }
 
-    // initial value
-    dc.push_number("0").unwrap();
-
     for result in r.lines() {
         let s = result.map_err(|e| format!("I/O error: {e}"))?;
         // dc uses '_' to designate negative numbers because '-' is used for subtraction, so
         // replace it.
-        if let Err(e) = dc.push_number(s.replace('-', "_").trim()) {
+        if let Err(e) = dc.fold_number(s.replace('-', "_").trim(), Action::Add) {
             return Err(format!("invalid input {s:?}: {e}").into());
         }
-        action(&mut dc, Action::Add, &mut w)?;
     }
 
-    action(&mut dc, Action::PrintStack, &mut w)?;
+    match dc.take_result() {
+        Ok(sum) => dc.print_value(&sum, &mut w),
+        Err(_) => write!(w, "0").unwrap(), // no input
+    }
+    writeln!(w).unwrap();
     Ok(())
 }
 
@@ -4,67 +4,579 @@
 // Copyright (c) 2016-2024 by William R. Fraser
 //
 
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::cmp::{max, Ordering};
 use std::hash::{Hash, Hasher};
-use std::ops::{Add, Sub, Mul, Neg, Shr};
+use std::io::{self, Write};
+use std::ops::{Add, Sub, Mul, Shr};
 
-use num_bigint::BigInt;
+use num_bigint::{BigInt, BigUint};
 use num_integer::Integer;
 use num_traits::{Zero, One, Signed, ToPrimitive, FromPrimitive};
 
+/// Powers of ten which fit in an `i64`.
+const POW10: [i64; 19] = {
+    let mut table = [1; 19];
+    let mut i = 1;
+    while i < table.len() {
+        table[i] = table[i - 1] * 10;
+        i += 1;
+    }
+    table
+};
+
+thread_local! {
+    /// The last power of ten [`Int::num_digits`] compared against, with its exponent. Digits tend
+    /// to be counted for numbers of about the same size over and over, like with `Z` in a loop.
+    static DIGITS_POW10: RefCell<(u64, BigUint)> = RefCell::new((0, BigUint::one()));
+}
+
+/// The digits of a `BigReal`, as an integer.
+///
+/// Most numbers in dc programs are small, like loop counters, so rather than allocating a
+/// `BigInt` for every one, those which fit in an `i64` are kept inline. Arithmetic on them stays
+/// inline until a result doesn't fit. Results are always stored inline when they fit, but either
+/// representation of a value works the same, and compares and hashes equal to the other.
 #[derive(Clone, Debug)]
-pub struct BigReal {
-    shift: u32, // in decimal digits
-    value: BigInt,
+enum Int {
+    Inline(i64),
+    Big(BigInt),
 }
 
-impl BigReal {
-    fn change_shift(&self, desired_shift: u32) -> BigReal {
-        let mut result = self.clone();
-        if desired_shift > result.shift {
-            for _ in 0..(desired_shift - self.shift) {
-                result.value = &result.value * 10;
+impl Int {
+    fn from_big(big: BigInt) -> Int {
+        match big.to_i64() {
+            Some(n) => Int::Inline(n),
+            None => Int::Big(big),
+        }
+    }
+
+    fn from_prim<T: Copy + TryInto<i64> + Into<BigInt>>(n: T) -> Int {
+        match n.try_into() {
+            Ok(n) => Int::Inline(n),
+            Err(_) => Int::Big(n.into()),
+        }
+    }
+
+    fn to_big(&self) -> Cow<'_, BigInt> {
+        match self {
+            Int::Inline(n) => Cow::Owned(BigInt::from(*n)),
+            Int::Big(big) => Cow::Borrowed(big),
+        }
+    }
+
+    fn into_big(self) -> BigInt {
+        match self {
+            Int::Inline(n) => BigInt::from(n),
+            Int::Big(big) => big,
+        }
+    }
+
+    fn to_i64(&self) -> Option<i64> {
+        match self {
+            Int::Inline(n) => Some(*n),
+            Int::Big(big) => big.to_i64(),
+        }
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        match self {
+            Int::Inline(n) => u64::try_from(*n).ok(),
+            Int::Big(big) => big.to_u64(),
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Int::Inline(n) => *n == 0,
+            Int::Big(big) => big.is_zero(),
+        }
+    }
+
+    fn is_positive(&self) -> bool {
+        match self {
+            Int::Inline(n) => *n > 0,
+            Int::Big(big) => big.is_positive(),
+        }
+    }
+
+    fn is_negative(&self) -> bool {
+        match self {
+            Int::Inline(n) => *n < 0,
+            Int::Big(big) => big.is_negative(),
+        }
+    }
+
+    fn is_odd(&self) -> bool {
+        match self {
+            Int::Inline(n) => n % 2 != 0,
+            Int::Big(big) => big.is_odd(),
+        }
+    }
+
+    /// The number of bits in the magnitude.
+    fn bits(&self) -> u64 {
+        match self {
+            Int::Inline(n) => u64::from(u64::BITS - n.unsigned_abs().leading_zeros()),
+            Int::Big(big) => big.bits(),
+        }
+    }
+
+    /// The number of decimal digits in the magnitude, at least one. For big numbers, the number
+    /// of bits narrows it down to two possibilities, and one comparison against a power of ten
+    /// decides between them, without converting the whole number to decimal.
+    fn num_digits(&self) -> u64 {
+        match self {
+            Int::Inline(n) => u64::from(n.unsigned_abs().checked_ilog10().unwrap_or(0)) + 1,
+            Int::Big(big) => {
+                // It's between 2^(bits-1) and 2^bits, which have either the same number of digits
+                // or one apart. The estimates are nudged outwards a little, in case of rounding.
+                let digits = |bits: u64, nudge: f64| {
+                    (bits as f64 * std::f64::consts::LOG10_2 + nudge).max(0.) as u64 + 1
+                };
+                let bits = big.bits();
+                let fewest = digits(bits.saturating_sub(1), -1e-3);
+                let mut most = digits(bits, 1e-3);
+                while most > fewest && !at_least_pow10(big.magnitude(), most - 1) {
+                    most -= 1;
+                }
+                most
             }
         }
-        else {
-            for _ in 0..(result.shift - desired_shift) {
-                result.value = &result.value / 10;
+    }
+
+    fn abs(&self) -> Int {
+        match self {
+            Int::Inline(n) => n.checked_abs().map(Int::Inline)
+                .unwrap_or_else(|| Int::Big(BigInt::from(*n).abs())),
+            Int::Big(big) => Int::from_big(big.abs()),
+        }
+    }
+
+    fn neg(&self) -> Int {
+        match self {
+            Int::Inline(n) => n.checked_neg().map(Int::Inline)
+                .unwrap_or_else(|| Int::Big(-BigInt::from(*n))),
+            Int::Big(big) => Int::from_big(-big),
+        }
+    }
+
+    fn add(&self, rhs: &Int) -> Int {
+        if let (Int::Inline(a), Int::Inline(b)) = (self, rhs) {
+            if let Some(sum) = a.checked_add(*b) {
+                return Int::Inline(sum);
             }
         }
-        result.shift = desired_shift;
-        result
+        Int::from_big(self.to_big().as_ref() + rhs.to_big().as_ref())
     }
 
-    /// Reduce the shift as much as possible without losing any precision.
-    pub fn simplify(&mut self) {
-        let ten = BigInt::from(10);
-        loop {
-            if self.shift == 0 {
-                break;
+    fn mul(&self, rhs: &Int) -> Int {
+        if let (Int::Inline(a), Int::Inline(b)) = (self, rhs) {
+            if let Some(product) = a.checked_mul(*b) {
+                return Int::Inline(product);
+            }
+        }
+        Int::from_big(self.to_big().as_ref() * rhs.to_big().as_ref())
+    }
+
+    /// Divide, truncating towards zero.
+    fn div(&self, rhs: &Int) -> Int {
+        if let (Int::Inline(a), Int::Inline(b)) = (self, rhs) {
+            if let Some(quotient) = a.checked_div(*b) {
+                return Int::Inline(quotient);
+            }
+        }
+        Int::from_big(self.to_big().as_ref() / rhs.to_big().as_ref())
+    }
+
+    fn mul_pow10(&self, n: u32) -> Int {
+        if let Int::Inline(a) = self {
+            if let Some(product) = POW10.get(n as usize).and_then(|p| a.checked_mul(*p)) {
+                return Int::Inline(product);
             }
-            let (quotient, remainder) = self.value.div_rem(&ten);
-            if !remainder.is_zero() {
+        }
+        Int::from_big(self.to_big().as_ref() * BigInt::from(10u32).pow(n))
+    }
+
+    /// Divide by 10^n, truncating towards zero, and also return the remainder.
+    fn div_rem_pow10(&self, n: u32) -> (Int, Int) {
+        match self {
+            Int::Inline(a) => match POW10.get(n as usize) {
+                Some(p) => (Int::Inline(a / p), Int::Inline(a % p)),
+                None => (Int::Inline(0), Int::Inline(*a)),
+            },
+            Int::Big(big) => {
+                let (quotient, remainder) = big.div_rem(&BigInt::from(10u32).pow(n));
+                (Int::from_big(quotient), Int::from_big(remainder))
+            }
+        }
+    }
+
+    fn to_str_radix(&self, radix: u32) -> String {
+        match self {
+            Int::Inline(n) if radix == 10 => n.to_string(),
+            _ => self.to_big().to_str_radix(radix),
+        }
+    }
+}
+
+/// Whether `n` is at least 10^`exp`.
+fn at_least_pow10(n: &BigUint, exp: u64) -> bool {
+    DIGITS_POW10.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.0 != exp {
+            let exp32 = u32::try_from(exp).expect("too many digits");
+            *cache = (exp, BigUint::from(10u32).pow(exp32));
+        }
+        *n >= cache.1
+    })
+}
+
+impl PartialEq for Int {
+    fn eq(&self, rhs: &Int) -> bool {
+        self.cmp(rhs) == Ordering::Equal
+    }
+}
+
+impl Eq for Int {
+}
+
+impl PartialOrd for Int {
+    fn partial_cmp(&self, rhs: &Int) -> Option<Ordering> {
+        Some(self.cmp(rhs))
+    }
+}
+
+impl Ord for Int {
+    fn cmp(&self, rhs: &Int) -> Ordering {
+        match (self, rhs) {
+            (Int::Inline(a), Int::Inline(b)) => a.cmp(b),
+            _ => self.to_big().cmp(&rhs.to_big()),
+        }
+    }
+}
+
+impl Hash for Int {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.to_i64() {
+            Some(n) => n.hash(state),
+            None => self.to_big().hash(state),
+        }
+    }
+}
+
+/// atan(x) for x between 0 and 1, to the given scale. The argument is made smaller first, with
+/// atan(x) = 2 atan(x / (1 + sqrt(1 + x²))), so that the series converges quickly.
+fn atan_reduced(mut x: BigReal, scale: u32, interrupt: Interrupt<'_>)
+    -> Result<BigReal, Interrupted>
+{
+    let one = BigReal::one();
+    let fifth = BigReal::new(2, 1);
+    let mut doublings = 0;
+    while x > fifth {
+        let root = (&one + &(&x * &x).change_shift(scale)).sqrt_interruptible(scale, interrupt)?
+            .unwrap();
+        x = x.div(&(&one + &root), scale);
+        doublings += 1;
+    }
+    Ok(&atan_series(&x, scale, interrupt)? * &BigReal::from(1u32 << doublings))
+}
+
+/// π/4, to the given scale, from Machin's formula: π/4 = 4 atan(1/5) - atan(1/239).
+fn quarter_pi(scale: u32, interrupt: Interrupt<'_>) -> Result<BigReal, Interrupted> {
+    let one = BigReal::one();
+    let a = atan_series(&one.div(&BigReal::from(5), scale), scale, interrupt)?;
+    let b = atan_series(&one.div(&BigReal::from(239), scale), scale, interrupt)?;
+    Ok(&(&a * &BigReal::from(4)) - &b)
+}
+
+/// atan(x) = x - x³/3 + x⁵/5 - ..., to the given scale. This converges quickly when x is small.
+fn atan_series(x: &BigReal, scale: u32, interrupt: Interrupt<'_>)
+    -> Result<BigReal, Interrupted>
+{
+    let x2 = (x * x).change_shift(scale);
+    let mut power = x.change_shift(scale);
+    let mut sum = power.clone();
+    let mut n = 1u32;
+    loop {
+        interrupt.check()?;
+        power = (&power * &x2).change_shift(scale);
+        n += 2;
+        let term = power.div(&BigReal::from(n), scale);
+        if term.is_zero() {
+            break;
+        }
+        sum = if n % 4 == 3 { &sum - &term } else { &sum + &term };
+    }
+    Ok(sum)
+}
+
+/// The n-th root of a nonnegative integer, rounded down, by Newton's method.
+fn int_nth_root(x: &BigInt, n: u32, interrupt: Interrupt<'_>) -> Result<BigInt, Interrupted> {
+    if x.is_zero() || n == 1 {
+        return Ok(x.clone());
+    }
+    // Start above the root, so that the steps go down towards it and stop once they don't. While
+    // far above it, each step only goes down by a factor of about (n - 1) / n, so start from a
+    // floating point estimate, nudged up, instead of the next power of two up.
+    let bits = x.bits();
+    let top_bits = bits.min(64);
+    let top = (x >> (bits - top_bits)).to_u64().unwrap() as f64;
+    let log2_root = (top.log2() + (bits - top_bits) as f64) / f64::from(n) + 1e-12;
+    let mut y = if log2_root < 52. {
+        BigInt::from(log2_root.exp2().ceil() as u64)
+    } else {
+        let whole = log2_root.floor();
+        BigInt::from((log2_root - whole + 52.).exp2().ceil() as u64) << (whole as u64 - 52)
+    };
+    while y.pow(n) <= *x {
+        interrupt.check()?;
+        y <<= 1;
+    }
+    loop {
+        interrupt.check()?;
+        let next = (&y * (n - 1) + x / y.pow(n - 1)) / n;
+        if next >= y {
+            return Ok(y);
+        }
+        y = next;
+    }
+}
+
+/// The product of the integers from `low` up to `high`, inclusive, checking `interrupt` as it
+/// goes. Splitting the range in half keeps the numbers being multiplied about the same size,
+/// which is much faster than multiplying them in one at a time once they get big.
+fn range_product(low: u64, high: u64, interrupt: Interrupt<'_>) -> Result<BigInt, Interrupted> {
+    if high - low < 16 {
+        interrupt.check()?;
+        return Ok((low ..= high).fold(BigInt::one(), |product, i| product * i));
+    }
+    let mid = low + (high - low) / 2;
+    Ok(range_product(low, mid, interrupt)? * range_product(mid + 1, high, interrupt)?)
+}
+
+/// ln((1 + z) / (1 - z)), which is 2 atanh(z), to the given scale. This converges quickly when
+/// z is small.
+fn ln_ratio(z: &BigReal, scale: u32, interrupt: Interrupt<'_>) -> Result<BigReal, Interrupted> {
+    let z2 = (z * z).change_shift(scale);
+    let mut power = z.change_shift(scale);
+    let mut sum = power.clone();
+    let mut n = 1u32;
+    loop {
+        interrupt.check()?;
+        power = (&power * &z2).change_shift(scale);
+        n += 2;
+        let term = power.div(&BigReal::from(n), scale);
+        if term.is_zero() {
+            break;
+        }
+        sum = &sum + &term;
+    }
+    Ok(&sum + &sum)
+}
+
+/// How [`BigReal::round`] treats the digits it drops.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Drop them, like everything else in dc does.
+    Truncate,
+    /// Round to the nearest, and away from zero when exactly halfway: 2.5 becomes 3, and -2.5
+    /// becomes -3.
+    #[default]
+    HalfUp,
+    /// Round to the nearest, and to the even neighbor when exactly halfway: 2.5 becomes 2, and
+    /// 3.5 becomes 4. This is also known as banker's rounding.
+    HalfEven,
+}
+
+/// Lets a long calculation be stopped part way through: the `_interruptible` methods, like
+/// `sqrt_interruptible`, check it between steps of their loops, and give up with [`Interrupted`]
+/// once it says to stop.
+#[derive(Clone, Copy)]
+pub struct Interrupt<'a> {
+    stop: Option<&'a dyn Fn() -> bool>,
+}
+
+impl<'a> Interrupt<'a> {
+    /// Never stop.
+    pub const NEVER: Interrupt<'static> = Interrupt { stop: None };
+
+    /// Stop once `stop` returns true.
+    pub fn new(stop: &'a dyn Fn() -> bool) -> Self {
+        Interrupt { stop: Some(stop) }
+    }
+
+    fn check(&self) -> Result<(), Interrupted> {
+        match self.stop {
+            Some(stop) if stop() => Err(Interrupted),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A calculation was stopped by its [`Interrupt`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted;
+
+/// The fewest digits, at least one, which a place value in `radix` needs to be no bigger than
+/// 1/`max_place`, where `max_place` is 10^`scale`.
+fn radix_digits(radix: u32, scale: u32, max_place: &BigInt) -> u32 {
+    // Estimate from logarithms, then make it exact.
+    let estimate = (f64::from(scale) * std::f64::consts::LN_10 / f64::from(radix).ln()) as u32;
+    let mut digits = estimate.max(1);
+    let mut place = BigInt::from(radix).pow(digits);
+    while digits > 1 && &place / radix >= *max_place {
+        place /= radix;
+        digits -= 1;
+    }
+    while place < *max_place {
+        place *= radix;
+        digits += 1;
+    }
+    digits
+}
+
+/// How many digits a non-negative integer has in the given radix.
+fn count_digits(n: &BigInt, radix: u32) -> u64 {
+    // Estimate low from the number of bits, then make it exact.
+    let estimate = (n.bits().saturating_sub(1) as f64 * std::f64::consts::LN_2
+        / f64::from(radix).ln()) as u64;
+    let mut digits = estimate.max(1);
+    let mut place = BigInt::from(radix).pow(places(digits as i64));
+    while place <= *n {
+        place *= radix;
+        digits += 1;
+    }
+    digits
+}
+
+/// A number of decimal places as a `u32`, with negative numbers as zero, and ones too big as
+/// `u32::MAX`.
+fn places(n: i64) -> u32 {
+    u32::try_from(n.max(0)).unwrap_or(u32::MAX)
+}
+
+#[derive(Clone, Debug)]
+pub struct BigReal {
+    /// In decimal digits. This is negative for integers stored without their trailing zeros: a
+    /// value of 15 with a shift of -3 is 15000.
+    shift: i64,
+    value: Int,
+    /// The number of fractional digits the number is considered to have: what `X` reports, and
+    /// how many get printed. This is more than `shift` if trailing zeros have been trimmed off by
+    /// `simplify`.
+    scale: u32,
+}
+
+impl BigReal {
+    fn with_value(value: Int, shift: impl Into<i64>) -> BigReal {
+        let shift = shift.into();
+        BigReal { shift, value, scale: places(shift) }
+    }
+
+    fn change_shift(&self, desired_shift: impl Into<i64>) -> BigReal {
+        let desired_shift = desired_shift.into();
+        let value = if desired_shift > self.shift {
+            self.value.mul_pow10(places(desired_shift - self.shift))
+        } else {
+            self.value.div_rem_pow10(places(self.shift - desired_shift)).0
+        };
+        BigReal::with_value(value, desired_shift)
+    }
+
+    /// Reduce the shift as much as possible without losing any precision, past zero for integers
+    /// with trailing zeros. The scale is unchanged, so this only affects how the number is stored,
+    /// and how much work arithmetic on it takes.
+    pub fn simplify(&mut self) {
+        if self.value.is_zero() {
+            self.shift = 0;
+            return;
+        }
+        // Take off many zeros at a time while possible, since each division goes over the whole
+        // number. Odd numbers have no trailing zeros, so don't bother dividing those.
+        let mut step = 18;
+        while !self.value.is_odd() {
+            let (quotient, remainder) = self.value.div_rem_pow10(step);
+            if remainder.is_zero() {
+                self.shift -= i64::from(step);
+                self.value = quotient;
+            } else if step > 1 {
+                step = 1;
+            } else {
                 break;
             }
-            self.shift -= 1;
-            self.value = quotient;
         }
     }
 
+    /// Drop trailing zeros from the fractional digits, so that the scale is as small as it can be
+    /// without changing the value.
+    pub fn trim(&mut self) {
+        self.simplify();
+        self.scale = places(self.shift);
+    }
+
     pub fn set_shift(&mut self, shift: u32) {
-        self.shift = shift;
+        self.shift = i64::from(shift);
+        self.scale = shift;
+    }
+
+    /// Multiply by 10^`places`, which may be negative, by moving the decimal point. No digits are
+    /// lost: the scale goes down by `places`, as far as zero, or up by as many as it takes.
+    pub fn shift_decimal(&self, places: i64) -> BigReal {
+        BigReal {
+            shift: self.shift.saturating_sub(places),
+            value: self.value.clone(),
+            scale: self::places(i64::from(self.scale).saturating_sub(places)),
+        }
+    }
+
+    pub fn num_frx_digits(&self) -> u64 {
+        u64::from(self.scale)
+    }
+
+    pub fn num_digits(&self) -> u64 {
+        // the sign isn't a digit
+        let digits = self.value.num_digits();
+        if self.value.is_zero() {
+            digits
+        } else {
+            // count any trailing zeros which were trimmed off, too
+            digits + (i64::from(self.scale) - self.shift) as u64
+        }
+    }
+
+    /// An upper bound on [`num_digits`](Self::num_digits), which unlike it takes no work to find:
+    /// it's exact, or up to two more.
+    pub fn num_digits_bound(&self) -> u64 {
+        let digits = (self.value.bits() as f64 * std::f64::consts::LOG10_2) as u64 + 2;
+        digits + (i64::from(self.scale) - self.shift).max(0) as u64
     }
 
-    pub fn num_frx_digits(&self) -> u32 {
-        self.shift
+    /// Roughly how many bytes the number takes in memory.
+    pub fn size_bytes(&self) -> u64 {
+        self.value.bits().div_ceil(8) + std::mem::size_of::<BigReal>() as u64
     }
 
-    pub fn num_digits(&self) -> u32 {
-        self.value.to_str_radix(10).len() as u32
+    /// Write the number as [`to_str_radix`](Self::to_str_radix) gives it, but with any letter
+    /// digits in upper case. Integers small enough to be stored inline are written in decimal
+    /// without making a string first, which is the common case when printing.
+    pub fn write_radix(&self, radix: u32, w: &mut impl Write) -> io::Result<()> {
+        match self.value {
+            Int::Inline(n) if radix == 10 && self.shift == 0 && self.scale == 0 => write!(w, "{n}"),
+            _ => {
+                let mut s = self.to_str_radix(radix);
+                s.make_ascii_uppercase();
+                w.write_all(s.as_bytes())
+            }
+        }
     }
 
     pub fn to_str_radix(&self, radix: u32) -> String {
-        if self.shift == 0 {
+        if self.shift < i64::from(self.scale) {
+            self.change_shift(self.scale).to_str_radix(radix)
+        }
+        else if self.shift == 0 {
             self.value.to_str_radix(radix)
         }
         else if radix == 10 {
@@ -109,53 +621,171 @@ impl BigReal {
             }
             string_result.push('.');
 
-            // start with the part shifted over one place value (because otherwise the first
-            // iteration would always yield zero).
-            let mut part = (&self.value - whole.change_shift(self.shift).value).abs() * radix;
+            // The fractional digits are as many as it takes for a place value in the output radix
+            // to be at least as fine as the last decimal place: the fewest d, at least one, with
+            // radix^d >= 10^scale. They're the fractional part times radix^d, divided by 10^scale
+            // and truncated, which is what long division one digit at a time would give, but
+            // with one division instead of one per digit.
+            let whole_shifted = whole.change_shift(self.shift).value.into_big();
+            let part = self.value.abs().into_big() - whole_shifted;
+            let max_place = BigInt::from(10u32).pow(self.scale);
+            let digits = radix_digits(radix, self.scale, &max_place);
+            let fraction = part * BigInt::from(radix).pow(digits) / &max_place;
+            let fraction = fraction.to_str_radix(radix);
+            for _ in fraction.len() .. digits as usize {
+                string_result.push('0');
+            }
+            string_result.push_str(&fraction);
 
-            // These control when we stop the iteration.
-            // When the current place value (in whatever radix) is greater than the amount of the
-            // shift (in decimal), we stop.
-            let max_place = BigReal::one().change_shift(self.shift).value;
-            let mut place = BigInt::from(radix);
+            string_result
+        }
+    }
 
-            loop {
-                let div_rem = part.div_rem(&max_place);
+    /// The start of what [`to_str_radix`](Self::to_str_radix) gives, up to `max_len` characters,
+    /// and the length of all of it. Only the digits in the start are worked out, so this is quick
+    /// even for a number with millions of digits.
+    pub fn to_str_radix_prefix(&self, radix: u32, max_len: usize) -> (String, u64) {
+        if self.shift < i64::from(self.scale) {
+            return self.change_shift(self.scale).to_str_radix_prefix(radix, max_len);
+        }
+        let shift = places(self.shift);
+        let mut text = if self.is_negative() { "-".to_owned() } else { String::new() };
+        let (whole, part) = self.value.abs().into_big().div_rem(&BigInt::from(10u32).pow(shift));
+
+        // The whole part is left out if it's zero, unless there's no fraction.
+        let whole_digits = if shift == 0 || !whole.is_zero() { count_digits(&whole, radix) } else { 0 };
+        let max_place = BigInt::from(10u32).pow(self.scale);
+        let fraction_digits = match shift {
+            0 => 0,
+            _ if radix == 10 => shift,
+            _ => radix_digits(radix, self.scale, &max_place),
+        };
+        let mut len = text.len() as u64 + whole_digits;
+        if shift > 0 {
+            len += 1 + u64::from(fraction_digits);
+        }
 
-                string_result.push_str(&div_rem.0.to_str_radix(radix));
-                part = div_rem.1 * radix;
+        let room = max_len.saturating_sub(text.len()) as u64;
+        if whole_digits > room {
+            if room > 0 {
+                let lead = whole / BigInt::from(radix).pow(places((whole_digits - room) as i64));
+                text.push_str(&lead.to_str_radix(radix));
+            }
+            text.truncate(max_len);
+            return (text, len);
+        }
+        if whole_digits > 0 {
+            text.push_str(&whole.to_str_radix(radix));
+        }
+        if shift > 0 && text.len() < max_len {
+            text.push('.');
+            // The first digits of the fraction, worked out the same way as all of them would be.
+            let digits = fraction_digits.min(places((max_len - text.len()) as i64));
+            if digits == 0 {
+                return (text, len);
+            }
+            let fraction = if radix == 10 {
+                part / BigInt::from(10u32).pow(shift - digits)
+            } else {
+                part * BigInt::from(radix).pow(digits) / &max_place
+            };
+            let fraction = fraction.to_str_radix(radix);
+            for _ in fraction.len() .. digits as usize {
+                text.push('0');
+            }
+            text.push_str(&fraction);
+        }
+        text.truncate(max_len);
+        (text, len)
+    }
 
-                // check if we've reached the appropriate precision
-                if place >= max_place {
-                    break;
+    /// Format in decimal scientific notation, like `-1.2346e+20`, rounded half up to `digits`
+    /// significant digits (at least one). With `engineering`, the exponent is a multiple of 3,
+    /// with one to three digits before the point, like `-123.46e+18`. Zero has an exponent of 0.
+    pub fn to_scientific(&self, digits: u32, engineering: bool) -> String {
+        let digits = digits.max(1) as usize;
+        let mut mantissa = self.value.abs().to_str_radix(10);
+        // The power of ten of the first digit.
+        let mut exponent = mantissa.len() as i64 - 1 - self.shift;
+        if self.value.is_zero() {
+            exponent = 0;
+        }
+        if mantissa.len() > digits {
+            let round_up = mantissa.as_bytes()[digits] >= b'5';
+            mantissa.truncate(digits);
+            if round_up {
+                let rounded = (mantissa.parse::<BigInt>().unwrap() + 1u32).to_string();
+                if rounded.len() > digits {
+                    // 9.99 became 10.0
+                    exponent += 1;
                 }
-                place *= radix;
+                mantissa = rounded[.. digits].to_owned();
             }
-
-            string_result
         }
+        while mantissa.len() < digits {
+            mantissa.push('0');
+        }
+        let whole_digits = if engineering { exponent.rem_euclid(3) as usize + 1 } else { 1 };
+        exponent -= whole_digits as i64 - 1;
+        while mantissa.len() < whole_digits {
+            mantissa.push('0');
+        }
+        if mantissa.len() > whole_digits {
+            mantissa.insert(whole_digits, '.');
+        }
+        let sign = if self.is_negative() { "-" } else { "" };
+        let exponent_sign = if exponent < 0 { '-' } else { '+' };
+        format!("{sign}{mantissa}e{exponent_sign}{:02}", exponent.unsigned_abs())
     }
 
+    /// Raise to the given power. The fractional part of the exponent is ignored.
+    ///
+    /// The scale of the result follows the POSIX bc rule (which GNU and BSD dc both use): for a
+    /// positive exponent `b`, the result is truncated to `min(scale(a) * b, max(scale, scale(a)))`
+    /// digits; for a negative exponent, the reciprocal is taken at `scale` digits.
     pub fn pow(&self, exponent: &BigReal, scale: u32) -> BigReal {
+        match self.pow_interruptible(exponent, scale, Interrupt::NEVER) {
+            Ok(result) => result,
+            Err(Interrupted) => unreachable!(),
+        }
+    }
+
+    /// Like `pow`, but checking `interrupt` before each squaring.
+    pub fn pow_interruptible(&self, exponent: &BigReal, scale: u32, interrupt: Interrupt<'_>)
+        -> Result<BigReal, Interrupted>
+    {
         let negative = exponent.is_negative();
 
         // Ignore the fractional part of the exponent.
-        let mut exponent: BigInt = exponent.change_shift(0).value.abs();
+        let mut exponent: BigInt = exponent.change_shift(0).value.into_big().abs();
 
         if exponent.is_zero() {
-            return BigReal::one();
+            return Ok(BigReal::one());
         }
 
+        let result_scale = {
+            let cap = max(scale, self.scale);
+            match exponent.to_u64() {
+                Some(b) => u64::from(self.scale).saturating_mul(b).min(u64::from(cap)) as u32,
+                None if self.scale == 0 => 0,
+                None => cap,
+            }
+        };
+
         let one = BigInt::one();
+        // Keep any trailing zeros as a negative shift, rather than squaring them out.
         let mut base = self.clone();
+        base.simplify();
 
         while exponent.is_even() {
+            interrupt.check()?;
             base = &base * &base;
             exponent = exponent.shr(1);
         }
 
         let mut result = base.clone();
         while (&exponent - &one).is_positive() {
+            interrupt.check()?;
             exponent = exponent.shr(1);
             base = &base * &base;
             if exponent.is_odd() {
@@ -163,54 +793,341 @@ impl BigReal {
             }
         }
 
-        if negative {
+        Ok(if negative {
             BigReal::from(one).div(&result, scale)
+        } else if result.shift > i64::from(result_scale) {
+            result.change_shift(result_scale)
         } else {
+            result.scale = result_scale;
             result
+        })
+    }
+
+    /// Raise to the given power, including its fractional part, as e^(exponent × ln(self)). This
+    /// is `None` if this is negative, or zero with a negative exponent. The result has
+    /// `max(scale, scale(self))` fractional digits, truncated.
+    ///
+    /// Like `log`, results within a few digits past the last one of the next digit up are taken
+    /// to be exact, so that 4^0.5 is 2 rather than 1.999....
+    pub fn pow_fractional(&self, exponent: &BigReal, scale: u32) -> Option<BigReal> {
+        match self.pow_fractional_interruptible(exponent, scale, Interrupt::NEVER) {
+            Ok(result) => result,
+            Err(Interrupted) => unreachable!(),
         }
     }
 
+    /// Like `pow_fractional`, but checking `interrupt` as it goes.
+    pub fn pow_fractional_interruptible(&self, exponent: &BigReal, scale: u32,
+        interrupt: Interrupt<'_>) -> Result<Option<BigReal>, Interrupted>
+    {
+        if self.is_zero() {
+            return Ok((!exponent.is_negative()).then(BigReal::zero));
+        }
+        let scale = max(self.scale, scale);
+        // An error in the logarithm is multiplied by the exponent, and an error in the power of e
+        // is multiplied by the result, so work with extra digits for the integer parts of both.
+        let exponent_digits = (exponent.to_int().bits() / 3 + 1) as u32;
+        let Some(rough) = self.ln_interruptible(3, interrupt)? else {
+            return Ok(None);
+        };
+        let rough = (rough * exponent).to_u64().unwrap_or(0) + 1;
+        let result_digits = (rough as f64 * std::f64::consts::LOG10_E) as u32 + 1;
+        let work_scale = scale.saturating_add(result_digits).saturating_add(10);
+        let Some(ln) = self.ln_interruptible(work_scale.saturating_add(exponent_digits), interrupt)?
+        else {
+            return Ok(None);
+        };
+        let power = (&ln * exponent).change_shift(work_scale)
+            .exp_interruptible(work_scale, interrupt)?;
+        let nudge = BigReal::with_value(Int::Inline(1), scale.saturating_add(5));
+        Ok(Some((&power + &nudge).change_shift(scale)))
+    }
+
     pub fn sqrt(&self, scale: u32) -> Option<BigReal> {
+        match self.sqrt_interruptible(scale, Interrupt::NEVER) {
+            Ok(result) => result,
+            Err(Interrupted) => unreachable!(),
+        }
+    }
+
+    /// Like `sqrt`, but checking `interrupt` before each step.
+    pub fn sqrt_interruptible(&self, scale: u32, interrupt: Interrupt<'_>)
+        -> Result<Option<BigReal>, Interrupted>
+    {
         if self.is_negative() {
-            return None;
+            return Ok(None);
         }
 
-        let scale = ::std::cmp::max(self.shift, scale);
+        let scale = ::std::cmp::max(self.scale, scale);
 
         let mut x = self.clone();
-        let one_int = BigInt::one();
+        let one_int = Int::Inline(1);
         let two_real = BigReal::from(2);
 
         loop {
+            interrupt.check()?;
             let next = (&x + self.div(&x, scale)).div(&two_real, scale);
             let delta = (&x - &next).abs();
             x = next;
 
-            if !(delta.value - &one_int).is_positive() {
+            if delta.value <= one_int {
                 break;
             }
         }
 
-        Some(x)
+        Ok(Some(x))
+    }
+
+    /// The n-th root, where the fractional part of n is ignored, or `None` if n is zero or
+    /// doesn't fit in 32 bits, this is negative and n is even, this is zero and n is negative, or
+    /// n times the scale doesn't fit in 32 bits either. Like `sqrt`, the result has
+    /// `max(scale, scale(self))` fractional digits, truncated.
+    pub fn nth_root(&self, n: &BigReal, scale: u32) -> Option<BigReal> {
+        match self.nth_root_interruptible(n, scale, Interrupt::NEVER) {
+            Ok(result) => result,
+            Err(Interrupted) => unreachable!(),
+        }
+    }
+
+    /// Like `nth_root`, but checking `interrupt` before each step.
+    pub fn nth_root_interruptible(&self, n: &BigReal, scale: u32, interrupt: Interrupt<'_>)
+        -> Result<Option<BigReal>, Interrupted>
+    {
+        let n = n.to_int();
+        let Some(index) = n.abs().to_u32().filter(|&i| i != 0) else {
+            return Ok(None);
+        };
+        if (self.is_negative() && index % 2 == 0) || (self.is_zero() && n.is_negative()) {
+            return Ok(None);
+        }
+        let scale = max(self.scale, scale);
+
+        // Find the integer root of the number times 10^(n × scale), which is the result times
+        // 10^scale. For a negative n, take the root of 10^(n × scale) divided by the number
+        // instead. Rounding down before taking the root doesn't change the root rounded down.
+        let Some(digits) = index.checked_mul(scale) else {
+            return Ok(None);
+        };
+        let this = self.change_shift(self.shift.max(0));
+        let shift = places(this.shift);
+        let x = this.value.abs();
+        let radicand = if n.is_negative() {
+            let Some(places) = digits.checked_add(shift) else {
+                return Ok(None);
+            };
+            Int::Inline(1).mul_pow10(places).into_big() / x.into_big()
+        } else {
+            x.mul_pow10(digits - shift).into_big()
+        };
+        let root = int_nth_root(&radicand, index, interrupt)?;
+        let root = if self.is_negative() { -root } else { root };
+        Ok(Some(BigReal::with_value(Int::from_big(root), scale)))
+    }
+
+    /// Raise e to this power. Like `sqrt`, the result has `max(scale, scale(self))` fractional
+    /// digits, truncated.
+    pub fn exp(&self, scale: u32) -> BigReal {
+        match self.exp_interruptible(scale, Interrupt::NEVER) {
+            Ok(result) => result,
+            Err(Interrupted) => unreachable!(),
+        }
+    }
+
+    /// Like `exp`, but checking `interrupt` before each step.
+    pub fn exp_interruptible(&self, scale: u32, interrupt: Interrupt<'_>)
+        -> Result<BigReal, Interrupted>
+    {
+        let scale = max(self.scale, scale);
+        if self.is_negative() {
+            // Once e^x is below 10^-scale, which is when -x is more than scale × ln(10), it
+            // truncates to zero, so there's no need to work out e^-x, however big it is.
+            let magnitude = self.abs().to_u64().unwrap_or(u64::MAX);
+            if magnitude as f64 > f64::from(scale) * std::f64::consts::LN_10 + 1. {
+                return Ok(BigReal::with_value(Int::Inline(0), scale));
+            }
+            // e^-x is bigger than one, so with a few extra digits its reciprocal is exact.
+            let reciprocal = self.abs().exp_interruptible(scale.saturating_add(10), interrupt)?;
+            return Ok(BigReal::one().div(&reciprocal, scale));
+        }
+
+        // Halve the argument until it's well below one, so the power series converges quickly,
+        // then square the result back up. Halving it more means fewer terms but more squarings;
+        // about the square root of the number of digits each is the least work. Work with enough
+        // extra digits for the result's integer part (x × log10(e) of them), for the error
+        // doubling with each squaring, and to spare.
+        let whole = self.to_u64().unwrap_or(u64::MAX);
+        let int_digits = (whole as f64 * std::f64::consts::LOG10_E) as u64 + 1;
+        let extra = ((u64::from(scale) + int_digits) as f64).sqrt() as u32;
+        let halvings = 64 - whole.leading_zeros() + 1 + extra;
+        let work_scale = u64::from(scale) + int_digits + u64::from(halvings) + 10;
+        let work_scale = u32::try_from(work_scale).unwrap_or(u32::MAX);
+
+        let x = self.div(&BigReal::from(BigInt::one() << halvings), work_scale);
+        let mut sum = BigReal::one();
+        let mut term = BigReal::one();
+        let mut n = 1u32;
+        loop {
+            interrupt.check()?;
+            term = (&term * &x).div(&BigReal::from(n), work_scale);
+            if term.is_zero() {
+                break;
+            }
+            sum = &sum + &term;
+            n += 1;
+        }
+        for _ in 0 .. halvings {
+            interrupt.check()?;
+            sum = (&sum * &sum).change_shift(work_scale);
+        }
+        Ok(sum.change_shift(scale))
+    }
+
+    /// The natural logarithm, or `None` if this isn't positive. Like `exp`, the result has
+    /// `max(scale, scale(self))` fractional digits, truncated.
+    pub fn ln(&self, scale: u32) -> Option<BigReal> {
+        match self.ln_interruptible(scale, Interrupt::NEVER) {
+            Ok(result) => result,
+            Err(Interrupted) => unreachable!(),
+        }
+    }
+
+    /// Like `ln`, but checking `interrupt` before each step.
+    pub fn ln_interruptible(&self, scale: u32, interrupt: Interrupt<'_>)
+        -> Result<Option<BigReal>, Interrupted>
+    {
+        if !self.is_positive() {
+            return Ok(None);
+        }
+        let scale = max(self.scale, scale);
+
+        // Write the number as m × 2^k with m between 1 and 2, so that the series for ln(m)
+        // converges by about a digit per term, and ln(self) = ln(m) + k × ln(2). Work with extra
+        // digits for the error in ln(2) being multiplied by k, and to spare.
+        let bits = self.value.to_big().bits() as i64;
+        let mut k = bits - (self.shift as f64 * std::f64::consts::LOG2_10) as i64;
+        let k_digits = k.unsigned_abs().max(1).ilog10() + 1;
+        let work_scale = scale.saturating_add(k_digits + 10);
+
+        let power = BigReal::from(BigInt::one() << k.unsigned_abs());
+        let mut m = if k >= 0 {
+            self.div(&power, work_scale)
+        } else {
+            (self * &power).change_shift(work_scale)
+        };
+        let one = BigReal::one();
+        let two = BigReal::from(2);
+        while m >= two {
+            m = m.div(&two, work_scale);
+            k += 1;
+        }
+        while m < one {
+            m = (&m * &two).change_shift(work_scale);
+            k -= 1;
+        }
+
+        let ratio = (&m - &one).div(&(&m + &one), work_scale);
+        let mut result = ln_ratio(&ratio, work_scale, interrupt)?;
+        if k != 0 {
+            let ln2 = ln_ratio(&one.div(&BigReal::from(3), work_scale), work_scale, interrupt)?;
+            result = &result + &(&ln2 * &BigReal::from(k));
+        }
+        Ok(Some(result.change_shift(scale)))
+    }
+
+    /// The arctangent, in radians. Like `exp`, the result has `max(scale, scale(self))`
+    /// fractional digits, truncated.
+    pub fn atan(&self, scale: u32) -> BigReal {
+        match self.atan_interruptible(scale, Interrupt::NEVER) {
+            Ok(result) => result,
+            Err(Interrupted) => unreachable!(),
+        }
+    }
+
+    /// Like `atan`, but checking `interrupt` before each step.
+    pub fn atan_interruptible(&self, scale: u32, interrupt: Interrupt<'_>)
+        -> Result<BigReal, Interrupted>
+    {
+        let scale = max(self.scale, scale);
+        let work_scale = scale.saturating_add(10);
+        let one = BigReal::one();
+        let x = self.abs();
+        let result = if x > one {
+            // Big arguments would make the series diverge; use atan(x) = π/2 - atan(1/x).
+            let half_pi = &quarter_pi(work_scale, interrupt)? * &BigReal::from(2);
+            &half_pi - &atan_reduced(one.div(&x, work_scale), work_scale, interrupt)?
+        } else {
+            atan_reduced(x, work_scale, interrupt)?
+        };
+        let result = result.change_shift(scale);
+        Ok(if self.is_negative() {
+            &BigReal::zero() - &result
+        } else {
+            result
+        })
+    }
+
+    /// The logarithm in the given base, or `None` if this or the base isn't positive, or the base
+    /// is one. The result has `scale` fractional digits, truncated.
+    ///
+    /// The logarithms it's computed from are inexact, so an exact answer like log₁₀(1000) might
+    /// come out a hair below 3. To keep truncation from turning that into 2.999..., results
+    /// within a few digits past the last one of the next digit up are taken to be exact.
+    pub fn log(&self, base: &BigReal, scale: u32) -> Option<BigReal> {
+        match self.log_interruptible(base, scale, Interrupt::NEVER) {
+            Ok(result) => result,
+            Err(Interrupted) => unreachable!(),
+        }
+    }
+
+    /// Like `log`, but checking `interrupt` before each step.
+    pub fn log_interruptible(&self, base: &BigReal, scale: u32, interrupt: Interrupt<'_>)
+        -> Result<Option<BigReal>, Interrupted>
+    {
+        if *base == BigReal::one() {
+            return Ok(None);
+        }
+        // The closer the base is to one, the smaller its logarithm, and the more digits dividing
+        // by it loses.
+        let work_scale = scale.saturating_add(base.scale).saturating_add(10);
+        let (Some(ln), Some(base_ln)) = (self.ln_interruptible(work_scale, interrupt)?,
+            base.ln_interruptible(work_scale, interrupt)?) else
+        {
+            return Ok(None);
+        };
+        let quotient = ln.div(&base_ln, work_scale);
+        let sign = if quotient.is_negative() { -1 } else { 1 };
+        let nudge = BigReal::with_value(Int::Inline(sign), work_scale - 5);
+        Ok(Some((&quotient + &nudge).change_shift(scale)))
     }
 
     pub fn modexp(base: &BigReal, exponent: &BigReal, modulus: &BigReal, scale: u32)
             -> Option<BigReal> {
+        match BigReal::modexp_interruptible(base, exponent, modulus, scale, Interrupt::NEVER) {
+            Ok(result) => result,
+            Err(Interrupted) => unreachable!(),
+        }
+    }
+
+    /// Like `modexp`, but checking `interrupt` before each round.
+    pub fn modexp_interruptible(base: &BigReal, exponent: &BigReal, modulus: &BigReal,
+        scale: u32, interrupt: Interrupt<'_>) -> Result<Option<BigReal>, Interrupted>
+    {
         if exponent.is_negative() || modulus.is_zero() {
-            return None;
+            return Ok(None);
         }
 
         let one = BigReal::one();
         let two = BigReal::from(2);
 
         if (modulus - &one).is_zero() {
-            return Some(BigReal::zero());
+            return Ok(Some(BigReal::zero()));
         }
 
         let mut base = base.rem(modulus, 0);
         let mut exponent = exponent.change_shift(0);
         let mut result = one.clone();
         while !exponent.is_zero() {
+            interrupt.check()?;
             if (exponent.rem(&two, scale) - &one).is_zero() {
                 result = (result * &base).rem(modulus, 0);
             }
@@ -218,25 +1135,175 @@ impl BigReal {
             base = (&base * &base).rem(modulus, 0);
         }
 
-        Some(result)
+        Ok(Some(result))
+    }
+
+    /// Round to at most `scale` fractional digits. Numbers which already have no more than that
+    /// are unchanged.
+    pub fn round(&self, scale: u32, mode: RoundingMode) -> BigReal {
+        if self.scale <= scale {
+            return self.clone();
+        }
+        if self.shift <= i64::from(scale) {
+            // Only zeros would be dropped.
+            return BigReal { scale, ..self.clone() };
+        }
+        let divisor = BigInt::from(10u32).pow(places(self.shift - i64::from(scale)));
+        let (mut quotient, remainder) = self.value.to_big().div_rem(&divisor);
+        let away = match (remainder.magnitude() * 2u32).cmp(divisor.magnitude()) {
+            _ if mode == RoundingMode::Truncate => false,
+            Ordering::Less => false,
+            Ordering::Equal => mode == RoundingMode::HalfUp || quotient.is_odd(),
+            Ordering::Greater => true,
+        };
+        if away {
+            quotient += if remainder.is_negative() { -1 } else { 1 };
+        }
+        BigReal::with_value(Int::from_big(quotient), scale)
+    }
+
+    /// The greatest integer not greater than the number.
+    pub fn floor(&self) -> BigReal {
+        let int = self.to_int();
+        if self.is_negative() && self.has_fraction() {
+            BigReal::from(int - 1)
+        } else {
+            BigReal::from(int)
+        }
+    }
+
+    /// The least integer not less than the number.
+    pub fn ceil(&self) -> BigReal {
+        let int = self.to_int();
+        if self.is_positive() && self.has_fraction() {
+            BigReal::from(int + 1)
+        } else {
+            BigReal::from(int)
+        }
+    }
+
+    /// The integer part of the number, rounding toward zero.
+    pub fn trunc(&self) -> BigReal {
+        BigReal::from(self.to_int())
+    }
+
+    /// n!, checking `interrupt` as it goes.
+    pub fn factorial(n: u64, interrupt: Interrupt<'_>) -> Result<BigReal, Interrupted> {
+        if n < 2 {
+            return Ok(BigReal::one());
+        }
+        Ok(BigReal::from(range_product(2, n, interrupt)?))
+    }
+
+    /// The greatest common divisor of the two numbers' absolute values, or `None` if either has a
+    /// non-zero fractional part. gcd(0, n) is |n|, and gcd(0, 0) is 0.
+    pub fn gcd(&self, other: &BigReal) -> Option<BigReal> {
+        let (a, b) = self.integer_operands(other)?;
+        Some(BigReal::from(a.gcd(&b)))
+    }
+
+    /// The least common multiple of the two numbers' absolute values, or `None` if either has a
+    /// non-zero fractional part. lcm(0, n) is 0.
+    pub fn lcm(&self, other: &BigReal) -> Option<BigReal> {
+        let (a, b) = self.integer_operands(other)?;
+        if a.is_zero() || b.is_zero() {
+            return Some(BigReal::zero());
+        }
+        let gcd = a.gcd(&b);
+        Some(BigReal::from((a * b).abs() / gcd))
+    }
+
+    /// The bitwise AND of two nonnegative integers, or `None` if either is negative or has a
+    /// non-zero fractional part.
+    pub fn bitand(&self, other: &BigReal) -> Option<BigReal> {
+        let (a, b) = self.bitwise_operands(other)?;
+        Some(BigReal::from(a & b))
+    }
+
+    /// The bitwise OR of two nonnegative integers, as for `bitand`.
+    pub fn bitor(&self, other: &BigReal) -> Option<BigReal> {
+        let (a, b) = self.bitwise_operands(other)?;
+        Some(BigReal::from(a | b))
+    }
+
+    /// The bitwise exclusive OR of two nonnegative integers, as for `bitand`.
+    pub fn bitxor(&self, other: &BigReal) -> Option<BigReal> {
+        let (a, b) = self.bitwise_operands(other)?;
+        Some(BigReal::from(a ^ b))
+    }
+
+    /// The number times 2^n, or `None` if it has a non-zero fractional part.
+    pub fn shl(&self, n: u32) -> Option<BigReal> {
+        (!self.has_fraction()).then(|| BigReal::from(self.to_int() << n))
+    }
+
+    /// The number divided by 2^n and rounded down, or `None` if it has a non-zero fractional
+    /// part.
+    pub fn shr(&self, n: u32) -> Option<BigReal> {
+        (!self.has_fraction()).then(|| BigReal::from(self.to_int() >> n))
+    }
+
+    /// The inverse of the number modulo `modulus`: the x from 0 to `modulus` - 1 for which the
+    /// number times x is 1 more than a multiple of `modulus`. `None` if there isn't one, because
+    /// they have a common factor, or if either has a non-zero fractional part. Both should be
+    /// positive.
+    pub fn modinv(&self, modulus: &BigReal) -> Option<BigReal> {
+        let (a, m) = self.integer_operands(modulus)?;
+        let euclid = a.extended_gcd(&m);
+        euclid.gcd.is_one().then(|| BigReal::from(euclid.x.mod_floor(&m)))
+    }
+
+    /// Both numbers as integers, for the bitwise operations, if neither is negative.
+    fn bitwise_operands(&self, other: &BigReal) -> Option<(BigInt, BigInt)> {
+        if self.is_negative() || other.is_negative() {
+            return None;
+        }
+        self.integer_operands(other)
+    }
+
+    /// Both numbers as integers, for `gcd` and `lcm`, if neither has a non-zero fractional part.
+    fn integer_operands(&self, other: &BigReal) -> Option<(BigInt, BigInt)> {
+        if self.has_fraction() || other.has_fraction() {
+            return None;
+        }
+        Some((self.to_int(), other.to_int()))
     }
 
+    /// Whether the number has no fractional digits, not even zeros.
     pub fn is_integer(&self) -> bool {
-        self.shift == 0
+        self.scale == 0
+    }
+
+    /// Whether any of the number's fractional digits are non-zero.
+    pub fn has_fraction(&self) -> bool {
+        self.shift > 0 && !self.value.div_rem_pow10(places(self.shift)).1.is_zero()
+    }
+
+    /// Multiply, keeping min(scale(a) + scale(b), max(scale, scale(a), scale(b))) fractional
+    /// digits and truncating the rest. This is the scale dc documents for `*`.
+    pub fn mul_truncated(&self, rhs: &BigReal, scale: u32) -> BigReal {
+        let mut product = self * rhs;
+        let result_scale = product.scale.min(max(scale, max(self.scale, rhs.scale)));
+        if product.shift > i64::from(result_scale) {
+            product.change_shift(result_scale)
+        } else {
+            product.scale = result_scale;
+            product
+        }
     }
 
     // Our own implementations of Div and Rem, which need an extra "scale" argument:
 
-    fn adjust_for_div(&self, rhs: &BigReal, scale: u32) -> (BigInt, BigInt) {
+    fn adjust_for_div(&self, rhs: &BigReal, scale: u32) -> (Int, Int) {
         let max_shift = max(self.shift, rhs.shift);
-        let self_adj = self.change_shift(max_shift + scale).value;
+        let self_adj = self.change_shift(max_shift + i64::from(scale)).value;
         let rhs_adj = rhs.change_shift(max_shift).value;
         (self_adj, rhs_adj)
     }
 
     pub fn div(&self, rhs: &BigReal, scale: u32) -> BigReal {
         let (self_adj, rhs_adj) = self.adjust_for_div(rhs, scale);
-        BigReal::new(self_adj / rhs_adj, scale)
+        BigReal::with_value(self_adj.div(&rhs_adj), scale)
     }
 
     pub fn rem(&self, rhs: &BigReal, scale: u32) -> BigReal {
@@ -264,15 +1331,23 @@ impl BigReal {
     }
 
     pub fn abs(&self) -> BigReal {
-        BigReal::new(self.value.abs(), self.shift)
+        BigReal { value: self.value.abs(), ..*self }
+    }
+
+    /// How the number compares to zero.
+    fn sign(&self) -> Ordering {
+        if self.value.is_negative() {
+            Ordering::Less
+        } else if self.value.is_positive() {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
     }
 
     /// Return the number as a `BigInt`, with the fractional part truncated off.
     pub fn to_int(&self) -> BigInt {
-        let mut shifted = self.change_shift(0);
-        shifted.simplify();
-        assert_eq!(0, shifted.shift);
-        shifted.value
+        self.change_shift(0).value.into_big()
     }
 }
 
@@ -280,6 +1355,11 @@ impl PartialOrd for BigReal {
     fn partial_cmp(&self, rhs: &BigReal) -> Option<Ordering> {
         if self.shift == rhs.shift {
             self.value.partial_cmp(&rhs.value)
+        } else if rhs.is_zero() {
+            // Comparisons with zero, as in `0<x`, are common in loops, and the sign is enough.
+            Some(self.sign())
+        } else if self.is_zero() {
+            Some(rhs.sign().reverse())
         } else {
             let max_shift = max(self.shift, rhs.shift);
             let a = self.change_shift(max_shift);
@@ -293,6 +1373,8 @@ impl PartialEq for BigReal {
     fn eq(&self, rhs: &BigReal) -> bool {
         if self.shift == rhs.shift {
             self.value.eq(&rhs.value)
+        } else if self.is_zero() || rhs.is_zero() {
+            self.is_zero() && rhs.is_zero()
         } else {
             let max_shift = max(self.shift, rhs.shift);
             let a = self.change_shift(max_shift);
@@ -339,13 +1421,13 @@ macro_rules! bigreal_from_primitive {
     ($prim:ident) => {
         impl BigRealFrom<$prim> for BigReal {
             fn new(value: $prim, shift: u32) -> BigReal {
-                BigReal::new(BigInt::from(value), shift)
+                BigReal::with_value(Int::from_prim(value), shift)
             }
         }
 
         impl From<$prim> for BigReal {
             fn from(value: $prim) -> BigReal {
-                BigReal::new(BigInt::from(value), 0)
+                BigReal::with_value(Int::from_prim(value), 0)
             }
         }
     }
@@ -359,6 +1441,16 @@ impl ToPrimitive for BigReal {
     fn to_u64(&self) -> Option<u64> {
         self.change_shift(0).value.to_u64()
     }
+
+    /// The nearest `f64`, fractional part included, or `None` if the number is too big for one.
+    /// Numbers too small for one become zero.
+    fn to_f64(&self) -> Option<f64> {
+        // The standard library's parsing rounds correctly, however many digits it's given.
+        format!("{}e{}", self.value.to_str_radix(10), -self.shift)
+            .parse::<f64>()
+            .ok()
+            .filter(|f| f.is_finite())
+    }
 }
 
 impl FromPrimitive for BigReal {
@@ -369,6 +1461,25 @@ impl FromPrimitive for BigReal {
     fn from_u64(n: u64) -> Option<BigReal> {
         Some(BigReal::from(n))
     }
+
+    /// The shortest decimal which converts back to the same `f64`, rather than its exact binary
+    /// value: 0.1 becomes 0.1, not 0.1000000000000000055511151231257827021181583404541015625.
+    /// Infinities and NaN give `None`.
+    fn from_f64(n: f64) -> Option<BigReal> {
+        if !n.is_finite() {
+            return None;
+        }
+        // Rust's float formatting gives the shortest round-trip digits, e.g. "-1.5e-7".
+        let formatted = format!("{:e}", n);
+        let (mantissa, exponent) = formatted.split_once('e')?;
+        let exponent = exponent.parse::<i64>().ok()?;
+        let (int_part, frac_part) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let digits = format!("{int_part}{frac_part}").parse::<BigInt>().ok()?;
+        let shift = frac_part.len() as i64 - exponent;
+        let mut value = BigReal::with_value(Int::from_big(digits), shift);
+        value.trim();
+        Some(value)
+    }
 }
 
 bigreal_from_primitive!(u8);
@@ -384,19 +1495,13 @@ bigreal_from_primitive!(isize);
 
 impl BigRealFrom<BigInt> for BigReal {
     fn new(value: BigInt, shift: u32) -> BigReal {
-        BigReal {
-            shift,
-            value,
-        }
+        BigReal::with_value(Int::from_big(value), shift)
     }
 }
 
 impl From<BigInt> for BigReal {
     fn from(value: BigInt) -> BigReal {
-        BigReal {
-            shift: 0,
-            value,
-        }
+        BigReal::with_value(Int::from_big(value), 0)
     }
 }
 
@@ -453,8 +1558,8 @@ impl Add<&BigReal> for &BigReal {
     type Output = BigReal;
 
     fn add(self, rhs: &BigReal) -> BigReal {
-        if self.shift == rhs.shift {
-            BigReal::new(&self.value + &rhs.value, self.shift)
+        let mut sum = if self.shift == rhs.shift {
+            BigReal::with_value(self.value.add(&rhs.value), self.shift)
         }
         else {
             let (x, y): (&BigReal, &BigReal) = if self.shift > rhs.shift {
@@ -464,8 +1569,10 @@ impl Add<&BigReal> for &BigReal {
                 // adjust self
                 (rhs, self)
             };
-            BigReal::new(&x.value + y.change_shift(x.shift).value, x.shift)
-        }
+            BigReal::with_value(x.value.add(&y.change_shift(x.shift).value), x.shift)
+        };
+        sum.scale = max(self.scale, rhs.scale);
+        sum
     }
 }
 
@@ -476,7 +1583,7 @@ impl Sub<&BigReal> for &BigReal {
 
     #[inline]
     fn sub(self, rhs: &BigReal) -> BigReal {
-        self.add(BigReal::new(rhs.value.clone().neg(), rhs.shift))
+        self.add(BigReal { value: rhs.value.neg(), ..*rhs })
     }
 }
 
@@ -486,23 +1593,43 @@ impl Mul<&BigReal> for &BigReal {
     type Output = BigReal;
 
     fn mul(self, rhs: &BigReal) -> BigReal {
-        let value = &self.value * &rhs.value;
+        let value = self.value.mul(&rhs.value);
 
         #[allow(clippy::suspicious_arithmetic_impl)]
         let shift = self.shift + rhs.shift;
 
-        BigReal::new(value, shift)
+        #[allow(clippy::suspicious_arithmetic_impl)]
+        let scale = self.scale + rhs.scale;
+
+        BigReal { shift, value, scale }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::rng::Rng;
+
+    #[test]
+    fn test_num_digits() {
+        let mut rng = Rng::from_seed(527);
+        for k in 0 .. 400u32 {
+            let pow = BigInt::from(10u32).pow(k);
+            let random = rng.below(&pow) + 1u32;
+            let candidates = [&pow - 1u32, pow.clone(), &pow + 1u32, BigInt::from(1) << k, random];
+            for n in candidates.into_iter().flat_map(|n| [-&n, n]) {
+                let int = Int::from_big(n.clone());
+                assert_eq!(int.num_digits(), n.abs().to_string().len() as u64, "{n}");
+                // Either way of holding it gives the same count.
+                assert_eq!(Int::Big(n.clone()).num_digits(), int.num_digits(), "{n}");
+            }
+        }
+    }
 
     #[test]
     fn test_new() {
         let n = BigReal::new(1234, 5);
-        assert_eq!(n.value, BigInt::from(1234));
+        assert_eq!(n.value.to_big().as_ref(), &BigInt::from(1234));
         assert_eq!(n.shift, 5);
     }
 
@@ -603,6 +1730,7 @@ mod test {
         assert_eq!(a.to_str_radix(10), "1.100");
         assert_eq!(a.to_str_radix(16), "1.199");
         assert_eq!(a.to_str_radix(2), "1.0001100110");
+        assert_eq!(BigReal::new(-1234, 2).to_str_radix(16), "-c.57");
     }
 
     #[test]
@@ -613,6 +1741,228 @@ mod test {
         assert!(a == b);
         assert_eq!(b.shift, 1);
         assert_eq!(b.value.to_str_radix(10), "11");
+
+        // The scale is unchanged, and so is everything derived from it.
+        assert_eq!(b.num_frx_digits(), 3);
+        assert_eq!(b.num_digits(), 4);
+        assert_eq!(b.to_str_radix(10), "1.100");
+        assert_eq!(b.to_str_radix(16), "1.199");
+        assert!(!b.is_integer());
+
+        let mut d = BigReal::new(BigInt::from(123) * BigInt::from(10).pow(45), 50);
+        d.simplify();
+        assert_eq!((d.shift, d.value.to_str_radix(10).as_str()), (5, "123"));
+        let mut zero = BigReal::new(0, 3);
+        zero.simplify();
+        assert_eq!(zero.shift, 0);
+        assert_eq!(zero.to_str_radix(10), ".000");
+
+        let mut c = BigReal::new(50, 3); // 0.050
+        c.simplify();
+        assert_eq!(c.num_digits(), 2);
+        assert_eq!((&c + &BigReal::new(1, 1)).to_str_radix(10), ".150");
+        assert_eq!((&c * &c).to_str_radix(10), ".002500");
+        assert_eq!(c.pow(&BigReal::from(2), 0).to_str_radix(10), ".002");
+        assert_eq!(c.sqrt(0).unwrap().to_str_radix(10), ".223");
+    }
+
+    #[test]
+    fn test_negative_shift() {
+        let mut a = BigReal::from(1_500_000);
+        a.simplify();
+        assert_eq!((a.shift, a.value.to_str_radix(10).as_str()), (-5, "15"));
+        assert_eq!(a.to_str_radix(10), "1500000");
+        assert_eq!(a.to_str_radix(16), "16e360");
+        assert_eq!(a.num_digits(), 7);
+        assert!(a.is_integer() && !a.has_fraction());
+        assert_eq!(a.to_int(), BigInt::from(1_500_000));
+        assert_eq!(a.to_f64(), Some(1.5e6));
+        assert!(a == BigReal::from(1_500_000));
+        assert!(a > BigReal::new(1_499_999_999, 3));
+
+        let half = BigReal::new(5, 1);
+        assert_eq!((&a + &half).to_str_radix(10), "1500000.5");
+        assert_eq!((&a - &BigReal::from(1)).to_str_radix(10), "1499999");
+        assert_eq!((&a * &half).to_str_radix(10), "750000.0");
+        assert_eq!(a.div(&BigReal::from(7), 2).to_str_radix(10), "214285.71");
+        assert_eq!(BigReal::from(7).div(&a, 9).to_str_radix(10), ".000004666");
+        assert_eq!(a.rem(&BigReal::from(7), 0).to_str_radix(10), "5");
+        assert_eq!(a.sqrt(0).unwrap().to_str_radix(10), "1224");
+        assert_eq!(a.nth_root(&BigReal::from(-2), 5).unwrap().to_str_radix(10), ".00081");
+        assert_eq!(a.round(0, RoundingMode::HalfUp).to_str_radix(10), "1500000");
+        assert_eq!(a.to_scientific(3, false), "1.50e+06");
+
+        // Powers of ten keep their zeros out of the value.
+        let power = BigReal::from(10).pow(&BigReal::from(1000), 0);
+        assert_eq!((power.shift, power.value.to_str_radix(10).as_str()), (-1000, "1"));
+        assert_eq!(power.num_digits(), 1001);
+        assert_eq!(power.shift_decimal(3).shift, -1003);
+        assert_eq!(BigReal::new(25, 1).shift_decimal(4).value.to_str_radix(10), "25");
+
+        let mut hashes = std::collections::HashSet::new();
+        hashes.insert(a.clone());
+        assert!(hashes.contains(&BigReal::from(1_500_000)));
+    }
+
+    #[test]
+    fn test_large_counts() {
+        // Counts of things (digits, string lengths, stack depth) can exceed 32 bits.
+        let len = u64::from(u32::MAX) + 1;
+        assert_eq!(BigReal::from(len).to_str_radix(10), "4294967296");
+        assert_eq!(BigReal::from(usize::MAX).to_str_radix(10), usize::MAX.to_string());
+        assert_eq!(BigReal::new(1, u32::MAX).num_frx_digits(), u64::from(u32::MAX));
+    }
+
+    /// Numbers which are small, near the limits of an i64, or too big for one.
+    fn random_real(rng: &mut Rng) -> BigReal {
+        let n = rng.next_u64() as i64;
+        let value = match rng.next_u64() % 4 {
+            0 => BigInt::from(n % 1000),
+            1 => BigInt::from(i64::MAX - (n % 1000).abs()),
+            2 => BigInt::from(i64::MIN + (n % 1000).abs()),
+            _ => BigInt::from(n) * BigInt::from(rng.next_u64()),
+        };
+        let value = if rng.next_u64().is_multiple_of(2) { value } else { -value };
+        BigReal::new(value, (rng.next_u64() % 5) as u32)
+    }
+
+    /// The same number, but with its value in a BigInt even if it fits inline.
+    fn big(n: &BigReal) -> BigReal {
+        BigReal { value: Int::Big(n.value.to_big().into_owned()), ..*n }
+    }
+
+    fn hash(n: &BigReal) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        n.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_write_radix() {
+        let mut rng = Rng::from_seed(463);
+        for _ in 0 .. 1000 {
+            let n = random_real(&mut rng);
+            for radix in [2, 10, 16] {
+                let mut out = vec![];
+                n.write_radix(radix, &mut out).unwrap();
+                assert_eq!(out, n.to_str_radix(radix).to_uppercase().into_bytes(), "{n:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_str_radix_prefix() {
+        let mut rng = Rng::from_seed(486);
+        let mut numbers = (0 .. 300).map(|_| random_real(&mut rng)).collect::<Vec<_>>();
+        numbers.push(BigReal::from(0));
+        numbers.push(BigReal::new(BigInt::from(-5), 40));
+        numbers.push(BigReal::new(BigInt::from(10u32).pow(100) - 1u32, 3));
+        for n in &numbers {
+            for radix in [2, 7, 10, 16] {
+                let full = n.to_str_radix(radix);
+                for max_len in [0, 1, 2, 5, 20, 40, 1000] {
+                    let (prefix, len) = n.to_str_radix_prefix(radix, max_len);
+                    assert_eq!(len, full.len() as u64, "{n:?} in radix {radix}");
+                    assert_eq!(prefix, full[.. full.len().min(max_len)],
+                        "{n:?} in radix {radix}, up to {max_len}");
+                }
+            }
+        }
+
+        // The rest of a huge number isn't formatted.
+        let huge = BigReal::new(BigInt::from(7u32).pow(300_000), 5);
+        let (prefix, len) = huge.to_str_radix_prefix(10, 10);
+        assert_eq!(prefix, "2582285621");
+        assert_eq!(len, 253_531);
+    }
+
+    #[test]
+    fn test_int_nth_root() {
+        let mut rng = Rng::from_seed(501);
+        for _ in 0 .. 300 {
+            let factor = BigInt::from(rng.next_u64()).pow(rng.next_u64() as u32 % 8);
+            let x = random_real(&mut rng).to_int().abs() * factor;
+            for n in [1, 2, 3, 7, 64, 1000] {
+                let root = int_nth_root(&x, n, Interrupt::NEVER).unwrap();
+                assert!(root.pow(n) <= x && (&root + 1u32).pow(n) > x, "{n}-th root of {x}");
+            }
+        }
+        assert_eq!(int_nth_root(&BigInt::from(10u32).pow(3000), 1000, Interrupt::NEVER),
+            Ok(BigInt::from(1000)));
+
+        let stop = || true;
+        assert_eq!(int_nth_root(&BigInt::from(1000), 3, Interrupt::new(&stop)), Err(Interrupted));
+        assert_eq!(BigReal::from(2).exp_interruptible(10, Interrupt::new(&stop)), Err(Interrupted));
+    }
+
+    #[test]
+    fn test_inline_matches_big() {
+        let mut rng = Rng::from_seed(0x2545_f491_4f6c_dd1d);
+        for _ in 0 .. 2000 {
+            let a = random_real(&mut rng);
+            let b = random_real(&mut rng);
+            let scale = (rng.next_u64() % 5) as u32;
+            let check = |op: &str, inline: BigReal, big: BigReal| {
+                let context = format!("{op} on {a:?} and {b:?}");
+                assert_eq!(inline.to_str_radix(10), big.to_str_radix(10), "{context}");
+                assert_eq!(inline.to_str_radix(16), big.to_str_radix(16), "{context}");
+                assert_eq!((inline.shift, inline.scale), (big.shift, big.scale), "{context}");
+                assert_eq!(inline, big, "{context}");
+                assert_eq!(hash(&inline), hash(&big), "{context}");
+                // Results are kept inline whenever they fit.
+                let fits = inline.value.to_big().to_i64().is_some();
+                assert_eq!(matches!(inline.value, Int::Inline(_)), fits, "{context}");
+            };
+
+            check("+", &a + &b, &big(&a) + &big(&b));
+            check("-", &a - &b, &big(&a) - &big(&b));
+            check("*", &a * &b, &big(&a) * &big(&b));
+            if !b.is_zero() {
+                check("/", a.div(&b, scale), big(&a).div(&big(&b), scale));
+                check("%", a.rem(&b, scale), big(&a).rem(&big(&b), scale));
+            }
+            let exponent = BigReal::from(rng.next_u64() % 5);
+            check("^", a.pow(&exponent, scale), big(&a).pow(&exponent, scale));
+            if !a.is_zero() {
+                check("v", a.abs().sqrt(scale).unwrap(), big(&a.abs()).sqrt(scale).unwrap());
+            }
+            check("abs", a.abs(), big(&a).abs());
+            check("shift", a.change_shift(scale), big(&a).change_shift(scale));
+            let (mut simple, mut simple_big) = (a.clone(), big(&a));
+            simple.simplify();
+            simple_big.simplify();
+            check("simplify", simple, simple_big);
+
+            assert_eq!(a.partial_cmp(&b), big(&a).partial_cmp(&big(&b)));
+            assert_eq!(a.partial_cmp(&b), a.partial_cmp(&big(&b)));
+            assert_eq!(a.num_digits(), big(&a).num_digits());
+            assert_eq!(a.to_int(), big(&a).to_int());
+            assert_eq!(a.to_i64(), big(&a).to_i64());
+            assert_eq!(a.to_u64(), big(&a).to_u64());
+            assert_eq!(hash(&a), hash(&big(&a)));
+        }
+    }
+
+    #[test]
+    fn test_compare_zero() {
+        // Comparisons with zero skip rescaling; check them against comparing rescaled values.
+        let mut rng = Rng::from_seed(0x9e37_79b9_7f4a_7c15);
+        for i in 0 .. 2000 {
+            let a = if i % 10 == 0 {
+                BigReal::new(0, (rng.next_u64() % 5) as u32)
+            } else {
+                random_real(&mut rng)
+            };
+            let zero = BigReal::new(0, (rng.next_u64() % 5) as u32);
+            let shift = max(a.shift, zero.shift);
+            let (a_shifted, zero_shifted) = (a.change_shift(shift), zero.change_shift(shift));
+            let expected = a_shifted.value.cmp(&zero_shifted.value);
+            assert_eq!(a.partial_cmp(&zero), Some(expected), "{a:?} vs {zero:?}");
+            assert_eq!(zero.partial_cmp(&a), Some(expected.reverse()), "{zero:?} vs {a:?}");
+            assert_eq!(a == zero, expected == Ordering::Equal, "{a:?} == {zero:?}");
+            assert_eq!(zero == a, expected == Ordering::Equal, "{zero:?} == {a:?}");
+            assert_eq!(big(&a).partial_cmp(&zero), Some(expected), "{a:?} vs {zero:?}");
+        }
     }
 
     #[test]
@@ -622,4 +1972,231 @@ mod test {
         let x = base.pow(&exp, 2);
         assert_eq!(x.to_str_radix(10), "1");
     }
+
+    #[test]
+    fn test_pow_scale() {
+        let base = BigReal::new(15, 1); // 1.5
+        let x = base.pow(&BigReal::from(30), 0);
+        assert_eq!(x.num_frx_digits(), 1);
+        assert_eq!(x.to_str_radix(10), "191751.0");
+
+        let x = base.pow(&BigReal::from(3), 100);
+        assert_eq!(x.to_str_radix(10), "3.375");
+    }
+
+    #[test]
+    fn test_round() {
+        let round = |value, shift, scale, mode| {
+            BigReal::new(value, shift).round(scale, mode).to_str_radix(10)
+        };
+        use RoundingMode::*;
+        // 2.005 is exact in decimal, so it's exactly halfway.
+        assert_eq!(round(2005, 3, 2, HalfUp), "2.01");
+        assert_eq!(round(2005, 3, 2, HalfEven), "2.00");
+        assert_eq!(round(2005, 3, 2, Truncate), "2.00");
+        assert_eq!(round(-2005, 3, 2, HalfUp), "-2.01");
+        assert_eq!(round(-2015, 3, 2, HalfEven), "-2.02");
+        assert_eq!(round(-2006, 3, 2, HalfEven), "-2.01");
+        assert_eq!(round(-2004, 3, 2, HalfUp), "-2.00");
+        assert_eq!(round(-9999, 3, 0, HalfUp), "-10");
+        // Nothing to drop.
+        assert_eq!(round(15, 1, 2, HalfUp), "1.5");
+        let mut trimmed = BigReal::new(1500, 3);
+        trimmed.simplify();
+        assert_eq!(trimmed.round(2, HalfUp).to_str_radix(10), "1.50");
+    }
+
+    #[test]
+    fn test_gcd() {
+        let gcd = |a: i64, b: i64| {
+            BigReal::from(a).gcd(&BigReal::from(b)).unwrap().to_str_radix(10)
+        };
+        assert_eq!(gcd(12, 18), "6");
+        assert_eq!(gcd(-12, 18), "6");
+        assert_eq!(gcd(-12, -18), "6");
+        assert_eq!(gcd(0, -7), "7");
+        assert_eq!(gcd(7, 0), "7");
+        assert_eq!(gcd(0, 0), "0");
+        assert_eq!(gcd(17, 5), "1");
+        assert!(BigReal::new(15, 1).gcd(&BigReal::from(3)).is_none());
+        assert_eq!(BigReal::new(30, 1).gcd(&BigReal::from(6)).unwrap().to_str_radix(10), "3");
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let n = BigReal::from;
+        assert_eq!(n(12).bitand(&n(10)).unwrap().to_str_radix(10), "8");
+        assert_eq!(n(12).bitor(&n(10)).unwrap().to_str_radix(10), "14");
+        assert_eq!(n(12).bitxor(&n(10)).unwrap().to_str_radix(10), "6");
+        assert_eq!(BigReal::new(1200, 2).bitor(&n(1)).unwrap().to_str_radix(10), "13");
+        assert!(n(-1).bitand(&n(1)).is_none());
+        assert!(n(1).bitor(&BigReal::new(15, 1)).is_none());
+    }
+
+    #[test]
+    fn test_shift() {
+        let n = BigReal::from;
+        assert_eq!(n(5).shl(3).unwrap().to_str_radix(10), "40");
+        assert_eq!(n(-5).shl(1).unwrap().to_str_radix(10), "-10");
+        assert_eq!(n(40).shr(3).unwrap().to_str_radix(10), "5");
+        assert_eq!(n(5).shr(1).unwrap().to_str_radix(10), "2");
+        assert_eq!(n(-5).shr(1).unwrap().to_str_radix(10), "-3");
+        assert_eq!(n(-1).shr(100).unwrap().to_str_radix(10), "-1");
+        assert_eq!(BigReal::new(500, 2).shl(1).unwrap().to_str_radix(10), "10");
+        assert!(BigReal::new(55, 1).shr(1).is_none());
+    }
+
+    #[test]
+    fn test_to_str_radix_large_scale() {
+        // The fractional digits, by long division one digit at a time, as they used to be done.
+        fn long_division(n: &BigReal, radix: u32) -> String {
+            let max_place = BigInt::from(10u32).pow(n.scale);
+            let mut part = n.change_shift(n.scale).value.into_big() % &max_place * radix;
+            let mut place = BigInt::from(radix);
+            let mut digits = String::new();
+            loop {
+                let (digit, rest) = part.div_rem(&max_place);
+                digits.push_str(&digit.to_str_radix(radix));
+                part = rest * radix;
+                if place >= max_place {
+                    return digits;
+                }
+                place *= radix;
+            }
+        }
+
+        let third = BigReal::from(1).div(&BigReal::from(3), 5000);
+        let hex = third.to_str_radix(16);
+        assert_eq!(hex.len(), 4154);
+        assert_eq!(hex, format!(".{}", long_division(&third, 16)));
+
+        let n = BigReal::new(BigInt::from(10).pow(4000) * 3 + 7, 4000).div(&BigReal::from(7), 5000);
+        for radix in [2, 3, 8, 16] {
+            assert_eq!(n.to_str_radix(radix), format!(".{}", long_division(&n, radix)),
+                "radix {radix}");
+        }
+    }
+
+    #[test]
+    fn test_change_shift() {
+        // Reducing the shift truncates toward zero, like dc does.
+        assert_eq!(BigReal::new(-1999, 3).change_shift(1).to_str_radix(10), "-1.9");
+        assert_eq!(BigReal::new(1999, 3).change_shift(0).to_str_radix(10), "1");
+
+        // Big differences are done in one go, and round trip.
+        let n = BigReal::new(12345, 2);
+        let wide = n.change_shift(10_000);
+        assert_eq!(wide.num_frx_digits(), 10_000);
+        assert_eq!(wide.num_digits(), 10_003);
+        assert!(wide == n);
+        assert_eq!(wide.change_shift(2).to_str_radix(10), "123.45");
+        assert!(wide.change_shift(9_999) == n);
+    }
+
+    #[test]
+    fn test_modinv() {
+        let n = BigReal::from;
+        assert_eq!(n(3).modinv(&n(7)).unwrap().to_str_radix(10), "5");
+        assert_eq!(n(10).modinv(&n(7)).unwrap().to_str_radix(10), "5");
+        assert_eq!(n(1).modinv(&n(1)).unwrap().to_str_radix(10), "0");
+        assert_eq!(n(65537).modinv(&n(3120)).unwrap().to_str_radix(10), "2753");
+        assert!(n(4).modinv(&n(8)).is_none());
+        assert!(BigReal::new(35, 1).modinv(&n(7)).is_none());
+    }
+
+    #[test]
+    fn test_to_scientific() {
+        let sci = |n: BigReal, digits| n.to_scientific(digits, false);
+        let eng = |n: BigReal, digits| n.to_scientific(digits, true);
+        assert_eq!(sci(BigReal::new(12345678, 3), 4), "1.235e+04");
+        assert_eq!(sci(BigReal::new(12344999, 3), 4), "1.234e+04");
+        assert_eq!(sci(BigReal::new(1, 20), 4), "1.000e-20");
+        assert_eq!(sci(BigReal::new(-6666666666i64, 10), 3), "-6.67e-01");
+        assert_eq!(sci(BigReal::new(99996, 4), 4), "1.000e+01");
+        assert_eq!(sci(BigReal::new(5, 0), 1), "5e+00");
+        assert_eq!(sci(BigReal::new(95, 0), 1), "1e+02");
+        assert_eq!(sci(BigReal::new(0, 5), 3), "0.00e+00");
+        assert_eq!(sci(BigReal::new(7, 0), 0), "7e+00");
+        assert_eq!(sci(BigReal::from(BigInt::from(2).pow(1000)), 5), "1.0715e+301");
+
+        assert_eq!(eng(BigReal::new(12345678, 3), 4), "12.35e+03");
+        assert_eq!(eng(BigReal::new(123, 6), 3), "123e-06");
+        assert_eq!(eng(BigReal::new(1, 20), 3), "10.0e-21");
+        assert_eq!(eng(BigReal::new(-12345, 0), 1), "-10e+03");
+        assert_eq!(eng(BigReal::new(999996, 3), 4), "1.000e+03");
+        assert_eq!(eng(BigReal::new(0, 0), 3), "0.00e+00");
+    }
+
+    #[test]
+    fn test_f64() {
+        let from = |f| BigReal::from_f64(f).unwrap().to_str_radix(10);
+        assert_eq!(from(0.1), ".1");
+        assert_eq!(from(-2.5), "-2.5");
+        assert_eq!(from(-0.0), "0");
+        assert_eq!(from(1e21), "1000000000000000000000");
+        assert_eq!(from(1.5e-7), ".00000015");
+        assert!(BigReal::from_f64(f64::NAN).is_none());
+        assert!(BigReal::from_f64(f64::INFINITY).is_none());
+
+        for f in [0.0, 0.1, -0.1, 1.0 / 3.0, 123456.789, 1e300, -1e300, f64::MAX, f64::MIN_POSITIVE,
+            5e-324, 2.2250738585072e-308, f64::EPSILON]
+        {
+            assert_eq!(BigReal::from_f64(f).unwrap().to_f64(), Some(f), "{f:e}");
+        }
+
+        // The fraction isn't truncated away, unlike to_i64.
+        assert_eq!(BigReal::new(15, 1).to_f64(), Some(1.5));
+        assert_eq!(BigReal::new(-15, 1).to_i64(), Some(-1));
+        // Too big for an f64; too small rounds to zero.
+        let big = BigReal::from(BigInt::from(10).pow(400));
+        assert_eq!(big.to_f64(), None);
+        assert_eq!(BigReal::new(1, 400).to_f64(), Some(0.0));
+    }
+
+    #[test]
+    fn test_factorial() {
+        let factorial = |n| BigReal::factorial(n, Interrupt::NEVER).unwrap().to_str_radix(10);
+        assert_eq!(factorial(0), "1");
+        assert_eq!(factorial(1), "1");
+        assert_eq!(factorial(5), "120");
+        assert_eq!(factorial(20), "2432902008176640000");
+        // Enough to be split up a few times.
+        let product = (1 ..= 60u32).fold(BigInt::one(), |product, i| product * i);
+        assert_eq!(factorial(60), product.to_string());
+    }
+
+    #[test]
+    fn test_lcm() {
+        let lcm = |a: i64, b: i64| {
+            BigReal::from(a).lcm(&BigReal::from(b)).unwrap().to_str_radix(10)
+        };
+        assert_eq!(lcm(4, 6), "12");
+        assert_eq!(lcm(-4, 6), "12");
+        assert_eq!(lcm(-4, -6), "12");
+        assert_eq!(lcm(0, 5), "0");
+        assert_eq!(lcm(5, 0), "0");
+        assert_eq!(lcm(0, 0), "0");
+        assert_eq!(lcm(7, 7), "7");
+        assert!(BigReal::from(3).lcm(&BigReal::new(15, 1)).is_none());
+    }
+
+    #[test]
+    fn test_floor_ceil_trunc() {
+        let cases = [
+            // value, shift, floor, ceil, trunc
+            (15, 1, "1", "2", "1"),
+            (-15, 1, "-2", "-1", "-1"),
+            (-5, 1, "-1", "0", "0"),
+            (0, 0, "0", "0", "0"),
+            (7, 0, "7", "7", "7"),
+            (-7, 0, "-7", "-7", "-7"),
+            (-300, 2, "-3", "-3", "-3"),
+        ];
+        for (value, shift, floor, ceil, trunc) in cases {
+            let n = BigReal::new(value, shift);
+            assert_eq!(n.floor().to_str_radix(10), floor, "floor {value} {shift}");
+            assert_eq!(n.ceil().to_str_radix(10), ceil, "ceil {value} {shift}");
+            assert_eq!(n.trunc().to_str_radix(10), trunc, "trunc {value} {shift}");
+        }
+    }
 }
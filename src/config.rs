@@ -0,0 +1,327 @@
+//
+// dc4 :: config file
+//
+// This is part of the dc4 program, not the library. It reads the settings file, which is written
+// in a small subset of TOML: `key = value` lines, with strings, integers, and booleans.
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use dc4::Dc4;
+use dc4::parser::Flavor;
+
+use crate::{DiagnosticsFormat, StrictInputs};
+
+/// Settings which can be given in the config file or on the command line. Each key in the config
+/// file has the same name as a field here. Unset ones take their defaults.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Settings {
+    pub flavor: Option<Flavor>,
+    pub diagnostics: Option<DiagnosticsFormat>,
+    pub warn_once: Option<bool>,
+    pub verbose_errors: Option<bool>,
+    pub tail_call_optimization: Option<bool>,
+    pub echo: Option<bool>,
+    pub strict: Option<StrictInputs>,
+    pub strict_digits: Option<bool>,
+    pub exact_fractions: Option<bool>,
+    pub scientific_notation: Option<bool>,
+    pub max_output: Option<u64>,
+}
+
+impl Settings {
+    /// Combine with settings which take precedence over these, like ones from the command line.
+    pub fn overridden_by(self, other: Settings) -> Settings {
+        Settings {
+            flavor: other.flavor.or(self.flavor),
+            diagnostics: other.diagnostics.or(self.diagnostics),
+            warn_once: other.warn_once.or(self.warn_once),
+            verbose_errors: other.verbose_errors.or(self.verbose_errors),
+            tail_call_optimization: other.tail_call_optimization.or(self.tail_call_optimization),
+            echo: other.echo.or(self.echo),
+            strict: other.strict.or(self.strict),
+            strict_digits: other.strict_digits.or(self.strict_digits),
+            exact_fractions: other.exact_fractions.or(self.exact_fractions),
+            scientific_notation: other.scientific_notation.or(self.scientific_notation),
+            max_output: other.max_output.or(self.max_output),
+        }
+    }
+
+    pub fn flavor(&self) -> Flavor {
+        self.flavor.unwrap_or_default()
+    }
+
+    pub fn diagnostics(&self) -> DiagnosticsFormat {
+        self.diagnostics.unwrap_or_default()
+    }
+
+    /// Make a new dc with these settings.
+    pub fn build(&self, program_name: String) -> Dc4 {
+        let mut dc = Dc4::with_flavor(program_name, self.flavor());
+        // Unlike the library, the program only warns once about each kind of problem by default.
+        dc.set_warn_once(self.warn_once.unwrap_or(true));
+        if let Some(enabled) = self.verbose_errors {
+            dc.set_verbose_errors(enabled);
+        }
+        if let Some(enabled) = self.tail_call_optimization {
+            dc.set_tail_call_optimization(enabled);
+        }
+        if let Some(enabled) = self.strict_digits {
+            dc.set_strict_digits(enabled);
+        }
+        if let Some(enabled) = self.exact_fractions {
+            dc.set_exact_fractions(enabled);
+        }
+        if let Some(enabled) = self.scientific_notation {
+            dc.set_scientific_notation(enabled);
+        }
+        if let Some(max) = self.max_output {
+            dc.set_max_output_bytes(Some(max));
+        }
+        if self.echo == Some(true) {
+            dc.set_echo(io::stderr());
+            dc.set_track_provenance(true);
+        }
+        dc
+    }
+}
+
+/// The contents of a config file.
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    pub settings: Settings,
+    /// Problems which don't stop the file from being used, like unknown keys.
+    pub warnings: Vec<String>,
+}
+
+/// Where to look for a config file if none is given: `~/.config/dc4/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| Path::new(&home).join(".config/dc4/config.toml"))
+}
+
+/// Read a config file. If it's not `required`, a missing file is the same as an empty one.
+pub fn load(path: &Path, required: bool) -> Result<Config, String> {
+    match fs::read_to_string(path) {
+        Ok(text) => parse(&text),
+        Err(e) if !required && e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(format!("can't read config file: {e}")),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "a string",
+            Value::Int(_) => "an integer",
+            Value::Bool(_) => "a boolean",
+        }
+    }
+}
+
+/// Parse the text of a config file. Error and warning messages say which line they're about.
+pub fn parse(text: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut seen = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line_num = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(format!("line {line_num}: tables are not supported"));
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {line_num}: expected 'key = value'"));
+        };
+        let key = key.trim();
+        if key.is_empty()
+            || !key.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+        {
+            return Err(format!("line {line_num}: invalid key {key:?}"));
+        }
+        let value = parse_value(value.trim())
+            .ok_or_else(|| format!("line {line_num}: invalid value for '{key}'"))?;
+        if seen.contains(&key) {
+            return Err(format!("line {line_num}: '{key}' is set more than once"));
+        }
+        seen.push(key);
+        let settings = &mut config.settings;
+        let result = match key {
+            "flavor" => string_value(value, Flavor::from_name,
+                "'gnu', 'bsd', or 'gavin'").map(|v| settings.flavor = Some(v)),
+            "diagnostics" => string_value(value, DiagnosticsFormat::from_name,
+                "'text' or 'json'").map(|v| settings.diagnostics = Some(v)),
+            "warn_once" => bool_value(value).map(|v| settings.warn_once = Some(v)),
+            "verbose_errors" => bool_value(value).map(|v| settings.verbose_errors = Some(v)),
+            "tail_call_optimization" =>
+                bool_value(value).map(|v| settings.tail_call_optimization = Some(v)),
+            "echo" => bool_value(value).map(|v| settings.echo = Some(v)),
+            "strict" => match value {
+                Value::Bool(true) => Ok(StrictInputs::ALL),
+                Value::Bool(false) => Ok(StrictInputs::default()),
+                other => string_value(other, StrictInputs::from_names,
+                    "a comma-separated list of 'expressions', 'files', and 'stdin'"),
+            }.map(|v| settings.strict = Some(v)),
+            "strict_digits" => bool_value(value).map(|v| settings.strict_digits = Some(v)),
+            "exact_fractions" => bool_value(value).map(|v| settings.exact_fractions = Some(v)),
+            "scientific_notation" =>
+                bool_value(value).map(|v| settings.scientific_notation = Some(v)),
+            "max_output" => count_value(value).map(|v| settings.max_output = Some(v)),
+            _ => {
+                config.warnings.push(format!("line {line_num}: unknown key '{key}'"));
+                Ok(())
+            }
+        };
+        result.map_err(|msg| format!("line {line_num}: '{key}' {msg}"))?;
+    }
+    Ok(config)
+}
+
+fn string_value<T>(value: Value, lookup: impl Fn(&str) -> Option<T>, expected: &str)
+    -> Result<T, String>
+{
+    match value {
+        Value::Str(s) => lookup(&s).ok_or_else(|| format!("must be {expected}, not {s:?}")),
+        other => Err(format!("must be a string, not {}", other.type_name())),
+    }
+}
+
+fn bool_value(value: Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(b),
+        other => Err(format!("must be a boolean, not {}", other.type_name())),
+    }
+}
+
+fn count_value(value: Value) -> Result<u64, String> {
+    match value {
+        Value::Int(n) => {
+            u64::try_from(n).map_err(|_| format!("must be a nonnegative integer, not {n}"))
+        }
+        other => Err(format!("must be an integer, not {}", other.type_name())),
+    }
+}
+
+/// Parse a value, and any comment following it.
+fn parse_value(text: &str) -> Option<Value> {
+    let (value, rest) = if let Some(quoted) = text.strip_prefix('"') {
+        let mut s = String::new();
+        let mut chars = quoted.char_indices();
+        loop {
+            match chars.next()? {
+                (i, '"') => break (Value::Str(s), &quoted[i + 1 ..]),
+                (_, '\\') => s.push(match chars.next()?.1 {
+                    '"' => '"',
+                    '\\' => '\\',
+                    'n' => '\n',
+                    't' => '\t',
+                    _ => return None,
+                }),
+                (_, c) => s.push(c),
+            }
+        }
+    } else if let Some(quoted) = text.strip_prefix('\'') {
+        let (s, rest) = quoted.split_once('\'')?;
+        (Value::Str(s.to_owned()), rest)
+    } else {
+        let end = text.find(|c: char| c.is_whitespace() || c == '#').unwrap_or(text.len());
+        let (word, rest) = text.split_at(end);
+        let value = match word {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => Value::Int(word.replace('_', "").parse().ok()?),
+        };
+        (value, rest)
+    };
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fixture() {
+        let config = parse(include_str!("../tests/fixtures/config.toml")).unwrap();
+        assert_eq!(config.settings, Settings {
+            flavor: Some(Flavor::Bsd),
+            diagnostics: Some(DiagnosticsFormat::Json),
+            warn_once: None,
+            verbose_errors: Some(true),
+            tail_call_optimization: Some(false),
+            echo: None,
+            strict: None,
+            strict_digits: None,
+            exact_fractions: None,
+            scientific_notation: None,
+            max_output: None,
+        });
+        assert_eq!(config.warnings, vec!["line 12: unknown key 'line_length'".to_owned()]);
+
+        // Command line flags win.
+        let cli = Settings { flavor: Some(Flavor::Gavin), ..Settings::default() };
+        let effective = config.settings.overridden_by(cli);
+        assert_eq!(effective.flavor(), Flavor::Gavin);
+        assert_eq!(effective.diagnostics(), DiagnosticsFormat::Json);
+        assert_eq!(effective.verbose_errors, Some(true));
+    }
+
+    #[test]
+    fn test_strict() {
+        let strict = |text| parse(text).map(|config| config.settings.strict);
+        assert_eq!(strict("strict = true"), Ok(Some(StrictInputs::ALL)));
+        assert_eq!(strict("strict = false"), Ok(Some(StrictInputs::default())));
+        assert_eq!(strict("strict = 'files,stdin'"),
+            Ok(Some(StrictInputs { expressions: false, files: true, stdin: true })));
+        assert_eq!(strict("strict = 'all'"), Err("line 1: 'strict' must be a comma-separated \
+            list of 'expressions', 'files', and 'stdin', not \"all\"".to_owned()));
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(parse_value(r#""a \"b\"\n" # comment"#),
+            Some(Value::Str("a \"b\"\n".to_owned())));
+        assert_eq!(parse_value(r"'C:\dc4'"), Some(Value::Str(r"C:\dc4".to_owned())));
+        assert_eq!(parse_value("-1_000"), Some(Value::Int(-1000)));
+        assert_eq!(parse_value("false#"), Some(Value::Bool(false)));
+        assert_eq!(parse_value("\"unterminated"), None);
+        assert_eq!(parse_value("yes"), None);
+        assert_eq!(parse_value("true false"), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse("\nflavor = 3\n"),
+            Err("line 2: 'flavor' must be a string, not an integer".to_owned()));
+        assert_eq!(parse("flavor = \"vax\""),
+            Err("line 1: 'flavor' must be 'gnu', 'bsd', or 'gavin', not \"vax\"".to_owned()));
+        assert_eq!(parse("warn_once = \"yes\""),
+            Err("line 1: 'warn_once' must be a boolean, not a string".to_owned()));
+        assert_eq!(parse("warn_once = yes"),
+            Err("line 1: invalid value for 'warn_once'".to_owned()));
+        assert_eq!(parse("max_output = -1"),
+            Err("line 1: 'max_output' must be a nonnegative integer, not -1".to_owned()));
+        assert_eq!(parse("warn_once = true\nwarn_once = false"),
+            Err("line 2: 'warn_once' is set more than once".to_owned()));
+        assert_eq!(parse("[dc4]"), Err("line 1: tables are not supported".to_owned()));
+        assert_eq!(parse("flavor"), Err("line 1: expected 'key = value'".to_owned()));
+    }
+}
@@ -0,0 +1,218 @@
+//
+// dc4 :: standard output on Windows consoles
+//
+// dc programs can print any bytes at all. On Unix, they reach the terminal unchanged, but on
+// Windows the standard library refuses to write anything but UTF-8 to a console. So when
+// standard output is a console, text is written with WriteConsoleW, and anything which isn't
+// UTF-8 is written as raw bytes, for the console to show in its code page. When standard output
+// is redirected, bytes are written unchanged, just as on Unix.
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::io;
+
+/// A piece of output, as split up by [`segments`].
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(not(windows), allow(dead_code))] // only used for writing to a Windows console
+pub enum Segment<'a> {
+    /// Valid UTF-8.
+    Text(&'a str),
+    /// A run of bytes which aren't part of any UTF-8 character.
+    Binary(&'a [u8]),
+}
+
+/// Split output into text and binary. A UTF-8 character cut off at the end, which may be
+/// finished by the next write, is left out; the second value returned is how many bytes it
+/// has so far.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn segments(bytes: &[u8]) -> (Vec<Segment<'_>>, usize) {
+    let mut segments = vec![];
+    // Where the run of binary being built up started.
+    let mut binary = None;
+    let mut pos = 0;
+    loop {
+        let (valid_len, error) = match std::str::from_utf8(&bytes[pos ..]) {
+            Ok(text) => (text.len(), None),
+            Err(e) => (e.valid_up_to(), e.error_len()),
+        };
+        if valid_len != 0 {
+            if let Some(start) = binary.take() {
+                segments.push(Segment::Binary(&bytes[start .. pos]));
+            }
+            let text = std::str::from_utf8(&bytes[pos .. pos + valid_len]).unwrap();
+            segments.push(Segment::Text(text));
+            pos += valid_len;
+        }
+        match error {
+            Some(len) => {
+                binary.get_or_insert(pos);
+                pos += len;
+            }
+            None => {
+                if let Some(start) = binary {
+                    segments.push(Segment::Binary(&bytes[start .. pos]));
+                }
+                return (segments, bytes.len() - pos);
+            }
+        }
+    }
+}
+
+/// Standard output, for running programs.
+#[cfg(not(windows))]
+pub fn stdout() -> io::Stdout {
+    io::stdout()
+}
+
+/// Standard output, for running programs, written through the console API if it's a console.
+#[cfg(windows)]
+pub fn stdout() -> windows::Stdout {
+    windows::Stdout::open()
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::c_void;
+    use std::io::{self, Write};
+    use std::os::windows::io::{AsRawHandle, RawHandle};
+    use std::ptr;
+
+    use super::{segments, Segment};
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetConsoleMode(console: RawHandle, mode: *mut u32) -> i32;
+        fn WriteConsoleW(console: RawHandle, buffer: *const u16, len: u32, written: *mut u32,
+            reserved: *mut c_void) -> i32;
+        fn WriteFile(file: RawHandle, buffer: *const u8, len: u32, written: *mut u32,
+            overlapped: *mut c_void) -> i32;
+    }
+
+    /// Whether the handle is a console, rather than a file or pipe.
+    pub fn is_console(handle: RawHandle) -> bool {
+        let mut mode = 0;
+        // SAFETY: it only writes to mode, and fails for handles which aren't consoles.
+        unsafe { GetConsoleMode(handle, &mut mode) != 0 }
+    }
+
+    /// Standard output, written through the console API when it's a console.
+    pub struct Stdout {
+        stdout: io::Stdout,
+        console: Option<RawHandle>,
+        /// The start of a UTF-8 character, waiting for the rest of it.
+        pending: Vec<u8>,
+    }
+
+    impl Stdout {
+        pub(super) fn open() -> Self {
+            let stdout = io::stdout();
+            let handle = stdout.as_raw_handle();
+            let console = is_console(handle).then_some(handle);
+            Stdout { stdout, console, pending: vec![] }
+        }
+    }
+
+    fn write_text(handle: RawHandle, text: &str) -> io::Result<()> {
+        let wide = text.encode_utf16().collect::<Vec<u16>>();
+        let mut rest = &wide[..];
+        while !rest.is_empty() {
+            let mut written = 0;
+            let len = u32::try_from(rest.len()).unwrap_or(u32::MAX);
+            // SAFETY: the buffer is valid for len units.
+            let ok = unsafe {
+                WriteConsoleW(handle, rest.as_ptr(), len, &mut written, ptr::null_mut())
+            };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            rest = &rest[written as usize ..];
+        }
+        Ok(())
+    }
+
+    fn write_bytes(handle: RawHandle, bytes: &[u8]) -> io::Result<()> {
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let mut written = 0;
+            let len = u32::try_from(rest.len()).unwrap_or(u32::MAX);
+            // SAFETY: the buffer is valid for len bytes.
+            let ok = unsafe {
+                WriteFile(handle, rest.as_ptr(), len, &mut written, ptr::null_mut())
+            };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            rest = &rest[written as usize ..];
+        }
+        Ok(())
+    }
+
+    impl Write for Stdout {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let Some(handle) = self.console else {
+                return self.stdout.write(buf);
+            };
+            // Anything printed with println! has to come out first.
+            self.stdout.flush()?;
+            self.pending.extend_from_slice(buf);
+            let pending = std::mem::take(&mut self.pending);
+            let (segments, incomplete) = segments(&pending);
+            for segment in segments {
+                match segment {
+                    Segment::Text(text) => write_text(handle, text)?,
+                    Segment::Binary(bytes) => write_bytes(handle, bytes)?,
+                }
+            }
+            self.pending = pending[pending.len() - incomplete ..].to_vec();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stdout.flush()
+        }
+    }
+
+    impl Drop for Stdout {
+        fn drop(&mut self) {
+            // The rest of the character is never coming, so it's binary after all.
+            if let Some(handle) = self.console {
+                let _ = write_bytes(handle, &self.pending);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_is_console() {
+            let path = std::env::temp_dir().join(format!("dc4-console-{}", std::process::id()));
+            let file = std::fs::File::create(&path).unwrap();
+            assert!(!is_console(file.as_raw_handle()));
+            drop(file);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_segments() {
+        use Segment::*;
+        assert_eq!(segments(b""), (vec![], 0));
+        assert_eq!(segments(b"hello"), (vec![Text("hello")], 0));
+        assert_eq!(segments("caf\u{e9}!".as_bytes()), (vec![Text("caf\u{e9}!")], 0));
+        assert_eq!(segments(b"ab\xfe\xffcd\x80"),
+            (vec![Text("ab"), Binary(b"\xfe\xff"), Text("cd"), Binary(b"\x80")], 0));
+        assert_eq!(segments(b"\xff\x00"), (vec![Binary(b"\xff"), Text("\0")], 0));
+        // A character cut off at the end is held back...
+        assert_eq!(segments(b"ab\xe2\x82"), (vec![Text("ab")], 2));
+        // ...but one cut off in the middle is binary.
+        assert_eq!(segments(b"\xe2\x82ab"), (vec![Binary(b"\xe2\x82"), Text("ab")], 0));
+    }
+}
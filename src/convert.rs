@@ -0,0 +1,109 @@
+//
+// dc4 conversions out of stack values
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::convert::Infallible;
+use std::fmt;
+
+use num_traits::ToPrimitive;
+
+use crate::{DcString, DcValue};
+
+/// A value which couldn't be converted to the type wanted, as from [`Dc4::drain_results`].
+///
+/// The value is kept here, since it has already been taken off the stack.
+///
+/// [`Dc4::drain_results`]: crate::Dc4::drain_results
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConversionError {
+    /// The value which couldn't be converted.
+    pub value: DcValue,
+    /// The name of the type it couldn't be converted to.
+    pub wanted: &'static str,
+}
+
+impl ConversionError {
+    fn new(value: DcValue, wanted: &'static str) -> Self {
+        Self { value, wanted }
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            DcValue::Num(n) => write!(f, "can't convert {} to {}", n.to_str_radix(10), self.wanted),
+            DcValue::Str(_) => write!(f, "can't convert a string to {}", self.wanted),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Converting a value to itself can't fail.
+impl From<Infallible> for ConversionError {
+    fn from(never: Infallible) -> Self {
+        match never {}
+    }
+}
+
+/// Integers convert if they fit; numbers with a fractional part don't.
+impl TryFrom<DcValue> for i64 {
+    type Error = ConversionError;
+
+    fn try_from(value: DcValue) -> Result<Self, Self::Error> {
+        match &value {
+            DcValue::Num(n) if !n.has_fraction() => n.to_i64(),
+            _ => None,
+        }.ok_or_else(|| ConversionError::new(value, "i64"))
+    }
+}
+
+/// Integers convert if they fit; numbers with a fractional part don't.
+impl TryFrom<DcValue> for u64 {
+    type Error = ConversionError;
+
+    fn try_from(value: DcValue) -> Result<Self, Self::Error> {
+        match &value {
+            DcValue::Num(n) if !n.has_fraction() => n.to_u64(),
+            _ => None,
+        }.ok_or_else(|| ConversionError::new(value, "u64"))
+    }
+}
+
+/// Numbers convert to the nearest `f64`, unless they're too big for one.
+impl TryFrom<DcValue> for f64 {
+    type Error = ConversionError;
+
+    fn try_from(value: DcValue) -> Result<Self, Self::Error> {
+        match &value {
+            DcValue::Num(n) => n.to_f64(),
+            DcValue::Str(_) => None,
+        }.ok_or_else(|| ConversionError::new(value, "f64"))
+    }
+}
+
+/// Strings convert if they're valid UTF-8. Numbers don't: use
+/// [`Dc4::print_value`](crate::Dc4::print_value) to format them.
+impl TryFrom<DcValue> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: DcValue) -> Result<Self, Self::Error> {
+        match &value {
+            DcValue::Str(s) => std::str::from_utf8(s).ok().map(str::to_owned),
+            DcValue::Num(_) => None,
+        }.ok_or_else(|| ConversionError::new(value, "String"))
+    }
+}
+
+impl TryFrom<DcValue> for DcString {
+    type Error = ConversionError;
+
+    fn try_from(value: DcValue) -> Result<Self, Self::Error> {
+        match value {
+            DcValue::Str(s) => Ok(s),
+            num => Err(ConversionError::new(num, "DcString")),
+        }
+    }
+}
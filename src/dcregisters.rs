@@ -1,17 +1,20 @@
 //
 // dc4 registers
 //
-// Copyright (c) 2015-2021 by William R. Fraser
+// Copyright (c) 2015-2024 by William R. Fraser
 //
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::rc::Rc;
+use num_bigint::BigInt;
 use num_traits::Zero;
 use crate::big_real::BigReal;
+use crate::provenance::Tag;
 use crate::DcValue;
 
 const MAX_REGISTER: usize = 255;
 
+#[derive(Clone)]
 pub struct DcRegisters {
     registers: Vec<DcRegisterStack>,
 }
@@ -36,6 +39,20 @@ impl DcRegisters {
     }
 }
 
+/// One register, as a stack of levels, following GNU dc's model:
+///
+/// * Each level has a value, or none if the level was only made to hold an array, and an array.
+/// * `s` sets the value of the top level and keeps its array, making a level with an empty array
+///   if there are none.
+/// * `S` pushes a new level with the value and an empty array.
+/// * `L` pops the top level, returning its value and discarding its array. If the top level has
+///   no value, it's an error and the register is left as it was.
+/// * `l` reads the top level's value, which is an error if it has none.
+/// * `:` and `;` use the top level's array, and `:` makes a level with no value if there are
+///   none.
+///
+/// The register tests in `tests/testlib.rs` go through the orderings of these.
+#[derive(Clone)]
 pub struct DcRegisterStack {
     stack: Vec<DcRegister>,
 }
@@ -54,6 +71,16 @@ impl DcRegisterStack {
         }
     }
 
+    /// The tag of the register's value, if provenance was being tracked when it was stored.
+    pub fn tag(&self) -> Option<&Tag> {
+        self.stack.last()?.main_tag.as_ref()
+    }
+
+    /// How many levels the register's stack has.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
     pub fn array_store(&mut self, key: BigReal, value: DcValue) {
         if self.stack.is_empty() {
             self.stack.push(DcRegister::new(None));
@@ -71,40 +98,92 @@ impl DcRegisterStack {
         }
     }
 
-    pub fn set(&mut self, value: DcValue) {
-        if !self.stack.is_empty() {
-            self.stack.pop();
+    /// The array at the top level of the register, if there is one.
+    pub fn array(&self) -> Option<&BTreeMap<BigInt, Rc<DcValue>>> {
+        self.stack.last().map(|reg| &reg.map)
+    }
+
+    /// Replace the whole array at the top level of the register.
+    pub fn set_array(&mut self, map: BTreeMap<BigInt, Rc<DcValue>>) {
+        if self.stack.is_empty() {
+            self.stack.push(DcRegister::new(None));
+        }
+        self.stack.last_mut().unwrap().map = map;
+    }
+
+    /// Set the value of the top level, keeping its array.
+    pub fn set(&mut self, value: DcValue, tag: Option<Tag>) {
+        match self.stack.last_mut() {
+            Some(reg) => {
+                reg.main_value = Some(value);
+                reg.main_tag = tag;
+            }
+            None => self.push(value, tag),
         }
-        self.stack.push(DcRegister::new(Some(value)));
     }
 
-    pub fn pop(&mut self) -> Option<DcValue> {
-        self.stack.pop().and_then(|v| v.main_value)
+    /// Pop the top level and return its value. If it has no value, nothing is popped.
+    pub fn pop(&mut self) -> Option<(DcValue, Option<Tag>)> {
+        self.stack.last()?.main_value.as_ref()?;
+        let reg = self.stack.pop().unwrap();
+        Some((reg.main_value.unwrap(), reg.main_tag))
+    }
+
+    /// Discard the top level, whether it has a value or not.
+    pub fn drop_level(&mut self) {
+        self.stack.pop();
+    }
+
+    /// Push a copy of the top level, value and array, so that popping it puts the register back
+    /// the way it was.
+    pub fn save_level(&mut self) {
+        let level = self.stack.last().cloned().unwrap_or_else(|| DcRegister::new(None));
+        self.stack.push(level);
     }
 
-    pub fn push(&mut self, value: DcValue) {
-        self.stack.push(DcRegister::new(Some(value)))
+    pub fn push(&mut self, value: DcValue, tag: Option<Tag>) {
+        self.stack.push(DcRegister { main_tag: tag, ..DcRegister::new(Some(value)) })
     }
 }
 
+#[derive(Clone)]
 pub struct DcRegister {
     pub main_value: Option<DcValue>,
-    pub map: HashMap<BigReal, Rc<DcValue>>,
+    pub main_tag: Option<Tag>,
+    /// The register's array. Indexes are truncated to integers, like GNU dc does, and iterating
+    /// over it goes in ascending order of index.
+    pub map: BTreeMap<BigInt, Rc<DcValue>>,
 }
 
 impl DcRegister {
     pub fn new(value: Option<DcValue>) -> DcRegister {
         DcRegister {
             main_value: value,
-            map: HashMap::new(),
+            main_tag: None,
+            map: BTreeMap::new(),
         }
     }
 
     pub fn map_lookup(&self, key: &BigReal) -> Option<&Rc<DcValue>> {
-        self.map.get(key)
+        self.map.get(&key.to_int())
     }
 
     pub fn map_insert(&mut self, key: BigReal, value: DcValue) {
-        self.map.insert(key, Rc::new(value));
+        self.map.insert(key.to_int(), Rc::new(value));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_array_order() {
+        let mut reg = DcRegister::new(None);
+        for key in [10, 2, 33, 0, 7] {
+            reg.map_insert(BigReal::from(key), DcValue::Num(BigReal::from(key * 2)));
+        }
+        let keys = reg.map.keys().cloned().collect::<Vec<_>>();
+        assert_eq!(keys, [0, 2, 7, 10, 33].map(BigInt::from));
     }
 }
@@ -0,0 +1,183 @@
+//
+// DcString :: a cheaply cloneable and sliceable byte string
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::rc::Rc;
+
+/// A string value, as stored on the stack and in registers.
+///
+/// Strings are often large blobs of data or macro text which get loaded, duplicated and executed
+/// many times, so the bytes are kept in a shared buffer: cloning a `DcString` never copies them,
+/// and neither does slicing one, unless the slice is short.
+#[derive(Clone)]
+pub struct DcString {
+    buf: Rc<Vec<u8>>,
+    start: usize,
+    len: usize,
+    /// The name of the input the string was defined in, if known.
+    origin: Option<Rc<str>>,
+}
+
+/// Slices no longer than this are copied instead of sharing the buffer, so that a few bytes taken
+/// from a large string don't keep all of it alive.
+const SMALL_SLICE: usize = 32;
+
+impl DcString {
+    /// Make a new string holding the given bytes.
+    pub fn new(bytes: Vec<u8>) -> Self {
+        let len = bytes.len();
+        Self { buf: Rc::new(bytes), start: 0, len, origin: None }
+    }
+
+    /// Make a new string using a buffer which may be shared with other strings.
+    pub(crate) fn from_shared(buf: Rc<Vec<u8>>) -> Self {
+        let len = buf.len();
+        Self { buf, start: 0, len, origin: None }
+    }
+
+    pub(crate) fn with_origin(mut self, origin: Option<Rc<str>>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub(crate) fn origin(&self) -> Option<&Rc<str>> {
+        self.origin.as_ref()
+    }
+
+    /// The contents of the string.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[self.start .. self.start + self.len]
+    }
+
+    /// Get a view of part of this string, without copying it unless it's short.
+    ///
+    /// Panics if the range is out of bounds, like slice indexing does.
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        assert!(range.start <= range.end && range.end <= self.len,
+            "range {range:?} out of bounds for string of length {}", self.len);
+        if range.end - range.start <= SMALL_SLICE {
+            return Self::from(&self.as_bytes()[range]).with_origin(self.origin.clone());
+        }
+        Self {
+            buf: Rc::clone(&self.buf),
+            start: self.start + range.start,
+            len: range.end - range.start,
+            origin: self.origin.clone(),
+        }
+    }
+
+    /// Get the contents as an owned vector. This only copies if the buffer is shared with another
+    /// string, or if this is a slice of it.
+    pub fn into_vec(self) -> Vec<u8> {
+        if self.start == 0 && self.len == self.buf.len() {
+            Rc::try_unwrap(self.buf).unwrap_or_else(|buf| buf.as_ref().clone())
+        } else {
+            self.as_bytes().to_vec()
+        }
+    }
+}
+
+impl Deref for DcString {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsRef<[u8]> for DcString {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl From<Vec<u8>> for DcString {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl From<&[u8]> for DcString {
+    fn from(bytes: &[u8]) -> Self {
+        Self::new(bytes.to_vec())
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for DcString {
+    fn from(bytes: &[u8; N]) -> Self {
+        Self::new(bytes.to_vec())
+    }
+}
+
+impl From<&str> for DcString {
+    fn from(s: &str) -> Self {
+        Self::new(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for DcString {
+    fn from(s: String) -> Self {
+        Self::new(s.into_bytes())
+    }
+}
+
+impl PartialEq for DcString {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for DcString {}
+
+impl fmt::Debug for DcString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_slice() {
+        let s = DcString::from("hello ".repeat(10) + "world");
+        let long = s.slice(6 .. 65);
+        assert_eq!(long.as_bytes(), &s.as_bytes()[6 .. 65]);
+        assert!(Rc::ptr_eq(&s.buf, &long.buf));
+        let world = long.slice(54 .. 59);
+        assert_eq!(world.as_bytes(), b"world");
+        assert_eq!(world.slice(1 .. 3).as_bytes(), b"or");
+        assert_eq!(s.slice(5 .. 5).as_bytes(), b"");
+        // Short slices get their own buffer.
+        assert!(!Rc::ptr_eq(&s.buf, &world.buf));
+        assert_eq!(world.buf.len(), 5);
+        assert_eq!(world, DcString::from("world"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_slice_out_of_bounds() {
+        DcString::from("hello").slice(2 .. 6);
+    }
+
+    #[test]
+    fn test_into_vec() {
+        let s = DcString::from("hello");
+        let ptr = s.as_bytes().as_ptr();
+        // Not shared: the buffer is reused.
+        let v = s.into_vec();
+        assert_eq!(v.as_ptr(), ptr);
+
+        let s = DcString::from("hello");
+        let t = s.clone();
+        assert_eq!(s.into_vec(), b"hello");
+        assert_eq!(t.slice(1 .. 4).into_vec(), b"ell");
+        let long = "hello ".repeat(10);
+        let s = DcString::from(long.as_str());
+        assert_eq!(s.slice(1 .. 60).into_vec(), &long.as_bytes()[1 .. 60]);
+    }
+}
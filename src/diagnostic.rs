@@ -0,0 +1,187 @@
+//
+// dc4 diagnostics: errors and warnings in a form other programs can consume.
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::fmt::{self, Write};
+
+/// How bad a diagnostic is. Neither kind stops the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+/// The location of a byte in an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// Byte offset from the start of the input, starting at 0.
+    pub offset: u64,
+    /// Line number, starting at 1.
+    pub line: u64,
+    /// Byte offset from the start of the line, starting at 1.
+    pub column: u64,
+}
+
+impl Position {
+    /// The position of the first byte of an input.
+    pub fn start() -> Self {
+        Self { offset: 0, line: 1, column: 1 }
+    }
+
+    /// The position of the byte following `c`, which is at this position.
+    pub(crate) fn next(self, c: u8) -> Self {
+        if c == b'\n' {
+            Self { offset: self.offset + 1, line: self.line + 1, column: 1 }
+        } else {
+            Self { offset: self.offset + 1, column: self.column + 1, ..self }
+        }
+    }
+}
+
+/// An error or warning emitted while running a program.
+///
+/// # Order
+///
+/// A command can give several warnings, and an error, and they always come in the same order, so
+/// that output can be compared with what was expected:
+///
+/// * Warnings about a command's operands come in the order the operands were pushed. For `|`,
+///   that's the base, then the exponent, then the modulus.
+/// * An error comes last, after any warnings. There's at most one per command.
+/// * Operands are checked before anything is warned about, so a command which rejects its
+///   operands, like `|` with a zero modulus or a string, gives only the error. A command which
+///   accepts them and then fails, like by being [cancelled](crate::CancelHandle), gives its
+///   warnings, then the error.
+///
+/// Any command given warnings in future, like for a fractional radix or scale, will keep to this.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// A short identifier for the kind of problem, which won't change between versions, e.g.
+    /// "stack_empty".
+    pub code: &'static str,
+    /// The message as it would be printed, without the program name prefix.
+    pub message: String,
+    /// Where in the input the command that caused it was. This is the top-level command: for
+    /// problems inside a macro, it's where the macro was run from, unless the macro was defined
+    /// in a different input, in which case this is `None`.
+    pub position: Option<Position>,
+    /// The name of the input, as given to [`Dc4::stream_named`](crate::Dc4::stream_named) or
+    /// [`Dc4::text_named`](crate::Dc4::text_named). For problems in a macro, this is the input the
+    /// macro was defined in, if known.
+    pub input: Option<String>,
+    /// For errors inside a macro, the part of the macro's text around the command which caused
+    /// it. Only given with [`Dc4::set_verbose_errors`](crate::Dc4::set_verbose_errors).
+    pub excerpt: Option<Excerpt>,
+}
+
+impl Diagnostic {
+    /// The version of the JSON schema emitted by [`to_json`](Self::to_json).
+    pub const JSON_VERSION: u32 = 1;
+
+    /// Format as a single-line JSON object. Unknown fields are `null`, except for the excerpt,
+    /// which is left out when there isn't one.
+    pub fn to_json(&self) -> String {
+        let mut json = format!("{{\"version\":{},\"severity\":\"{}\",\"code\":\"{}\",\"message\":",
+            Self::JSON_VERSION, self.severity, self.code);
+        write_json_str(&mut json, &self.message);
+        json += ",\"input\":";
+        match &self.input {
+            Some(name) => write_json_str(&mut json, name),
+            None => json += "null",
+        }
+        match self.position {
+            Some(pos) => write!(json, ",\"offset\":{},\"line\":{},\"column\":{}",
+                pos.offset, pos.line, pos.column).unwrap(),
+            None => json += ",\"offset\":null,\"line\":null,\"column\":null",
+        }
+        if let Some(excerpt) = &self.excerpt {
+            json += ",\"excerpt\":{\"text\":";
+            write_json_str(&mut json, &excerpt.text);
+            write!(json, ",\"caret\":{}}}", excerpt.caret).unwrap();
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// A short piece of the text of a macro, around a command in it, for pointing out the command
+/// like a compiler does. [`Display`](fmt::Display) shows it that way, as two lines:
+///
+/// ```text
+/// 1 2 + * 3 +
+///       ^
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Excerpt {
+    /// The text, with control characters and bytes which aren't ASCII escaped, like `\n` and
+    /// `\xff`, and `...` at either end if it was cut short there.
+    pub text: String,
+    /// How many characters into `text` the last character of the command is.
+    pub caret: usize,
+}
+
+impl Excerpt {
+    /// How many bytes of text are shown on each side of the command.
+    const CONTEXT: usize = 20;
+
+    /// The excerpt of `text` around the byte at `index`.
+    pub(crate) fn new(text: &[u8], index: usize) -> Self {
+        let start = index.saturating_sub(Self::CONTEXT);
+        let end = text.len().min(index + Self::CONTEXT + 1);
+        let mut excerpt = String::new();
+        if start > 0 {
+            excerpt += "...";
+        }
+        let mut caret = 0;
+        for (i, &byte) in text[start .. end].iter().enumerate() {
+            if start + i == index {
+                caret = excerpt.chars().count();
+            }
+            match byte {
+                b'\n' => excerpt += "\\n",
+                b'\r' => excerpt += "\\r",
+                b'\t' => excerpt += "\\t",
+                b' ' ..= b'~' => excerpt.push(byte as char),
+                _ => write!(excerpt, "\\x{byte:02x}").unwrap(),
+            }
+        }
+        if end < text.len() {
+            excerpt += "...";
+        }
+        Self { text: excerpt, caret }
+    }
+}
+
+impl fmt::Display for Excerpt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n{:caret$}^", self.text, "", caret = self.caret)
+    }
+}
+
+fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
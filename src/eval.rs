@@ -0,0 +1,92 @@
+//
+// dc4 one-shot evaluation
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+use crate::Dc4;
+use crate::diagnostic::{Diagnostic, Severity};
+
+/// Run a program on a new interpreter with the default settings, and return what it printed.
+///
+/// This is for when all that's wanted is the answer to a calculation, such as in a build script.
+/// Byte output that isn't valid UTF-8 is converted lossily. The `?` command reads nothing.
+///
+/// Any error or warning makes this fail, since either usually means the answer is wrong; see
+/// [`eval_once_tolerating_warnings`] to only fail on errors.
+///
+/// ```
+/// assert_eq!(dc4::eval_once("20k 2v p").unwrap(), "1.41421356237309504880\n");
+///
+/// let err = dc4::eval_once("1 0/p").unwrap_err();
+/// assert_eq!(err.to_string(), "divide by zero");
+/// assert_eq!(err.diagnostics[0].code, "divide_by_zero");
+/// ```
+pub fn eval_once(program: &str) -> Result<String, EvalError> {
+    eval(program, false)
+}
+
+/// Like [`eval_once`], but only errors make it fail: warnings are ignored.
+///
+/// ```
+/// assert!(dc4::eval_once("4 13.9 497 |p").is_err());
+/// assert_eq!(dc4::eval_once_tolerating_warnings("4 13.9 497 |p").unwrap(), "445\n");
+/// ```
+pub fn eval_once_tolerating_warnings(program: &str) -> Result<String, EvalError> {
+    eval(program, true)
+}
+
+fn eval(program: &str, tolerate_warnings: bool) -> Result<String, EvalError> {
+    let diagnostics = Rc::new(RefCell::new(Vec::<Diagnostic>::new()));
+    let mut dc = Dc4::new("dc4".to_owned());
+    let sink = Rc::clone(&diagnostics);
+    dc.set_diagnostic_handler(move |diag| {
+        if diag.severity == Severity::Error || !tolerate_warnings {
+            sink.borrow_mut().push(diag.clone());
+        }
+    });
+    dc.set_line_reader(io::empty());
+
+    let mut out = Vec::new();
+    dc.text_into(program.as_bytes().to_vec(), &mut out);
+    let output = String::from_utf8_lossy(&out).into_owned();
+    drop(dc);
+
+    let diagnostics = Rc::try_unwrap(diagnostics).expect("handler still alive").into_inner();
+    if diagnostics.is_empty() {
+        Ok(output)
+    } else {
+        Err(EvalError { diagnostics, output })
+    }
+}
+
+/// The problems with a program run by [`eval_once`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalError {
+    /// The errors and warnings emitted, in order. There is always at least one.
+    pub diagnostics: Vec<Diagnostic>,
+    /// What the program printed anyway.
+    pub output: String,
+}
+
+impl fmt::Display for EvalError {
+    /// The first diagnostic's message, and how many more there were.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let first = &self.diagnostics[0];
+        if first.severity == Severity::Warning {
+            f.write_str("warning: ")?;
+        }
+        f.write_str(&first.message)?;
+        match self.diagnostics.len() {
+            1 => Ok(()),
+            n => write!(f, " (and {} more)", n - 1),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
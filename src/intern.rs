@@ -0,0 +1,84 @@
+//
+// dc4 string interning
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::rc::{Rc, Weak};
+
+/// Shares one buffer between identical strings, so that a big macro pushed over and over, like one
+/// defined inside a loop, doesn't get a new copy each time.
+///
+/// Strings are never changed in place, so sharing is invisible: anything which changes one, like
+/// [`DcString::into_vec`](crate::DcString::into_vec), copies it first if it's shared.
+#[derive(Debug, Default)]
+pub(crate) struct Interner {
+    /// Buffers by the hash of their contents. Entries are weak, so strings which are no longer
+    /// used anywhere are freed as usual, and the dead entries cleared out now and then.
+    buffers: HashMap<u64, Vec<Weak<Vec<u8>>>>,
+    /// The number of entries, live or dead.
+    entries: usize,
+    /// The number of entries after the last clear-out.
+    live: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl Interner {
+    /// Strings shorter than this aren't worth looking up.
+    pub const MIN_LEN: usize = 64;
+
+    /// Get a buffer holding the given bytes: a shared one if there already is one, or else a new
+    /// one, which is shared from now on.
+    pub fn intern(&mut self, bytes: Vec<u8>) -> Rc<Vec<u8>> {
+        if bytes.len() < Self::MIN_LEN {
+            return Rc::new(bytes);
+        }
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        let bucket = self.buffers.entry(hasher.finish()).or_default();
+        if let Some(buf) = bucket.iter().filter_map(Weak::upgrade).find(|buf| **buf == bytes) {
+            self.hits += 1;
+            return buf;
+        }
+        self.misses += 1;
+        let buf = Rc::new(bytes);
+        bucket.push(Rc::downgrade(&buf));
+        self.entries += 1;
+        if self.entries > 2 * self.live.max(64) {
+            self.clear_dead();
+        }
+        buf
+    }
+
+    fn clear_dead(&mut self) {
+        self.buffers.retain(|_, bucket| {
+            bucket.retain(|buf| buf.strong_count() > 0);
+            !bucket.is_empty()
+        });
+        self.entries = self.buffers.values().map(Vec::len).sum();
+        self.live = self.entries;
+    }
+
+    /// How many strings were found already interned.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// How many strings were interned for the first time.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// The total size of the interned strings still in use.
+    pub fn live_bytes(&self) -> u64 {
+        self.buffers.values()
+            .flatten()
+            .filter_map(Weak::upgrade)
+            .map(|buf| buf.len() as u64)
+            .sum()
+    }
+}
@@ -7,63 +7,613 @@
 #![deny(rust_2018_idioms)]
 
 mod big_real;
+mod convert;
 mod dcregisters;
+mod dcstring;
+pub mod diagnostic;
+mod eval;
+mod intern;
+mod lint;
+mod output;
 pub mod parser;
+mod program;
+mod provenance;
 mod reader_parser;
+mod rng;
+mod stack;
 mod state;
+pub mod stdlib;
+mod timing;
+mod transaction;
 
-use parser::Action;
+#[cfg(feature = "num")]
+pub use num_bigint;
+
+use num_bigint::BigInt;
+use diagnostic::{Diagnostic, Position};
+use output::{BudgetedWrite, LastByteWrite, WriteDetector};
+use parser::{Action, Flavor};
 use state::Dc4State;
+use std::collections::BTreeMap;
 use std::io::{BufRead, Write};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use timing::TimedWrite;
+
+pub use big_real::RoundingMode;
+pub use convert::ConversionError;
+pub use dcstring::DcString;
+pub use eval::{eval_once, eval_once_tolerating_warnings, EvalError};
+pub use lint::{lint_path, lint_reader, LintIssue, LintOptions};
+pub use output::OutputSink;
+pub use program::Program;
+pub use provenance::Provenance;
+pub use transaction::Transaction;
+
+/// The writer that the interpreter's output goes through.
+type Output<'a, W> = BudgetedWrite<'a, LastByteWrite<'a, TimedWrite<'a, W>>>;
+
+/// The biggest index allowed for arrays, unless changed with
+/// [`Dc4::set_max_array_index`].
+pub const DEFAULT_MAX_ARRAY_INDEX: u64 = u32::MAX as u64;
+
+/// The extensions dc4 has in the default flavor, as a comma-separated list of names, like
+/// `"print-no-newline,root,round"`. See [`features_for`].
+pub fn features() -> &'static str {
+    features_for(Flavor::default())
+}
+
+/// The extensions dc4 has in the given flavor, as a comma-separated list of names. This is the
+/// string the `@` command pushes.
+///
+/// A name is only listed if all the commands it covers are in the flavor. Names are only ever
+/// added to the end, so a program which has checked for this string, or any beginning of it,
+/// keeps working with later versions.
+pub fn features_for(flavor: Flavor) -> &'static str {
+    static FEATURES: [OnceLock<String>; 3] = [OnceLock::new(), OnceLock::new(), OnceLock::new()];
+    FEATURES[flavor as usize].get_or_init(|| {
+        parser::commands::feature_names(flavor).collect::<Vec<_>>().join(",")
+    })
+}
 
 /// Desk Calculator 4
 pub struct Dc4 {
     state: Dc4State,
+    /// How far [`stream_poll`](Self::stream_poll) has got through its input, while it's waiting
+    /// for more.
+    poll_progress: Option<reader_parser::Progress>,
 }
 
 impl Dc4 {
     /// Make a new DC4 instance with the given name.
     pub fn new(program_name: String) -> Self {
-        Self { state: Dc4State::new(program_name) }
+        Self::with_flavor(program_name, Flavor::default())
+    }
+
+    /// Make a new DC4 instance with the given name, which behaves like the given flavor of dc.
+    pub fn with_flavor(program_name: String, flavor: Flavor) -> Self {
+        Self { state: Dc4State::new(program_name, flavor), poll_progress: None }
+    }
+
+    /// Which flavor of dc this behaves like.
+    pub fn flavor(&self) -> Flavor {
+        self.state.flavor()
+    }
+
+    /// Change which flavor of dc this behaves like. This takes effect from the next input or macro
+    /// to be run; anything already being parsed carries on with the flavor it started with.
+    pub fn set_flavor(&mut self, flavor: Flavor) {
+        self.state.set_flavor(flavor);
     }
 
     /// Run a program from a stream of bytes.
     ///
-    /// This consumes the entire stream. Errors do not stop the program; they are written to
-    /// output, but execution continues.
+    /// This consumes the entire stream, unless the program quits first. Errors are written to
+    /// output, and execution continues, unless the [error policy](Self::set_error_policy) says
+    /// otherwise. A reader which isn't ready is an error here; for non-blocking readers, use
+    /// [`stream_poll`](Self::stream_poll).
     pub fn stream(&mut self, r: &mut impl BufRead, w: &mut impl Write) -> DcResult
     {
-        let mut actions = reader_parser::ReaderParser::new(r);
+        self.stream_until_quit(r, w).0
+    }
+
+    /// Run a program from a stream of bytes, like [`stream`](Self::stream), and also return how
+    /// many bytes of the stream were part of the program. This is for when the program is
+    /// followed by other data: if it quits, like with `q`, the rest of the stream starts that
+    /// many bytes from where it was when this was called.
+    ///
+    /// The stream is read at most one byte past the end of the program. That only happens when
+    /// the command which quit was only known to be complete by looking at the byte after it: a
+    /// conditional in the BSD and Gavin flavors, which could be followed by an `e` and an else
+    /// register, running a macro which quits. A `q` which isn't in a macro ends the program
+    /// without reading any further, so for data which can't be rewound, end the program that way.
+    pub fn stream_until_quit(&mut self, r: &mut impl BufRead, w: &mut impl Write)
+        -> (DcResult, u64)
+    {
+        let mut actions = if self.state.skip_bom() {
+            reader_parser::ReaderParser::new_skipping_bom(r, self.state.flavor())
+        } else {
+            reader_parser::ReaderParser::new(r, self.state.flavor())
+        };
+        actions.set_scientific_notation(self.state.scientific_notation());
         // There's no safe way to stop mid-stream on an error, because ReaderParser may have read
         // the source stream past the action that caused it, and so returning from here could lose
         // data from the source stream. So you can't really make a `try_stream()` that doesn't do
         // this.
-        loop {
-            match self.actions(&mut actions, w) {
-                Err(e) => self.state.error(w, format_args!("{e}")),
-                Ok(result) => return result,
+        let result = self.run_input(w, |dc, w| loop {
+            match dc.run_actions(&mut actions, w, |actions| actions.position()) {
+                Err(e) => {
+                    dc.state.report_error(w, &e);
+                    if dc.state.error_policy() == ErrorPolicy::AbortProgram {
+                        break DcResult::Aborted;
+                    }
+                }
+                Ok(result) => break result,
             }
+        });
+        (result, actions.bytes_used())
+    }
+
+    /// Run a program from a stream of bytes which may not always be ready, like a non-blocking
+    /// socket or pipe, as part of an event loop.
+    ///
+    /// This runs the program, like [`stream`](Self::stream), until the reader returns
+    /// [`WouldBlock`](std::io::ErrorKind::WouldBlock), and then returns
+    /// [`StreamPoll::Pending`]. Call it again, with the same reader or one carrying on from it,
+    /// once there's more to read: anything partly read, like half a number, carries on where it
+    /// left off. Reads which are [`Interrupted`](std::io::ErrorKind::Interrupted) are tried
+    /// again straight away. Once the input ends or the program quits, this returns
+    /// [`StreamPoll::Done`] with what `stream` would have, and the next call starts a new
+    /// program.
+    pub fn stream_poll(&mut self, r: &mut impl BufRead, w: &mut impl Write) -> StreamPoll {
+        let mut actions = match self.poll_progress.take() {
+            Some(progress) => reader_parser::ReaderParser::resume(r, progress),
+            None => {
+                if self.state.skip_bom() {
+                    // Wait until there's something to check for a byte order mark. As with
+                    // `stream`, it has to be all there in the first lot of input.
+                    match r.fill_buf() {
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                            return StreamPoll::Pending;
+                        }
+                        _ => reader_parser::ReaderParser::new_skipping_bom(r, self.flavor()),
+                    }
+                } else {
+                    reader_parser::ReaderParser::new(r, self.flavor())
+                }
+            }
+        };
+        actions.set_poll(true);
+        actions.set_scientific_notation(self.state.scientific_notation());
+        let timer = self.state.timer();
+        if let Some(timer) = &timer {
+            timer.start();
+        }
+        let result = self.with_output(w, |dc, w| {
+            let result = loop {
+                match dc.run_actions(&mut actions, w, |actions| actions.position()) {
+                    Err(e) => {
+                        dc.state.report_error(w, &e);
+                        if dc.state.error_policy() == ErrorPolicy::AbortProgram {
+                            break DcResult::Aborted;
+                        }
+                    }
+                    Ok(result) => break result,
+                }
+            };
+            let pending = matches!(result, DcResult::Continue) && actions.blocked();
+            if !pending {
+                dc.state.end_of_input(w);
+            }
+            (!pending).then_some(result)
+        });
+        if let Some(timer) = timer {
+            timer.stop();
         }
+        match result {
+            Some(result) => StreamPoll::Done(result),
+            None => {
+                self.poll_progress = Some(actions.suspend());
+                StreamPoll::Pending
+            }
+        }
+    }
+
+    /// Run a program from a stream of bytes, like [`stream`](Self::stream), labelling any errors
+    /// and warnings with the given name for the input (e.g. a file name).
+    ///
+    /// Strings defined in the input remember its name, so errors in macros defined here are
+    /// labelled with it even if they're run later from another input.
+    pub fn stream_named(&mut self, name: &str, r: &mut impl BufRead, w: &mut impl Write)
+        -> DcResult
+    {
+        let outer = self.state.set_input_name(Some(name.into()));
+        let result = self.stream(r, w);
+        self.state.set_input_name(outer);
+        result
+    }
+
+    /// Run a program held in memory, like [`stream`](Self::stream), but without copying it: it's
+    /// parsed straight from the slice as it runs. This is for big programs already in memory,
+    /// such as a memory-mapped file.
+    pub fn run_slice(&mut self, text: &[u8], w: &mut impl Write) -> DcResult {
+        self.stream(&mut &text[..], w)
+    }
+
+    /// Run a program held in memory, like [`run_slice`](Self::run_slice), labelling any errors
+    /// and warnings with the given name for the input.
+    pub fn run_slice_named(&mut self, name: &str, text: &[u8], w: &mut impl Write) -> DcResult {
+        self.stream_named(name, &mut &text[..], w)
+    }
+
+    /// Run a given program text as if it was a macro, like [`text`](Self::text), labelling any
+    /// errors and warnings with the given name for the input.
+    pub fn text_named(&mut self, name: &str, text: impl Into<DcString>, w: &mut impl Write)
+        -> DcResult
+    {
+        let outer = self.state.set_input_name(Some(name.into()));
+        let result = self.text(text, w);
+        self.state.set_input_name(outer);
+        result
     }
 
     /// Run a given program text as if it was a macro.
     ///
-    /// Errors do not stop the program; they are written to output, but execution continues.
-    pub fn text(&mut self, text: impl Into<Vec<u8>>, w: &mut impl Write) -> DcResult {
-        self.state.run_macro(text.into(), w)
+    /// Errors are written to output, and execution continues, unless the
+    /// [error policy](Self::set_error_policy) says otherwise.
+    pub fn text(&mut self, text: impl Into<DcString>, w: &mut impl Write) -> DcResult {
+        let text = text.into();
+        self.run_input(w, |dc, w| dc.state.run_input(text, w))
+    }
+
+    /// Run a program which was parsed ahead of time with [`Program::compile`]. This is the same as
+    /// running its text with [`text`](Self::text), but without parsing it again.
+    ///
+    /// The program must have been compiled in this interpreter's flavor, since the flavor decides
+    /// what its commands are. If it wasn't, none of it runs, and [`DcError::FlavorMismatch`] is
+    /// reported like any other error.
+    pub fn run_program(&mut self, program: &Program, w: &mut impl Write) -> DcResult {
+        self.run_input(w, |dc, w| dc.state.run_program(program, w))
+    }
+
+    /// Run a whole input with [`with_output`](Self::with_output), timing it if timings are
+    /// enabled, and finish up at the end of it.
+    fn run_input<W: Write>(&mut self, w: &mut W, run: impl FnOnce(&mut Self, &mut Output<'_, W>)
+        -> DcResult) -> DcResult
+    {
+        let timer = self.state.timer();
+        if let Some(timer) = &timer {
+            timer.start();
+        }
+        let result = self.with_output(w, |dc, w| {
+            let result = run(dc, w);
+            dc.state.end_of_input(w);
+            result
+        });
+        if let Some(timer) = timer {
+            timer.stop();
+        }
+        result
+    }
+
+    /// Everything the interpreter writes goes through here: output is charged to the output
+    /// phase of the timings, the last byte written is noted, and output past the
+    /// [limit](Self::set_max_output_bytes) is dropped.
+    fn with_output<W: Write, T>(&mut self, w: &mut W, f: impl FnOnce(&mut Self, &mut Output<'_, W>)
+        -> T) -> T
+    {
+        let mut timed = TimedWrite::new(w, self.state.timer());
+        let mut last = LastByteWrite::new(&mut timed);
+        let mut w = BudgetedWrite::new(&mut last, self.state.output_budget());
+        let result = f(self, &mut w);
+        if let Some(byte) = w.inner().last() {
+            self.state.set_last_output_byte(byte);
+        }
+        result
+    }
+
+    /// Whether the output written by the last program or action to write any ended with a
+    /// newline, or `None` if nothing has been written yet.
+    ///
+    /// This is for composing the output with other text: `p` and `f` end their output with a
+    /// newline, but `n` and `P` don't. Error messages written to the output count too.
+    pub fn output_ended_with_newline(&self) -> Option<bool> {
+        self.state.last_output_byte().map(|byte| byte == b'\n')
+    }
+
+    /// Run a given program text as if it was a macro, appending any output to the given buffer.
+    ///
+    /// This is the same as [`text`](Self::text): existing contents of the buffer are kept, so a
+    /// caller running many programs can clear and reuse one buffer rather than allocating a new
+    /// one for each. See also [`OutputSink`].
+    pub fn text_into(&mut self, text: impl Into<DcString>, buf: &mut Vec<u8>) -> DcResult {
+        self.text(text, buf)
+    }
+
+    /// Run a given program text as if it was a macro, appending its output to the sink and
+    /// collecting its errors and warnings there as [`Diagnostic`]s, instead of sending them to
+    /// the diagnostic handler or the output.
+    ///
+    /// Call [`OutputSink::reset`] between runs to reuse its memory for both.
+    pub fn text_to_sink(&mut self, text: impl Into<DcString>, sink: &mut OutputSink) -> DcResult {
+        let (output, diagnostics) = sink.parts();
+        let previous = self.state.replace_diagnostic_sink(Some(std::mem::take(diagnostics)));
+        let result = self.text(text, output);
+        *diagnostics = self.state.replace_diagnostic_sink(previous).unwrap_or_default();
+        result
+    }
+
+    /// Set whether each kind of warning should only be emitted once per input.
+    ///
+    /// A warning inside a loop can otherwise repeat many times and drown out the real output. When
+    /// enabled, repeats are counted instead, and a count is emitted at the end of the input
+    /// (the end of [`stream`](Self::stream) or [`text`](Self::text)). This is disabled by default.
+    pub fn set_warn_once(&mut self, enabled: bool) {
+        self.state.set_warn_once(enabled);
+    }
+
+    /// Set whether error messages should include more detail than GNU dc's do.
+    ///
+    /// For example, a comparison of a string will say which comparison and register were
+    /// involved, rather than just "non-numeric value", and errors inside macros show the part of
+    /// the macro around the command which caused them. This is disabled by default.
+    pub fn set_verbose_errors(&mut self, enabled: bool) {
+        self.state.set_verbose_errors(enabled);
+    }
+
+    /// Set whether a macro executed as the very last thing in another macro should replace it,
+    /// rather than running nested inside it.
+    ///
+    /// This is enabled by default, and lets loops written with tail recursion run forever without
+    /// running out of stack. Programs should behave the same either way, including how many
+    /// levels `Q` quits: disabling it gives every macro its own real frame, which can be useful
+    /// when debugging, but deep recursion then uses the native stack and can overflow it.
+    pub fn set_tail_call_optimization(&mut self, enabled: bool) {
+        self.state.set_tail_call_optimization(enabled);
+    }
+
+    /// Set what happens after an error is reported while running a program. By default, it carries
+    /// on with the next command.
+    ///
+    /// This only affects [`stream`](Self::stream), [`text`](Self::text), and their variants;
+    /// single actions always return their errors to the caller.
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.state.set_error_policy(policy);
+    }
+
+    /// Set how `p`, `n`, `f`, and the other commands which print numbers as text write them, for
+    /// results too long to read. This only applies in output radix 10. The mantissa is rounded,
+    /// half away from zero, rather than truncated like everything else in dc: 2/3 to 3 digits
+    /// is `6.67e-01`. At least one significant digit is always shown. `P` is unaffected, as are
+    /// the values themselves. The default is [`Notation::Plain`].
+    pub fn set_notation(&mut self, notation: Notation) {
+        self.state.set_notation(notation);
+    }
+
+    /// Set whether the results of arithmetic should be stored without trailing fractional zeros.
+    ///
+    /// Dividing at a large scale gives results with that many fractional digits, even when the
+    /// quotient ends much sooner (`1 2/` at a scale of 100 is `.5` followed by 99 zeros), and those
+    /// digits make later arithmetic on the result slower. With this enabled, the zeros are dropped
+    /// from how the number is stored, but it still remembers its scale: printing, `X`, `Z`, and
+    /// the scale of anything computed from it are unaffected. This is disabled by default.
+    pub fn set_auto_simplify(&mut self, enabled: bool) {
+        self.state.set_auto_simplify(enabled);
+    }
+
+    /// Set whether numbers must only use digits which are valid in the current input radix.
+    ///
+    /// Normally, like other dc implementations, a digit too big for the radix is still taken at its
+    /// face value, so in decimal `12A3` is 1×1000 + 2×100 + 10×10 + 3 = 1303. That's rarely what
+    /// was meant, and usually means a `16i` was forgotten. With this enabled, such a number is an
+    /// error instead, and nothing is pushed. A number which is just a single digit still means
+    /// that digit, whatever the radix, so that `Ai` always gets back to decimal. This is disabled
+    /// by default.
+    pub fn set_strict_digits(&mut self, enabled: bool) {
+        self.state.set_strict_digits(enabled);
+    }
+
+    /// Set whether fractions in input radixes other than ten are read exactly.
+    ///
+    /// Normally, like other dc implementations, a number like `1.F` in hexadecimal gets only as
+    /// many decimal places as it has digits after the point, so it reads as 1.9 rather than
+    /// 1.9375. With this enabled, it gets as many as its value needs, and no more: `1.F0` is
+    /// 1.9375 too, and `1.0` is 1. In a radix made up of only twos and fives, like 2, 8, or 16, that's
+    /// always exact. In other radixes, like 3 or 12, most fractions never end in decimal, so they
+    /// are truncated after as many decimal places for each digit as the radix's biggest digit
+    /// takes to write in decimal: `.1` in radix 12 is .08. This is disabled by default.
+    pub fn set_exact_fractions(&mut self, enabled: bool) {
+        self.state.set_exact_fractions(enabled);
+    }
+
+    /// Set whether numbers in programs can be written in scientific notation, like numbers pasted
+    /// from other tools: `6.022e23` is 602200000000000000000000, and `2e_2` or `2e-2` is .02, with
+    /// a scale of 2. The exponent is always a power of ten, written in decimal, whatever the
+    /// input radix. It has to come straight after the number, so the BSD `=xey` syntax isn't
+    /// affected, and capital `E` is still a digit. An `e` with no digits after it is an exponent
+    /// of zero. This takes effect from the next input or macro to be run, and is disabled by
+    /// default. It applies to [`push_number`](Self::push_number) as well.
+    pub fn set_scientific_notation(&mut self, enabled: bool) {
+        self.state.set_scientific_notation(enabled);
+    }
+
+    /// Set whether `^` raises to the whole of an exponent with a fractional part, rather than
+    /// ignoring the fractional part.
+    ///
+    /// Normally, like other dc implementations, `3 2.5 ^` is 3 squared, with a warning or an error
+    /// depending on the [flavor](Self::set_flavor). With this enabled, it's 3 to the power 2.5,
+    /// to the current scale (or the base's, if that's more), so `10k 2 .5 ^` is 1.4142135623. A
+    /// negative base with a fractional exponent is an error. Exponents without a fractional part
+    /// work the same either way. This is disabled by default.
+    pub fn set_fractional_exponents(&mut self, enabled: bool) {
+        self.state.set_fractional_exponents(enabled);
+    }
+
+    /// Set whether to skip a UTF-8 byte order mark at the start of each input given to
+    /// [`stream`](Self::stream) or [`text`](Self::text), as some editors write at the start of
+    /// files. Otherwise, it's three unimplemented commands. This is disabled by default.
+    pub fn set_skip_bom(&mut self, enabled: bool) {
+        self.state.set_skip_bom(enabled);
+    }
+
+    /// Set the biggest index which can be used with the `:` and `;` commands, and with
+    /// [`import_array`](Self::import_array). Using a bigger one is an error.
+    ///
+    /// Arrays only hold the entries which have been stored, so big indexes don't take up any more
+    /// memory than small ones, but a program using one is almost certainly broken. GNU dc only
+    /// allows indexes which fit in a C `long`. The default is 2^32 - 1
+    /// ([`DEFAULT_MAX_ARRAY_INDEX`]).
+    pub fn set_max_array_index(&mut self, max: u64) {
+        self.state.set_max_array_index(max);
+    }
+
+    /// Seed the random numbers given by the `'` command, so that a program gives the same ones
+    /// each time, like for testing. Otherwise they're seeded from the operating system, and are
+    /// different each time.
+    ///
+    /// The numbers aren't suitable for anything which needs to be unpredictable, like passwords,
+    /// even when not seeded.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.state.seed_rng(seed);
+    }
+
+    /// Keep track of how much time is spent on parsing, running each kind of command, and writing
+    /// output, while running programs with [`stream`](Self::stream) and [`text`](Self::text). The
+    /// results go in the [stats](Self::stats). This is disabled by default; disabling it again
+    /// throws away the times so far.
+    ///
+    /// Time is measured coarsely, by taking a timestamp whenever the kind of work changes: before
+    /// and after each command which isn't part of a number or string, and around each write. In
+    /// the benchmarks, taking a timestamp costs about as much as running a cheap command like `d`,
+    /// so a program of nothing but cheap commands runs two to three times slower. Next to
+    /// anything expensive enough to be worth timing, this is negligible.
+    pub fn set_timings(&mut self, enabled: bool) {
+        self.state.set_timings(enabled);
+    }
+
+    /// Keep track of the biggest numbers, strings, and arrays programs make, and the most memory
+    /// the stack takes, in the [stats](Self::stats). This is disabled by default; disabling it
+    /// again throws away the sizes so far.
+    ///
+    /// The cost is a comparison or two for each value pushed or array entry stored.
+    pub fn set_high_water_marks(&mut self, enabled: bool) {
+        self.state.set_high_water_marks(enabled);
+    }
+
+    /// Limit how many bytes of output programs can write, or remove the limit with `None`.
+    ///
+    /// This is for running programs which can't be trusted not to write endlessly, like
+    /// `[1nlxx]dsxx`. Output past the limit is dropped, and the command which went over it fails
+    /// with [`DcError::OutputLimit`]. The message for that error is the one thing written after
+    /// that. Under [`ErrorPolicy::AbortProgram`] this stops the program; otherwise it carries on,
+    /// without output.
+    ///
+    /// The count starts over from nothing each time this is called, so a caller running many
+    /// programs can give each its own budget by calling it before running each one. The count so
+    /// far is in [`DcStats::output_bytes`].
+    pub fn set_max_output_bytes(&mut self, max: Option<u64>) {
+        self.state.set_max_output_bytes(max);
+    }
+
+    /// Get a handle for stopping whatever this interpreter is running, from another thread.
+    ///
+    /// Cancelling takes effect at the next command, or part way through a long `v`, `^`, or `|`,
+    /// which fails with [`DcError::Cancelled`]. That stops the whole program, whatever the
+    /// [`ErrorPolicy`], leaving the stack and registers as they were before the command. The
+    /// interpreter can be used again after that. Cancelling when nothing is running cancels the
+    /// next command run.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.state.cancel_handle()
+    }
+
+    /// Get counters of various things which have happened so far.
+    pub fn stats(&self) -> DcStats {
+        self.state.stats()
+    }
+
+    /// Send errors and warnings to the given function, instead of writing them to the output.
+    ///
+    /// Besides keeping them out of the program's output, this gives more information about them:
+    /// see [`Diagnostic`].
+    pub fn set_diagnostic_handler(&mut self, handler: impl FnMut(&Diagnostic) + 'static) {
+        self.state.set_diagnostic_handler(Some(Box::new(handler)));
+    }
+
+    /// Go back to writing errors and warnings to the output.
+    pub fn clear_diagnostic_handler(&mut self) {
+        self.state.set_diagnostic_handler(None);
+    }
+
+    /// Set where the `?` command reads lines of input from. By default, it reads standard input.
+    ///
+    /// A program read from standard input which uses `?` should use this, so that both can share
+    /// one reader without either buffering input meant for the other. `?` then gets the line
+    /// following the one it's on.
+    pub fn set_line_reader(&mut self, reader: impl BufRead + 'static) {
+        self.state.set_line_reader(Some(Box::new(reader)));
+    }
+
+    /// Go back to reading standard input for the `?` command.
+    pub fn clear_line_reader(&mut self) {
+        self.state.set_line_reader(None);
+    }
+
+    /// Echo each command to the given writer as it runs, like `set -x` in a shell. Each command
+    /// goes on its own line, after a `>` for each level of macro it's being run from (starting at
+    /// one for the top level). Numbers and strings are echoed as they were written. Errors writing
+    /// to `w` are ignored.
+    ///
+    /// The `Y` command goes well with this: it prints the stack like `f`, but with each value
+    /// prefixed by its depth from the top, starting at zero.
+    pub fn set_echo(&mut self, w: impl Write + 'static) {
+        self.state.set_echo(Some(Box::new(w)));
+    }
+
+    /// Stop echoing commands.
+    pub fn clear_echo(&mut self) {
+        self.state.set_echo(None);
+    }
+
+    /// Keep track of where each value was created, for finding out where a wrong value came from.
+    /// Off by default, since it costs time and memory for every value.
+    ///
+    /// While tracking, [`provenance`](Self::provenance) gives a value's origin, and it's shown
+    /// after each value by [`dump_state`](Self::dump_state) and by the `Y` command. Values which
+    /// were already on the stack or in registers when tracking started have no provenance.
+    pub fn set_track_provenance(&mut self, enabled: bool) {
+        self.state.set_track_provenance(enabled);
+    }
+
+    /// Where the value `depth` places from the top of the stack was created, if provenance is
+    /// being tracked and it's known.
+    pub fn provenance(&self, depth: usize) -> Option<Provenance> {
+        self.state.provenance(depth).cloned()
     }
 
     /// Run a program from an iterator of actions.
     ///
     /// Stops on the first error encountered.
-    pub fn actions(&mut self, actions: impl Iterator<Item = Action>, w: &mut impl Write)
+    pub fn actions(&mut self, mut actions: impl Iterator<Item = Action>, w: &mut impl Write)
         -> Result<DcResult, DcError>
     {
-        for action in actions {
+        self.with_output(w, |dc, w| dc.run_actions(&mut actions, w, |_| None))
+    }
+
+    fn run_actions<I: Iterator<Item = Action>>(
+        &mut self,
+        actions: &mut I,
+        w: &mut impl Write,
+        position: impl Fn(&I) -> Option<Position>,
+    ) -> Result<DcResult, DcError> {
+        while let Some(action) = actions.next() {
+            self.state.set_position(position(actions));
+            self.state.time_action(&action);
             let mut result = self.state.action(action, w);
             if let Ok(DcResult::Macro(text)) = result {
                 result = Ok(self.state.run_macro(text, w));
             }
+            self.state.time_parsing();
             match result {
                 Ok(DcResult::Continue) => (),
                 Ok(DcResult::QuitLevels(_)) => (), // 'Q' mustn't exit the top level
@@ -80,84 +630,558 @@ impl Dc4 {
         self.state.push_number(input)
     }
 
+    /// Push a fixed-point number onto the stack: the value pushed is `value` × 10^-`scale`.
+    ///
+    /// Unlike [`push_number`](Self::push_number), this does not involve any string formatting,
+    /// and the current input radix has no effect. For example, an amount of currency held as a
+    /// number of cents can be pushed with a scale of 2.
+    pub fn push_scaled_int(&mut self, value: i128, scale: u32) {
+        self.state.push_scaled(BigInt::from(value), scale)
+    }
+
+    /// Push a fixed-point number onto the stack: the value pushed is `int` × 10^-`scale`.
+    ///
+    /// This is the same as [`push_scaled_int`](Self::push_scaled_int), for callers already
+    /// holding a `BigInt`.
+    #[cfg(feature = "num")]
+    pub fn push_big(&mut self, int: num_bigint::BigInt, scale: u32) {
+        self.state.push_scaled(int, scale)
+    }
+
+    /// Push a floating-point number onto the stack, as the shortest decimal which converts back
+    /// to the same `f64`, so 0.1 is pushed as 0.1. Infinities and NaN are an error.
+    ///
+    /// To get one back, use `f64::try_from` on a value taken from the stack.
+    pub fn push_f64(&mut self, value: f64) -> Result<(), DcError> {
+        self.state.push_f64(value)
+    }
+
     /// Convenience function for pushing a string directly onto the stack (rather than running
     /// Action::StringChar for each byte, followed by Action::PushString).
-    pub fn push_string(&mut self, string: impl Into<Vec<u8>>) {
+    pub fn push_string(&mut self, string: impl Into<DcString>) {
         self.state.push_string(string)
     }
 
+    /// Push a number, like [`push_number`](Self::push_number), and combine it with the running
+    /// value on the stack using `op`, which must be a binary operator like [`Action::Add`] (see
+    /// [`Action::is_binary_operator`]), or the result is [`DcError::InvalidActionSequence`]. If
+    /// the stack is empty, the number becomes the running value.
+    ///
+    /// This is for streaming arithmetic, where the stack holds nothing but the running value: it
+    /// is an error for the stack to hold more than one value beforehand. If `op` fails, the stack
+    /// is put back the way it was, with the running value on it. Warnings only go to the
+    /// diagnostic handler, if there is one.
+    pub fn fold_number(&mut self, input: impl AsRef<[u8]>, op: Action) -> Result<(), DcError> {
+        self.state.fold_number(input, op)
+    }
+
+    /// Pop the result of a computation, checking that it's the only value on the stack.
+    pub fn take_result(&mut self) -> Result<DcValue, DcError> {
+        self.state.take_result()
+    }
+
+    /// Pop every value off the stack, from the top down, converting each one to `T`.
+    ///
+    /// Values are popped as the iterator is advanced, so stopping early leaves the rest on the
+    /// stack. A value which can't be converted is still popped, and is returned in the error.
+    ///
+    /// ```
+    /// let mut dc = dc4::Dc4::new("dc4".to_owned());
+    /// dc.text("1 2 3 [four]", &mut Vec::new());
+    /// let results = dc.drain_results::<i64>().collect::<Vec<_>>();
+    /// assert_eq!(results[0].as_ref().unwrap_err().to_string(), "can't convert a string to i64");
+    /// assert_eq!(results[1..], [Ok(3), Ok(2), Ok(1)]);
+    /// ```
+    pub fn drain_results<T>(&mut self) -> impl Iterator<Item = Result<T, ConversionError>> + '_
+        where T: TryFrom<DcValue>,
+              ConversionError: From<T::Error>,
+    {
+        std::iter::from_fn(move || {
+            let value = self.state.pop()?;
+            Some(T::try_from(value).map_err(ConversionError::from))
+        })
+    }
+
+    /// Copy out the array of the given register, as it would be seen by the `;` command: its
+    /// entries are in order of index, and unset entries (which read as zero) are left out.
+    ///
+    /// Indexes which don't fit in a `u64`, which can only come from storing at a negative or
+    /// enormous index, are left out too.
+    pub fn export_array(&self, register: u8) -> Vec<(u64, DcValue)> {
+        self.state.export_array(register)
+    }
+
+    /// Replace the array of the given register with the given entries, as if each had been stored
+    /// with the `:` command. This is much faster than running `:` for each one when loading a
+    /// large table.
+    ///
+    /// Like `:`, this affects the array at the top of the register's stack, so the array goes
+    /// away again if it was imported after `S` and the register is then popped with `L`. If an
+    /// index is given more than once, the last entry for it wins.
+    ///
+    /// If any index is bigger than the [maximum](Self::set_max_array_index), nothing is imported,
+    /// and [`DcError::ArrayIndexTooBig`] is returned.
+    pub fn import_array(&mut self, register: u8, entries: impl IntoIterator<Item = (u64, DcValue)>)
+        -> Result<(), DcError>
+    {
+        self.state.import_array(register, entries)
+    }
+
+    /// Load packages from the [library of macros](stdlib) into their registers, by name, like
+    /// `&["math", "array"]`. See [`stdlib::PACKAGES`] for what each one has, and
+    /// [`stdlib::Package::registers`] for the registers it uses.
+    ///
+    /// If any name isn't a package, nothing is loaded, and [`DcError::LibraryUnknown`] is
+    /// returned.
+    pub fn load_stdlib(&mut self, packages: &[&str]) -> Result<(), DcError> {
+        let packages = packages.iter()
+            .map(|&name| stdlib::package(name)
+                .ok_or_else(|| DcError::LibraryUnknown(name.to_owned())))
+            .collect::<Result<Vec<_>, _>>()?;
+        for package in packages {
+            for m in package.macros {
+                self.state.set_register(m.register, DcValue::Str(DcString::from(m.text)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a summary of the state, for seeing what a program left behind: the scale and
+    /// radixes, the stack (top first), and each register in use, with its value, how deep its
+    /// stack is, and how many entries its array has. Values are written in the current output
+    /// radix.
+    ///
+    /// However big the state is, the summary stays readable: only the top 20 values of the stack
+    /// are shown, values are cut short after 60 characters, and arrays are only counted.
+    pub fn dump_state(&self, w: &mut impl Write) -> std::io::Result<()> {
+        self.state.dump_state(w)
+    }
+
+    /// Write a value the way the `p` command would, in the current output radix, without a
+    /// newline.
+    pub fn print_value(&self, value: &DcValue, w: &mut impl Write) {
+        self.state.print_elem(value, w)
+    }
+
+    /// Make several changes to the state as one: `f` pushes values and runs actions through the
+    /// [`Transaction`] it's given, and if it returns an error, the stack, registers, scale,
+    /// radixes, and rounding mode are all put back as they were before, as if none of it had
+    /// happened. So are registers saved with `t`, and any number or string part way through
+    /// being read. If it returns `Ok`, the changes are kept.
+    ///
+    /// The same happens if, under [`ErrorPolicy::AbortProgram`], text run by
+    /// [`Transaction::text`] stops on an error: then the changes are undone even if `f` returns
+    /// `Ok`, and [`DcError::TransactionAborted`] is returned instead.
+    ///
+    /// Output can't be undone: anything written while running `f` stays written, and so do
+    /// warnings and errors sent to the [diagnostic handler](Self::set_diagnostic_handler). To only
+    /// have output from transactions which succeed, write it to a `Vec<u8>` in `f`, and copy it
+    /// to where it's going afterwards.
+    ///
+    /// Saving the state copies the stack and the registers' stacks, though not strings or arrays,
+    /// so a transaction costs about as much as the state is big. Transactions can't be nested.
+    ///
+    /// ```
+    /// let mut dc = dc4::Dc4::new("dc4".to_owned());
+    /// dc.push_number("1").unwrap();
+    /// let result = dc.transaction(|txn| {
+    ///     txn.push_number("2")?;
+    ///     txn.push_string("oops");
+    ///     txn.action(dc4::parser::Action::Add, &mut Vec::new())
+    /// });
+    /// assert!(matches!(result, Err(dc4::DcError::NonNumericValue)));
+    /// // The 2 and "oops" are gone again.
+    /// assert_eq!(i64::try_from(dc.take_result().unwrap()).unwrap(), 1);
+    /// ```
+    pub fn transaction<T, E: From<DcError>>(
+        &mut self,
+        f: impl FnOnce(&mut Transaction<'_>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let snapshot = self.state.snapshot();
+        let mut txn = Transaction::new(self);
+        let result = match f(&mut txn) {
+            Ok(_) if txn.aborted() => Err(DcError::TransactionAborted.into()),
+            result => result,
+        };
+        if result.is_err() {
+            self.state.restore(snapshot);
+        }
+        result
+    }
+
     /// Run a single action.
     ///
     /// Any output gets written to the given writer.
     ///
     /// Errors get returned to the caller and are not written to the writer, but any warnings will
     /// get written as output.
+    ///
+    /// Actions don't have to come from the parser: any sequence of them can be run, and the worst
+    /// that happens is an error, such as [`DcError::InvalidActionSequence`] for a conditional
+    /// made with a register action that isn't a comparison. (Writing to `w` has to work, though.)
+    /// What they do is well-defined for sequences the parser could produce, and also for a
+    /// number's or string's characters followed by the push which ends them, with nothing in
+    /// between; a character which can't be in a number is an error. Other sequences, like a number
+    /// whose characters are interleaved with other commands, don't panic, but what they do may
+    /// change.
     pub fn action(&mut self, action: Action, w: &mut impl Write) -> Result<DcResult, DcError> {
-        self.state.action(action, w)
+        self.with_output(w, |dc, w| dc.state.action(action, w))
+    }
+
+    /// Run a single action, like [`action`](Self::action), and also return what it did.
+    ///
+    /// Stack changes are reported as a net effect: values which were left alone aren't included,
+    /// even if the action looked at them. If the action returns a macro to run, running it is up to
+    /// the caller, and isn't included.
+    pub fn action_traced(&mut self, action: Action, w: &mut impl Write)
+        -> (Result<DcResult, DcError>, ActionEffect)
+    {
+        let mut w = WriteDetector::new(w);
+        let (result, mut effect) =
+            self.with_output(&mut w, |dc, w| dc.state.action_traced(action, w));
+        effect.output = w.written();
+        (result, effect)
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum DcValue {
-    Str(Vec<u8>),
+    Str(DcString),
     Num(big_real::BigReal)
 }
 
+/// What running a single action did, as returned by [`Dc4::action_traced`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ActionEffect {
+    /// Values removed from the stack, bottom first.
+    pub popped: Vec<DcValue>,
+    /// Values added to the stack in their place, bottom first.
+    pub pushed: Vec<DcValue>,
+    /// Registers whose values were read, in the order they were used.
+    pub registers_read: Vec<u8>,
+    /// Registers which were changed, in the order they were used.
+    pub registers_written: Vec<u8>,
+    /// Whether anything was written to the output.
+    pub output: bool,
+}
+
+/// Stops a [`Dc4`] part way through running a program. See [`Dc4::cancel_handle`].
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Stop the program the interpreter is running.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Whether cancelling was asked for, resetting it so the interpreter can carry on afterwards.
+    pub(crate) fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Counters of things which have happened over the lifetime of a [`Dc4`] instance.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DcStats {
+    /// The number of macro calls which replaced the calling macro rather than running nested
+    /// inside it. See [`Dc4::set_tail_call_optimization`].
+    pub tail_calls_elided: u64,
+    /// Where the time has gone, if enabled with [`Dc4::set_timings`].
+    pub timings: Option<DcTimings>,
+    /// The biggest things made, if enabled with [`Dc4::set_high_water_marks`].
+    pub high_water: Option<DcHighWater>,
+    /// Bytes of output written since the limit was last set with
+    /// [`Dc4::set_max_output_bytes`], or since the start if it never has been. Output dropped
+    /// for going over the limit isn't counted.
+    pub output_bytes: u64,
+    /// The number of strings defined by programs which turned out to be identical to one already
+    /// in use, and so share its memory instead of taking more. Only strings of at least 64 bytes
+    /// are looked for.
+    pub intern_hits: u64,
+    /// The number of strings of at least 64 bytes defined by programs which weren't identical to
+    /// any already in use.
+    pub intern_misses: u64,
+    /// The memory taken by the distinct strings of at least 64 bytes defined by programs which are
+    /// still in use, counting each once however many times it's shared.
+    pub interned_bytes: u64,
+}
+
+/// How much time has been spent on each part of running programs. See [`Dc4::set_timings`].
+///
+/// Each moment of a run is counted in exactly one of these, so they add up to the total.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DcTimings {
+    /// Parsing program text into commands, including numbers and strings, and reading it in.
+    pub parsing: Duration,
+    /// Arithmetic commands, like `+` and `v`.
+    pub arithmetic: Duration,
+    /// Storing and loading registers and arrays.
+    pub registers: Duration,
+    /// Formatting values for the print commands.
+    pub printing: Duration,
+    /// Running macros, conditionals, and quitting, not including the commands the macros run.
+    pub macros: Duration,
+    /// Everything else, like stack manipulation and changing radixes.
+    pub other: Duration,
+    /// Writing output, including errors and warnings.
+    pub output: Duration,
+    /// The total time spent running programs.
+    pub total: Duration,
+}
+
+/// The biggest things programs have made, for sizing the machines to run them on. See
+/// [`Dc4::set_high_water_marks`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DcHighWater {
+    /// The most digits of any number pushed on the stack, counting fractional digits as `Z`
+    /// does.
+    pub max_digits: u64,
+    /// The length of the longest string pushed on the stack.
+    pub max_string_bytes: u64,
+    /// The most entries each register's array has had, for the registers whose arrays have
+    /// been stored into.
+    pub max_array_entries: BTreeMap<u8, u64>,
+    /// An estimate of the most memory the values on the stack have taken at once. Values kept
+    /// in registers aren't counted, and strings which share memory are counted separately.
+    pub max_stack_bytes: u64,
+}
+
 #[derive(Debug)]
 pub enum DcResult {
+    /// The program should exit, because of `q`. See [`parser::QuitOverflow`] for the rules.
     Terminate(u32),
     QuitLevels(u32),
     Continue,
-    Macro(Vec<u8>),
+    Macro(DcString),
+    /// The program was stopped by an error, because of [`ErrorPolicy::AbortProgram`]. This is
+    /// never returned under the default policy, but a `match` on `DcResult` still needs an arm
+    /// for it.
+    Aborted,
+}
+
+/// How far [`Dc4::stream_poll`] got.
+#[derive(Debug)]
+pub enum StreamPoll {
+    /// The input isn't ready: call again when there's more to read.
+    Pending,
+    /// The program is finished, because the input ended or it quit.
+    Done(DcResult),
+}
+
+/// What to do after reporting an error. See [`Dc4::set_error_policy`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Carry on with the next command, like GNU dc does.
+    #[default]
+    Continue,
+    /// Stop running the input, and any macros, returning [`DcResult::Aborted`].
+    AbortProgram,
+}
+
+/// How numbers are written by the commands which print them as text. See
+/// [`Dc4::set_notation`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Notation {
+    /// Every digit, like other dc implementations.
+    #[default]
+    Plain,
+    /// One digit before the point and a power of ten, like `1.2346e+20`, rounded to this many
+    /// significant digits.
+    Scientific(u32),
+    /// Like `Scientific`, but with the power of ten a multiple of 3, like `123.46e+18`.
+    Engineering(u32),
 }
 
 #[derive(Debug)]
 pub enum DcError {
     ArrayIndexInvalid,
+    ArrayIndexString,
+    ArrayIndexTooBig(u64),
+    ArctanNonNumeric,
+    BitwiseInvalid,
+    Cancelled,
+    ComparisonNonNumeric(&'static str, u8),
+    DigitInvalid(u8, u32),
     DivideByZero,
+    ExpNonNumeric,
+    ExponentTooBig,
+    FactorialInvalid,
+    FactorialTooBig,
+    FlavorMismatch(Flavor, Flavor),
+    FloatNotFinite,
     InputError(std::io::Error),
     InputRadixInvalid,
+    InvalidActionSequence,
+    LibraryUnknown(String),
+    LogBaseInvalid,
+    LogNonNumeric,
+    LogNonPositive,
+    ModInverseInvalid,
+    ModInverseNone,
+    NegativeBase,
     NegativeExponent,
+    NonInteger,
     NonNumericValue,
+    OutputLimit,
     OutputRadixInvalid,
     QuitInvalid,
     QuitTooBig,
+    QuitTooDeep,
+    RandomBoundInvalid,
     RegisterEmpty(u8),
     RemainderByZero,
+    RootIndexInvalid,
+    RootNegative,
+    RoundNonNumeric,
+    RoundingModeInvalid,
     ScaleInvalid,
     ScaleTooBig,
     ShellUnsupported,
+    ShiftCountInvalid,
+    ShiftCountTooBig,
     SqrtNegative,
     SqrtNonNumeric,
+    StackDepth(usize),
     StackEmpty,
     StackRegisterEmpty(u8),
+    TransactionAborted,
     UnexpectedNumberChar(u8),
     Unimplemented(u8),
 }
 
+impl DcError {
+    /// A short identifier for the kind of error, for machine consumption. These don't change
+    /// between versions, unlike the messages.
+    pub fn code(&self) -> &'static str {
+        use DcError::*;
+        match self {
+            ArrayIndexInvalid => "array_index_invalid",
+            ArrayIndexString => "array_index_string",
+            ArrayIndexTooBig(_) => "array_index_too_big",
+            ArctanNonNumeric => "arctan_non_numeric",
+            BitwiseInvalid => "bitwise_invalid",
+            Cancelled => "cancelled",
+            ComparisonNonNumeric(..) => "comparison_non_numeric",
+            DigitInvalid(..) => "digit_invalid",
+            DivideByZero => "divide_by_zero",
+            ExpNonNumeric => "exp_non_numeric",
+            ExponentTooBig => "exponent_too_big",
+            FactorialInvalid => "factorial_invalid",
+            FactorialTooBig => "factorial_too_big",
+            FlavorMismatch(..) => "flavor_mismatch",
+            FloatNotFinite => "float_not_finite",
+            InputError(_) => "input_error",
+            InputRadixInvalid => "input_radix_invalid",
+            InvalidActionSequence => "invalid_action_sequence",
+            LibraryUnknown(_) => "library_unknown",
+            LogBaseInvalid => "log_base_invalid",
+            LogNonNumeric => "log_non_numeric",
+            LogNonPositive => "log_non_positive",
+            ModInverseInvalid => "mod_inverse_invalid",
+            ModInverseNone => "mod_inverse_none",
+            NegativeBase => "negative_base",
+            NegativeExponent => "negative_exponent",
+            NonInteger => "non_integer",
+            NonNumericValue => "non_numeric_value",
+            OutputLimit => "output_limit",
+            OutputRadixInvalid => "output_radix_invalid",
+            QuitInvalid => "quit_invalid",
+            QuitTooBig => "quit_too_big",
+            QuitTooDeep => "quit_too_deep",
+            RandomBoundInvalid => "random_bound_invalid",
+            RegisterEmpty(_) => "register_empty",
+            RemainderByZero => "remainder_by_zero",
+            RootIndexInvalid => "root_index_invalid",
+            RootNegative => "root_negative",
+            RoundNonNumeric => "round_non_numeric",
+            RoundingModeInvalid => "rounding_mode_invalid",
+            ScaleInvalid => "scale_invalid",
+            ScaleTooBig => "scale_too_big",
+            ShellUnsupported => "shell_unsupported",
+            ShiftCountInvalid => "shift_count_invalid",
+            ShiftCountTooBig => "shift_count_too_big",
+            SqrtNegative => "sqrt_negative",
+            SqrtNonNumeric => "sqrt_non_numeric",
+            StackDepth(_) => "stack_depth",
+            StackEmpty => "stack_empty",
+            StackRegisterEmpty(_) => "stack_register_empty",
+            TransactionAborted => "transaction_aborted",
+            UnexpectedNumberChar(_) => "unexpected_number_char",
+            Unimplemented(_) => "unimplemented",
+        }
+    }
+}
+
 impl std::fmt::Display for DcError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use DcError::*;
         // error messages should match those from GNU dc as much as possible
         match self {
             ArrayIndexInvalid => f.write_str("array index must be a nonnegative integer"),
+            ArrayIndexString => f.write_str("array index must be a nonnegative integer, not a string"),
+            ArrayIndexTooBig(max) => write!(f, "array index out of range (must be at most {max})"),
+            ArctanNonNumeric => f.write_str("arctangent of nonnumeric attempted"),
+            BitwiseInvalid => f.write_str("bitwise operation on negative or non-integer number"),
+            Cancelled => f.write_str("cancelled"),
+            ComparisonNonNumeric(op, r) => write!(f,
+                "non-numeric value in '{op}' comparison for register '{}' (0{r:o})", *r as char),
+            DigitInvalid(c, radix) => write!(f,
+                "digit '{}' is not valid in input radix {radix}", *c as char),
             DivideByZero => f.write_str("divide by zero"),
+            ExpNonNumeric => f.write_str("exponential of nonnumeric attempted"),
+            ExponentTooBig => f.write_str("exponent out of range (must fit into 32 bits)"),
+            FactorialInvalid => f.write_str("factorial of negative or non-integer number"),
+            FactorialTooBig => f.write_str("factorial argument too big"),
+            FlavorMismatch(program, interpreter) => write!(f,
+                "program was compiled for the {program} flavor, but this is the {interpreter} flavor"),
+            FloatNotFinite => f.write_str("infinity and NaN can't be pushed as numbers"),
             InputError(e) => write!(f, "error reading input: {e}"),
             InputRadixInvalid => f.write_str("input base must be a number between 2 and 16 (inclusive)"),
+            InvalidActionSequence => f.write_str("invalid action, which no program could give"),
+            LibraryUnknown(name) => write!(f, "no such library package: {name:?}"),
+            LogBaseInvalid => f.write_str("logarithm base must be positive and not 1"),
+            LogNonNumeric => f.write_str("logarithm of nonnumeric attempted"),
+            LogNonPositive => f.write_str("logarithm of nonpositive number attempted"),
+            ModInverseInvalid =>
+                f.write_str("modular inverse needs a positive integer and modulus"),
+            ModInverseNone =>
+                f.write_str("no modular inverse: the number and modulus have a common factor"),
+            NegativeBase => f.write_str("negative base with fractional exponent"),
             NegativeExponent => f.write_str("negative exponent"),
+            NonInteger => f.write_str("non-integer number"),
             NonNumericValue => f.write_str("non-numeric value"),
+            OutputLimit => f.write_str("output limit reached; further output suppressed"),
             OutputRadixInvalid => f.write_str("output base must be a number between 2 and 16 (inclusive)"),
             QuitInvalid => f.write_str("Q command requires a number >= 1"),
             QuitTooBig => f.write_str("quit levels out of range (must fit into 32 bits)"),
+            QuitTooDeep => f.write_str("Q command argument exceeded string execution depth"),
+            RandomBoundInvalid => f.write_str("random bound must be a positive integer"),
             RegisterEmpty(r) => write!(f, "register '{}' (0{r:o}) is empty", *r as char),
             RemainderByZero => f.write_str("remainder by zero"),
+            RootIndexInvalid => f.write_str("root index must be nonzero and fit into 32 bits"),
+            RootNegative => f.write_str("even root of negative number"),
+            RoundNonNumeric => f.write_str("rounding of nonnumeric attempted"),
+            RoundingModeInvalid =>
+                f.write_str("rounding mode must be 0 (truncate), 1 (half up), or 2 (half even)"),
             ScaleInvalid => f.write_str("scale must be a nonnegative integer"),
             ScaleTooBig => f.write_str("scale must fit into 32 bits"),
             ShellUnsupported => f.write_str("running shell commands is not supported"),
+            ShiftCountInvalid => f.write_str("shift count must be a nonnegative integer"),
+            ShiftCountTooBig => f.write_str("shift count out of range (must fit into 32 bits)"),
             SqrtNegative => f.write_str("square root of negative number"),
             SqrtNonNumeric => f.write_str("square root of nonnumeric attempted"),
+            StackDepth(n) => write!(f, "expected exactly one value on the stack, found {n}"),
             StackEmpty => f.write_str("stack empty"),
             StackRegisterEmpty(r) => write!(f, "stack register '{}' (0{r:o}) is empty", *r as char),
+            TransactionAborted => f.write_str("transaction rolled back after an error"),
             UnexpectedNumberChar(c) => write!(f, "unexpected character in number: {:?}", *c as char),
             Unimplemented(c) => write!(f, "{:?} (0{c:o}) unimplemented", *c as char),
         }
@@ -165,3 +1189,9 @@ impl std::fmt::Display for DcError {
 }
 
 impl std::error::Error for DcError {}
+
+impl From<big_real::Interrupted> for DcError {
+    fn from(_: big_real::Interrupted) -> Self {
+        DcError::Cancelled
+    }
+}
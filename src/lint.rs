@@ -0,0 +1,136 @@
+//
+// dc4 :: checking program files for problems without running them
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::diagnostic::Position;
+use crate::parser::{check_program_with_notation, Flavor, ParseIssueKind};
+use crate::reader_parser::BOM;
+
+/// Which problems [`lint_path`] and [`lint_reader`] report, besides the ones which are always
+/// reported: unimplemented commands, shell commands, and commands cut off at the end. Also how
+/// they read numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LintOptions {
+    /// Numbers with digits too big for the input radix, like `1A` in decimal. dc reads them
+    /// anyway, unless [`Dc4::set_strict_digits`](crate::Dc4::set_strict_digits) is on, so these
+    /// are off by default.
+    pub strict_digits: bool,
+    /// A string still open at the end of the text. dc pushes it anyway, but it usually means a
+    /// `]` is missing, so these are on by default.
+    pub unterminated_strings: bool,
+    /// Whether numbers can have an exponent, like `6.022e23`, as with
+    /// [`Dc4::set_scientific_notation`](crate::Dc4::set_scientific_notation). Otherwise the `e`
+    /// is reported as unimplemented. Off by default, like there.
+    pub scientific_notation: bool,
+}
+
+impl Default for LintOptions {
+    fn default() -> Self {
+        Self { strict_digits: false, unterminated_strings: true, scientific_notation: false }
+    }
+}
+
+/// A problem found by [`lint_path`] or [`lint_reader`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintIssue {
+    /// Where the problem was found. A byte order mark at the start of the text isn't counted, as
+    /// when running it.
+    pub position: Position,
+    pub kind: ParseIssueKind,
+}
+
+impl std::fmt::Display for LintIssue {
+    /// Formats the issue as `line:column: message`, to follow a file name and a colon.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.position.line, self.position.column, self.kind)
+    }
+}
+
+/// Read a program file and check it for problems without running it, as
+/// [`check_program_with_flavor`] does.
+pub fn lint_path(path: impl AsRef<Path>, flavor: Flavor, options: LintOptions)
+    -> io::Result<Vec<LintIssue>>
+{
+    let text = std::fs::read(path)?;
+    Ok(lint_text(&text, flavor, options))
+}
+
+/// Read a program to the end and check it for problems without running it, as
+/// [`check_program_with_flavor`] does.
+pub fn lint_reader(mut r: impl Read, flavor: Flavor, options: LintOptions)
+    -> io::Result<Vec<LintIssue>>
+{
+    let mut text = vec![];
+    r.read_to_end(&mut text)?;
+    Ok(lint_text(&text, flavor, options))
+}
+
+fn lint_text(text: &[u8], flavor: Flavor, options: LintOptions) -> Vec<LintIssue> {
+    let text = text.strip_prefix(BOM).unwrap_or(text);
+    // Where each line starts, to find the line and column of an offset.
+    let line_starts = std::iter::once(0)
+        .chain(text.iter().enumerate().filter(|(_, &c)| c == b'\n').map(|(i, _)| i + 1))
+        .collect::<Vec<usize>>();
+    check_program_with_notation(text, flavor, options.scientific_notation)
+        .into_iter()
+        .filter(|issue| match issue.kind {
+            ParseIssueKind::DigitTooBig { .. } => options.strict_digits,
+            ParseIssueKind::UnterminatedString => options.unterminated_strings,
+            _ => true,
+        })
+        .map(|issue| {
+            let line = line_starts.partition_point(|&start| start <= issue.offset);
+            let position = Position {
+                offset: issue.offset as u64,
+                line: line as u64,
+                column: (issue.offset - line_starts[line - 1] + 1) as u64,
+            };
+            LintIssue { position, kind: issue.kind }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lint(text: &[u8], options: LintOptions) -> Vec<String> {
+        lint_reader(text, Flavor::Gnu, options).unwrap()
+            .iter()
+            .map(LintIssue::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_positions() {
+        let options = LintOptions::default();
+        assert_eq!(lint(b"&\n1 2 &\n\n  ]", options),
+            ["1:1: '&' (046) unimplemented", "2:5: '&' (046) unimplemented",
+                "4:3: ']' (0135) unimplemented"]);
+        assert_eq!(lint(b"1 2+p\n[abc\n", options), ["3:1: unterminated string"]);
+        assert_eq!(lint(b"", options), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_options() {
+        let text = b"1A p [abc";
+        assert_eq!(lint(text, LintOptions::default()), ["1:10: unterminated string"]);
+        assert_eq!(lint(text, LintOptions { strict_digits: true, unterminated_strings: false,
+                scientific_notation: false }),
+            ["1:1: digit 'A' is not valid in input radix 10; the number is read as 20"]);
+        let options = LintOptions { scientific_notation: true, ..LintOptions::default() };
+        assert_eq!(lint(b"1e5 2.5e_3 p", options), Vec::<String>::new());
+        // Only the digits before the exponent have to be valid in the input radix.
+        let options = LintOptions { strict_digits: true, ..options };
+        assert_eq!(lint(b"15e1 2i 10e5 p 12e1", options),
+            ["1:16: digit '2' is not valid in input radix 2; the number is read as 40"]);
+        // A number whose exponent is too big has no value to show, so it's left to the run.
+        assert_eq!(lint(b"2i 12e99999999999", options), Vec::<String>::new());
+        assert_eq!(lint(b"1e5 p", LintOptions::default()), ["1:2: 'e' (0145) unimplemented"]);
+    }
+}
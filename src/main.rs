@@ -9,13 +9,26 @@
 
 #![deny(rust_2018_idioms)]
 
+mod config;
+mod console;
+mod selftest;
+
+use std::cell::RefCell;
 use std::env;
+use std::ffi::OsStr;
+use std::fmt::Write as _;
 use std::fs::File;
-use std::io;
-use std::path::Path;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 use dc4::Dc4;
-use dc4::DcResult;
+use dc4::{DcHighWater, DcResult, DcTimings, ErrorPolicy, LintOptions};
+use dc4::diagnostic::{Diagnostic, Severity};
+use dc4::parser::{command_table, CommandHelp, Flavor};
+
+use config::Settings;
 
 fn progname() -> String {
     Path::new(env::args_os().next().expect("no program name?!").as_os_str())
@@ -24,6 +37,26 @@ fn progname() -> String {
         .into_owned()
 }
 
+/// The exit status after an internal error (a panic), to tell it apart from errors in the program
+/// being run. This is `EX_SOFTWARE` from sysexits.h.
+const INTERNAL_ERROR_STATUS: i32 = 70;
+
+/// Report panics as internal errors, after flushing whatever output came before them.
+fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let _ = io::stdout().flush();
+        let payload = info.payload();
+        let message = payload.downcast_ref::<&str>().copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        let location = info.location()
+            .map(|loc| format!(" at {}:{}", loc.file(), loc.line()))
+            .unwrap_or_default();
+        eprintln!("{}: internal error: {message}{location}", progname());
+        eprintln!("{}: this is a bug in dc4; please report it", progname());
+    }));
+}
+
 fn print_version() {
     println!("dc4 version {}", env!("CARGO_PKG_VERSION"));
     println!("Copyright (c) 2015-2024 by William R. Fraser");
@@ -35,6 +68,55 @@ fn print_usage() {
     println!("options:");
     println!("  -e EXPR | --expression=EXPR     evaluate expression");
     println!("  -f FILE | --file=FILE           evaluate contents of file");
+    println!("  --flavor=FLAVOR                 behave like another dc where they differ: 'gnu'");
+    println!("                                  (the default), 'bsd', or 'gavin'");
+    println!("  --diagnostics=FORMAT            how to write errors and warnings: 'text' (the");
+    println!("                                  default) writes them to standard output along");
+    println!("                                  with the program's output; 'json' writes one");
+    println!("                                  JSON object per line to standard error");
+    println!("  --color=WHEN                    color text errors and warnings: 'auto' (the");
+    println!("                                  default) when standard output is a terminal,");
+    println!("                                  unless NO_COLOR is set, or whenever FORCE_COLOR");
+    println!("                                  is set (and not 0); 'always'; or 'never'");
+    println!("  --strict[=INPUTS]               stop at the first error and exit with a");
+    println!("                                  non-zero status, for the INPUTS given as a");
+    println!("                                  comma-separated list of 'expressions',");
+    println!("                                  'files', and 'stdin' (all of them if none are");
+    println!("                                  given), except for commands typed at a");
+    println!("                                  terminal");
+    println!("  --strict-digits                 make it an error for a number to have digits");
+    println!("                                  too big for the input radix, like '1A' in");
+    println!("                                  decimal (a lone digit like 'A' is allowed)");
+    println!("  --exact-fractions               read fractions in other input radixes");
+    println!("                                  exactly, so '16i 1.F' is 1.9375, not 1.9");
+    println!("  --scientific-notation           allow numbers with an exponent, like '6.022e23'");
+    println!("                                  or '2e-2'");
+    println!("  --max-output=BYTES              stop writing output after BYTES bytes; with");
+    println!("                                  --strict, stop the program there too");
+    println!("  --permissive                    skip a '#!' line at the start of files, even");
+    println!("                                  one ending in a lone carriage return");
+    #[cfg(feature = "mmap")]
+    println!("  --mmap                          memory-map input files instead of reading them");
+    println!("  --echo                          write each command to standard error as it runs");
+    println!("  --timings                       at the end, write a table of where the time");
+    println!("                                  went to standard error");
+    println!("  --stats                         at the end, write the sizes of the biggest");
+    println!("                                  numbers, strings, and arrays made to standard");
+    println!("                                  error");
+    println!("  --dump-state                    at the end, write a summary of the stack and");
+    println!("                                  registers to standard error");
+    println!("  --stdlib=PACKAGES               load macros into registers before running:");
+    println!("                                  a comma-separated list of 'math', 'stack',");
+    println!("                                  and 'array' (see the dc4::stdlib docs)");
+    println!("  --check                         check the inputs for problems without running");
+    println!("                                  them, printing 'FILE:LINE:COLUMN: problem'");
+    println!("                                  for each, and exit with a non-zero status if");
+    println!("                                  there are any; with --strict-digits, report");
+    println!("                                  digits too big for the input radix too");
+    println!("  --self-test                     run a quick built-in set of checks and exit,");
+    println!("                                  with a non-zero status if any fail");
+    println!("  --config=FILE                   read settings from FILE instead of");
+    println!("                                  ~/.config/dc4/config.toml");
     println!("  -h | --help                     display this help and exit");
     println!("  -V | --version                  output version information and exit");
     println!();
@@ -43,6 +125,196 @@ fn print_usage() {
     println!("input. An argument of '--' disables further command line option processing and");
     println!("all subsequent arguments are interpreted as file names. If no inputs are given,");
     println!("input will be taken from standard input.");
+    println!();
+    println!("The config file sets defaults for the options above, as 'key = value' lines:");
+    println!("'flavor', 'diagnostics', and 'strict' take the same values as the options,");
+    println!("and 'strict' can also be true or false; 'strict_digits', 'exact_fractions',");
+    println!("'scientific_notation', 'echo', 'warn_once', 'verbose_errors', and");
+    println!("'tail_call_optimization' are true or false; 'max_output' is a number of bytes.");
+    println!("Options given on the command line take precedence.");
+    println!();
+    println!("When standard input is a terminal, type ':help' on a line by itself for a");
+    println!("summary of dc commands.");
+}
+
+fn help_line(cmd: &CommandHelp) -> String {
+    let mut line = format!("  {:<8}{}", cmd.syntax, cmd.summary);
+    if cmd.extension {
+        line += " [dc4 extension]";
+    }
+    line
+}
+
+/// Summary of all commands, for the interactive ":help" meta-command.
+fn help_summary(flavor: Flavor) -> String {
+    let mut text = format!("dc commands ({flavor} flavor):\n");
+    for cmd in command_table(flavor) {
+        text += &help_line(cmd);
+        text.push('\n');
+    }
+    text += "Type ':help C' for help on the commands beginning with C.\n";
+    text
+}
+
+/// Help for the commands beginning with the first character of `arg`.
+fn help_command(flavor: Flavor, arg: &[u8]) -> String {
+    let mut text = String::new();
+    for cmd in command_table(flavor).filter(|cmd| Some(&cmd.byte) == arg.first()) {
+        text += &help_line(cmd);
+        text.push('\n');
+    }
+    if text.is_empty() {
+        text = format!("no such command: {}\n", String::from_utf8_lossy(arg));
+    }
+    text
+}
+
+/// Wraps standard input when it's a terminal, to handle meta-commands which aren't part of the
+/// dc language. These must be on a line by themselves:
+///   :help     print a summary of all commands
+///   :help C   describe the commands beginning with C
+struct InteractiveInput<R> {
+    inner: R,
+    flavor: Flavor,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> InteractiveInput<R> {
+    fn new(inner: R, flavor: Flavor) -> Self {
+        Self { inner, flavor, line: vec![], pos: 0 }
+    }
+
+    fn meta_command(&self) -> Option<String> {
+        let rest = self.line.strip_prefix(b":help")?;
+        if !rest.is_empty() && !rest[0].is_ascii_whitespace() {
+            return None;
+        }
+        let arg = rest.trim_ascii();
+        Some(if arg.is_empty() {
+            help_summary(self.flavor)
+        } else {
+            help_command(self.flavor, arg)
+        })
+    }
+}
+
+impl<R: BufRead> Read for InteractiveInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for InteractiveInput<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.line.len() {
+            self.line.clear();
+            self.pos = 0;
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                break; // EOF
+            }
+            if let Some(text) = self.meta_command() {
+                print!("{text}");
+                self.line.clear();
+            }
+        }
+        Ok(&self.line[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+/// Reads a line at a time from a reader shared with others. When the program is read from
+/// standard input, the `?` command needs to read from it too: with both reading through one of
+/// these, the program reader only ever buffers the line it's on, and `?` gets the next one.
+struct SharedLines<R> {
+    shared: Rc<RefCell<R>>,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> SharedLines<R> {
+    fn new(shared: Rc<RefCell<R>>) -> Self {
+        Self { shared, line: vec![], pos: 0 }
+    }
+}
+
+impl<R: BufRead> Read for SharedLines<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for SharedLines<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.line.len() {
+            self.line.clear();
+            self.pos = 0;
+            self.shared.borrow_mut().read_until(b'\n', &mut self.line)?;
+        }
+        Ok(&self.line[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+/// Skip a `#!` line at the start of a file. A `#` comment does the same, except that it only ends
+/// at a newline, which files with old Mac line endings (a lone carriage return) don't have.
+fn skip_shebang(r: &mut impl BufRead) -> io::Result<()> {
+    if !r.fill_buf()?.starts_with(b"#!") {
+        return Ok(());
+    }
+    loop {
+        let buf = r.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|&c| c == b'\n' || c == b'\r') {
+            Some(end) => {
+                r.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buf.len();
+                r.consume(len);
+            }
+        }
+    }
+}
+
+/// Memory-map a file to run the program in it, or `None` to read it normally instead, if it's a
+/// pipe or something else that can't be mapped.
+#[cfg(feature = "mmap")]
+fn map_file(file: &File) -> Option<memmap2::Mmap> {
+    if !file.metadata().ok()?.is_file() {
+        return None;
+    }
+    // SAFETY: the map is only read from. If another process changes the file while it's mapped,
+    // the program can change as it runs, or the process can be killed if the file shrinks; that's
+    // what --mmap asks for.
+    unsafe { memmap2::Mmap::map(file) }.ok()
+}
+
+/// Run a program from standard input (or whatever is wrapping it), with the `?` command reading
+/// from it too.
+fn run_stdin(dc: &mut Dc4, input: impl BufRead + 'static) -> DcResult {
+    let shared = Rc::new(RefCell::new(input));
+    dc.set_line_reader(SharedLines::new(Rc::clone(&shared)));
+    let result = dc.stream_named("<stdin>", &mut SharedLines::new(shared), &mut console::stdout());
+    dc.clear_line_reader();
+    result
 }
 
 #[derive(Debug, PartialEq)]
@@ -52,13 +324,171 @@ enum DcInput<'a> {
     Stdin,
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum DiagnosticsFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl DiagnosticsFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(DiagnosticsFormat::Text),
+            "json" => Some(DiagnosticsFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Which kinds of input stop at the first error, from `--strict`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct StrictInputs {
+    expressions: bool,
+    files: bool,
+    stdin: bool,
+}
+
+impl StrictInputs {
+    const ALL: Self = StrictInputs { expressions: true, files: true, stdin: true };
+
+    /// Parse a comma-separated list of kinds of input.
+    fn from_names(names: &str) -> Option<Self> {
+        let mut inputs = StrictInputs::default();
+        for name in names.split(',') {
+            match name {
+                "expressions" => inputs.expressions = true,
+                "files" => inputs.files = true,
+                "stdin" => inputs.stdin = true,
+                _ => return None,
+            }
+        }
+        Some(inputs)
+    }
+
+    fn policy(self, input: &DcInput<'_>) -> ErrorPolicy {
+        let strict = match input {
+            DcInput::Expression(_) => self.expressions,
+            DcInput::File(_) => self.files,
+            DcInput::Stdin => self.stdin,
+        };
+        if strict {
+            ErrorPolicy::AbortProgram
+        } else {
+            ErrorPolicy::Continue
+        }
+    }
+}
+
+/// Whether to color errors and warnings, from `--color`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum ColorChoice {
+    /// Only when standard output is a terminal, unless the environment says otherwise.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+}
+
+/// Whether to use color, given the choice from the command line, the `NO_COLOR` and
+/// `FORCE_COLOR` environment variables, and whether the output is a terminal. An empty variable
+/// counts as unset. When it's left to the environment, `FORCE_COLOR` wins over `NO_COLOR`, and
+/// `FORCE_COLOR=0` means no color.
+fn color_enabled(choice: ColorChoice, no_color: Option<&OsStr>, force_color: Option<&OsStr>,
+    terminal: bool) -> bool
+{
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => match force_color.filter(|value| !value.is_empty()) {
+            Some(value) => value != "0",
+            None => no_color.is_none_or(OsStr::is_empty) && terminal,
+        },
+    }
+}
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// Format a diagnostic as text, the same as the library writes it to the output when there's no
+/// diagnostic handler. With `color`, the program name is red for errors and yellow for warnings,
+/// as are the word "warning" and the caret under an excerpt, and the input name is dimmed.
+fn render_diagnostic(diag: &Diagnostic, program_name: &str, color: bool) -> String {
+    let paint = |code: &str, text: &str| {
+        if color { format!("{code}{text}{RESET}") } else { text.to_owned() }
+    };
+    let severity_color = match diag.severity {
+        Severity::Error => RED,
+        Severity::Warning => YELLOW,
+    };
+    let mut out = paint(severity_color, &format!("{program_name}:"));
+    if let Some(input) = &diag.input {
+        out += " ";
+        out += &paint(DIM, &format!("{input}:"));
+    }
+    if diag.severity == Severity::Warning {
+        out += " ";
+        out += &paint(YELLOW, "warning:");
+    }
+    writeln!(out, " {}", diag.message).unwrap();
+    if let Some(excerpt) = &diag.excerpt {
+        // Anything but printable ASCII is escaped in the excerpt, so each character is one
+        // column, and the caret lines up.
+        writeln!(out, "    {}", excerpt.text).unwrap();
+        writeln!(out, "    {:caret$}{}", "", paint(severity_color, "^"), caret = excerpt.caret)
+            .unwrap();
+    }
+    out
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Options<'a> {
+    inputs: Vec<DcInput<'a>>,
+    /// Settings given on the command line.
+    settings: Settings,
+    config: Option<&'a str>,
+    timings: bool,
+    stats: bool,
+    dump_state: bool,
+    permissive: bool,
+    /// Memory-map input files, from `--mmap`.
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    self_test: bool,
+    check: bool,
+    color: ColorChoice,
+    /// Library packages to load, from `--stdlib`.
+    stdlib: Vec<&'a str>,
+}
+
 fn parse_arguments<'a>(args: &'a [&'a str])
-        -> Option<Vec<DcInput<'a>>> {
-    let mut inputs: Vec<DcInput<'a>> = Vec::new();
+        -> Option<Options<'a>> {
+    let mut opts = Options::default();
+    let inputs = &mut opts.inputs;
     let mut bare_file_args: Vec<DcInput<'a>> = Vec::new();
 
     let expression_str = "--expression=";
     let file_str = "--file=";
+    let diagnostics_str = "--diagnostics=";
+    let flavor_str = "--flavor=";
+    let config_str = "--config=";
+    let max_output_str = "--max-output=";
+    let strict_str = "--strict=";
+    let stdlib_str = "--stdlib=";
+    let color_str = "--color=";
 
     let mut process_stdin = true;
     let mut seen_double_dash = false;
@@ -71,6 +501,13 @@ fn parse_arguments<'a>(args: &'a [&'a str])
             continue;
         }
 
+        // This is only an option when it's been built in.
+        #[cfg(feature = "mmap")]
+        if arg == "--mmap" && !seen_double_dash {
+            opts.mmap = true;
+            continue;
+        }
+
         if seen_double_dash {
             inputs.push(DcInput::File(arg));
             process_stdin = false;
@@ -117,6 +554,110 @@ fn parse_arguments<'a>(args: &'a [&'a str])
             skip = 1;
             process_stdin = false;
         }
+        else if let Some(format) = arg.strip_prefix(diagnostics_str) {
+            opts.settings.diagnostics = match DiagnosticsFormat::from_name(format) {
+                Some(format) => Some(format),
+                None => {
+                    println!("invalid diagnostics format {format:?}: must be 'text' or 'json'.");
+                    return None;
+                }
+            };
+        }
+        else if let Some(choice) = arg.strip_prefix(color_str) {
+            opts.color = match ColorChoice::from_name(choice) {
+                Some(choice) => choice,
+                None => {
+                    println!("invalid color choice {choice:?}: must be 'auto', 'always', or \
+                        'never'.");
+                    return None;
+                }
+            };
+        }
+        else if let Some(name) = arg.strip_prefix(flavor_str) {
+            opts.settings.flavor = match Flavor::from_name(name) {
+                Some(flavor) => Some(flavor),
+                None => {
+                    println!("invalid flavor {name:?}: must be 'gnu', 'bsd', or 'gavin'.");
+                    return None;
+                }
+            };
+        }
+        else if arg == "--strict" {
+            opts.settings.strict = Some(StrictInputs::ALL);
+        }
+        else if let Some(names) = arg.strip_prefix(strict_str) {
+            opts.settings.strict = match StrictInputs::from_names(names) {
+                Some(inputs) => Some(inputs),
+                None => {
+                    println!("invalid strict inputs {names:?}: must be a comma-separated list \
+                        of 'expressions', 'files', and 'stdin'.");
+                    return None;
+                }
+            };
+        }
+        else if arg == "--strict-digits" {
+            opts.settings.strict_digits = Some(true);
+        }
+        else if arg == "--exact-fractions" {
+            opts.settings.exact_fractions = Some(true);
+        }
+        else if arg == "--scientific-notation" {
+            opts.settings.scientific_notation = Some(true);
+        }
+        else if let Some(bytes) = arg.strip_prefix(max_output_str) {
+            opts.settings.max_output = match bytes.parse() {
+                Ok(max) => Some(max),
+                Err(_) => {
+                    println!("invalid output limit {bytes:?}: must be a number of bytes.");
+                    return None;
+                }
+            };
+        }
+        else if arg == "--echo" {
+            opts.settings.echo = Some(true);
+        }
+        else if arg == "--timings" {
+            opts.timings = true;
+        }
+        else if arg == "--stats" {
+            opts.stats = true;
+        }
+        else if arg == "--dump-state" {
+            opts.dump_state = true;
+        }
+        else if arg == "--permissive" {
+            opts.permissive = true;
+        }
+        else if let Some(names) = arg.strip_prefix(stdlib_str) {
+            for name in names.split(',') {
+                if dc4::stdlib::package(name).is_none() {
+                    let all = dc4::stdlib::PACKAGES.iter()
+                        .map(|p| format!("'{}'", p.name))
+                        .collect::<Vec<_>>();
+                    println!("invalid library package {name:?}: must be one of {}.",
+                        all.join(", "));
+                    return None;
+                }
+                opts.stdlib.push(name);
+            }
+        }
+        else if arg == "--self-test" {
+            opts.self_test = true;
+        }
+        else if arg == "--check" {
+            opts.check = true;
+        }
+        else if arg == "--config" {
+            if i + 1 == args.len() {
+                println!("\"--config\" must be followed by an argument.");
+                return None;
+            }
+            opts.config = Some(args[i + 1]);
+            skip = 1;
+        }
+        else if let Some(path) = arg.strip_prefix(config_str) {
+            opts.config = Some(path);
+        }
         else if arg == "--" {
             seen_double_dash = true;
         }
@@ -143,50 +684,289 @@ fn parse_arguments<'a>(args: &'a [&'a str])
         inputs.push(DcInput::Stdin);
     }
 
-    Some(inputs)
+    Some(opts)
+}
+
+/// Write a table of where the time went, for `--timings`.
+fn print_timings(timings: &DcTimings) {
+    let total = timings.total.as_secs_f64();
+    let rows = [
+        ("parsing", timings.parsing),
+        ("arithmetic", timings.arithmetic),
+        ("registers", timings.registers),
+        ("printing", timings.printing),
+        ("macros", timings.macros),
+        ("other", timings.other),
+        ("output", timings.output),
+    ];
+    eprintln!("{:<12}{:>14}{:>8}", "phase", "time", "share");
+    for (name, time) in rows {
+        let share = if total > 0. { time.as_secs_f64() / total * 100. } else { 0. };
+        eprintln!("{name:<12}{:>12.3}ms{share:>7.1}%", time.as_secs_f64() * 1000.);
+    }
+    eprintln!("{:<12}{:>12.3}ms", "total", total * 1000.);
+}
+
+/// Write the high-water marks, for `--stats`.
+fn print_high_water(marks: &DcHighWater) {
+    eprintln!("{:<20}{:>12}", "largest number", format!("{} digits", marks.max_digits));
+    eprintln!("{:<20}{:>12}", "longest string", format!("{} bytes", marks.max_string_bytes));
+    eprintln!("{:<20}{:>12}", "stack memory", format!("{} bytes", marks.max_stack_bytes));
+    for (&register, entries) in &marks.max_array_entries {
+        let name = format!("array {}", register as char);
+        eprintln!("{name:<20}{:>12}", format!("{entries} entries"));
+    }
+}
+
+/// Report a problem which happens outside of running a dc program.
+fn report(format: DiagnosticsFormat, color: bool, severity: Severity, code: &'static str,
+    message: String, input: Option<&str>)
+{
+    let diag = Diagnostic {
+        severity,
+        code,
+        message,
+        position: None,
+        input: input.map(str::to_owned),
+        excerpt: None,
+    };
+    match format {
+        DiagnosticsFormat::Text => print!("{}", render_diagnostic(&diag, &progname(), color)),
+        DiagnosticsFormat::Json => eprintln!("{}", diag.to_json()),
+    }
+}
+
+/// Check the inputs for problems without running them, for `--check`, and return the exit
+/// status: 0 if there are none, 1 if there are, and 2 if an input couldn't be read.
+fn check(inputs: &[DcInput<'_>], settings: &Settings, color: bool) -> i32 {
+    let flavor = settings.flavor();
+    let options = LintOptions {
+        strict_digits: settings.strict_digits == Some(true),
+        scientific_notation: settings.scientific_notation == Some(true),
+        ..LintOptions::default()
+    };
+    let count = |n: usize, what: &str| format!("{n} {what}{}", if n == 1 { "" } else { "s" });
+    let mut problems = 0;
+    let mut bad_inputs = 0;
+    let mut unreadable = false;
+    let mut expression_count = 0;
+    for input in inputs {
+        let (name, result) = match *input {
+            DcInput::Expression(expr) => {
+                expression_count += 1;
+                (format!("-e expression #{expression_count}"),
+                    dc4::lint_reader(expr.as_bytes(), flavor, options))
+            }
+            DcInput::File(path) => (path.to_owned(), dc4::lint_path(path, flavor, options)),
+            DcInput::Stdin =>
+                ("<stdin>".to_owned(), dc4::lint_reader(io::stdin().lock(), flavor, options)),
+        };
+        match result {
+            Ok(issues) => {
+                for issue in &issues {
+                    println!("{name}:{issue}");
+                }
+                if !issues.is_empty() {
+                    problems += issues.len();
+                    bad_inputs += 1;
+                }
+            }
+            Err(e) => {
+                report(settings.diagnostics(), color, Severity::Error, "file_open_failed",
+                    format!("reading failed: {e}"), Some(&name));
+                unreadable = true;
+            }
+        }
+    }
+    if problems == 0 {
+        println!("{}: no problems found in {}", progname(), count(inputs.len(), "input"));
+    } else {
+        println!("{}: {} found in {} of {}", progname(), count(problems, "problem"), bad_inputs,
+            count(inputs.len(), "input"));
+    }
+    if unreadable {
+        2
+    } else if problems != 0 {
+        1
+    } else {
+        0
+    }
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let args_references: Vec<&str> = args.iter().map(|owned| &owned[..]).collect();
 
-    let inputs: Vec<DcInput<'_>> = match parse_arguments(&args_references) {
+    let opts = match parse_arguments(&args_references) {
         Some(x) => x,
         None => return,
     };
 
-    let mut dc = Dc4::new(progname());
+    if opts.self_test {
+        match selftest::run(selftest::GROUPS, &mut io::stdout()) {
+            Ok(true) => return,
+            Ok(false) => println!("self-test failed"),
+            Err(e) => eprintln!("{}: self-test output failed: {e}", progname()),
+        }
+        std::process::exit(1);
+    }
+
+    // Diagnostics in text form go to standard output, along with the program's output.
+    let color = color_enabled(opts.color, env::var_os("NO_COLOR").as_deref(),
+        env::var_os("FORCE_COLOR").as_deref(), io::stdout().is_terminal());
 
-    for input in inputs {
-        let result = match input {
+    let config_path = match opts.config {
+        Some(path) => Some(PathBuf::from(path)),
+        None => config::default_path(),
+    };
+    let mut settings = opts.settings.clone();
+    if let Some(path) = config_path {
+        let path_str = path.to_string_lossy();
+        match config::load(&path, opts.config.is_some()) {
+            Ok(config) => {
+                settings = config.settings.overridden_by(opts.settings);
+                for warning in config.warnings {
+                    report(settings.diagnostics(), color, Severity::Warning, "config_warning",
+                        warning, Some(&path_str));
+                }
+            }
+            Err(message) => {
+                report(settings.diagnostics(), color, Severity::Error, "config_invalid", message,
+                    Some(&path_str));
+                return;
+            }
+        }
+    }
+
+    if opts.check {
+        std::process::exit(check(&opts.inputs, &settings, color));
+    }
+
+    let mut dc = settings.build(progname());
+    dc.set_skip_bom(true);
+    dc.set_timings(opts.timings);
+    dc.set_high_water_marks(opts.stats);
+    dc.load_stdlib(&opts.stdlib).expect("library packages were checked already");
+    match settings.diagnostics() {
+        DiagnosticsFormat::Json => {
+            dc.set_diagnostic_handler(|diag| eprintln!("{}", diag.to_json()));
+        }
+        DiagnosticsFormat::Text if color => {
+            let name = progname();
+            dc.set_diagnostic_handler(move |diag| {
+                print!("{}", render_diagnostic(diag, &name, true));
+            });
+        }
+        DiagnosticsFormat::Text => (),
+    }
+
+    let strict = settings.strict.unwrap_or_default();
+
+    install_panic_hook();
+
+    let mut expression_count = 0;
+    let mut exit_status = None;
+    for input in opts.inputs {
+        // In strict mode, errors stop everything, except when typing at a terminal.
+        let policy = strict.policy(&input);
+        dc.set_error_policy(policy);
+        // A panic has already been reported by the hook, and the interpreter may be left in a
+        // bad state, so stop there.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| match input {
             DcInput::Expression(expr) => {
-                dc.text(expr.as_bytes().to_vec(), &mut io::stdout())
+                expression_count += 1;
+                // For testing how internal errors are handled. Release builds don't have it.
+                #[cfg(debug_assertions)]
+                if env::var_os("DC4_TEST_PANIC").is_some_and(|text| text == expr) {
+                    panic!("test panic on {expr:?}");
+                }
+                let name = format!("-e expression #{expression_count}");
+                dc.text_named(&name, expr.as_bytes().to_vec(), &mut console::stdout())
             },
             DcInput::File(path) => {
                 match File::open(path) {
-                    Ok(file) => dc.stream(&mut std::io::BufReader::new(file), &mut io::stdout()),
+                    Ok(file) => {
+                        #[cfg(feature = "mmap")]
+                        if let Some(map) = opts.mmap.then(|| map_file(&file)).flatten() {
+                            let mut text = &map[..];
+                            if opts.permissive {
+                                let _ = skip_shebang(&mut text);
+                            }
+                            return dc.run_slice_named(path, text, &mut console::stdout());
+                        }
+                        let mut reader = io::BufReader::new(file);
+                        if opts.permissive {
+                            // Any error will happen again when reading the program.
+                            let _ = skip_shebang(&mut reader);
+                        }
+                        dc.stream_named(path, &mut reader, &mut console::stdout())
+                    }
                     Err(e)       => {
-                        println!("{}: File open failed on {:?}: {}", progname(), path, e);
-                        DcResult::Terminate(0)
+                        let message = format!("File open failed on {path:?}: {e}");
+                        // The message names the file, so the text form leaves it out of the prefix.
+                        let format = settings.diagnostics();
+                        let input = Some(path).filter(|_| format == DiagnosticsFormat::Json);
+                        report(format, color, Severity::Error, "file_open_failed", message, input);
+                        if policy == ErrorPolicy::AbortProgram {
+                            DcResult::Aborted
+                        } else {
+                            DcResult::Terminate(0)
+                        }
                     }
                 }
             },
             DcInput::Stdin => {
                 let stdin = io::stdin();
-                let mut lock = stdin.lock();
-                dc.stream(&mut lock, &mut io::stdout())
+                if stdin.is_terminal() {
+                    dc.set_error_policy(ErrorPolicy::Continue);
+                    let flavor = dc.flavor();
+                    // Someone typing at a terminal can decide for themselves whether to carry on
+                    // after an internal error, so go on to the next line.
+                    loop {
+                        let input = InteractiveInput::new(stdin.lock(), flavor);
+                        match panic::catch_unwind(AssertUnwindSafe(|| run_stdin(&mut dc, input))) {
+                            Ok(result) => break result,
+                            Err(_) => continue,
+                        }
+                    }
+                } else {
+                    run_stdin(&mut dc, stdin.lock())
+                }
             },
-        };
+        }));
 
+        let Ok(result) = result else {
+            exit_status = Some(INTERNAL_ERROR_STATUS);
+            break;
+        };
         match result {
             DcResult::Macro(_) => panic!("unhandled macro"),
-            DcResult::Terminate(_) => return,
+            DcResult::Terminate(_) => break,
+            DcResult::Aborted => {
+                exit_status = Some(1);
+                break;
+            }
             DcResult::QuitLevels(_) // if there are quit levels left at the end of an input, they
                                     // are ignored.
                 | DcResult::Continue
                 => (),
         }
     }
+
+    if opts.dump_state {
+        // Nothing useful can be done if writing this fails.
+        let _ = dc.dump_state(&mut io::stderr());
+    }
+    let stats = dc.stats();
+    if let Some(timings) = stats.timings {
+        print_timings(&timings);
+    }
+    if let Some(marks) = stats.high_water {
+        print_high_water(&marks);
+    }
+    if let Some(status) = exit_status {
+        std::process::exit(status);
+    }
 }
 
 #[cfg(test)]
@@ -196,7 +976,10 @@ mod test {
     #[test]
     fn test_parseargs() {
         let args: Vec<&str> = vec!["-e", "e1", "file1", "--expression=e2", "file2", "--file=file3", "-", "file4"];
-        let result = parse_arguments(&args).unwrap();
+        let opts = parse_arguments(&args).unwrap();
+        assert_eq!(opts.settings, Settings::default());
+        assert_eq!(opts.config, None);
+        let result = opts.inputs;
 
         // first, the options:
         assert_eq!(result[0], DcInput::Expression("e1"));
@@ -211,4 +994,178 @@ mod test {
 
         assert_eq!(result.len(), 7);
     }
+
+    #[test]
+    fn test_parseargs_settings() {
+        let args: Vec<&str> = vec!["dc4", "--config", "a.toml", "--flavor=bsd", "--config=b.toml"];
+        let opts = parse_arguments(&args).unwrap();
+        assert_eq!(opts.config, Some("b.toml"));
+        assert_eq!(opts.settings, Settings { flavor: Some(Flavor::Bsd), ..Settings::default() });
+        assert_eq!(opts.inputs, vec![DcInput::Stdin]);
+    }
+
+    #[test]
+    fn test_parseargs_stdlib() {
+        let args: Vec<&str> = vec!["dc4", "--stdlib=math,array", "--stdlib=stack"];
+        let opts = parse_arguments(&args).unwrap();
+        assert_eq!(opts.stdlib, vec!["math", "array", "stack"]);
+        assert!(parse_arguments(&["dc4", "--stdlib=math,nope"]).is_none());
+    }
+
+    const SNAPSHOT: &str = r#"dc commands (gnu flavor):
+  p       print the top of the stack, followed by a newline
+  n       pop and print the top of the stack, without a newline
+  P       pop and print a string, or a number as a stream of bytes
+  f       print the entire stack, one value per line
+  +       pop two values and push their sum
+  -       pop two values and push their difference
+  *       pop two values and push their product
+  /       pop two values and push their quotient, to the current scale
+  %       pop two values and push the remainder of their division
+  ~       pop two values and push their quotient, then the remainder
+  ^       pop two values and push the first raised to the power of the second
+  |       pop base, exponent, and modulus, and push the modular exponentiation
+  v       pop a value and push its square root
+  c       clear the stack
+  d       duplicate the top of the stack
+  r       swap the top two values on the stack
+  sr      pop a value and store it in register r
+  lr      push a copy of the value in register r
+  Sr      pop a value and push it onto register r's stack
+  Lr      pop a value off register r's stack and push it
+  i       pop a value and use it as the input radix
+  o       pop a value and use it as the output radix
+  k       pop a value and use it as the scale
+  I       push the input radix
+  O       push the output radix
+  K       push the scale
+  [...]   push a string
+  a       pop a value and push its first character (or low byte) as a string
+  x       pop a value and run it as a macro
+  >r      pop two values and run register r if the top one is greater
+  !>r     pop two values and run register r if the top one is not greater
+  <r      pop two values and run register r if the top one is less
+  !<r     pop two values and run register r if the top one is not less
+  =r      pop two values and run register r if they are equal
+  !=r     pop two values and run register r if they are not equal
+  ?       read a line of input and run it
+  q       exit the current macro and the one that called it
+  Q       pop a value and exit that many levels of macros
+  Z       pop a value and push its number of digits (or length)
+  X       pop a value and push its number of fractional digits
+  z       push the depth of the stack
+  !       run a shell command (not supported)
+  #       ignore the rest of the line
+  :r      pop an index and a value, and store the value in array r
+  ;r      pop an index and push the value at that index of array r
+  @       push the extension list, the version, then "dc4" [dc4 extension]
+  Y       like f, but with each value's depth from the top [dc4 extension]
+  J       print the top of the stack, without a newline or popping it [dc4 extension]
+  tr      save register r until the current macro ends [dc4 extension]
+  V       pop n and a value, and push the value's n-th root [dc4 extension]
+  w       pop a value and push it rounded to the scale [dc4 extension]
+  W       pop a mode for w: 0 truncate, 1 half up, 2 half even [dc4 extension]
+  {       pop a value and push it rounded down to an integer [dc4 extension]
+  }       pop a value and push it rounded up to an integer [dc4 extension]
+  $       pop a value and push its integer part [dc4 extension]
+  m       pop a value and push its absolute value [dc4 extension]
+  (       pop two values and push the smaller one [dc4 extension]
+  )       pop two values and push the larger one [dc4 extension]
+  y       pop two integers and push their greatest common divisor [dc4 extension]
+  M       pop two integers and push their least common multiple [dc4 extension]
+  T       pop n and push n factorial [dc4 extension]
+  H       pop n and an integer; push it shifted left n bits [dc4 extension]
+  h       pop n and an integer; push it shifted right n bits [dc4 extension]
+  '       pop n and push a random integer from 0 to n-1 [dc4 extension]
+  ,       pop m and a; push the inverse of a modulo m [dc4 extension]
+Type ':help C' for help on the commands beginning with C.
+"#;
+
+    #[test]
+    fn test_help_summary_gnu() {
+        assert_eq!(help_summary(Flavor::Gnu), SNAPSHOT);
+    }
+
+    #[test]
+    fn test_help_command() {
+        assert_eq!(help_command(Flavor::Gnu, b"v"), "  v       pop a value and push its square root\n");
+        assert_eq!(help_command(Flavor::Gnu, b"!"), concat!(
+            "  !>r     pop two values and run register r if the top one is not greater\n",
+            "  !<r     pop two values and run register r if the top one is not less\n",
+            "  !=r     pop two values and run register r if they are not equal\n",
+            "  !       run a shell command (not supported)\n"));
+        assert_eq!(help_command(Flavor::Gnu, b"&"), "no such command: &\n");
+    }
+
+    #[test]
+    fn test_color_enabled() {
+        let os = |s| Some(OsStr::new(s));
+        assert!(color_enabled(ColorChoice::Auto, None, None, true));
+        assert!(!color_enabled(ColorChoice::Auto, None, None, false));
+        assert!(!color_enabled(ColorChoice::Auto, os("1"), None, true));
+        assert!(color_enabled(ColorChoice::Auto, os(""), None, true));
+        assert!(color_enabled(ColorChoice::Auto, None, os("1"), false));
+        assert!(color_enabled(ColorChoice::Auto, os("1"), os("1"), false));
+        assert!(!color_enabled(ColorChoice::Auto, None, os("0"), true));
+        assert!(!color_enabled(ColorChoice::Auto, None, os(""), false));
+        // The command line wins over the environment.
+        assert!(color_enabled(ColorChoice::Always, os("1"), None, false));
+        assert!(!color_enabled(ColorChoice::Never, None, os("1"), true));
+
+        assert_eq!(parse_arguments(&["dc4", "--color=never"]).unwrap().color, ColorChoice::Never);
+        assert!(parse_arguments(&["dc4", "--color=sometimes"]).is_none());
+    }
+
+    #[test]
+    fn test_render_diagnostic() {
+        let diag = |severity, input: Option<&str>| Diagnostic {
+            severity,
+            code: "stack_empty",
+            message: "stack empty".to_owned(),
+            position: None,
+            input: input.map(str::to_owned),
+            excerpt: None,
+        };
+        let error = diag(Severity::Error, Some("prog.dc"));
+        assert_eq!(render_diagnostic(&error, "dc4", false), "dc4: prog.dc: stack empty\n");
+        assert_eq!(render_diagnostic(&error, "dc4", true),
+            "\x1b[31mdc4:\x1b[0m \x1b[2mprog.dc:\x1b[0m stack empty\n");
+        let warning = diag(Severity::Warning, None);
+        assert_eq!(render_diagnostic(&warning, "dc4", false), "dc4: warning: stack empty\n");
+        assert_eq!(render_diagnostic(&warning, "dc4", true),
+            "\x1b[33mdc4:\x1b[0m \x1b[33mwarning:\x1b[0m stack empty\n");
+    }
+
+    #[test]
+    fn test_render_excerpt() {
+        // Get a real diagnostic from a macro with multi-byte characters in it.
+        let diags = Rc::new(RefCell::new(vec![]));
+        let mut dc = Dc4::new("dc4".to_owned());
+        dc.set_verbose_errors(true);
+        let sink = Rc::clone(&diags);
+        dc.set_diagnostic_handler(move |diag| sink.borrow_mut().push(diag.clone()));
+        dc.text_named("test", "[\u{e9}t\u{e9} 1 +]x".as_bytes().to_vec(), &mut io::sink());
+        let diag = diags.borrow_mut().pop().unwrap();
+
+        let plain = render_diagnostic(&diag, "dc4", false);
+        assert_eq!(plain, concat!(
+            "dc4: test: stack empty\n",
+            "    \\xc3\\xa9t\\xc3\\xa9 1 +\n",
+            "                        ^\n"));
+        // The caret is under the '+', in the same column with or without color.
+        let lines = plain.lines().collect::<Vec<_>>();
+        assert_eq!(lines[1].find('+'), lines[2].find('^'));
+        assert_eq!(render_diagnostic(&diag, "dc4", true), concat!(
+            "\x1b[31mdc4:\x1b[0m \x1b[2mtest:\x1b[0m stack empty\n",
+            "    \\xc3\\xa9t\\xc3\\xa9 1 +\n",
+            "                        \x1b[31m^\x1b[0m\n"));
+    }
+
+    #[test]
+    fn test_interactive_input() {
+        let mut input = InteractiveInput::new(&b"1 2+\n:help v\np\n:helpless\n"[..], Flavor::Gnu);
+        let mut program = vec![];
+        input.read_to_end(&mut program).unwrap();
+        assert_eq!(program, b"1 2+\np\n:helpless\n");
+    }
 }
@@ -0,0 +1,219 @@
+//
+// dc4 output buffering
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::diagnostic::Diagnostic;
+
+/// A reusable in-memory destination for output, and for diagnostics.
+///
+/// This is for callers which run many programs and capture the output of each separately: calling
+/// [`reset`](Self::reset) between runs keeps the allocations around instead of making new ones
+/// each time. Run programs into it with [`Dc4::text_to_sink`](crate::Dc4::text_to_sink) to collect
+/// their errors and warnings as [`Diagnostic`]s, or use it as any other writer to get them in the
+/// output as usual.
+#[derive(Debug, Default)]
+pub struct OutputSink {
+    output: Vec<u8>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl OutputSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discard everything written and collected so far, keeping the allocated memory for reuse.
+    pub fn reset(&mut self) {
+        self.output.clear();
+        self.diagnostics.clear();
+    }
+
+    /// The output written since the last reset.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// The diagnostics collected since the last reset.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// The output buffer and diagnostics list, for running a program into.
+    pub(crate) fn parts(&mut self) -> (&mut Vec<u8>, &mut Vec<Diagnostic>) {
+        (&mut self.output, &mut self.diagnostics)
+    }
+
+    /// Take the buffer of output, leaving the sink empty.
+    pub fn into_output(self) -> Vec<u8> {
+        self.output
+    }
+}
+
+impl Write for OutputSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.output.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Passes writes through to another writer, noting whether anything was written.
+pub(crate) struct WriteDetector<'a, W> {
+    inner: &'a mut W,
+    written: bool,
+}
+
+impl<'a, W: Write> WriteDetector<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        Self { inner, written: false }
+    }
+
+    pub fn written(&self) -> bool {
+        self.written
+    }
+}
+
+impl<W: Write> Write for WriteDetector<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            self.written = true;
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes writes through to another writer, noting the last byte written.
+pub(crate) struct LastByteWrite<'a, W> {
+    inner: &'a mut W,
+    last: Option<u8>,
+}
+
+impl<'a, W: Write> LastByteWrite<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        Self { inner, last: None }
+    }
+
+    /// The last byte written, if anything was.
+    pub fn last(&self) -> Option<u8> {
+        self.last
+    }
+}
+
+impl<W: Write> Write for LastByteWrite<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.last = Some(buf[n - 1]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Counts the bytes of output written, and cuts the output off once it reaches a limit. This is
+/// shared between the interpreter and the writer its output goes through.
+#[derive(Debug, Default)]
+pub(crate) struct OutputBudget {
+    max: Cell<Option<u64>>,
+    written: Cell<u64>,
+    /// Whether output has been cut off since the last check.
+    cut_off: Cell<bool>,
+    /// Whether output has been cut off and reported as such.
+    reported: Cell<bool>,
+    /// Set while writing the error saying output was cut off, to let it through uncounted.
+    exempt: Cell<bool>,
+}
+
+impl OutputBudget {
+    /// Set the limit, and start counting again from nothing.
+    pub fn set_max(&self, max: Option<u64>) {
+        self.max.set(max);
+        self.written.set(0);
+        self.cut_off.set(false);
+        self.reported.set(false);
+    }
+
+    /// The number of bytes written since the limit was last set.
+    pub fn written(&self) -> u64 {
+        self.written.get()
+    }
+
+    /// Whether output has been cut off, the first time this is asked after it happens.
+    pub fn take_cut_off(&self) -> bool {
+        self.cut_off.get() && !self.reported.replace(true)
+    }
+
+    pub fn set_exempt(&self, exempt: bool) {
+        self.exempt.set(exempt);
+    }
+
+    /// How many bytes of a write of `len` bytes fit in what's left of the budget.
+    fn allowed(&self, len: usize) -> usize {
+        match self.max.get() {
+            Some(max) => {
+                let left = max.saturating_sub(self.written.get());
+                len.min(usize::try_from(left).unwrap_or(usize::MAX))
+            }
+            None => len,
+        }
+    }
+}
+
+/// Passes writes through to another writer until the [`OutputBudget`] runs out, then drops them.
+pub(crate) struct BudgetedWrite<'a, W> {
+    inner: &'a mut W,
+    budget: Rc<OutputBudget>,
+}
+
+impl<'a, W: Write> BudgetedWrite<'a, W> {
+    pub fn new(inner: &'a mut W, budget: Rc<OutputBudget>) -> Self {
+        Self { inner, budget }
+    }
+
+    pub fn inner(&self) -> &W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for BudgetedWrite<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.budget.exempt.get() {
+            return self.inner.write(buf);
+        }
+        let allowed = self.budget.allowed(buf.len());
+        if allowed == buf.len() {
+            let n = self.inner.write(buf)?;
+            self.budget.written.set(self.budget.written.get() + n as u64);
+            return Ok(n);
+        }
+        // Write what fits, and claim to have written the rest too, since the interpreter treats
+        // failing to write as fatal.
+        self.inner.write_all(&buf[.. allowed])?;
+        self.budget.written.set(self.budget.written.get() + allowed as u64);
+        self.budget.cut_off.set(true);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -4,15 +4,120 @@
 // Copyright (c) 2019-2024 by William R. Fraser
 //
 
+pub mod commands;
+
+pub use commands::{command_table, describe, ActionKind, CommandHelp};
+
+use num_traits::ToPrimitive;
+
+use crate::big_real::BigReal;
+use crate::state::literal_value;
+
+/// Which implementation of dc to behave like, where they differ.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flavor {
+    /// GNU dc. This is the default.
+    #[default]
+    Gnu,
+    /// The BSD dc (as found in OpenBSD, FreeBSD, and macOS).
+    Bsd,
+    /// Gavin Howard's dc (as found in bc-gh, and some Linux distributions).
+    Gavin,
+}
+
+impl Flavor {
+    /// Look up a flavor by the name it displays as.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gnu" => Some(Flavor::Gnu),
+            "bsd" => Some(Flavor::Bsd),
+            "gavin" => Some(Flavor::Gavin),
+            _ => None,
+        }
+    }
+
+    /// What the `Q` command does in this flavor when told to quit more levels of macros than are
+    /// running.
+    pub fn quit_overflow(self) -> QuitOverflow {
+        match self {
+            Flavor::Gnu | Flavor::Gavin => QuitOverflow::StopAtInput,
+            Flavor::Bsd => QuitOverflow::Error,
+        }
+    }
+
+    /// What the `^` command does in this flavor when the exponent isn't an integer.
+    pub fn fractional_exponent(self) -> FractionalOperand {
+        match self {
+            Flavor::Gnu => FractionalOperand::Truncate,
+            Flavor::Bsd => FractionalOperand::WarnFraction,
+            Flavor::Gavin => FractionalOperand::Error,
+        }
+    }
+
+    /// What the `|` command does in this flavor when the base, exponent, or modulus isn't an
+    /// integer.
+    pub fn fractional_modexp(self) -> FractionalOperand {
+        match self {
+            Flavor::Gnu | Flavor::Bsd => FractionalOperand::WarnScale,
+            Flavor::Gavin => FractionalOperand::Error,
+        }
+    }
+}
+
+/// What an arithmetic command which only works on integers does with an operand which isn't one.
+/// Apart from [`Error`](Self::Error), the operand's fractional part is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FractionalOperand {
+    /// Say nothing. (GNU `^`)
+    Truncate,
+    /// Warn "non-zero scale in ..." if it has any fractional digits, even if they're all zeros.
+    /// (GNU `|`, which BSD follows too)
+    WarnScale,
+    /// Warn "non-zero fractional part in ..." if any of its fractional digits aren't zero. (BSD
+    /// `^`)
+    WarnFraction,
+    /// It's an error if any of its fractional digits aren't zero. (Gavin)
+    Error,
+}
+
+/// What the `Q` command does when told to quit more levels of macros than are running.
+///
+/// The rest of the quitting rules are the same in every flavor. `q` quits the running macro and
+/// the one which called it, and if that reaches the top level of an input (because `q` was run
+/// from there, or from a macro called directly from there), the whole program ends: no more of
+/// that input or any other is run. `Q` never ends the program, and never quits any part of the
+/// input itself, so the command after the outermost macro it quits is always the next to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuitOverflow {
+    /// Quit all the macros which are running, and carry on with the rest of the input. (GNU and
+    /// Gavin)
+    StopAtInput,
+    /// Quit nothing: it's an error. (BSD)
+    Error,
+}
+
+impl std::fmt::Display for Flavor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Flavor::Gnu => "gnu",
+            Flavor::Bsd => "bsd",
+            Flavor::Gavin => "gavin",
+        })
+    }
+}
+
 pub struct Parser {
     state: Option<ParseState>,
+    flavor: Flavor,
+    /// Whether numbers can have an exponent, like `6.022e23`.
+    scientific: bool,
+    /// The characters of the number or string being parsed, when coalescing literals.
+    literal: Option<Vec<u8>>,
 }
 
 impl Default for Parser {
     fn default() -> Self {
-        Self {
-            state: Some(ParseState::Start),
-        }
+        Self::new(Flavor::default())
     }
 }
 
@@ -28,6 +133,14 @@ pub enum Action {
     PushNumber,
     PushString,
 
+    /// A whole number, as written: the same as its `NumberChar`s followed by `PushNumber`. The
+    /// parser only produces these when [coalescing literals](Parser::set_coalesce_literals).
+    NumberLiteral(Vec<u8>),
+    /// A whole string, without the brackets: the same as its `StringChar`s followed by
+    /// `PushString`. The parser only produces these when
+    /// [coalescing literals](Parser::set_coalesce_literals).
+    StringLiteral(Vec<u8>),
+
     Register(RegisterAction, u8),
 
     Print,              // 'p'
@@ -70,9 +183,123 @@ pub enum Action {
     /// NOTE: DC4 purposely does not implement this or buffer the command to be executed.
     ShellExec,          // '!'
 
-    /// DC4 extension.
+    /// DC4 extension: push the list of extensions in the current flavor (see
+    /// [`features_for`](crate::features_for)), then the version number, then the string "dc4".
+    ///
+    /// Other dcs don't have `@`, and report an error without pushing anything, so a program can
+    /// check whether it's running on dc4 by seeing whether the stack got deeper:
+    ///
+    /// ```text
+    /// [sn sv sf 1sh]sd 0sh   # on dc4: keep the name, version, and features, and set h
+    /// zsz @ z lz- 3=d        # run d if @ pushed three values
+    /// ```
     Version,            // '@'
 
+    /// DC4 extension, for debugging: like `PrintStack`, but with each value's depth from the
+    /// top.
+    PrintStackIndexed,  // 'Y'
+
+    /// DC4 extension: like `Print`, but without the newline. (Like `PrintNoNewlinePop`, but
+    /// without popping.)
+    PrintNoNewline,     // 'J'
+
+    /// DC4 extension: pop n and a value, and push the value's n-th root. Not in Gavin's flavor,
+    /// where 'V' is already a command.
+    Root,               // 'V'
+
+    /// DC4 extension: pop a value and push it rounded to the scale, in the rounding mode.
+    Round,              // 'w'
+
+    /// DC4 extension: pop a value and use it as the rounding mode for `Round`: 0 to truncate, 1
+    /// for half up (the default), or 2 for half even. Not in Gavin's flavor, where 'W' is
+    /// already a command, so there `Round` always rounds half up.
+    SetRoundingMode,    // 'W'
+
+    /// DC4 extension: pop a value and push the greatest integer not greater than it. Not in
+    /// Gavin's flavor, where '{' is already a command.
+    Floor,              // '{'
+
+    /// DC4 extension: pop a value and push the least integer not less than it. Not in Gavin's
+    /// flavor, where '}' is already a command.
+    Ceil,               // '}'
+
+    /// DC4 extension: pop a value and push its integer part, dropping the fraction.
+    Trunc,              // '$'
+
+    /// DC4 extension: pop a value and push its absolute value, keeping its scale. Not in Gavin's
+    /// flavor, where 'm' is already a command.
+    Abs,                // 'm'
+
+    /// DC4 extension: pop two values and push the smaller one. Not in Gavin's flavor, where '('
+    /// is already a command.
+    Min,                // '('
+
+    /// DC4 extension: pop two values and push the larger one. Not in Gavin's flavor, where ')' is
+    /// already a command.
+    Max,                // ')'
+
+    /// DC4 extension: pop two integers and push their greatest common divisor. Not in Gavin's
+    /// flavor, where 'y' is already a command.
+    Gcd,                // 'y'
+
+    /// DC4 extension: pop two integers and push their least common multiple. Not in Gavin's
+    /// flavor, where 'M' is already a command.
+    Lcm,                // 'M'
+
+    /// DC4 extension: pop a nonnegative integer n and push n!. Not in Gavin's flavor, where 'T'
+    /// is already a command.
+    Factorial,          // 'T'
+
+    /// DC4 extension: pop a shift count n and an integer, and push the integer times 2^n.
+    ShiftLeft,          // 'H'
+
+    /// DC4 extension: pop a shift count n and an integer, and push the integer divided by 2^n,
+    /// rounded down, as an arithmetic shift right would in two's complement: so -5 shifted right
+    /// 1 is -3, not -2.
+    ShiftRight,         // 'h'
+
+    /// DC4 extension: pop a positive integer n and push a random integer from 0 to n - 1. Not in
+    /// Gavin's flavor, where '\'' is already a command.
+    Random,             // '\''
+
+    /// DC4 extension: pop a modulus m and a value a, both positive integers, and push the inverse
+    /// of a modulo m: the x from 0 to m - 1 for which a * x mod m is 1. Not in Gavin's flavor,
+    /// where ',' is already a command.
+    ModInverse,         // ','
+
+    // Commands only found in some flavors:
+
+    /// Like `Register` with a comparison, but with a second register to run if the comparison is
+    /// false. (BSD and Gavin)
+    IfElse(RegisterAction, u8, u8), // e.g. '=aeb'
+    /// Pop two values and push 1 if they are equal, 0 otherwise. (Gavin)
+    CompareEq,          // 'G'
+    /// Pop a value and push 1 if it is zero, 0 otherwise. (Gavin)
+    Not,                // 'N'
+    /// DC4 extension: pop a value and push e raised to its power. (Gavin)
+    NaturalExp,         // 'j'
+    /// DC4 extension: pop a value and push its natural logarithm. (Gavin)
+    NaturalLog,         // 'g'
+    /// DC4 extension: pop a base and a value, and push the value's logarithm in that base.
+    /// (Gavin)
+    Log,                // 'b'
+    /// DC4 extension: pop a value and push its arctangent, in radians. (Gavin)
+    Arctan,             // 'u'
+    /// DC4 extension: pop two nonnegative integers and push their bitwise AND. (Gavin)
+    BitAnd,             // '&'
+    /// DC4 extension: pop two nonnegative integers and push their bitwise OR. (Gavin)
+    BitOr,              // 'U'
+    /// DC4 extension: pop two nonnegative integers and push their bitwise exclusive OR.
+    /// (Gavin)
+    BitXor,             // 'R'
+    /// DC4 extension: pop an index, then store values at that index of the arrays in the listed
+    /// registers, the top value going in the last one, so that a record kept in parallel arrays
+    /// can be stored all at once. (Gavin)
+    StoreRecord(Vec<u8>), // e.g. '\:abc'
+    /// DC4 extension: pop an index, and push the value at that index of the array in each listed
+    /// register, in order: the opposite of `StoreRecord`. (Gavin)
+    LoadRecord(Vec<u8>), // e.g. '\;abc'
+
     /// End of input was reached.
     Eof,
 
@@ -85,7 +312,248 @@ pub enum Action {
     InputError(std::io::Error),
 }
 
-#[derive(Debug)]
+impl Action {
+    /// The action for a command which is just one byte.
+    fn single(c: u8) -> Self {
+        match c {
+            // Where possible, keep things ordered like in the GNU dc man page.
+            commands::PRINT => Action::Print,
+            commands::PRINT_NO_NEWLINE_POP => Action::PrintNoNewlinePop,
+            commands::PRINT_BYTES_POP => Action::PrintBytesPop,
+            commands::PRINT_STACK => Action::PrintStack,
+            commands::ADD => Action::Add,
+            commands::SUB => Action::Sub,
+            commands::MUL => Action::Mul,
+            commands::DIV => Action::Div,
+            commands::REM => Action::Rem,
+            commands::DIV_REM => Action::DivRem,
+            commands::EXP => Action::Exp,
+            commands::MOD_EXP => Action::ModExp,
+            commands::SQRT => Action::Sqrt,
+            commands::CLEAR_STACK => Action::ClearStack,
+            commands::DUP => Action::Dup,
+            commands::SWAP => Action::Swap,
+            commands::SET_INPUT_RADIX => Action::SetInputRadix,
+            commands::SET_OUTPUT_RADIX => Action::SetOutputRadix,
+            commands::SET_PRECISION => Action::SetPrecision,
+            commands::LOAD_INPUT_RADIX => Action::LoadInputRadix,
+            commands::LOAD_OUTPUT_RADIX => Action::LoadOutputRadix,
+            commands::LOAD_PRECISION => Action::LoadPrecision,
+            commands::ASCIIFY => Action::Asciify,
+            commands::EXECUTE_MACRO => Action::ExecuteMacro,
+            commands::INPUT => Action::Input,
+            commands::QUIT => Action::Quit,
+            commands::QUIT_LEVELS => Action::QuitLevels,
+            commands::NUM_DIGITS => Action::NumDigits,
+            commands::NUM_FRX_DIGITS => Action::NumFrxDigits,
+            commands::STACK_DEPTH => Action::StackDepth,
+            commands::VERSION => Action::Version,
+            commands::PRINT_STACK_INDEXED => Action::PrintStackIndexed,
+            commands::PRINT_NO_NEWLINE => Action::PrintNoNewline,
+            commands::ROOT => Action::Root,
+            commands::ROUND => Action::Round,
+            commands::SET_ROUNDING_MODE => Action::SetRoundingMode,
+            commands::FLOOR => Action::Floor,
+            commands::CEIL => Action::Ceil,
+            commands::TRUNC => Action::Trunc,
+            commands::ABS => Action::Abs,
+            commands::MIN => Action::Min,
+            commands::MAX => Action::Max,
+            commands::GCD => Action::Gcd,
+            commands::LCM => Action::Lcm,
+            commands::FACTORIAL => Action::Factorial,
+            commands::SHIFT_LEFT => Action::ShiftLeft,
+            commands::SHIFT_RIGHT => Action::ShiftRight,
+            commands::RANDOM => Action::Random,
+            commands::MOD_INVERSE => Action::ModInverse,
+            commands::COMPARE_EQ => Action::CompareEq,
+            commands::NOT => Action::Not,
+            commands::NATURAL_EXP => Action::NaturalExp,
+            commands::NATURAL_LOG => Action::NaturalLog,
+            commands::LOG => Action::Log,
+            commands::ARCTAN => Action::Arctan,
+            commands::BIT_AND => Action::BitAnd,
+            commands::BIT_OR => Action::BitOr,
+            commands::BIT_XOR => Action::BitXor,
+            _ => unreachable!("no action for the command {:?}", c as char),
+        }
+    }
+
+    /// The command as it would be written in a program, e.g. `sx` for
+    /// `Register(RegisterAction::Store, b'x')`. Numbers and strings are made up of several actions,
+    /// so for those this is empty, as it is for actions which don't come from a command, except
+    /// for whole literals, which give the number or the bracketed string.
+    pub fn command(&self) -> Vec<u8> {
+        let cmd: &[u8] = match self {
+            Action::NumberLiteral(digits) => return digits.clone(),
+            Action::StringLiteral(text) => return [&b"["[..], text, b"]"].concat(),
+            Action::NumberChar(_)
+                | Action::StringChar(_)
+                | Action::PushNumber
+                | Action::PushString
+                | Action::Eof
+                | Action::InputError(_) => b"",
+            Action::Register(action, r) => return [action.command().as_bytes(), &[*r]].concat(),
+            Action::Print => b"p",
+            Action::PrintNoNewlinePop => b"n",
+            Action::PrintBytesPop => b"P",
+            Action::PrintStack => b"f",
+            Action::Add => b"+",
+            Action::Sub => b"-",
+            Action::Mul => b"*",
+            Action::Div => b"/",
+            Action::Rem => b"%",
+            Action::DivRem => b"~",
+            Action::Exp => b"^",
+            Action::ModExp => b"|",
+            Action::Sqrt => b"v",
+            Action::ClearStack => b"c",
+            Action::Dup => b"d",
+            Action::Swap => b"r",
+            Action::SetInputRadix => b"i",
+            Action::SetOutputRadix => b"o",
+            Action::SetPrecision => b"k",
+            Action::LoadInputRadix => b"I",
+            Action::LoadOutputRadix => b"O",
+            Action::LoadPrecision => b"K",
+            Action::Asciify => b"a",
+            Action::ExecuteMacro => b"x",
+            Action::Input => b"?",
+            Action::Quit => b"q",
+            Action::QuitLevels => b"Q",
+            Action::NumDigits => b"Z",
+            Action::NumFrxDigits => b"X",
+            Action::StackDepth => b"z",
+            Action::ShellExec => b"!",
+            Action::Version => b"@",
+            Action::PrintStackIndexed => b"Y",
+            Action::PrintNoNewline => b"J",
+            Action::Root => b"V",
+            Action::Round => b"w",
+            Action::SetRoundingMode => b"W",
+            Action::Floor => b"{",
+            Action::Ceil => b"}",
+            Action::Trunc => b"$",
+            Action::Abs => b"m",
+            Action::Min => b"(",
+            Action::Max => b")",
+            Action::Gcd => b"y",
+            Action::Lcm => b"M",
+            Action::Factorial => b"T",
+            Action::ShiftLeft => b"H",
+            Action::ShiftRight => b"h",
+            Action::Random => b"'",
+            Action::ModInverse => b",",
+            Action::IfElse(action, r, else_r) =>
+                return [action.command().as_bytes(), &[*r, b'e', *else_r]].concat(),
+            Action::CompareEq => b"G",
+            Action::Not => b"N",
+            Action::NaturalExp => b"j",
+            Action::NaturalLog => b"g",
+            Action::Log => b"b",
+            Action::Arctan => b"u",
+            Action::BitAnd => b"&",
+            Action::BitOr => b"U",
+            Action::BitXor => b"R",
+            Action::StoreRecord(registers) => return [&b"\\:"[..], registers].concat(),
+            Action::LoadRecord(registers) => return [&b"\\;"[..], registers].concat(),
+            Action::Unimplemented(c) => return vec![*c],
+        };
+        cmd.to_vec()
+    }
+
+    /// Whether this pops two values and pushes one result in their place, like `+`.
+    pub fn is_binary_operator(&self) -> bool {
+        matches!(self, Action::Add | Action::Sub | Action::Mul | Action::Div | Action::Rem
+            | Action::Exp | Action::Root | Action::Log | Action::Min | Action::Max | Action::Gcd
+            | Action::Lcm | Action::ModInverse | Action::ShiftLeft | Action::ShiftRight
+            | Action::BitAnd | Action::BitOr | Action::BitXor | Action::CompareEq)
+    }
+}
+
+/// Cloning an [`InputError`](Action::InputError) makes a new error with the same kind and message.
+impl Clone for Action {
+    fn clone(&self) -> Self {
+        match self {
+            Action::NumberChar(c) => Action::NumberChar(*c),
+            Action::StringChar(c) => Action::StringChar(*c),
+            Action::PushNumber => Action::PushNumber,
+            Action::PushString => Action::PushString,
+            Action::NumberLiteral(digits) => Action::NumberLiteral(digits.clone()),
+            Action::StringLiteral(text) => Action::StringLiteral(text.clone()),
+            Action::Register(action, r) => Action::Register(*action, *r),
+            Action::Print => Action::Print,
+            Action::PrintNoNewlinePop => Action::PrintNoNewlinePop,
+            Action::PrintBytesPop => Action::PrintBytesPop,
+            Action::PrintStack => Action::PrintStack,
+            Action::Add => Action::Add,
+            Action::Sub => Action::Sub,
+            Action::Mul => Action::Mul,
+            Action::Div => Action::Div,
+            Action::Rem => Action::Rem,
+            Action::DivRem => Action::DivRem,
+            Action::Exp => Action::Exp,
+            Action::ModExp => Action::ModExp,
+            Action::Sqrt => Action::Sqrt,
+            Action::ClearStack => Action::ClearStack,
+            Action::Dup => Action::Dup,
+            Action::Swap => Action::Swap,
+            Action::SetInputRadix => Action::SetInputRadix,
+            Action::SetOutputRadix => Action::SetOutputRadix,
+            Action::SetPrecision => Action::SetPrecision,
+            Action::LoadInputRadix => Action::LoadInputRadix,
+            Action::LoadOutputRadix => Action::LoadOutputRadix,
+            Action::LoadPrecision => Action::LoadPrecision,
+            Action::Asciify => Action::Asciify,
+            Action::ExecuteMacro => Action::ExecuteMacro,
+            Action::Input => Action::Input,
+            Action::Quit => Action::Quit,
+            Action::QuitLevels => Action::QuitLevels,
+            Action::NumDigits => Action::NumDigits,
+            Action::NumFrxDigits => Action::NumFrxDigits,
+            Action::StackDepth => Action::StackDepth,
+            Action::ShellExec => Action::ShellExec,
+            Action::Version => Action::Version,
+            Action::PrintStackIndexed => Action::PrintStackIndexed,
+            Action::PrintNoNewline => Action::PrintNoNewline,
+            Action::Root => Action::Root,
+            Action::Round => Action::Round,
+            Action::SetRoundingMode => Action::SetRoundingMode,
+            Action::Floor => Action::Floor,
+            Action::Ceil => Action::Ceil,
+            Action::Trunc => Action::Trunc,
+            Action::Abs => Action::Abs,
+            Action::Min => Action::Min,
+            Action::Max => Action::Max,
+            Action::Gcd => Action::Gcd,
+            Action::Lcm => Action::Lcm,
+            Action::Factorial => Action::Factorial,
+            Action::ShiftLeft => Action::ShiftLeft,
+            Action::ShiftRight => Action::ShiftRight,
+            Action::Random => Action::Random,
+            Action::ModInverse => Action::ModInverse,
+            Action::IfElse(action, r, else_r) => Action::IfElse(*action, *r, *else_r),
+            Action::CompareEq => Action::CompareEq,
+            Action::Not => Action::Not,
+            Action::NaturalExp => Action::NaturalExp,
+            Action::NaturalLog => Action::NaturalLog,
+            Action::Log => Action::Log,
+            Action::Arctan => Action::Arctan,
+            Action::BitAnd => Action::BitAnd,
+            Action::BitOr => Action::BitOr,
+            Action::BitXor => Action::BitXor,
+            Action::StoreRecord(registers) => Action::StoreRecord(registers.clone()),
+            Action::LoadRecord(registers) => Action::LoadRecord(registers.clone()),
+            Action::Eof => Action::Eof,
+            Action::Unimplemented(c) => Action::Unimplemented(*c),
+            // The error itself can't be cloned, but its kind and message can.
+            Action::InputError(e) =>
+                Action::InputError(std::io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum RegisterAction {
     Store,              // 's'
     Load,               // 'l'
@@ -99,6 +567,36 @@ pub enum RegisterAction {
     Ne,                 // '!='
     StoreRegArray,      // ':'
     LoadRegArray,       // ';'
+    /// DC4 extension: push a copy of the register's top level onto its stack, to be popped
+    /// again when the current macro ends.
+    SaveScoped,         // 't'
+}
+
+impl RegisterAction {
+    /// The command, as written in a program (without the register name).
+    pub fn command(&self) -> &'static str {
+        match self {
+            RegisterAction::Store => "s",
+            RegisterAction::Load => "l",
+            RegisterAction::PushRegStack => "S",
+            RegisterAction::PopRegStack => "L",
+            RegisterAction::Gt => ">",
+            RegisterAction::Le => "!>",
+            RegisterAction::Lt => "<",
+            RegisterAction::Ge => "!<",
+            RegisterAction::Eq => "=",
+            RegisterAction::Ne => "!=",
+            RegisterAction::StoreRegArray => ":",
+            RegisterAction::LoadRegArray => ";",
+            RegisterAction::SaveScoped => "t",
+        }
+    }
+
+    /// Whether this is one of the conditional macro commands.
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, RegisterAction::Gt | RegisterAction::Le | RegisterAction::Lt
+            | RegisterAction::Ge | RegisterAction::Eq | RegisterAction::Ne)
+    }
 }
 
 #[derive(Debug)]
@@ -106,25 +604,344 @@ enum ParseState {
     Start,
     Comment,
     Number { decimal: bool },
+    /// The exponent of a number in scientific notation, and whether it has any sign or digits yet.
+    Exponent { started: bool },
     String { level: usize },
     ShellExec,
     Bang,
     TwoChar(RegisterAction),
+    /// A conditional, which may be followed by 'e' and an else register.
+    Conditional(RegisterAction, u8),
+    Else(RegisterAction, u8),
+    /// A backslash, to be followed by ':' or ';' and a list of registers.
+    Backslash,
+    /// The registers of a `StoreRecord` or `LoadRecord`, which go on until whitespace.
+    Record { load: bool, registers: Vec<u8> },
 }
 
 impl Parser {
+    pub fn new(flavor: Flavor) -> Self {
+        Self {
+            state: Some(ParseState::Start),
+            flavor,
+            scientific: false,
+            literal: None,
+        }
+    }
+
+    pub fn flavor(&self) -> Flavor {
+        self.flavor
+    }
+
+    /// Set whether to gather up the characters of each number and string, and produce them as a
+    /// single [`NumberLiteral`](Action::NumberLiteral) or [`StringLiteral`](Action::StringLiteral)
+    /// action instead of one action per character. This is disabled by default.
+    pub fn set_coalesce_literals(&mut self, enabled: bool) {
+        self.literal = enabled.then(Vec::new);
+    }
+
+    /// Set whether numbers can be written in scientific notation, with an exponent of ten after
+    /// an `e`: `1.5e3` is 1500, and `2e_2` or `2e-2` is .02. The `e` has to come straight after
+    /// the digits, so it never changes what a program meant without this, where a number
+    /// followed by `e` is an unknown command. Capital `E` is still a digit. This is disabled by
+    /// default.
+    pub fn set_scientific_notation(&mut self, enabled: bool) {
+        self.scientific = enabled;
+    }
+
+    /// Whether the parser is between commands, with nothing partly parsed.
+    #[cfg(test)]
+    pub(crate) fn between_commands(&self) -> bool {
+        matches!(self.state, Some(ParseState::Start))
+    }
+
     pub fn step(&mut self, input: &mut Option<u8>) -> Option<Action> {
-        let (new_state, result) = self.state.take().unwrap().next(input);
+        let (new_state, result) =
+            self.state.take().unwrap().next(input, self.flavor, self.scientific);
         self.state = Some(new_state);
-        result
+        let Some(literal) = self.literal.as_mut() else {
+            return result;
+        };
+        match result {
+            Some(Action::NumberChar(c) | Action::StringChar(c)) => {
+                literal.push(c);
+                None
+            }
+            Some(Action::PushNumber) => Some(Action::NumberLiteral(std::mem::take(literal))),
+            Some(Action::PushString) => Some(Action::StringLiteral(std::mem::take(literal))),
+            other => other,
+        }
+    }
+}
+
+/// Parses a complete program text held in memory.
+///
+/// Each action is yielded along with the byte offset of the input character that produced it.
+/// Actions produced by reaching the end of the text (such as pushing an unterminated string) have
+/// an offset equal to the length of the text.
+pub struct SliceParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    advance: usize,
+    cur: Option<u8>,
+    parser: Parser,
+    done: bool,
+}
+
+impl<'a> SliceParser<'a> {
+    pub fn new(text: &'a [u8]) -> Self {
+        Self::with_flavor(text, Flavor::default())
+    }
+
+    pub fn with_flavor(text: &'a [u8], flavor: Flavor) -> Self {
+        Self {
+            text,
+            pos: 0,
+            advance: 0,
+            cur: None,
+            parser: Parser::new(flavor),
+            done: false,
+        }
+    }
+
+    /// See [`Parser::set_coalesce_literals`].
+    pub fn set_coalesce_literals(&mut self, enabled: bool) {
+        self.parser.set_coalesce_literals(enabled);
+    }
+
+    /// See [`Parser::set_scientific_notation`].
+    pub fn set_scientific_notation(&mut self, enabled: bool) {
+        self.parser.set_scientific_notation(enabled);
+    }
+}
+
+impl Iterator for SliceParser<'_> {
+    type Item = (usize, Action);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if self.cur.is_none() {
+                self.cur = self.text.get(self.pos).cloned();
+                self.advance = if self.cur.is_some() { 1 } else { 0 };
+            }
+
+            let offset = self.pos;
+            let action = self.parser.step(&mut self.cur);
+            if self.cur.is_none() {
+                self.pos += self.advance;
+            }
+
+            match action {
+                None => (),
+                Some(Action::Eof) => self.done = true,
+                Some(action) => return Some((offset, action)),
+            }
+        }
+        None
+    }
+}
+
+/// A problem found by [`check_program`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseIssue {
+    /// Byte offset in the program text where the problem was found.
+    pub offset: usize,
+    pub kind: ParseIssueKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseIssueKind {
+    /// Unimplemented (or unrecognized) command.
+    Unimplemented(u8),
+    /// A shell command, which dc4 does not run.
+    ShellExec,
+    /// The text ends inside a string.
+    UnterminatedString,
+    /// The text ends in the middle of a two-character command.
+    IncompleteCommand,
+    /// A number with a digit too big for the input radix, which dc accepts anyway, using the
+    /// digit's usual value. Only reported where the radix is known without running the program.
+    DigitTooBig {
+        /// The first digit which is too big.
+        digit: u8,
+        /// The input radix in effect.
+        radix: u32,
+        /// What the number is read as, in decimal.
+        value: String,
+    },
+}
+
+impl std::fmt::Display for ParseIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "offset {}: {}", self.offset, self.kind)
+    }
+}
+
+impl std::fmt::Display for ParseIssueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseIssueKind::Unimplemented(c) => write!(f, "{:?} (0{c:o}) unimplemented", *c as char),
+            ParseIssueKind::ShellExec => f.write_str("running shell commands is not supported"),
+            ParseIssueKind::UnterminatedString => f.write_str("unterminated string"),
+            ParseIssueKind::IncompleteCommand => f.write_str("incomplete command at end of input"),
+            ParseIssueKind::DigitTooBig { digit, radix, value } =>
+                write!(f, "digit '{}' is not valid in input radix {radix}; the number is read as \
+                    {value}", *digit as char),
+        }
+    }
+}
+
+/// The text of a number or string as it was written, from the actions it was parsed into: the
+/// [`NumberChar`](Action::NumberChar)s of a number, or the [`StringChar`](Action::StringChar)s of
+/// a string, which get their brackets back from the [`PushString`](Action::PushString) ending it.
+/// Coalesced literals give their text too, and any other actions are ignored.
+///
+/// For any number the parser accepts, this is exactly the bytes it was parsed from, so a program
+/// can be echoed or reformatted without changing what it means. That includes `_` on its own
+/// and a trailing `.`, and `..`, which is two numbers. A string is the same unless it was left
+/// unterminated, in which case it gets the closing bracket it was missing.
+pub fn literal_text<'a>(actions: impl IntoIterator<Item = &'a Action>) -> Vec<u8> {
+    let mut text = vec![];
+    for action in actions {
+        match action {
+            Action::NumberChar(c) | Action::StringChar(c) => text.push(*c),
+            Action::PushString => text = [&b"["[..], &text, b"]"].concat(),
+            Action::NumberLiteral(_) | Action::StringLiteral(_) => text.extend(action.command()),
+            _ => (),
+        }
+    }
+    text
+}
+
+/// Parse the given program text without running it, and return any problems found.
+///
+/// This doesn't look inside strings, because whether a string is data or a macro is only known
+/// at run time.
+///
+/// Numbers with more than one digit are checked against the input radix, as long as it's only
+/// been set by `i` with a number written right before it. After running a macro or reading input
+/// it could be anything, so numbers aren't checked from there on. Single digits are never
+/// reported: they mean the same in any radix, which is why `Ai` is the usual way to go back to
+/// decimal.
+pub fn check_program(text: &[u8]) -> Vec<ParseIssue> {
+    check_program_with_flavor(text, Flavor::default())
+}
+
+/// Like [`check_program`], but parsing the text as the given flavor of dc does, so that its own
+/// commands aren't reported as unimplemented.
+pub fn check_program_with_flavor(text: &[u8], flavor: Flavor) -> Vec<ParseIssue> {
+    check_program_with_notation(text, flavor, false)
+}
+
+/// Like [`check_program_with_flavor`], but also reading numbers in scientific notation if
+/// `scientific_notation` is set, as [`SliceParser::set_scientific_notation`] does.
+pub(crate) fn check_program_with_notation(text: &[u8], flavor: Flavor, scientific_notation: bool)
+    -> Vec<ParseIssue>
+{
+    let mut issues = vec![];
+    // The input radix, if it's known, and the value of the number just pushed, if any.
+    let mut radix = Some(10);
+    let mut last_number = None;
+    let mut parser = SliceParser::with_flavor(text, flavor);
+    parser.set_coalesce_literals(true);
+    parser.set_scientific_notation(scientific_notation);
+    for (offset, action) in parser {
+        let number = match (&action, radix) {
+            (Action::NumberLiteral(digits), Some(radix)) => literal_value(digits, radix),
+            // A single digit is the same in any radix.
+            (Action::NumberLiteral(digits), None) if digits.len() == 1 =>
+                literal_value(digits, 10),
+            _ => None,
+        };
+        let kind = match action {
+            Action::NumberLiteral(digits) => {
+                let start = offset - digits.len();
+                // An exponent is always decimal, so only the digits before it are checked.
+                let mantissa = digits.split(|&c| c == b'e').next().unwrap();
+                let num_digits = mantissa.iter().filter(|c| c.is_ascii_alphanumeric()).count();
+                let too_big = radix.and_then(|radix| mantissa.iter()
+                    .find(|c| (**c as char).to_digit(16).is_some_and(|d| d >= radix))
+                    .map(|&digit| (digit, radix)));
+                last_number = number;
+                match (too_big, &last_number) {
+                    (Some((digit, radix)), Some(value)) if num_digits > 1 => {
+                        let value = value.to_str_radix(10);
+                        issues.push(ParseIssue {
+                            offset: start,
+                            kind: ParseIssueKind::DigitTooBig { digit, radix, value },
+                        });
+                    }
+                    _ => (),
+                }
+                continue;
+            }
+            Action::SetInputRadix => {
+                match last_number.take() {
+                    Some(n) => match n.to_u32() {
+                        Some(new) if (2 ..= 16).contains(&new) => radix = Some(new),
+                        // An error, which leaves the radix as it was.
+                        _ => (),
+                    }
+                    None => radix = None,
+                }
+                continue;
+            }
+            Action::LoadInputRadix => {
+                last_number = radix.map(BigReal::from);
+                continue;
+            }
+            // These run code which could change the radix.
+            Action::ExecuteMacro | Action::Input | Action::IfElse(..)
+                | Action::Register(RegisterAction::Gt | RegisterAction::Le | RegisterAction::Lt
+                    | RegisterAction::Ge | RegisterAction::Eq | RegisterAction::Ne, _) =>
+            {
+                radix = None;
+                last_number = None;
+                continue;
+            }
+            Action::Unimplemented(c) => ParseIssueKind::Unimplemented(c),
+            Action::ShellExec => ParseIssueKind::ShellExec,
+            Action::StringLiteral(_) if offset == text.len() => ParseIssueKind::UnterminatedString,
+            Action::InputError(_) => ParseIssueKind::IncompleteCommand,
+            _ => {
+                last_number = None;
+                continue;
+            }
+        };
+        last_number = None;
+        issues.push(ParseIssue { offset, kind });
     }
+    issues
 }
 
 impl ParseState {
+    /// The state for the rest of a command which goes on past its first byte.
+    fn multi_byte(c: u8) -> Self {
+        match c {
+            commands::STORE => ParseState::TwoChar(RegisterAction::Store),
+            commands::LOAD => ParseState::TwoChar(RegisterAction::Load),
+            commands::PUSH_REG_STACK => ParseState::TwoChar(RegisterAction::PushRegStack),
+            commands::POP_REG_STACK => ParseState::TwoChar(RegisterAction::PopRegStack),
+            commands::STRING_START => ParseState::String { level: 0 },
+            commands::BANG => ParseState::Bang,
+            commands::GT => ParseState::TwoChar(RegisterAction::Gt),
+            commands::LT => ParseState::TwoChar(RegisterAction::Lt),
+            commands::EQ => ParseState::TwoChar(RegisterAction::Eq),
+            commands::COMMENT => ParseState::Comment,
+            commands::STORE_REG_ARRAY => ParseState::TwoChar(RegisterAction::StoreRegArray),
+            commands::LOAD_REG_ARRAY => ParseState::TwoChar(RegisterAction::LoadRegArray),
+            commands::SAVE_SCOPED => ParseState::TwoChar(RegisterAction::SaveScoped),
+            commands::RECORD => ParseState::Backslash,
+            _ => unreachable!("no parser state for the command {:?}", c as char),
+        }
+    }
+
     /// Given the current state and an input character, return the new state and maybe an Action.
     /// If `input` is None after this call, it means the character was consumed. If not, it should
     /// be re-issued again.
-    pub fn next(self, input: &mut Option<u8>) -> (Self, Option<Action>) {
+    pub fn next(self, input: &mut Option<u8>, flavor: Flavor, scientific: bool)
+        -> (Self, Option<Action>)
+    {
         let Some(c) = input.take() else {
             // We are at EOF. We need to complete whatever we're in the middle of, or return
             // Action::Eof to positively indicate that we're done.
@@ -135,81 +952,34 @@ impl ParseState {
                                         // tries to execute it. This is pointless, so let's just
                                         // ignore it.
                     => Action::Eof,
-                ParseState::Number { .. } => Action::PushNumber,
+                ParseState::Number { .. } | ParseState::Exponent { .. } => Action::PushNumber,
                 ParseState::String { .. } =>
                     // Note: we push the string even if it is incomplete (unbalanced brackets).
                     Action::PushString,
                 ParseState::ShellExec => Action::ShellExec,
-                ParseState::TwoChar(_register_action) =>
-                    Action::InputError(std::io::ErrorKind::UnexpectedEof.into())
+                ParseState::TwoChar(_) | ParseState::Else(..) | ParseState::Backslash =>
+                    Action::InputError(std::io::ErrorKind::UnexpectedEof.into()),
+                ParseState::Conditional(action, register) => Action::Register(action, register),
+                ParseState::Record { load: false, registers } => Action::StoreRecord(registers),
+                ParseState::Record { load: true, registers } => Action::LoadRecord(registers),
             };
             return (ParseState::Start, Some(action));
         };
 
         match self {
             ParseState::Start => match c {
-                // Where possible, keep things ordered like in the GNU dc man page.
-
                 b' ' | b'\t' | b'\r' | b'\n' =>
                     (self, None),
 
                 b'_' | b'0' ..= b'9' | b'A' ..= b'F' | b'.' =>
                     (ParseState::Number { decimal: c == b'.' }, Some(Action::NumberChar(c))),
 
-                b'p' => (self, Some(Action::Print)),
-                b'n' => (self, Some(Action::PrintNoNewlinePop)),
-                b'P' => (self, Some(Action::PrintBytesPop)),
-                b'f' => (self, Some(Action::PrintStack)),
-
-                b'+' => (self, Some(Action::Add)),
-                b'-' => (self, Some(Action::Sub)),
-                b'*' => (self, Some(Action::Mul)),
-                b'/' => (self, Some(Action::Div)),
-                b'%' => (self, Some(Action::Rem)),
-                b'~' => (self, Some(Action::DivRem)),
-                b'^' => (self, Some(Action::Exp)),
-                b'|' => (self, Some(Action::ModExp)),
-                b'v' => (self, Some(Action::Sqrt)),
-
-                b'c' => (self, Some(Action::ClearStack)),
-                b'd' => (self, Some(Action::Dup)),
-                b'r' => (self, Some(Action::Swap)),
-
-                b's' => (ParseState::TwoChar(RegisterAction::Store), None),
-                b'l' => (ParseState::TwoChar(RegisterAction::Load), None),
-                b'S' => (ParseState::TwoChar(RegisterAction::PushRegStack), None),
-                b'L' => (ParseState::TwoChar(RegisterAction::PopRegStack), None),
-
-                b'i' => (self, Some(Action::SetInputRadix)),
-                b'o' => (self, Some(Action::SetOutputRadix)),
-                b'k' => (self, Some(Action::SetPrecision)),
-                b'I' => (self, Some(Action::LoadInputRadix)),
-                b'O' => (self, Some(Action::LoadOutputRadix)),
-                b'K' => (self, Some(Action::LoadPrecision)),
-
-                b'[' => (ParseState::String { level: 0 }, None),
-                b'a' => (self, Some(Action::Asciify)),
-                b'x' => (self, Some(Action::ExecuteMacro)),
-
-                b'!' => (ParseState::Bang, None),
-                b'>' => (ParseState::TwoChar(RegisterAction::Gt), None),
-                b'<' => (ParseState::TwoChar(RegisterAction::Lt), None),
-                b'=' => (ParseState::TwoChar(RegisterAction::Eq), None),
-                b'?' => (self, Some(Action::Input)),
-                b'q' => (self, Some(Action::Quit)),
-                b'Q' => (self, Some(Action::QuitLevels)),
-
-                b'Z' => (self, Some(Action::NumDigits)),
-                b'X' => (self, Some(Action::NumFrxDigits)),
-                b'z' => (self, Some(Action::StackDepth)),
-
-                b'#' => (ParseState::Comment, None),
-                b':' => (ParseState::TwoChar(RegisterAction::StoreRegArray), None),
-                b';' => (ParseState::TwoChar(RegisterAction::LoadRegArray), None),
-
-                b'@' => (self, Some(Action::Version)),
-
-                _ => (self, Some(Action::Unimplemented(c))),
+                // Which commands there are depends on the flavor, and that's all in the table.
+                _ => match commands::capability(c, flavor) {
+                    Some(ActionKind::Single) => (self, Some(Action::single(c))),
+                    Some(ActionKind::MultiByte) => (ParseState::multi_byte(c), None),
+                    None => (self, Some(Action::Unimplemented(c))),
+                },
             },
             ParseState::Comment => match c {
                 b'\n' => (ParseState::Start, None),
@@ -222,6 +992,9 @@ impl ParseState {
                 b'.' if !decimal => {
                     (ParseState::Number { decimal: true }, Some(Action::NumberChar(c)))
                 }
+                b'e' if scientific => {
+                    (ParseState::Exponent { started: false }, Some(Action::NumberChar(c)))
+                }
                 _ => {
                     // Any of: a negative sign while we're already in a number, or a decimal sign
                     // when we've already seen one, or any other non-number character. These all end
@@ -232,6 +1005,19 @@ impl ParseState {
                     (ParseState::Start, Some(Action::PushNumber))
                 }
             }
+            ParseState::Exponent { started } => match c {
+                b'0' ..= b'9' => {
+                    (ParseState::Exponent { started: true }, Some(Action::NumberChar(c)))
+                }
+                b'_' | b'-' if !started => {
+                    (ParseState::Exponent { started: true }, Some(Action::NumberChar(c)))
+                }
+                _ => {
+                    // As in a number, anything else ends it. An exponent with no digits is zero.
+                    *input = Some(c);
+                    (ParseState::Start, Some(Action::PushNumber))
+                }
+            }
             ParseState::String { level } => match c {
                 b'[' => {
                     (ParseState::String { level: level + 1 }, Some(Action::StringChar(c)))
@@ -254,7 +1040,300 @@ impl ParseState {
                 b'=' => (ParseState::TwoChar(RegisterAction::Ne), None),
                 _ => (ParseState::ShellExec, None),
             }
+            ParseState::TwoChar(action) if action.is_comparison() && flavor != Flavor::Gnu => {
+                (ParseState::Conditional(action, c), None)
+            }
             ParseState::TwoChar(action) => (ParseState::Start, Some(Action::Register(action, c))),
+            ParseState::Conditional(action, register) => match c {
+                b'e' => (ParseState::Else(action, register), None),
+                _ => {
+                    // No else register; the character is the start of the next command.
+                    *input = Some(c);
+                    (ParseState::Start, Some(Action::Register(action, register)))
+                }
+            }
+            ParseState::Else(action, register) => {
+                (ParseState::Start, Some(Action::IfElse(action, register, c)))
+            }
+            ParseState::Backslash => match c {
+                b':' => (ParseState::Record { load: false, registers: vec![] }, None),
+                b';' => (ParseState::Record { load: true, registers: vec![] }, None),
+                _ => {
+                    // Not a record command after all; the character is the next command.
+                    *input = Some(c);
+                    (ParseState::Start, Some(Action::Unimplemented(b'\\')))
+                }
+            }
+            ParseState::Record { load, mut registers } => match c {
+                b' ' | b'\t' | b'\r' | b'\n' => {
+                    let action = if load {
+                        Action::LoadRecord(registers)
+                    } else {
+                        Action::StoreRecord(registers)
+                    };
+                    (ParseState::Start, Some(action))
+                }
+                _ => {
+                    registers.push(c);
+                    (ParseState::Record { load, registers }, None)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_program_clean() {
+        assert_eq!(check_program(b"1 2+p [hello]sx lxx"), vec![]);
+        assert_eq!(check_program(b""), vec![]);
+    }
+
+    #[test]
+    fn test_check_program_issues() {
+        assert_eq!(check_program(b"1 2 &+ ]"), vec![
+            ParseIssue { offset: 4, kind: ParseIssueKind::Unimplemented(b'&') },
+            ParseIssue { offset: 7, kind: ParseIssueKind::Unimplemented(b']') },
+        ]);
+        assert_eq!(check_program(b"1 [abc"), vec![
+            ParseIssue { offset: 6, kind: ParseIssueKind::UnterminatedString },
+        ]);
+        assert_eq!(check_program(b"1 s"), vec![
+            ParseIssue { offset: 3, kind: ParseIssueKind::IncompleteCommand },
+        ]);
+        assert_eq!(check_program(b"! ls\n1p"), vec![
+            ParseIssue { offset: 4, kind: ParseIssueKind::ShellExec },
+        ]);
+    }
+
+    #[test]
+    fn test_check_program_radix() {
+        let too_big = |offset, digit, radix, value: &str| ParseIssue {
+            offset,
+            kind: ParseIssueKind::DigitTooBig { digit, radix, value: value.to_owned() },
+        };
+        assert_eq!(check_program(b"1 FA p 1A.5 _9B"), vec![
+            too_big(2, b'F', 10, "160"),
+            too_big(7, b'A', 10, "20.5"),
+            too_big(12, b'B', 10, "-101"),
+        ]);
+        assert_eq!(check_program(b"FA").first().unwrap().to_string(),
+            "offset 0: digit 'F' is not valid in input radix 10; the number is read as 160");
+
+        // Single digits mean the same in any radix.
+        assert_eq!(check_program(b"Ai F 2i 1 0 C"), vec![]);
+
+        // The radix is followed through constant changes, including ones which fail.
+        assert_eq!(check_program(b"16i FF 8i 78 Ai 99 Ii 1A 17i 1B _1i 1C"), vec![
+            too_big(10, b'8', 8, "64"),
+            too_big(22, b'A', 10, "20"),
+            too_big(29, b'B', 10, "21"),
+            too_big(36, b'C', 10, "22"),
+        ]);
+        assert_eq!(check_program(b"2i 12 [2]i 1A 12 Ai 3A"), vec![
+            too_big(3, b'2', 2, "4"),
+            too_big(20, b'A', 10, "40"),
+        ]);
+
+        // Once the radix depends on something run, it isn't known.
+        assert_eq!(check_program(b"lri 1A Ai 1A"), vec![too_big(10, b'A', 10, "20")]);
+        assert_eq!(check_program(b"[16i]x 1A"), vec![]);
+        assert_eq!(check_program(b"[16i]sx 1 1=x 1A"), vec![]);
+        assert_eq!(check_program(b"? 1A"), vec![]);
+        assert_eq!(check_program(b"[16i]sx 1A"), vec![too_big(8, b'A', 10, "20")]);
+    }
+
+    #[test]
+    fn test_check_program_prose() {
+        // Strings aren't looked into, but prose run as a program is full of problems.
+        assert_eq!(check_program(b"[this is some data]"), vec![]);
+        assert!(!check_program(b"this is some data").is_empty());
+    }
+
+    #[test]
+    fn test_check_program_flavor() {
+        // '&' is only a command in Gavin's dc.
+        assert_eq!(check_program_with_flavor(b"3 5&p", Flavor::Gnu), vec![
+            ParseIssue { offset: 3, kind: ParseIssueKind::Unimplemented(b'&') },
+        ]);
+        assert_eq!(check_program_with_flavor(b"3 5&p", Flavor::Gavin), vec![]);
+    }
+
+    fn actions(text: &[u8], flavor: Flavor) -> Vec<String> {
+        SliceParser::with_flavor(text, flavor)
+            .map(|(_offset, action)| format!("{action:?}"))
+            .collect()
+    }
+
+    #[test]
+    fn test_if_else() {
+        assert_eq!(actions(b"=aeb p", Flavor::Bsd), vec![
+            "IfElse(Eq, 97, 98)", "Print",
+        ]);
+        assert_eq!(actions(b"!<aebp", Flavor::Gavin), vec![
+            "IfElse(Ge, 97, 98)", "Print",
+        ]);
+        // without an 'e', the next character is a new command
+        assert_eq!(actions(b">ap", Flavor::Bsd), vec!["Register(Gt, 97)", "Print"]);
+        assert_eq!(actions(b">a", Flavor::Bsd), vec!["Register(Gt, 97)"]);
+        // other register commands don't take an else register
+        assert_eq!(actions(b"saeb", Flavor::Bsd), vec![
+            "Register(Store, 97)", "Unimplemented(101)", "Unimplemented(98)",
+        ]);
+        // and neither does GNU
+        assert_eq!(actions(b"=aeb", Flavor::Gnu), vec![
+            "Register(Eq, 97)", "Unimplemented(101)", "Unimplemented(98)",
+        ]);
+    }
+
+    #[test]
+    fn test_flavor_commands() {
+        // Each flavor's own commands are unimplemented in the others.
+        assert_eq!(actions(b"GNj, \\:ab", Flavor::Gavin), vec![
+            "CompareEq", "Not", "NaturalExp", "Unimplemented(44)", "StoreRecord([97, 98])",
+        ]);
+        for flavor in [Flavor::Gnu, Flavor::Bsd] {
+            assert_eq!(actions(b"GNj, \\:ab", flavor), vec![
+                "Unimplemented(71)", "Unimplemented(78)", "Unimplemented(106)", "ModInverse",
+                "Unimplemented(92)", "Register(StoreRegArray, 97)", "Unimplemented(98)",
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_scientific_notation() {
+        let actions = |text: &[u8], scientific| {
+            let mut parser = SliceParser::with_flavor(text, Flavor::Bsd);
+            parser.set_coalesce_literals(true);
+            parser.set_scientific_notation(scientific);
+            parser.map(|(_offset, action)| format!("{action:?}")).collect::<Vec<_>>()
+        };
+        assert_eq!(actions(b"1.5e3 2e-2-", true), vec![
+            "NumberLiteral([49, 46, 53, 101, 51])", "NumberLiteral([50, 101, 45, 50])", "Sub",
+        ]);
+        assert_eq!(actions(b"1.5e3", false), vec!["NumberLiteral([49, 46, 53])",
+            "Unimplemented(101)", "NumberLiteral([51])"]);
+        // A sign only counts straight after the 'e'.
+        assert_eq!(actions(b"1e2-3", true), vec![
+            "NumberLiteral([49, 101, 50])", "Sub", "NumberLiteral([51])",
+        ]);
+        // 'E' is a digit, and an 'e' after anything but a number isn't an exponent.
+        assert_eq!(actions(b"1E =xey", true), vec![
+            "NumberLiteral([49, 69])", "IfElse(Eq, 120, 121)",
+        ]);
+    }
+
+    #[test]
+    fn test_command() {
+        let commands = SliceParser::with_flavor(b"1 sx lx !=y >aeb ;z 2+p Zw", Flavor::Bsd)
+            .map(|(_offset, action)| String::from_utf8(action.command()).unwrap())
+            .filter(|cmd| !cmd.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(commands, vec!["sx", "lx", "!=y", ">aeb", ";z", "+", "p", "Z", "w"]);
+    }
+
+    #[test]
+    fn test_slice_parser_offsets() {
+        let offsets: Vec<usize> = SliceParser::new(b"12 p")
+            .map(|(offset, _action)| offset)
+            .collect();
+        // NumberChar, NumberChar, PushNumber (at the space), Print
+        assert_eq!(offsets, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_coalesce_literals() {
+        let mut parser = SliceParser::new(b"12_.5 [a[b]]p 3[x");
+        parser.set_coalesce_literals(true);
+        let actions = parser
+            .map(|(offset, action)| format!("{offset} {action:?}"))
+            .collect::<Vec<_>>();
+        // Literals cut off by the end of the text are still whole.
+        assert_eq!(actions, vec![
+            "2 NumberLiteral([49, 50])",
+            "5 NumberLiteral([95, 46, 53])",
+            "11 StringLiteral([97, 91, 98, 93])",
+            "12 Print",
+            "15 NumberLiteral([51])",
+            "17 StringLiteral([120])",
+        ]);
+    }
+
+    /// The text of each number and string in a program, split up where they're pushed.
+    fn literals(text: &[u8], scientific: bool) -> Vec<Vec<u8>> {
+        let mut parser = SliceParser::new(text);
+        parser.set_scientific_notation(scientific);
+        let mut literals = vec![];
+        let mut literal = vec![];
+        for (_offset, action) in parser {
+            let end = matches!(action, Action::PushNumber | Action::PushString);
+            if matches!(action, Action::NumberChar(_) | Action::StringChar(_)) || end {
+                literal.push(action);
+            }
+            if end {
+                literals.push(literal_text(&literal));
+                literal.clear();
+            }
+        }
+        literals
+    }
+
+    #[test]
+    fn test_literal_text() {
+        let strs = |text: &[u8]| literals(text, true).into_iter()
+            .map(|l| String::from_utf8(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(strs(b"___"), vec!["_", "_", "_"]);
+        assert_eq!(strs(b"1. .5 ..p"), vec!["1.", ".5", ".", "."]);
+        assert_eq!(strs(b"_1.2.3_4"), vec!["_1.2", ".3", "_4"]);
+        assert_eq!(strs(b"1e_5e 2e-"), vec!["1e_5", "2e-"]);
+        assert_eq!(strs(b"[a[b]c][]"), vec!["[a[b]c]", "[]"]);
+        // A string cut off by the end of the text gets its own closing bracket, but not any
+        // inside it.
+        assert_eq!(strs(b"[a[b"), vec!["[a[b]"]);
+        // Whole literals give the same text.
+        let mut parser = SliceParser::new(b"_1. [x]");
+        parser.set_coalesce_literals(true);
+        let coalesced = parser.map(|(_offset, action)| action).collect::<Vec<_>>();
+        assert_eq!(literal_text(&coalesced[..1]), b"_1.");
+        assert_eq!(literal_text(&coalesced), b"_1.[x]");
+    }
+
+    #[test]
+    fn test_literal_text_round_trip() {
+        let mut rng = crate::rng::Rng::from_seed(525);
+        let mut pick = |choices: &[u8]| choices[rng.next_u64() as usize % choices.len()];
+        const DIGITS: &[u8] = b"0123456789ABCDEF";
+        for _ in 0 .. 2000 {
+            let mut literal = vec![pick(b"_0123456789ABCDEF.")];
+            let mut decimal = literal[0] == b'.';
+            for _ in 0 .. pick(b"012345678") - b'0' {
+                match pick(b"dddd.") {
+                    b'.' if !decimal => {
+                        decimal = true;
+                        literal.push(b'.');
+                    }
+                    _ => literal.push(pick(DIGITS)),
+                }
+            }
+            let scientific = pick(b"ny") == b'y';
+            if scientific && pick(b"ny") == b'y' {
+                literal.push(b'e');
+                if pick(b"ny") == b'y' {
+                    literal.push(pick(b"_-"));
+                }
+                for _ in 0 .. pick(b"0123") - b'0' {
+                    literal.push(pick(b"0123456789"));
+                }
+            }
+            // Whatever comes next, or nothing at all, ends the number.
+            let text = [&literal[..], &[pick(b" p\n")]].concat();
+            let text = if pick(b"ny") == b'y' { &text[..] } else { &literal[..] };
+            assert_eq!(literals(text, scientific), vec![literal.clone()],
+                "{:?}", String::from_utf8_lossy(text));
         }
     }
 }
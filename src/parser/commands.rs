@@ -0,0 +1,387 @@
+//
+// dc4 command table
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+//! Constants for the bytes which begin each dc command, and a table describing them.
+//!
+//! The table is what decides which commands each flavor has: the parser looks up each command's
+//! first byte in it, and so do the help text and the list of [features](crate::features).
+
+use super::Flavor;
+
+pub const PRINT: u8 = b'p';
+pub const PRINT_NO_NEWLINE_POP: u8 = b'n';
+pub const PRINT_BYTES_POP: u8 = b'P';
+pub const PRINT_STACK: u8 = b'f';
+
+pub const ADD: u8 = b'+';
+pub const SUB: u8 = b'-';
+pub const MUL: u8 = b'*';
+pub const DIV: u8 = b'/';
+pub const REM: u8 = b'%';
+pub const DIV_REM: u8 = b'~';
+pub const EXP: u8 = b'^';
+pub const MOD_EXP: u8 = b'|';
+pub const SQRT: u8 = b'v';
+
+pub const CLEAR_STACK: u8 = b'c';
+pub const DUP: u8 = b'd';
+pub const SWAP: u8 = b'r';
+
+pub const STORE: u8 = b's';
+pub const LOAD: u8 = b'l';
+pub const PUSH_REG_STACK: u8 = b'S';
+pub const POP_REG_STACK: u8 = b'L';
+
+pub const SET_INPUT_RADIX: u8 = b'i';
+pub const SET_OUTPUT_RADIX: u8 = b'o';
+pub const SET_PRECISION: u8 = b'k';
+pub const LOAD_INPUT_RADIX: u8 = b'I';
+pub const LOAD_OUTPUT_RADIX: u8 = b'O';
+pub const LOAD_PRECISION: u8 = b'K';
+
+pub const STRING_START: u8 = b'[';
+pub const STRING_END: u8 = b']';
+pub const ASCIIFY: u8 = b'a';
+pub const EXECUTE_MACRO: u8 = b'x';
+pub const GT: u8 = b'>';
+pub const LT: u8 = b'<';
+pub const EQ: u8 = b'=';
+pub const BANG: u8 = b'!';
+pub const INPUT: u8 = b'?';
+pub const QUIT: u8 = b'q';
+pub const QUIT_LEVELS: u8 = b'Q';
+
+pub const NUM_DIGITS: u8 = b'Z';
+pub const NUM_FRX_DIGITS: u8 = b'X';
+pub const STACK_DEPTH: u8 = b'z';
+
+pub const COMMENT: u8 = b'#';
+pub const STORE_REG_ARRAY: u8 = b':';
+pub const LOAD_REG_ARRAY: u8 = b';';
+pub const SAVE_SCOPED: u8 = b't';
+
+pub const VERSION: u8 = b'@';
+pub const PRINT_STACK_INDEXED: u8 = b'Y';
+pub const PRINT_NO_NEWLINE: u8 = b'J';
+pub const ROOT: u8 = b'V';
+pub const ROUND: u8 = b'w';
+pub const SET_ROUNDING_MODE: u8 = b'W';
+pub const FLOOR: u8 = b'{';
+pub const CEIL: u8 = b'}';
+pub const TRUNC: u8 = b'$';
+pub const ABS: u8 = b'm';
+pub const MIN: u8 = b'(';
+pub const MAX: u8 = b')';
+pub const GCD: u8 = b'y';
+pub const LCM: u8 = b'M';
+pub const FACTORIAL: u8 = b'T';
+pub const SHIFT_LEFT: u8 = b'H';
+pub const SHIFT_RIGHT: u8 = b'h';
+pub const RANDOM: u8 = b'\'';
+pub const MOD_INVERSE: u8 = b',';
+
+pub const COMPARE_EQ: u8 = b'G';
+pub const NOT: u8 = b'N';
+pub const NATURAL_EXP: u8 = b'j';
+pub const NATURAL_LOG: u8 = b'g';
+pub const LOG: u8 = b'b';
+pub const ARCTAN: u8 = b'u';
+pub const BIT_AND: u8 = b'&';
+pub const BIT_OR: u8 = b'U';
+pub const BIT_XOR: u8 = b'R';
+pub const RECORD: u8 = b'\\';
+
+/// How the parser handles the byte which begins a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionKind {
+    /// The byte is the whole command, like `p`.
+    Single,
+    /// The command goes on past its first byte, like `sr`, `[...]`, or `!<r`, and the parser has
+    /// states of its own for the rest of it.
+    MultiByte,
+}
+
+/// The commands which go on past their first byte.
+const fn kind(byte: u8) -> ActionKind {
+    match byte {
+        STORE | LOAD | PUSH_REG_STACK | POP_REG_STACK | STRING_START | GT | LT | EQ | BANG
+            | COMMENT | STORE_REG_ARRAY | LOAD_REG_ARRAY | SAVE_SCOPED | RECORD
+            => ActionKind::MultiByte,
+        _ => ActionKind::Single,
+    }
+}
+
+/// A description of a dc command, for help text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandHelp {
+    /// The byte which begins the command.
+    pub byte: u8,
+    /// How the command is written. `r` stands for any register name.
+    pub syntax: &'static str,
+    /// A one-line description of what the command does.
+    pub summary: &'static str,
+    /// Whether the command is a dc4 extension, not found in any other dc.
+    pub extension: bool,
+    /// Which flavors the command is available in.
+    pub flavors: &'static [Flavor],
+    /// How the parser handles the command's first byte.
+    pub kind: ActionKind,
+    /// For extensions, the name in the [features](crate::features) list. Several commands can
+    /// share one.
+    pub feature: Option<&'static str>,
+}
+
+impl CommandHelp {
+    pub fn available_in(&self, flavor: Flavor) -> bool {
+        self.flavors.contains(&flavor)
+    }
+}
+
+const ALL: &[Flavor] = &[Flavor::Gnu, Flavor::Bsd, Flavor::Gavin];
+const BSD_GAVIN: &[Flavor] = &[Flavor::Bsd, Flavor::Gavin];
+const GNU_BSD: &[Flavor] = &[Flavor::Gnu, Flavor::Bsd];
+const GAVIN: &[Flavor] = &[Flavor::Gavin];
+
+macro_rules! command {
+    ($byte:expr, $syntax:expr, $summary:expr) => {
+        command!($byte, $syntax, $summary, false, ALL)
+    };
+    ($byte:expr, $syntax:expr, $summary:expr, feature $feature:literal) => {
+        command!($byte, $syntax, $summary, feature $feature, ALL)
+    };
+    ($byte:expr, $syntax:expr, $summary:expr, feature $feature:literal, $flavors:expr) => {
+        CommandHelp {
+            feature: Some($feature),
+            ..command!($byte, $syntax, $summary, true, $flavors)
+        }
+    };
+    ($byte:expr, $syntax:expr, $summary:expr, $extension:expr, $flavors:expr) => {
+        CommandHelp {
+            byte: $byte,
+            syntax: $syntax,
+            summary: $summary,
+            extension: $extension,
+            flavors: $flavors,
+            kind: kind($byte),
+            feature: None,
+        }
+    };
+}
+
+/// All the commands, ordered like in the GNU dc man page where possible.
+///
+/// Gavin's dc already has commands of its own on some of the bytes dc4's extensions use. Like
+/// `,`, those extensions are left out of Gavin's flavor, so that its programs do what they do
+/// there. The others are in every flavor, including `$`, which truncates in Gavin's dc too.
+static COMMANDS: &[CommandHelp] = &[
+    command!(PRINT, "p", "print the top of the stack, followed by a newline"),
+    command!(PRINT_NO_NEWLINE_POP, "n", "pop and print the top of the stack, without a newline"),
+    command!(PRINT_BYTES_POP, "P", "pop and print a string, or a number as a stream of bytes"),
+    command!(PRINT_STACK, "f", "print the entire stack, one value per line"),
+
+    command!(ADD, "+", "pop two values and push their sum"),
+    command!(SUB, "-", "pop two values and push their difference"),
+    command!(MUL, "*", "pop two values and push their product"),
+    command!(DIV, "/", "pop two values and push their quotient, to the current scale"),
+    command!(REM, "%", "pop two values and push the remainder of their division"),
+    command!(DIV_REM, "~", "pop two values and push their quotient, then the remainder"),
+    command!(EXP, "^", "pop two values and push the first raised to the power of the second"),
+    command!(MOD_EXP, "|", "pop base, exponent, and modulus, and push the modular exponentiation"),
+    command!(SQRT, "v", "pop a value and push its square root"),
+
+    command!(CLEAR_STACK, "c", "clear the stack"),
+    command!(DUP, "d", "duplicate the top of the stack"),
+    command!(SWAP, "r", "swap the top two values on the stack"),
+
+    command!(STORE, "sr", "pop a value and store it in register r"),
+    command!(LOAD, "lr", "push a copy of the value in register r"),
+    command!(PUSH_REG_STACK, "Sr", "pop a value and push it onto register r's stack"),
+    command!(POP_REG_STACK, "Lr", "pop a value off register r's stack and push it"),
+
+    command!(SET_INPUT_RADIX, "i", "pop a value and use it as the input radix"),
+    command!(SET_OUTPUT_RADIX, "o", "pop a value and use it as the output radix"),
+    command!(SET_PRECISION, "k", "pop a value and use it as the scale"),
+    command!(LOAD_INPUT_RADIX, "I", "push the input radix"),
+    command!(LOAD_OUTPUT_RADIX, "O", "push the output radix"),
+    command!(LOAD_PRECISION, "K", "push the scale"),
+
+    command!(STRING_START, "[...]", "push a string"),
+    command!(ASCIIFY, "a", "pop a value and push its first character (or low byte) as a string"),
+    command!(EXECUTE_MACRO, "x", "pop a value and run it as a macro"),
+    command!(GT, ">r", "pop two values and run register r if the top one is greater"),
+    command!(BANG, "!>r", "pop two values and run register r if the top one is not greater"),
+    command!(LT, "<r", "pop two values and run register r if the top one is less"),
+    command!(BANG, "!<r", "pop two values and run register r if the top one is not less"),
+    command!(EQ, "=r", "pop two values and run register r if they are equal"),
+    command!(BANG, "!=r", "pop two values and run register r if they are not equal"),
+    command!(EQ, "=res", "like =r, but run register s if the comparison is false (likewise for \
+        the other comparisons)", false, BSD_GAVIN),
+    command!(INPUT, "?", "read a line of input and run it"),
+    command!(QUIT, "q", "exit the current macro and the one that called it"),
+    command!(QUIT_LEVELS, "Q", "pop a value and exit that many levels of macros"),
+
+    command!(NUM_DIGITS, "Z", "pop a value and push its number of digits (or length)"),
+    command!(NUM_FRX_DIGITS, "X", "pop a value and push its number of fractional digits"),
+    command!(STACK_DEPTH, "z", "push the depth of the stack"),
+
+    command!(BANG, "!", "run a shell command (not supported)"),
+    command!(COMMENT, "#", "ignore the rest of the line"),
+    command!(STORE_REG_ARRAY, ":r", "pop an index and a value, and store the value in array r"),
+    command!(LOAD_REG_ARRAY, ";r", "pop an index and push the value at that index of array r"),
+
+    command!(VERSION, "@", "push the extension list, the version, then \"dc4\"", true, ALL),
+    command!(PRINT_STACK_INDEXED, "Y", "like f, but with each value's depth from the top",
+        feature "print-indexed"),
+    command!(PRINT_NO_NEWLINE, "J", "print the top of the stack, without a newline or popping it",
+        feature "print-no-newline"),
+    command!(SAVE_SCOPED, "tr", "save register r until the current macro ends",
+        feature "scoped-registers"),
+    command!(ROOT, "V", "pop n and a value, and push the value's n-th root", feature "root",
+        GNU_BSD),
+    command!(ROUND, "w", "pop a value and push it rounded to the scale", feature "round"),
+    command!(SET_ROUNDING_MODE, "W", "pop a mode for w: 0 truncate, 1 half up, 2 half even",
+        feature "round", GNU_BSD),
+    command!(FLOOR, "{", "pop a value and push it rounded down to an integer",
+        feature "floor-ceil-trunc", GNU_BSD),
+    command!(CEIL, "}", "pop a value and push it rounded up to an integer",
+        feature "floor-ceil-trunc", GNU_BSD),
+    command!(TRUNC, "$", "pop a value and push its integer part", feature "floor-ceil-trunc"),
+    command!(ABS, "m", "pop a value and push its absolute value", feature "abs", GNU_BSD),
+    command!(MIN, "(", "pop two values and push the smaller one", feature "min-max", GNU_BSD),
+    command!(MAX, ")", "pop two values and push the larger one", feature "min-max", GNU_BSD),
+    command!(GCD, "y", "pop two integers and push their greatest common divisor", feature "gcd",
+        GNU_BSD),
+    command!(LCM, "M", "pop two integers and push their least common multiple", feature "lcm",
+        GNU_BSD),
+    command!(FACTORIAL, "T", "pop n and push n factorial", feature "factorial", GNU_BSD),
+    command!(SHIFT_LEFT, "H", "pop n and an integer; push it shifted left n bits", feature "shift"),
+    command!(SHIFT_RIGHT, "h", "pop n and an integer; push it shifted right n bits",
+        feature "shift"),
+    command!(RANDOM, "'", "pop n and push a random integer from 0 to n-1", feature "random",
+        GNU_BSD),
+    command!(MOD_INVERSE, ",", "pop m and a; push the inverse of a modulo m",
+        feature "mod-inverse", GNU_BSD),
+
+    command!(COMPARE_EQ, "G", "pop two values and push 1 if they are equal, 0 otherwise", false,
+        GAVIN),
+    command!(NOT, "N", "pop a value and push 1 if it is zero, 0 otherwise", false, GAVIN),
+    command!(NATURAL_EXP, "j", "pop a value and push e raised to its power", feature "exp",
+        GAVIN),
+    command!(NATURAL_LOG, "g", "pop a value and push its natural logarithm", feature "ln", GAVIN),
+    command!(LOG, "b", "pop a base and a value, and push the value's logarithm in that base",
+        feature "log", GAVIN),
+    command!(ARCTAN, "u", "pop a value and push its arctangent, in radians", feature "arctan",
+        GAVIN),
+    command!(BIT_AND, "&", "pop two nonnegative integers and push their bitwise AND",
+        feature "bitops", GAVIN),
+    command!(BIT_OR, "U", "pop two nonnegative integers and push their bitwise OR",
+        feature "bitops", GAVIN),
+    command!(BIT_XOR, "R", "pop two nonnegative integers and push their bitwise XOR",
+        feature "bitops", GAVIN),
+    command!(RECORD, "\\:rs..", "pop an index, and store values at it in arrays r, s, ... (the \
+        list ends at whitespace)", feature "records", GAVIN),
+    command!(RECORD, "\\;rs..", "pop an index, and push the values at it in arrays r, s, ...",
+        feature "records", GAVIN),
+];
+
+/// The names the command table gives extensions, in the order the [features](crate::features)
+/// list has them. Programs check for the list, or a beginning of it, so names can only be added
+/// to the end, whatever order the commands are in.
+static FEATURES: &[&str] = &[
+    "print-indexed",
+    "print-no-newline",
+    "scoped-registers",
+    "root",
+    "round",
+    "floor-ceil-trunc",
+    "abs",
+    "min-max",
+    "gcd",
+    "lcm",
+    "factorial",
+    "shift",
+    "random",
+    "exp",
+    "ln",
+    "log",
+    "arctan",
+    "bitops",
+    "records",
+    "mod-inverse",
+];
+
+/// Iterate over descriptions of all the commands available in the given flavor.
+pub fn command_table(flavor: Flavor) -> impl Iterator<Item = &'static CommandHelp> {
+    COMMANDS.iter().filter(move |cmd| cmd.available_in(flavor))
+}
+
+/// The names in the features list whose commands are all in the given flavor, in order.
+pub(crate) fn feature_names(flavor: Flavor) -> impl Iterator<Item = &'static str> {
+    FEATURES.iter().copied().filter(move |&name| {
+        let mut commands = COMMANDS.iter().filter(|cmd| cmd.feature == Some(name)).peekable();
+        commands.peek().is_some() && commands.all(|cmd| cmd.available_in(flavor))
+    })
+}
+
+/// Describe the command beginning with the given byte, if there is one in the given flavor.
+///
+/// A few bytes begin more than one command (`!` in particular); this returns the first one. Use
+/// [`command_table`] to get all of them.
+pub fn describe(byte: u8, flavor: Flavor) -> Option<CommandHelp> {
+    command_table(flavor).find(|cmd| cmd.byte == byte).copied()
+}
+
+/// How the parser handles `byte` at the start of a command in the given flavor, or `None` if it
+/// doesn't begin any command there. Whitespace and the characters of numbers aren't commands.
+pub fn capability(byte: u8, flavor: Flavor) -> Option<ActionKind> {
+    CAPABILITIES[flavor as usize][byte as usize]
+}
+
+/// For each flavor, what each byte begins, built from the command table at compile time.
+static CAPABILITIES: [[Option<ActionKind>; 256]; 3] = [
+    capabilities(Flavor::Gnu),
+    capabilities(Flavor::Bsd),
+    capabilities(Flavor::Gavin),
+];
+
+const fn capabilities(flavor: Flavor) -> [Option<ActionKind>; 256] {
+    let mut table = [None; 256];
+    let mut i = 0;
+    while i < COMMANDS.len() {
+        let cmd = &COMMANDS[i];
+        let mut j = 0;
+        while j < cmd.flavors.len() {
+            if cmd.flavors[j] as usize == flavor as usize {
+                table[cmd.byte as usize] = Some(cmd.kind);
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_feature_names() {
+        // Every name the table gives is in the list, once.
+        for cmd in COMMANDS {
+            if let Some(name) = cmd.feature {
+                assert_eq!(FEATURES.iter().filter(|&&n| n == name).count(), 1, "{name}");
+            }
+        }
+        for name in FEATURES {
+            assert!(COMMANDS.iter().any(|cmd| cmd.feature == Some(name)), "{name}");
+        }
+        assert_eq!(feature_names(Flavor::Gavin).collect::<Vec<_>>(), [
+            "print-indexed", "print-no-newline", "scoped-registers", "shift", "exp", "ln", "log",
+            "arctan", "bitops", "records",
+        ]);
+    }
+}
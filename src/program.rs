@@ -0,0 +1,53 @@
+//
+// dc4 pre-parsed programs
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::sync::Arc;
+
+use crate::diagnostic::Position;
+use crate::parser::{Action, Flavor};
+use crate::reader_parser::ReaderParser;
+
+/// A program parsed ahead of time, for running many times with
+/// [`Dc4::run_program`](crate::Dc4::run_program) without parsing it again each time.
+///
+/// Numbers and strings are stored whole, as [`Action::NumberLiteral`] and
+/// [`Action::StringLiteral`], rather than as one action per character. Cloning is cheap, and a
+/// `Program` can be shared between threads.
+///
+/// Parsing can't fail: problems like unknown commands are reported when the program is run, just
+/// as they would be when running the text. Use [`check_program`](crate::parser::check_program) to
+/// find them beforehand. Strings are only parsed as macros when they are run, using the flavor of
+/// the interpreter running them.
+#[derive(Debug, Clone)]
+pub struct Program {
+    actions: Arc<[(Action, Option<Position>)]>,
+    flavor: Flavor,
+}
+
+impl Program {
+    /// Parse a program's text in the given flavor.
+    pub fn compile(text: impl AsRef<[u8]>, flavor: Flavor) -> Program {
+        let mut parser = ReaderParser::new(text.as_ref(), flavor);
+        parser.set_coalesce_literals(true);
+        let mut actions = vec![];
+        while let Some(action) = parser.next() {
+            // Each action is reported at the position of the last character it took, as it is
+            // when running text.
+            actions.push((action, parser.position()));
+        }
+        Program { actions: actions.into(), flavor }
+    }
+
+    /// The flavor the program was parsed in.
+    pub fn flavor(&self) -> Flavor {
+        self.flavor
+    }
+
+    /// The actions of the program, and where each one was in the text.
+    pub fn actions(&self) -> &[(Action, Option<Position>)] {
+        &self.actions
+    }
+}
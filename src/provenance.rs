@@ -0,0 +1,68 @@
+//
+// dc4 value provenance, for debugging where values came from
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::fmt;
+use std::rc::Rc;
+
+use crate::diagnostic::Position;
+
+/// Where a value was created, as tracked with
+/// [`Dc4::set_track_provenance`](crate::Dc4::set_track_provenance).
+///
+/// Values keep their provenance when they're duplicated, swapped, or stored in and loaded from
+/// registers. Anything which computes a new value, like arithmetic, gives it the provenance of
+/// the command which did so.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Provenance {
+    /// Created by a command in a program.
+    Program {
+        /// The input the command was in, as for
+        /// [`Diagnostic::input`](crate::diagnostic::Diagnostic::input).
+        input: Option<String>,
+        /// Where the top-level command running at the time was in the input, as for
+        /// [`Diagnostic::position`](crate::diagnostic::Diagnostic::position).
+        position: Option<Position>,
+        /// The register holding the macro the command was in, if it was run from one: either by
+        /// a conditional, or with `x` after loading it from the register.
+        macro_register: Option<u8>,
+    },
+    /// Pushed with a method like [`Dc4::push_number`](crate::Dc4::push_number).
+    Api,
+}
+
+/// Written like `input:3:10 in macro 'a'`, or `API` for values pushed through the API.
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Provenance::Program { input, position, macro_register } = self else {
+            return f.write_str("API");
+        };
+        match (input, position) {
+            (Some(input), Some(pos)) => write!(f, "{input}:{}:{}", pos.line, pos.column)?,
+            (None, Some(pos)) => write!(f, "{}:{}", pos.line, pos.column)?,
+            (Some(input), None) => f.write_str(input)?,
+            (None, None) => f.write_str("unknown position")?,
+        }
+        if let Some(r) = *macro_register {
+            write!(f, " in macro '{}'", r as char)?;
+        }
+        Ok(())
+    }
+}
+
+/// What's kept alongside a value while provenance is being tracked.
+#[derive(Debug, Clone)]
+pub struct Tag {
+    pub provenance: Rc<Provenance>,
+    /// The register the value was last loaded from, so that a macro run with `x` can be
+    /// attributed to it.
+    pub loaded_from: Option<u8>,
+}
+
+impl Tag {
+    pub fn new(provenance: Provenance) -> Self {
+        Tag { provenance: Rc::new(provenance), loaded_from: None }
+    }
+}
@@ -1,43 +1,80 @@
-use std::io::{Read, Bytes};
-use crate::parser::{Parser, Action};
+use std::io::{BufRead, ErrorKind};
+use crate::diagnostic::Position;
+use crate::parser::{Action, Flavor, Parser};
 
-pub struct ReaderParser<R: Read> {
-    inner: Option<Bytes<R>>,
+/// The UTF-8 byte order mark, which some editors put at the start of files.
+pub const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+pub struct ReaderParser<R: BufRead> {
+    inner: Option<R>,
+    progress: Progress,
+    /// Whether the input not being ready stops parsing for now, rather than being an error.
+    poll: bool,
+    blocked: bool,
+}
+
+/// How far a [`ReaderParser`] has got through its input, which can be put aside and carried on
+/// with later, when there's more input.
+pub struct Progress {
     parser: Parser,
     stashed: Option<u8>,
+    next_position: Position,
+    last_position: Option<Position>,
+    /// Bytes skipped before parsing started, i.e. a byte order mark.
+    skipped: u64,
 }
 
-impl<R: Read> Iterator for ReaderParser<R> {
+impl<R: BufRead> Iterator for ReaderParser<R> {
     type Item = Action;
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut c = None;
         loop {
             if c.is_none() {
-                c = if let Some(c) = self.stashed.take() {
+                c = if let Some(c) = self.progress.stashed.take() {
                     Some(c)
                 } else if let Some(mut inner) = self.inner.take() {
-                    match inner.next() {
-                        Some(Ok(c)) => {
-                            self.inner = Some(inner); // restore inner iterator
+                    // Take bytes straight from the reader's buffer, which for a slice is the
+                    // slice itself.
+                    match inner.fill_buf() {
+                        Ok(&[c, ..]) => {
+                            inner.consume(1);
+                            self.inner = Some(inner); // restore inner reader
                             Some(c)
                         }
-                        Some(Err(e)) => {
+                        Ok(_) => None,
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {
+                            self.inner = Some(inner);
+                            continue;
+                        }
+                        Err(e) if self.poll && e.kind() == ErrorKind::WouldBlock => {
+                            self.inner = Some(inner);
+                            self.blocked = true;
+                            return None;
+                        }
+                        Err(e) => {
                             return Some(Action::InputError(e));
                         }
-                        None => None,
                     }
                 } else {
                     None
                 };
             }
 
-            if let Some(action) = self.parser.step(&mut c) {
+            let input = c;
+            let action = self.progress.parser.step(&mut c);
+            if let (Some(byte), None) = (input, c) {
+                self.progress.last_position = Some(self.progress.next_position);
+                self.progress.next_position = self.progress.next_position.next(byte);
+            }
+
+            if let Some(action) = action {
                 if let Some(unused_char) = c {
                     // if the parser didn't use the character, stash it for next time around.
-                    self.stashed = Some(unused_char);
+                    self.progress.stashed = Some(unused_char);
                 }
                 if let Action::Eof = action {
+                    debug_assert!(self.progress.stashed.is_none(), "input left over at the end");
                     self.inner = None;
                     return None;
                 } else {
@@ -48,12 +85,71 @@ impl<R: Read> Iterator for ReaderParser<R> {
     }
 }
 
-impl<R: Read> ReaderParser<R> {
-    pub fn new(input: R) -> Self {
-        Self {
-            inner: Some(input.bytes()),
-            parser: Parser::default(),
+impl<R: BufRead> ReaderParser<R> {
+    pub fn new(input: R, flavor: Flavor) -> Self {
+        Self::resume(input, Progress {
+            parser: Parser::new(flavor),
             stashed: None,
+            next_position: Position::start(),
+            last_position: None,
+            skipped: 0,
+        })
+    }
+
+    /// Carry on parsing from where another parser got to, with the rest of its input.
+    pub fn resume(input: R, progress: Progress) -> Self {
+        Self { inner: Some(input), progress, poll: false, blocked: false }
+    }
+
+    /// Stop parsing, and return how far it got, to [`resume`](Self::resume) later.
+    pub fn suspend(self) -> Progress {
+        self.progress
+    }
+
+    /// Set whether the input returning [`WouldBlock`](ErrorKind::WouldBlock) ends the actions
+    /// for now, so they can carry on later, instead of being an input error. See
+    /// [`blocked`](Self::blocked).
+    pub fn set_poll(&mut self, enabled: bool) {
+        self.poll = enabled;
+    }
+
+    /// Whether the actions ended because the input wasn't ready, rather than because it ended.
+    pub fn blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// Like [`new`](Self::new), but first skip a UTF-8 byte order mark at the start of the input,
+    /// if there is one.
+    pub fn new_skipping_bom(mut input: R, flavor: Flavor) -> Self {
+        // A read error here will happen again, and be reported, when parsing starts.
+        let skip = input.fill_buf().is_ok_and(|buf| buf.starts_with(BOM));
+        if skip {
+            input.consume(BOM.len());
         }
+        let mut parser = Self::new(input, flavor);
+        parser.progress.skipped = if skip { BOM.len() as u64 } else { 0 };
+        parser
+    }
+
+    /// See [`Parser::set_coalesce_literals`].
+    pub fn set_coalesce_literals(&mut self, enabled: bool) {
+        self.progress.parser.set_coalesce_literals(enabled);
+    }
+
+    /// See [`Parser::set_scientific_notation`].
+    pub fn set_scientific_notation(&mut self, enabled: bool) {
+        self.progress.parser.set_scientific_notation(enabled);
+    }
+
+    /// How many bytes of input have been used by the parser so far, including a skipped byte
+    /// order mark. The input may have been read one byte further than this, if the parser had to
+    /// look at the next byte to see where the last command ended, but never more.
+    pub fn bytes_used(&self) -> u64 {
+        self.progress.skipped + self.progress.next_position.offset
+    }
+
+    /// The position of the last byte of input used by the parser so far.
+    pub fn position(&self) -> Option<Position> {
+        self.progress.last_position
     }
 }
@@ -0,0 +1,97 @@
+//
+// dc4 random numbers, for the ' command
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::SystemTime;
+
+use num_bigint::{BigInt, BigUint, Sign};
+
+/// A small, fast pseudo-random number generator (SplitMix64). It's fine for dice rolls and
+/// simulations, but not for anything which has to be unpredictable, like passwords.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// A generator which gives the same numbers each time for the same seed.
+    pub fn from_seed(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// A generator seeded differently each time, from the operating system's randomness.
+    pub fn from_os() -> Self {
+        // The standard library seeds its hash maps from the OS, which saves depending on a crate
+        // for it. Mix in the time too, in case that seed is reused within a process.
+        let mut hasher = RandomState::new().build_hasher();
+        if let Ok(time) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(time.as_nanos());
+        }
+        Self::from_seed(hasher.finish())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A uniformly random integer from 0 up to, but not including, `bound`, which must be
+    /// positive.
+    pub fn below(&mut self, bound: &BigInt) -> BigInt {
+        assert_eq!(bound.sign(), Sign::Plus, "bound must be positive");
+        let bits = bound.bits();
+        let words = bits.div_ceil(32) as usize;
+        // Mask off the bits above the bound's highest one, then try again if it's still too big,
+        // which happens less than half the time.
+        let top_mask = u32::MAX >> (words as u64 * 32 - bits);
+        loop {
+            let mut digits = (0 .. words).map(|_| self.next_u64() as u32).collect::<Vec<u32>>();
+            if let Some(top) = digits.last_mut() {
+                *top &= top_mask;
+            }
+            let n = BigInt::from(BigUint::new(digits));
+            if &n < bound {
+                return n;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seeded() {
+        let mut a = Rng::from_seed(42);
+        let mut b = Rng::from_seed(42);
+        let first = (0 .. 5).map(|_| a.next_u64()).collect::<Vec<_>>();
+        assert_eq!(first, (0 .. 5).map(|_| b.next_u64()).collect::<Vec<_>>());
+        let mut c = Rng::from_seed(43);
+        assert_ne!(first, (0 .. 5).map(|_| c.next_u64()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_below() {
+        let mut rng = Rng::from_seed(1);
+        let one = BigInt::from(1);
+        assert!((0 .. 100).all(|_| rng.below(&one) == BigInt::from(0)));
+        // Every value comes up, and nothing else does.
+        let mut seen = [false; 5];
+        for _ in 0 .. 200 {
+            let n = rng.below(&BigInt::from(5));
+            seen[usize::try_from(n).unwrap()] = true;
+        }
+        assert_eq!(seen, [true; 5]);
+        let big = BigInt::from(1) << 100u32;
+        assert!((0 .. 100).all(|_| rng.below(&big) < big));
+        assert!((0 .. 100).any(|_| rng.below(&big) > (BigInt::from(1) << 90u32)));
+    }
+}
@@ -0,0 +1,145 @@
+//
+// dc4 :: built-in self-test
+//
+// A small battery of quick checks run against the interpreter this binary was built with, for
+// `--self-test`. The same table is run by the unit tests below.
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::io::{self, Write};
+
+use dc4::Dc4;
+
+/// A program and the output it should write, run on a fresh interpreter.
+pub struct Check {
+    pub program: &'static str,
+    pub expected: &'static str,
+}
+
+/// A named group of checks, reported together.
+pub struct Group {
+    pub name: &'static str,
+    pub checks: &'static [Check],
+}
+
+const fn check(program: &'static str, expected: &'static str) -> Check {
+    Check { program, expected }
+}
+
+pub const GROUPS: &[Group] = &[
+    Group {
+        name: "arithmetic",
+        checks: &[
+            check("2 3+p", "5\n"),
+            check("10 3/p", "3\n"),
+            check("5k 10 3/p", "3.33333\n"),
+            check("_3 2%p", "-1\n"),
+            check("7 3~f", "1\n2\n"),
+            check("2 100^p", "1267650600228229401496703205376\n"),
+        ],
+    },
+    Group {
+        name: "radix conversion",
+        checks: &[
+            check("16o 255p", "FF\n"),
+            check("2o 10p", "1010\n"),
+            check("16o 255.5p", "FF.8\n"),
+            check("16i FFp", "255\n"),
+            check("2i 1010p", "10\n"),
+            check("16i Ipq", "16\n"),
+        ],
+    },
+    Group {
+        name: "macros",
+        checks: &[
+            check("[1+]sa 5 laxp", "6\n"),
+            check("[d1-d0<a]sa 3lax f", "0\n1\n2\n3\n"),
+            check("[[yes]p]sa 1 1=a", "yes\n"),
+            check("[[no]p]sa 1 2=a", ""),
+        ],
+    },
+    Group {
+        name: "quit levels",
+        checks: &[
+            check("1p[2pq]x3p", "1\n2\n"),
+            check("[[3Q]x4p]x5p", "5\n"),
+            check("[[[2Q]x8p]x9p]x10p", "9\n10\n"),
+        ],
+    },
+];
+
+/// A check whose output wasn't what was expected.
+#[derive(Debug)]
+pub struct Failure {
+    pub program: &'static str,
+    pub expected: &'static str,
+    pub actual: String,
+}
+
+/// Run each check in a group, returning the ones which failed.
+pub fn run_group(group: &Group) -> Vec<Failure> {
+    group.checks.iter().filter_map(|check| {
+        let mut dc = Dc4::new("dc4".to_owned());
+        let mut out = vec![];
+        dc.text(check.program, &mut out);
+        let actual = String::from_utf8_lossy(&out).into_owned();
+        (actual != check.expected).then_some(Failure {
+            program: check.program,
+            expected: check.expected,
+            actual,
+        })
+    }).collect()
+}
+
+/// Run every group, writing a pass/fail line for each, with the details of any failures.
+/// Returns whether they all passed.
+pub fn run(groups: &[Group], w: &mut impl Write) -> io::Result<bool> {
+    let mut all_passed = true;
+    for group in groups {
+        let failures = run_group(group);
+        if failures.is_empty() {
+            writeln!(w, "{}: PASS ({} checks)", group.name, group.checks.len())?;
+            continue;
+        }
+        all_passed = false;
+        writeln!(w, "{}: FAIL ({} of {} checks failed)", group.name, failures.len(),
+            group.checks.len())?;
+        for failure in failures {
+            writeln!(w, "    program:  {:?}", failure.program)?;
+            writeln!(w, "    expected: {:?}", failure.expected)?;
+            writeln!(w, "    actual:   {:?}", failure.actual)?;
+        }
+    }
+    Ok(all_passed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_self_test_passes() {
+        for group in GROUPS {
+            let failures = run_group(group);
+            assert!(failures.is_empty(), "{}: {failures:?}", group.name);
+        }
+    }
+
+    #[test]
+    fn test_corrupted_expectation() {
+        const GROUPS: &[Group] = &[
+            Group { name: "good", checks: &[check("1p", "1\n")] },
+            Group { name: "bad", checks: &[check("1p", "1\n"), check("2 2+p", "5\n")] },
+        ];
+        let mut out = vec![];
+        assert!(!run(GROUPS, &mut out).unwrap());
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+good: PASS (1 checks)
+bad: FAIL (1 of 2 checks failed)
+    program:  \"2 2+p\"
+    expected: \"5\\n\"
+    actual:   \"4\\n\"
+");
+    }
+}
@@ -0,0 +1,217 @@
+//
+// dc4 main stack
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::ops::Deref;
+use crate::{DcHighWater, DcValue};
+use crate::provenance::Tag;
+
+/// The main stack. Reading it is done through the slice it derefs to; changes go through methods
+/// here, so that they can be recorded.
+#[derive(Default, Clone)]
+pub struct Stack {
+    values: Vec<DcValue>,
+    recording: Option<Recording>,
+    tags: Option<Tags>,
+    sizes: Option<Sizes>,
+}
+
+/// While recording, everything below `low_water` is untouched since recording started, and
+/// `popped` holds the original values from `low_water` up.
+#[derive(Clone)]
+struct Recording {
+    low_water: usize,
+    popped: Vec<DcValue>,
+}
+
+/// While provenance is being tracked, the tag of each value, in step with the values. Values
+/// which were already on the stack when tracking started have none.
+#[derive(Clone)]
+struct Tags {
+    tags: Vec<Option<Tag>>,
+    /// The tag for values created by the command being run.
+    current: Option<Tag>,
+}
+
+/// While high-water marks are being kept, the biggest values pushed so far, and the memory taken
+/// by the values on the stack.
+#[derive(Clone, Default)]
+struct Sizes {
+    max_digits: u64,
+    max_string_bytes: u64,
+    bytes: u64,
+    max_bytes: u64,
+}
+
+impl Sizes {
+    fn push(&mut self, value: &DcValue) {
+        match value {
+            // Counting the digits exactly takes converting to decimal, so only do it for numbers
+            // which might be a new record.
+            DcValue::Num(n) => if n.num_digits_bound() > self.max_digits {
+                self.max_digits = self.max_digits.max(n.num_digits());
+            },
+            DcValue::Str(s) => self.max_string_bytes = self.max_string_bytes.max(s.len() as u64),
+        }
+        self.bytes += value_bytes(value);
+        self.max_bytes = self.max_bytes.max(self.bytes);
+    }
+}
+
+/// Roughly how many bytes a value on the stack takes in memory. Strings shared with other values
+/// are counted in full.
+fn value_bytes(value: &DcValue) -> u64 {
+    match value {
+        DcValue::Num(n) => n.size_bytes(),
+        DcValue::Str(s) => s.len() as u64 + std::mem::size_of::<DcValue>() as u64,
+    }
+}
+
+impl Stack {
+    /// Push a value created by the current command.
+    pub fn push(&mut self, value: DcValue) {
+        if let Some(sizes) = self.sizes.as_mut() {
+            sizes.push(&value);
+        }
+        self.values.push(value);
+        if let Some(tags) = self.tags.as_mut() {
+            tags.tags.push(tags.current.clone());
+        }
+    }
+
+    /// Push a value which keeps the tag it had elsewhere.
+    pub fn push_tagged(&mut self, value: DcValue, tag: Option<Tag>) {
+        if let Some(sizes) = self.sizes.as_mut() {
+            sizes.push(&value);
+        }
+        self.values.push(value);
+        if let Some(tags) = self.tags.as_mut() {
+            tags.tags.push(tag);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<DcValue> {
+        self.pop_tagged().map(|(value, _tag)| value)
+    }
+
+    /// Pop a value along with its tag, if provenance is being tracked.
+    pub fn pop_tagged(&mut self) -> Option<(DcValue, Option<Tag>)> {
+        if !self.values.is_empty() {
+            self.touch(self.values.len() - 1);
+        }
+        let value = self.values.pop()?;
+        if let Some(sizes) = self.sizes.as_mut() {
+            sizes.bytes -= value_bytes(&value);
+        }
+        let tag = self.tags.as_mut().and_then(|tags| tags.tags.pop().flatten());
+        Some((value, tag))
+    }
+
+    pub fn clear(&mut self) {
+        self.touch(0);
+        self.values.clear();
+        if let Some(tags) = self.tags.as_mut() {
+            tags.tags.clear();
+        }
+        if let Some(sizes) = self.sizes.as_mut() {
+            sizes.bytes = 0;
+        }
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.touch(a.min(b));
+        self.values.swap(a, b);
+        if let Some(tags) = self.tags.as_mut() {
+            tags.tags.swap(a, b);
+        }
+    }
+
+    /// Start or stop keeping a tag with each value.
+    pub fn set_tracking(&mut self, enabled: bool) {
+        if !enabled {
+            self.tags = None;
+        } else if self.tags.is_none() {
+            self.tags = Some(Tags { tags: vec![None; self.values.len()], current: None });
+        }
+    }
+
+    pub fn is_tracking(&self) -> bool {
+        self.tags.is_some()
+    }
+
+    /// Start or stop keeping track of the biggest values pushed, and the most memory the stack's
+    /// values have taken at once. Stopping throws away the sizes so far.
+    pub fn set_high_water_marks(&mut self, enabled: bool) {
+        if !enabled {
+            self.sizes = None;
+        } else if self.sizes.is_none() {
+            let bytes = self.values.iter().map(value_bytes).sum();
+            self.sizes = Some(Sizes { bytes, max_bytes: bytes, ..Sizes::default() });
+        }
+    }
+
+    /// Replace everything with a copy saved earlier, but keep the high-water marks, if they're
+    /// being kept track of.
+    pub fn restore(&mut self, saved: Stack) {
+        let sizes = self.sizes.take();
+        *self = saved;
+        self.sizes = sizes.map(|sizes| Sizes {
+            bytes: self.values.iter().map(value_bytes).sum(),
+            ..sizes
+        });
+    }
+
+    /// The high-water marks so far, if they're being kept track of, with no arrays.
+    pub fn high_water_marks(&self) -> Option<DcHighWater> {
+        self.sizes.as_ref().map(|sizes| DcHighWater {
+            max_digits: sizes.max_digits,
+            max_string_bytes: sizes.max_string_bytes,
+            max_stack_bytes: sizes.max_bytes,
+            ..DcHighWater::default()
+        })
+    }
+
+    /// Set the tag given to values pushed with [`push`](Self::push).
+    pub fn set_current_tag(&mut self, tag: Tag) {
+        if let Some(tags) = self.tags.as_mut() {
+            tags.current = Some(tag);
+        }
+    }
+
+    /// The tag of the value at `index`, if it has one.
+    pub fn tag(&self, index: usize) -> Option<&Tag> {
+        self.tags.as_ref()?.tags.get(index)?.as_ref()
+    }
+
+    /// Note that the value at `index` and everything above it may be about to change.
+    fn touch(&mut self, index: usize) {
+        if let Some(rec) = self.recording.as_mut() {
+            if index < rec.low_water {
+                let originals = self.values[index .. rec.low_water].to_vec();
+                rec.popped.splice(0 .. 0, originals);
+                rec.low_water = index;
+            }
+        }
+    }
+
+    /// Start keeping track of values removed from the stack.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Recording { low_water: self.values.len(), popped: vec![] });
+    }
+
+    /// Stop recording, and return the net change since it started: the values removed, and the
+    /// values added in their place, both in stack order (bottom first).
+    pub fn finish_recording(&mut self) -> (Vec<DcValue>, Vec<DcValue>) {
+        let rec = self.recording.take().expect("stack wasn't recording");
+        (rec.popped, self.values[rec.low_water ..].to_vec())
+    }
+}
+
+impl Deref for Stack {
+    type Target = [DcValue];
+    fn deref(&self) -> &[DcValue] {
+        &self.values
+    }
+}
@@ -4,107 +4,691 @@
 // Copyright (c) 2015-2022 by William R. Fraser
 //
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io::{self, BufRead, Write};
+use std::rc::Rc;
 use num_bigint::BigInt;
-use num_traits::{ToPrimitive, Zero};
+use num_traits::{FromPrimitive, One, ToPrimitive, Zero};
 
-use crate::big_real::BigReal;
+use crate::big_real::{BigReal, BigRealFrom, Interrupt, Interrupted, RoundingMode};
+use crate::diagnostic::{Diagnostic, Excerpt, Position, Severity};
 use crate::dcregisters::DcRegisters;
-use crate::parser::{Action, RegisterAction, Parser};
-use crate::{DcValue, DcResult, DcError};
+use crate::dcstring::DcString;
+use crate::parser::{literal_text, Action, Flavor, FractionalOperand, QuitOverflow, RegisterAction,
+    Parser};
+use crate::intern::Interner;
+use crate::output::OutputBudget;
+use crate::program::Program;
+use crate::provenance::{Provenance, Tag};
+use crate::reader_parser::BOM;
+use crate::rng::Rng;
+use crate::stack::Stack;
+use crate::timing::{Phase, Timer};
+use crate::{ActionEffect, CancelHandle, DcValue, DcResult, DcError, DcHighWater, DcStats,
+    ErrorPolicy, Notation, DEFAULT_MAX_ARRAY_INDEX};
+
+pub type DiagnosticHandler = Box<dyn FnMut(&Diagnostic)>;
+
+/// Writes each command out as it runs, one per line, prefixed with `>` once for the top level
+/// and once more for each level of macro it's in.
+struct Echo {
+    w: Box<dyn Write>,
+    /// The actions of the number or string being parsed.
+    literal: Vec<Action>,
+}
+
+impl Echo {
+    fn action(&mut self, action: &Action, macro_depth: usize) {
+        let text = match action {
+            Action::NumberChar(_) | Action::StringChar(_) => {
+                self.literal.push(action.clone());
+                return;
+            }
+            Action::PushNumber | Action::PushString => {
+                literal_text(self.literal.iter().chain([action]))
+            }
+            other => other.command(),
+        };
+        self.literal.clear();
+        if !text.is_empty() {
+            let prefix = ">".repeat(macro_depth + 1);
+            // Echoing is only an aid, so a closed stderr doesn't stop the program.
+            let _ = self.w.write_all(&[prefix.as_bytes(), b" ", &text, b"\n"].concat());
+        }
+    }
+
+}
+
+/// Where the commands being run come from.
+enum Source<'a> {
+    /// Text, parsed as it's run.
+    Text {
+        text: DcString,
+        parser: Parser,
+        pos: usize,
+        cur: Option<u8>,
+        advance: usize,
+        next_position: Position,
+    },
+    /// A program parsed ahead of time.
+    Compiled {
+        actions: &'a [(Action, Option<Position>)],
+        pos: usize,
+    },
+}
+
+impl<'a> Source<'a> {
+    fn text(text: DcString, parser: Parser) -> Self {
+        Source::Text {
+            text,
+            parser,
+            pos: 0,
+            cur: None,
+            advance: 0,
+            next_position: Position::start(),
+        }
+    }
+
+    /// Get the next thing to run, or `None` at the end. If `track` is set, `position` is updated
+    /// to where it is in the input.
+    fn next(&mut self, track: bool, position: &mut Option<Position>) -> Option<Action> {
+        match self {
+            Source::Text { text, parser, pos, cur, advance, next_position } => loop {
+                if cur.is_none() {
+                    *cur = text.get(*pos).cloned();
+                    *advance = if cur.is_some() { 1 } else { 0 };
+                }
+
+                let action = parser.step(cur);
+                if cur.is_none() {
+                    if track && *advance == 1 {
+                        *position = Some(*next_position);
+                        *next_position = next_position.next(text[*pos]);
+                    }
+                    *pos += *advance;
+                }
+
+                match action {
+                    None => (),
+                    Some(Action::Eof) => {
+                        debug_assert_eq!(*pos, text.len(), "input left over at the end");
+                        return None;
+                    }
+                    Some(action) => return Some(action),
+                }
+            }
+            Source::Compiled { actions, pos } => {
+                let (action, action_position) = actions.get(*pos)?;
+                *pos += 1;
+                if track {
+                    *position = *action_position;
+                }
+                Some(action.clone())
+            }
+        }
+    }
+
+    /// The text around the last command returned, if this is text.
+    fn excerpt(&self) -> Option<Excerpt> {
+        match self {
+            // The command ended at the last byte taken.
+            Source::Text { text, pos, .. } if *pos > 0 => Some(Excerpt::new(text, *pos - 1)),
+            _ => None,
+        }
+    }
+
+    /// Whether everything has been run, so that a macro called now can replace this as a tail
+    /// call.
+    fn at_end(&self) -> bool {
+        match self {
+            Source::Text { text, pos, .. } => *pos == text.len(),
+            Source::Compiled { actions, pos } => *pos == actions.len(),
+        }
+    }
+}
+
+/// The tag of a value loaded from a register, which notes the register.
+fn loaded_tag(tag: Option<Tag>, register: u8) -> Option<Tag> {
+    tag.map(|tag| Tag { loaded_from: Some(register), ..tag })
+}
+
+/// A value's provenance as it's shown after the value when debugging, if it has one.
+fn from(tag: Option<&Tag>) -> String {
+    match tag {
+        Some(tag) => format!(" (from {})", tag.provenance),
+        None => String::new(),
+    }
+}
+
+fn plural(n: usize, what: &str) -> String {
+    match (n, what.strip_suffix('y')) {
+        (1, _) => format!("1 {what}"),
+        (_, Some(stem)) => format!("{n} {stem}ies"),
+        (_, None) => format!("{n} {what}s"),
+    }
+}
+
+/// How many values from the top of the stack `dump_state` shows.
+const DUMP_MAX_VALUES: usize = 20;
+/// How many characters of a value `dump_state` shows.
+const DUMP_MAX_VALUE_LEN: usize = 60;
+
+/// The parts of the state which programs can change, saved for a
+/// [transaction](crate::Dc4::transaction).
+pub struct Snapshot {
+    stack: Stack,
+    registers: DcRegisters,
+    scale: u32,
+    iradix: u32,
+    oradix: u32,
+    rounding_mode: RoundingMode,
+    rng: Rng,
+    scoped_registers: Vec<u8>,
+    current_str: Vec<u8>,
+    current_num: Number,
+}
 
 pub struct Dc4State {
     program_name: String,
-    stack: Vec<DcValue>,
+    flavor: Flavor,
+    stack: Stack,
     registers: DcRegisters,
     scale: u32,
     iradix: u32,
     oradix: u32,
     current_str: Vec<u8>,
     current_num: Number,
+    warn_once: bool,
+    verbose_errors: bool,
+    tail_call_optimization: bool,
+    auto_simplify: bool,
+    strict_digits: bool,
+    exact_fractions: bool,
+    scientific_notation: bool,
+    notation: Notation,
+    fractional_exponents: bool,
+    rounding_mode: RoundingMode,
+    skip_bom: bool,
+    max_array_index: u64,
+    error_policy: ErrorPolicy,
+    stats: DcStats,
+    timer: Option<Rc<Timer>>,
+    /// The most entries each register's array has had, while high-water marks are being kept.
+    max_array_entries: Option<BTreeMap<u8, u64>>,
+    diagnostic_handler: Option<DiagnosticHandler>,
+    /// Where diagnostics are collected while running into an `OutputSink`. This takes precedence
+    /// over the handler.
+    diagnostic_sink: Option<Vec<Diagnostic>>,
+    /// Where '?' reads from, if not standard input.
+    line_reader: Option<Box<dyn BufRead>>,
+    echo: Option<Echo>,
+    /// How many macros deep the current action is.
+    macro_depth: usize,
+    /// Levels of macros which were run by tail calls, and so aren't counted in `macro_depth`.
+    tail_call_depth: u64,
+    input_name: Option<Rc<str>>,
+    /// The input that the macro currently running was defined in, if different from the current
+    /// input.
+    macro_origin: Option<Rc<str>>,
+    /// The register the macro currently running was run from, if known. Only kept up to date
+    /// while provenance is being tracked.
+    macro_register: Option<u8>,
+    /// The register the macro about to be run is from, if known.
+    next_macro_register: Option<u8>,
+    /// Position in the current input of the top-level command being run.
+    position: Option<Position>,
+    /// Registers saved with `t`, to be restored when the macro they were saved in ends.
+    scoped_registers: Vec<u8>,
+    /// The last byte written to the output, if anything has been.
+    last_output_byte: Option<u8>,
+    output_budget: Rc<OutputBudget>,
+    cancel: CancelHandle,
+    rng: Rng,
+    interner: Interner,
+    /// Registers used by the current action, if they're being recorded.
+    register_effects: Option<(Vec<u8>, Vec<u8>)>,
+    /// Warnings which have been emitted during the current input, and how many times they have
+    /// been repeated (and suppressed) since.
+    warnings: BTreeMap<Warning, u64>,
 }
 
 impl Dc4State {
-    pub fn new(program_name: String) -> Self {
+    pub fn new(program_name: String, flavor: Flavor) -> Self {
         Self {
             program_name,
-            stack: vec![],
+            flavor,
+            stack: Stack::default(),
             registers: DcRegisters::new(),
             scale: 0,
             iradix: 10,
             oradix: 10,
             current_str: vec![],
             current_num: Number::default(),
+            warn_once: false,
+            verbose_errors: false,
+            tail_call_optimization: true,
+            auto_simplify: false,
+            strict_digits: false,
+            exact_fractions: false,
+            scientific_notation: false,
+            notation: Notation::Plain,
+            fractional_exponents: false,
+            rounding_mode: RoundingMode::default(),
+            skip_bom: false,
+            max_array_index: DEFAULT_MAX_ARRAY_INDEX,
+            error_policy: ErrorPolicy::default(),
+            stats: DcStats::default(),
+            timer: None,
+            max_array_entries: None,
+            diagnostic_handler: None,
+            diagnostic_sink: None,
+            line_reader: None,
+            echo: None,
+            macro_depth: 0,
+            tail_call_depth: 0,
+            input_name: None,
+            macro_origin: None,
+            macro_register: None,
+            next_macro_register: None,
+            position: None,
+            scoped_registers: vec![],
+            last_output_byte: None,
+            output_budget: Rc::default(),
+            cancel: CancelHandle::default(),
+            rng: Rng::from_os(),
+            interner: Interner::default(),
+            register_effects: None,
+            warnings: BTreeMap::new(),
+        }
+    }
+
+    pub fn flavor(&self) -> Flavor {
+        self.flavor
+    }
+
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng::from_seed(seed);
+    }
+
+    /// Change the flavor. This takes effect for the next input or macro to be run.
+    pub fn set_flavor(&mut self, flavor: Flavor) {
+        self.flavor = flavor;
+    }
+
+    pub fn set_warn_once(&mut self, enabled: bool) {
+        self.warn_once = enabled;
+    }
+
+    pub fn set_verbose_errors(&mut self, enabled: bool) {
+        self.verbose_errors = enabled;
+    }
+
+    pub fn set_tail_call_optimization(&mut self, enabled: bool) {
+        self.tail_call_optimization = enabled;
+    }
+
+    pub fn set_auto_simplify(&mut self, enabled: bool) {
+        self.auto_simplify = enabled;
+    }
+
+    pub fn set_strict_digits(&mut self, enabled: bool) {
+        self.strict_digits = enabled;
+    }
+
+    pub fn set_exact_fractions(&mut self, enabled: bool) {
+        self.exact_fractions = enabled;
+    }
+
+    pub fn set_notation(&mut self, notation: Notation) {
+        self.notation = notation;
+    }
+
+    pub fn set_scientific_notation(&mut self, enabled: bool) {
+        self.scientific_notation = enabled;
+    }
+
+    /// A parser for the next input or macro to be run.
+    pub fn parser(&self) -> Parser {
+        let mut parser = Parser::new(self.flavor);
+        parser.set_scientific_notation(self.scientific_notation);
+        parser
+    }
+
+    pub fn set_fractional_exponents(&mut self, enabled: bool) {
+        self.fractional_exponents = enabled;
+    }
+
+    pub fn set_skip_bom(&mut self, enabled: bool) {
+        self.skip_bom = enabled;
+    }
+
+    pub fn skip_bom(&self) -> bool {
+        self.skip_bom
+    }
+
+    pub fn scientific_notation(&self) -> bool {
+        self.scientific_notation
+    }
+
+    pub fn set_max_array_index(&mut self, max: u64) {
+        self.max_array_index = max;
+    }
+
+    /// Check an index for the `:` and `;` commands, which truncate it to an integer.
+    ///
+    /// Strings are never used as indexes, even ones which look like numbers: GNU dc rejects them
+    /// all, and so does every flavor here. (Indexes computed with `Z` or `z` are always numbers.)
+    fn array_index(&self, value: DcValue) -> Result<BigReal, DcError> {
+        match value {
+            DcValue::Num(n) if !n.is_negative() => {
+                if n.to_int().to_u64().is_some_and(|i| i <= self.max_array_index) {
+                    Ok(n)
+                } else {
+                    Err(DcError::ArrayIndexTooBig(self.max_array_index))
+                }
+            }
+            DcValue::Num(_) => Err(DcError::ArrayIndexInvalid),
+            DcValue::Str(_) => Err(DcError::ArrayIndexString),
+        }
+    }
+
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    pub fn error_policy(&self) -> ErrorPolicy {
+        self.error_policy
+    }
+
+    pub fn stats(&self) -> DcStats {
+        DcStats {
+            timings: self.timer.as_ref().map(|timer| timer.timings()),
+            high_water: self.stack.high_water_marks().map(|marks| DcHighWater {
+                max_array_entries: self.max_array_entries.clone().unwrap_or_default(),
+                ..marks
+            }),
+            output_bytes: self.output_budget.written(),
+            intern_hits: self.interner.hits(),
+            intern_misses: self.interner.misses(),
+            interned_bytes: self.interner.live_bytes(),
+            ..self.stats.clone()
+        }
+    }
+
+    pub fn set_high_water_marks(&mut self, enabled: bool) {
+        self.stack.set_high_water_marks(enabled);
+        if !enabled {
+            self.max_array_entries = None;
+        } else if self.max_array_entries.is_none() {
+            self.max_array_entries = Some(BTreeMap::new());
+        }
+    }
+
+    /// Note the size of a register's array after storing into it, if high-water marks are being
+    /// kept.
+    fn note_array_entries(&mut self, register: u8) {
+        if let Some(marks) = self.max_array_entries.as_mut() {
+            let entries = self.registers.get(register).array().map_or(0, |map| map.len()) as u64;
+            let max = marks.entry(register).or_default();
+            *max = (*max).max(entries);
+        }
+    }
+
+    pub fn set_timings(&mut self, enabled: bool) {
+        if !enabled {
+            self.timer = None;
+        } else if self.timer.is_none() {
+            self.timer = Some(Rc::new(Timer::new()));
+        }
+    }
+
+    pub fn timer(&self) -> Option<Rc<Timer>> {
+        self.timer.clone()
+    }
+
+    /// Start charging time to the kind of work the given action does.
+    pub fn time_action(&self, action: &Action) {
+        if let Some(timer) = &self.timer {
+            timer.switch(Phase::of(action));
+        }
+    }
+
+    /// Go back to charging time to parsing, after running an action.
+    pub fn time_parsing(&self) {
+        if let Some(timer) = &self.timer {
+            timer.switch(Phase::Parsing);
+        }
+    }
+
+    pub fn set_diagnostic_handler(&mut self, handler: Option<DiagnosticHandler>) {
+        self.diagnostic_handler = handler;
+    }
+
+    /// Start collecting diagnostics into the given list, or stop if it's `None`. Returns the list
+    /// they were being collected into before.
+    pub fn replace_diagnostic_sink(&mut self, sink: Option<Vec<Diagnostic>>)
+        -> Option<Vec<Diagnostic>>
+    {
+        std::mem::replace(&mut self.diagnostic_sink, sink)
+    }
+
+    pub fn set_line_reader(&mut self, reader: Option<Box<dyn BufRead>>) {
+        self.line_reader = reader;
+    }
+
+    pub fn set_echo(&mut self, w: Option<Box<dyn Write>>) {
+        self.echo = w.map(|w| Echo { w, literal: vec![] });
+    }
+
+    pub fn set_input_name(&mut self, name: Option<Rc<str>>) -> Option<Rc<str>> {
+        std::mem::replace(&mut self.input_name, name)
+    }
+
+    /// The name of the input which the currently running code came from, as best as we know.
+    fn origin(&self) -> Option<&Rc<str>> {
+        self.macro_origin.as_ref().or(self.input_name.as_ref())
+    }
+
+    pub fn set_position(&mut self, position: Option<Position>) {
+        self.position = position;
+    }
+
+    pub fn set_last_output_byte(&mut self, byte: u8) {
+        self.last_output_byte = Some(byte);
+    }
+
+    pub fn last_output_byte(&self) -> Option<u8> {
+        self.last_output_byte
+    }
+
+    pub fn set_max_output_bytes(&mut self, max: Option<u64>) {
+        self.output_budget.set_max(max);
+    }
+
+    pub fn output_budget(&self) -> Rc<OutputBudget> {
+        Rc::clone(&self.output_budget)
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Start or stop tracking where each value came from. See
+    /// [`Dc4::set_track_provenance`](crate::Dc4::set_track_provenance).
+    pub fn set_track_provenance(&mut self, enabled: bool) {
+        self.stack.set_tracking(enabled);
+    }
+
+    /// Where the value `depth` places from the top of the stack came from, if known.
+    pub fn provenance(&self, depth: usize) -> Option<&Provenance> {
+        let index = self.stack.len().checked_sub(depth + 1)?;
+        self.stack.tag(index).map(|tag| &*tag.provenance)
+    }
+
+    /// The tag for values created by the command being run.
+    fn current_tag(&self) -> Tag {
+        let origin = self.origin().cloned();
+        Tag::new(Provenance::Program {
+            // the position is only meaningful in the current input, as for diagnostics
+            position: if origin == self.input_name { self.position } else { None },
+            input: origin.map(|name| name.to_string()),
+            macro_register: self.macro_register,
+        })
+    }
+
+    /// Make a string defined by the program, sharing the buffer of an identical one if possible.
+    fn new_string(&mut self, bytes: Vec<u8>) -> DcString {
+        DcString::from_shared(self.interner.intern(bytes)).with_origin(self.origin().cloned())
+    }
+
+    /// Push a value given through the API.
+    fn push_from_api(&mut self, value: DcValue) {
+        let tag = self.stack.is_tracking().then(|| Tag::new(Provenance::Api));
+        self.stack.push_tagged(value, tag);
+    }
+
+    pub fn run_macro(&mut self, text: DcString, w: &mut impl Write) -> DcResult {
+        self.macro_depth += 1;
+        let result = self.run_macro_tracked(text, w, false);
+        self.macro_depth -= 1;
+        result
+    }
+
+    /// Run the text of an input, keeping track of the position in it for diagnostics.
+    pub fn run_input(&mut self, mut text: DcString, w: &mut impl Write) -> DcResult {
+        if self.skip_bom && text.starts_with(BOM) {
+            text = text.slice(BOM.len() .. text.len());
         }
+        self.run_macro_tracked(text, w, true)
     }
 
-    pub fn run_macro(&mut self, mut text: Vec<u8>, w: &mut impl Write) -> DcResult {
-        let mut parser = Parser::default();
-        let mut tail_recursion_depth = 0;
-        let mut pos = 0;
-        let mut cur = None;
-        let mut advance = 0;
+    fn run_macro_tracked(&mut self, text: DcString, w: &mut impl Write, track: bool) -> DcResult {
+        let caller_origin = self.macro_origin.clone();
+        let caller_register = std::mem::replace(&mut self.macro_register,
+            self.next_macro_register.take());
+        let caller_tail_call_depth = self.tail_call_depth;
+        let caller_scoped = self.scoped_registers.len();
+        if let Some(origin) = text.origin() {
+            self.macro_origin = Some(Rc::clone(origin));
+        }
+        let result = self.run_text(text, w, track);
+        // However the macro ended, whether normally, by quitting, or by an error, put back
+        // registers it saved. Those saved by macros it tail called are put back here too.
+        self.restore_scoped_registers(caller_scoped);
+        self.macro_origin = caller_origin;
+        self.macro_register = caller_register;
+        self.tail_call_depth = caller_tail_call_depth;
+        result
+    }
+
+    /// Run a program parsed ahead of time, as an input.
+    pub fn run_program(&mut self, program: &Program, w: &mut impl Write) -> DcResult {
+        if program.flavor() != self.flavor {
+            let e = DcError::FlavorMismatch(program.flavor(), self.flavor);
+            self.report_error(w, &e);
+            return match self.error_policy {
+                ErrorPolicy::AbortProgram => DcResult::Aborted,
+                ErrorPolicy::Continue => DcResult::Continue,
+            };
+        }
+        let caller_tail_call_depth = self.tail_call_depth;
+        let source = Source::Compiled { actions: program.actions(), pos: 0 };
+        let result = self.run_source(source, w, true);
+        self.tail_call_depth = caller_tail_call_depth;
+        result
+    }
+
+    fn run_text(&mut self, text: DcString, w: &mut impl Write, track: bool) -> DcResult {
+        self.run_source(Source::text(text, self.parser()), w, track)
+    }
+
+    fn run_source(&mut self, mut source: Source<'_>, w: &mut impl Write, mut track: bool)
+        -> DcResult
+    {
+        // Only inputs are tracked, so this is the top level of one.
+        let input = track;
+        // This counts iterations of loops done with tail recursion, so it can get big.
+        let mut tail_recursion_depth: u64 = 0;
         loop {
-            if cur.is_none() {
-                cur = text.get(pos).cloned();
-                advance = if cur.is_some() { 1 } else { 0 };
-            }
-
-            let action = parser.step(&mut cur);
-            if cur.is_none() {
-                pos += advance;
-            }
-
-            match action {
-                None => (),
-                Some(Action::Eof) => return DcResult::Continue,
-                Some(action) => {
-                    let mut result = self.action(action, w);
-
-                    while let Ok(DcResult::Macro(new_text)) = result {
-                        if pos == text.len() {
-                            // tail recursion! :D
-                            // replace the current text with the new text and start over
-                            text = new_text;
-                            pos = 0;
-                            cur = None;
-                            advance = 0;
-                            tail_recursion_depth += 1;
-                            result = Ok(DcResult::Continue);
-                        } else {
-                            result = Ok(self.run_macro(new_text, w));
-                        }
+            let Some(action) = source.next(track, &mut self.position) else {
+                return DcResult::Continue;
+            };
+            self.time_action(&action);
+            let mut result = self.action(action, w);
+
+            while let Ok(DcResult::Macro(new_text)) = result {
+                if source.at_end() && self.tail_call_optimization {
+                    // tail recursion! :D
+                    // replace the current text with the new text and start over
+                    if let Some(origin) = new_text.origin() {
+                        self.macro_origin = Some(Rc::clone(origin));
                     }
+                    self.macro_register = self.next_macro_register.take();
+                    source = Source::text(new_text, self.parser());
+                    tail_recursion_depth += 1;
+                    // positions in the new text would be meaningless; keep reporting the
+                    // position it was called from.
+                    track = false;
+                    self.tail_call_depth += 1;
+                    self.stats.tail_calls_elided += 1;
+                    result = Ok(DcResult::Continue);
+                } else {
+                    result = Ok(self.run_macro(new_text, w));
+                }
+            }
 
-                    // the quit logic is the same for both types except for which result they return
-                    macro_rules! quit_handler {
-                        ($n:expr, $result_ctor:path) => {
-                            if $n - 1 > tail_recursion_depth {
-                                return $result_ctor($n - tail_recursion_depth - 1);
-                            } else if $n - 1 == tail_recursion_depth {
-                                // quitting stops here
-                                return DcResult::Continue;
-                            } else if $n > 0 && tail_recursion_depth > 0 {
-                                // if we're doing tail recursion at all, it means our parent virtual
-                                // stack frame is at the end of its text, so just unroll all the
-                                // virtual frames.
-                                return DcResult::Continue;
-                            }
-                        }
+            // the quit logic is the same for both types except for which result they return
+            macro_rules! quit_handler {
+                ($n:expr, $result_ctor:path) => {{
+                    let n = u64::from($n);
+                    if n - 1 > tail_recursion_depth {
+                        // this is less than n, so it fits into 32 bits
+                        return $result_ctor((n - tail_recursion_depth - 1) as u32);
+                    } else if n - 1 == tail_recursion_depth {
+                        // quitting stops here
+                        return DcResult::Continue;
+                    } else if n > 0 && tail_recursion_depth > 0 {
+                        // if we're doing tail recursion at all, it means our parent virtual
+                        // stack frame is at the end of its text, so just unroll all the
+                        // virtual frames.
+                        return DcResult::Continue;
                     }
+                }}
+            }
 
-                    match result {
-                        Ok(DcResult::Continue) => (),
-                        Ok(DcResult::QuitLevels(n)) => quit_handler!(n, DcResult::QuitLevels),
-                        Ok(DcResult::Terminate(n)) => quit_handler!(n, DcResult::Terminate),
-                        Ok(DcResult::Macro(_)) => unreachable!(),
-                        Err(msg) => {
-                            self.error(w, format_args!("{msg}"));
-                        }
+            match result {
+                Ok(DcResult::Continue) => (),
+                Ok(DcResult::QuitLevels(_)) if input => {
+                    // 'Q' never quits the input itself. If any macros were tail called
+                    // from it, they've been quit, and the input is at its end.
+                    if tail_recursion_depth > 0 {
+                        return DcResult::Continue;
+                    }
+                }
+                Ok(DcResult::Terminate(n)) if input
+                    && u64::from(n) > tail_recursion_depth =>
+                {
+                    // 'q' reaching the input itself ends the program.
+                    return DcResult::Terminate((u64::from(n) - 1 - tail_recursion_depth) as u32);
+                }
+                Ok(DcResult::QuitLevels(n)) => quit_handler!(n, DcResult::QuitLevels),
+                Ok(DcResult::Terminate(n)) => quit_handler!(n, DcResult::Terminate),
+                Ok(DcResult::Macro(_)) => unreachable!(),
+                Ok(DcResult::Aborted) => return DcResult::Aborted,
+                Err(e) => {
+                    // Positions are given for commands in inputs; for ones in macros, show
+                    // where in the macro instead.
+                    let excerpt = if track { None } else { source.excerpt() };
+                    self.report_error_at(w, &e, excerpt);
+                    if self.error_policy == ErrorPolicy::AbortProgram
+                        || matches!(e, DcError::Cancelled)
+                    {
+                        return DcResult::Aborted;
                     }
                 }
             }
+            self.time_parsing();
         }
     }
 
@@ -115,107 +699,331 @@ impl Dc4State {
         let mut first = true;
         for c in input.as_ref() {
             if first && *c == b'-' {
-                num.push(b'_', self.iradix)?;
+                num.push(b'_', self.iradix, self.scientific_notation)?;
             } else {
-                num.push(*c, self.iradix)?;
+                num.push(*c, self.iradix, self.scientific_notation)?;
             }
             first = false;
         }
-        self.stack.push(num.finish(self.iradix));
+        let value = num.finish(self.iradix, self.strict_digits, self.exact_fractions)?;
+        self.push_from_api(value);
+        Ok(())
+    }
+
+    /// Push the number `int` × 10^-`scale` onto the stack.
+    pub fn push_scaled(&mut self, int: BigInt, scale: u32) {
+        self.push_from_api(DcValue::Num(BigReal::new(int, scale)));
+    }
+
+    pub fn push_f64(&mut self, value: f64) -> Result<(), DcError> {
+        let num = BigReal::from_f64(value).ok_or(DcError::FloatNotFinite)?;
+        self.push_from_api(DcValue::Num(num));
         Ok(())
     }
 
     /// Convenience function for pushing a string directly onto the stack (rather than running
     /// Action::StringChar for each byte, followed by Action::PushString).
-    pub fn push_string(&mut self, string: impl Into<Vec<u8>>) {
-        self.stack.push(DcValue::Str(string.into()));
+    pub fn push_string(&mut self, string: impl Into<DcString>) {
+        self.push_from_api(DcValue::Str(string.into()));
+    }
+
+    /// Push a number and combine it with the running value using `op`. See
+    /// [`Dc4::fold_number`](crate::Dc4::fold_number).
+    pub fn fold_number(&mut self, input: impl AsRef<[u8]>, op: Action) -> Result<(), DcError> {
+        if !op.is_binary_operator() {
+            return Err(DcError::InvalidActionSequence);
+        }
+        match self.stack.len() {
+            0 => return self.push_number(input),
+            1 => (),
+            depth => return Err(DcError::StackDepth(depth)),
+        }
+        // The number is pushed before the operator runs, so keep the stack as it was to put back.
+        // Binary operators don't print anything, so there's no output to give them.
+        let saved = self.stack.clone();
+        self.push_number(input)?;
+        let result = self.action(op, &mut io::sink()).map(|_| ());
+        if result.is_err() {
+            self.stack.restore(saved);
+        }
+        result
+    }
+
+    /// Pop the only value on the stack.
+    pub fn take_result(&mut self) -> Result<DcValue, DcError> {
+        match self.stack.len() {
+            1 => Ok(self.stack.pop().unwrap()),
+            depth => Err(DcError::StackDepth(depth)),
+        }
+    }
+
+    /// The entries of a register's array, in order of index. See
+    /// [`Dc4::export_array`](crate::Dc4::export_array).
+    pub fn export_array(&self, register: u8) -> Vec<(u64, DcValue)> {
+        let Some(map) = self.registers.get(register).array() else {
+            return vec![];
+        };
+        map.iter()
+            .filter_map(|(key, value)| Some((u64::try_from(key).ok()?, DcValue::clone(value))))
+            .collect()
+    }
+
+    /// Set the value at the top of a register's stack, as if with the `s` command.
+    pub fn set_register(&mut self, register: u8, value: DcValue) {
+        let tag = self.stack.is_tracking().then(|| Tag::new(Provenance::Api));
+        self.registers.get_mut(register).set(value, tag);
+    }
+
+    /// Replace a register's array. See [`Dc4::import_array`](crate::Dc4::import_array).
+    pub fn import_array(&mut self, register: u8, entries: impl IntoIterator<Item = (u64, DcValue)>)
+        -> Result<(), DcError>
+    {
+        let map = entries.into_iter()
+            .map(|(key, value)| if key <= self.max_array_index {
+                Ok((BigInt::from(key), Rc::new(value)))
+            } else {
+                Err(DcError::ArrayIndexTooBig(self.max_array_index))
+            })
+            .collect::<Result<_, _>>()?;
+        self.registers.get_mut(register).set_array(map);
+        Ok(())
+    }
+
+    /// Save the parts of the state which programs can change. Values on the stack are copied, but
+    /// strings and array entries are shared.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            stack: self.stack.clone(),
+            registers: self.registers.clone(),
+            scale: self.scale,
+            iradix: self.iradix,
+            oradix: self.oradix,
+            rounding_mode: self.rounding_mode,
+            rng: self.rng.clone(),
+            scoped_registers: self.scoped_registers.clone(),
+            current_str: self.current_str.clone(),
+            current_num: self.current_num.clone(),
+        }
+    }
+
+    /// Put back the state saved by [`snapshot`](Self::snapshot).
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        let Snapshot {
+            stack, registers, scale, iradix, oradix, rounding_mode, rng, scoped_registers,
+            current_str, current_num,
+        } = snapshot;
+        self.stack.restore(stack);
+        self.registers = registers;
+        self.scale = scale;
+        self.iradix = iradix;
+        self.oradix = oradix;
+        self.rounding_mode = rounding_mode;
+        self.rng = rng;
+        self.scoped_registers = scoped_registers;
+        self.current_str = current_str;
+        self.current_num = current_num;
+    }
+
+    /// Write a summary of the state. See [`Dc4::dump_state`](crate::Dc4::dump_state).
+    pub fn dump_state(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "scale {}, input radix {}, output radix {}", self.scale, self.iradix,
+            self.oradix)?;
+        if self.stack.is_empty() {
+            writeln!(w, "stack: empty")?;
+        } else {
+            writeln!(w, "stack: {}, top first", plural(self.stack.len(), "value"))?;
+            for (index, value) in self.stack.iter().enumerate().rev().take(DUMP_MAX_VALUES) {
+                writeln!(w, "  {}{}", self.dump_value(value), from(self.stack.tag(index)))?;
+            }
+            if self.stack.len() > DUMP_MAX_VALUES {
+                writeln!(w, "  ({} more)", self.stack.len() - DUMP_MAX_VALUES)?;
+            }
+        }
+        for r in 0 ..= u8::MAX {
+            let register = self.registers.get(r);
+            if register.depth() == 0 {
+                continue;
+            }
+            let value = match register.value() {
+                Some(value) => self.dump_value(value) + &from(register.tag()),
+                None => "no value".to_owned(),
+            };
+            let entries = register.array().map_or(0, |map| map.len());
+            writeln!(w, "register '{}' (0{r:o}): {value}, stack depth {}, {}", r as char,
+                register.depth(), plural(entries, "array entry"))?;
+        }
+        Ok(())
+    }
+
+    /// A value as it's shown by `dump_state`: strings are in brackets with unprintable bytes
+    /// escaped, and long values are cut short.
+    fn dump_value(&self, value: &DcValue) -> String {
+        // Only the part that's shown is formatted, since a value can be millions of digits long.
+        let format = self.print_format();
+        let (mut shown, len) = match value {
+            DcValue::Num(n) if format.is_plain() && !n.is_zero() => {
+                let (mut text, len) = n.to_str_radix_prefix(format.radix, DUMP_MAX_VALUE_LEN);
+                text.make_ascii_uppercase();
+                (text, len)
+            }
+            DcValue::Num(_) => {
+                let mut text = Vec::new();
+                format.write(value, &mut text);
+                let len = text.len() as u64;
+                text.truncate(DUMP_MAX_VALUE_LEN);
+                (String::from_utf8(text).unwrap(), len)
+            }
+            DcValue::Str(s) => {
+                let text = &s[.. s.len().min(DUMP_MAX_VALUE_LEN)];
+                (format!("[{}]", text.escape_ascii()), s.len() as u64)
+            }
+        };
+        if len > DUMP_MAX_VALUE_LEN as u64 {
+            shown += &format!("... ({len} characters)");
+        }
+        shown
     }
 
     /// Perform the given action.
     /// Any output gets written to the given writer, as well as any warnings.
     /// Errors get returned to the caller and are not written to the writer.
     pub fn action(&mut self, action: Action, w: &mut impl Write) -> Result<DcResult, DcError> {
+        if self.cancel.take() {
+            return Err(DcError::Cancelled);
+        }
+        let result = self.run_action(action, w);
+        if matches!(result, Err(DcError::Cancelled)) {
+            // It's been acted on; don't cancel whatever runs next too.
+            self.cancel.take();
+        }
+        // Output being cut off is reported as an error from the command which went over.
+        if matches!(result, Ok(DcResult::Continue)) && self.output_budget.take_cut_off() {
+            return Err(DcError::OutputLimit);
+        }
+        result
+    }
+
+    fn run_action(&mut self, action: Action, w: &mut impl Write) -> Result<DcResult, DcError> {
+        if let Some(echo) = self.echo.as_mut() {
+            echo.action(&action, self.macro_depth);
+        }
+        if self.stack.is_tracking() {
+            self.stack.set_current_tag(self.current_tag());
+        }
         match action {
             Action::NumberChar(c) => {
-                self.current_num.push(c, self.iradix).expect("unexpected non-number character");
+                self.current_num.push(c, self.iradix, self.scientific_notation)?;
             }
             Action::PushNumber => {
                 let to_push = std::mem::take(&mut self.current_num);
-                self.stack.push(to_push.finish(self.iradix));
+                self.stack.push(to_push.finish(self.iradix, self.strict_digits, self.exact_fractions)?);
             }
             Action::StringChar(c) => {
                 self.current_str.push(c);
             }
             Action::PushString => {
-                self.stack.push(DcValue::Str(self.current_str.split_off(0)));
+                let bytes = self.current_str.split_off(0);
+                let s = self.new_string(bytes);
+                self.stack.push(DcValue::Str(s));
+            }
+            Action::NumberLiteral(digits) => {
+                let scientific = self.scientific_notation;
+                for c in digits {
+                    if let Err(e) = self.current_num.push(c, self.iradix, scientific) {
+                        // Don't leave the start of it for the next number to carry on from.
+                        self.current_num = Number::default();
+                        return Err(e);
+                    }
+                }
+                let to_push = std::mem::take(&mut self.current_num);
+                self.stack.push(to_push.finish(self.iradix, self.strict_digits, self.exact_fractions)?);
+            }
+            Action::StringLiteral(text) => {
+                let s = self.new_string(text);
+                self.stack.push(DcValue::Str(s));
             }
             Action::Register(action, register) => match action {
                 RegisterAction::Store => {
-                    let value = self.pop_top()?;
-                    self.registers.get_mut(register).set(value);
+                    let (value, tag) = self.pop_top_tagged()?;
+                    self.note_register_write(register);
+                    self.registers.get_mut(register).set(value, tag);
                 }
                 RegisterAction::Load => {
-                    match self.registers.get(register).value() {
-                        Some(value) => self.stack.push(value.clone()),
+                    self.note_register_read(register);
+                    let reg = self.registers.get(register);
+                    match reg.value() {
+                        Some(value) => {
+                            let tag = loaded_tag(reg.tag().cloned(), register);
+                            self.stack.push_tagged(value.clone(), tag);
+                        }
                         None => return Err(DcError::RegisterEmpty(register)),
                     }
                 }
                 RegisterAction::PushRegStack => {
-                    let value = self.pop_top()?;
-                    self.registers.get_mut(register).push(value);
+                    let (value, tag) = self.pop_top_tagged()?;
+                    self.note_register_write(register);
+                    self.registers.get_mut(register).push(value, tag);
                 }
                 RegisterAction::PopRegStack => {
+                    self.note_register_read(register);
+                    self.note_register_write(register);
                     match self.registers.get_mut(register).pop() {
-                        Some(value) => self.stack.push(value),
+                        Some((value, tag)) => {
+                            self.stack.push_tagged(value, loaded_tag(tag, register));
+                        }
                         None => return Err(DcError::StackRegisterEmpty(register)),
                     }
                 }
-                RegisterAction::Gt => return self.cond_macro(register, |a,b| b>a),
-                RegisterAction::Le => return self.cond_macro(register, |a,b| b<=a),
-                RegisterAction::Lt => return self.cond_macro(register, |a,b| b<a),
-                RegisterAction::Ge => return self.cond_macro(register, |a,b| b>=a),
-                RegisterAction::Eq => return self.cond_macro(register, |a,b| b==a),
-                RegisterAction::Ne => return self.cond_macro(register, |a,b| b!=a),
+                RegisterAction::Gt
+                    | RegisterAction::Le
+                    | RegisterAction::Lt
+                    | RegisterAction::Ge
+                    | RegisterAction::Eq
+                    | RegisterAction::Ne => return self.cond_macro(&action, register, None),
+                RegisterAction::SaveScoped => {
+                    self.note_register_write(register);
+                    self.registers.get_mut(register).save_level();
+                    self.scoped_registers.push(register);
+                }
                 RegisterAction::StoreRegArray => {
-                    let maybe_key = match self.pop_top()? {
-                        DcValue::Num(n) => {
-                            if n.is_negative() {
-                                None
-                            } else {
-                                Some(n)
-                            }
-                        }
-                        DcValue::Str(_) => None,
-                    };
+                    let key = self.pop_top()?;
                     let value = self.pop_top()?;
-                    match maybe_key {
-                        None => return Err(DcError::ArrayIndexInvalid),
-                        Some(key) => {
-                            self.registers.get_mut(register).array_store(key, value);
-                        }
-                    }
+                    let key = self.array_index(key)?;
+                    self.note_register_write(register);
+                    self.registers.get_mut(register).array_store(key, value);
+                    self.note_array_entries(register);
                 }
-                RegisterAction::LoadRegArray => match self.pop_top()? {
-                    DcValue::Num(n) if !n.is_negative() => {
-                        let value = self.registers.get(register)
-                            .array_load(&n)
-                            .as_ref()
-                            .clone();
-                        self.stack.push(value);
-                    }
-                    _ => return Err(DcError::ArrayIndexInvalid),
+                RegisterAction::LoadRegArray => {
+                    let key = self.pop_top()?;
+                    let key = self.array_index(key)?;
+                    self.note_register_read(register);
+                    let value = self.registers.get(register)
+                        .array_load(&key)
+                        .as_ref()
+                        .clone();
+                    self.stack.push(value);
                 }
             }
             Action::Print => {
+                let format = self.print_format();
                 match self.stack.last() {
-                    Some(v) => self.print_elem(v, w),
+                    Some(v) => format.write(v, w),
                     None => return Err(DcError::StackEmpty)
                 }
                 writeln!(w).unwrap();
             }
             Action::PrintNoNewlinePop => {
+                let format = self.print_format();
                 let v = self.pop_top()?;
-                self.print_elem(&v, w);
+                format.write(&v, w);
+                w.flush().unwrap();
+            }
+            Action::PrintNoNewline => {
+                let format = self.print_format();
+                match self.stack.last() {
+                    Some(v) => format.write(v, w),
+                    None => return Err(DcError::StackEmpty)
+                }
                 w.flush().unwrap();
             }
             Action::PrintBytesPop => {
@@ -229,14 +1037,26 @@ impl Dc4State {
                 w.flush().unwrap();
             }
             Action::PrintStack => {
+                let format = self.print_format();
                 for value in self.stack.iter().rev() {
-                    self.print_elem(value, w);
+                    format.write(value, w);
                     writeln!(w).unwrap();
                 }
             }
+            Action::PrintStackIndexed => {
+                let format = self.print_format();
+                for (depth, (index, value)) in self.stack.iter().enumerate().rev().enumerate() {
+                    write!(w, "{depth}: ").unwrap();
+                    format.write(value, w);
+                    writeln!(w, "{}", from(self.stack.tag(index))).unwrap();
+                }
+            }
             Action::Add => self.binary_operator(|a, b| Ok(a + b))?,
             Action::Sub => self.binary_operator(|a, b| Ok(a - b))?,
-            Action::Mul => self.binary_operator(|a, b| Ok(a * b))?,
+            Action::Mul => {
+                let scale = self.scale;
+                self.binary_operator(|a, b| Ok(a.mul_truncated(b, scale)))?
+            }
             Action::Div => {
                 let scale = self.scale;
                 self.binary_operator(|a, b| {
@@ -268,80 +1088,263 @@ impl Dc4State {
                 };
                 self.stack.pop();
                 self.stack.pop();
-                self.stack.push(DcValue::Num(n1));
-                self.stack.push(DcValue::Num(n2));
+                self.push_result(n1);
+                self.push_result(n2);
+            }
+            Action::Exp => {
+                let mut warnings = Vec::new();
+                let scale = self.scale;
+                let policy = self.flavor.fractional_exponent();
+                let fractional = self.fractional_exponents;
+                let cancel = self.cancel.clone();
+                let stop = || cancel.is_cancelled();
+                let result = self.binary_operator(|base, exponent| {
+                    if fractional && exponent.has_fraction() {
+                        return base.pow_fractional_interruptible(exponent, scale,
+                            Interrupt::new(&stop))?.ok_or_else(|| {
+                            if base.is_negative() {
+                                DcError::NegativeBase
+                            } else {
+                                DcError::DivideByZero
+                            }
+                        });
+                    }
+
+                    // have to print the warning outside the closure
+                    warnings.extend(match policy {
+                        FractionalOperand::Truncate => None,
+                        FractionalOperand::WarnScale => (!exponent.is_integer())
+                            .then_some(Warning::ExponentScale),
+                        FractionalOperand::WarnFraction => exponent.has_fraction()
+                            .then_some(Warning::ExponentFraction),
+                        FractionalOperand::Error if exponent.has_fraction() =>
+                            return Err(DcError::NonInteger),
+                        FractionalOperand::Error => None,
+                    });
+
+                    if base.is_zero() && exponent.is_negative() {
+                        // 1 / 0^n
+                        return Err(DcError::DivideByZero);
+                    }
+                    Ok(base.pow_interruptible(exponent, scale, Interrupt::new(&stop))?)
+                });
+                self.warn_all(w, warnings);
+                result?;
+            }
+            Action::ModExp => {
+                if self.stack.len() < 3 {
+                    return Err(DcError::StackEmpty);
+                }
+                // Like GNU dc, check all the types before any of the values, and the modulus
+                // before the exponent.
+                let operands = &self.stack[self.stack.len() - 3 ..];
+                if operands.iter().any(|value| matches!(value, DcValue::Str(_))) {
+                    return Err(DcError::NonNumericValue);
+                }
+                if matches!(&operands[2], DcValue::Num(n) if n.is_zero()) {
+                    return Err(DcError::RemainderByZero);
+                }
+                let policy = self.flavor.fractional_modexp();
+                let has_fraction = |value: &DcValue| matches!(value, DcValue::Num(n) if n.has_fraction());
+                if policy == FractionalOperand::Error && operands.iter().any(has_fraction) {
+                    return Err(DcError::NonInteger);
+                }
+                if matches!(&operands[1], DcValue::Num(n) if n.is_negative()) {
+                    return Err(DcError::NegativeExponent);
+                }
+
+                let [base, exponent, modulus] = [&operands[0], &operands[1], &operands[2]]
+                    .map(|value| match value {
+                        DcValue::Num(n) => n,
+                        DcValue::Str(_) => unreachable!(), // already checked above
+                    });
+                let mut warnings = Vec::new();
+                if policy == FractionalOperand::WarnScale {
+                    warnings.extend([
+                        (base, Warning::BaseScale),
+                        (exponent, Warning::ExponentScale),
+                        (modulus, Warning::ModulusScale),
+                    ].into_iter().filter(|(n, _)| !n.is_integer()).map(|(_, warning)| warning));
+                }
+
+                // Leave the operands on the stack until it's done, in case it's cancelled.
+                let stop = || self.cancel.is_cancelled();
+                let result = BigReal::modexp_interruptible(base, exponent, modulus, self.scale,
+                    Interrupt::new(&stop));
+                self.warn_all(w, warnings);
+                let result = result?.ok_or(DcError::RemainderByZero)?;
+                self.stack.pop();
+                self.stack.pop();
+                self.stack.pop();
+                self.push_result(result);
+            }
+            Action::Sqrt => match self.pop_top_tagged()? {
+                (DcValue::Num(n), tag) => {
+                    if n.is_negative() {
+                        return Err(DcError::SqrtNegative);
+                    } else if n.is_zero() {
+                        self.stack.push(DcValue::Num(n));
+                    } else {
+                        let stop = || self.cancel.is_cancelled();
+                        match n.sqrt_interruptible(self.scale, Interrupt::new(&stop)) {
+                            Ok(Some(x)) => self.push_result(x),
+                            Ok(None) => {
+                                self.stack.push_tagged(DcValue::Num(n), tag);
+                                return Err(DcError::SqrtNegative);
+                            }
+                            Err(Interrupted) => {
+                                self.stack.push_tagged(DcValue::Num(n), tag);
+                                return Err(DcError::Cancelled);
+                            }
+                        }
+                    }
+                }
+                (DcValue::Str(_), _) => return Err(DcError::SqrtNonNumeric),
             }
-            Action::Exp => {
-                let mut warn = false;
+            Action::Root => {
+                let mut warnings = Vec::new();
                 let scale = self.scale;
-                self.binary_operator(|base, exponent| {
-                    if !exponent.is_integer() {
-                        // have to print the warning outside the closure
-                        warn = true;
+                let cancel = self.cancel.clone();
+                let stop = || cancel.is_cancelled();
+                let result = self.binary_operator(|x, n| {
+                    let index = n.to_int().magnitude().to_u32().filter(|&i| i != 0);
+                    if index.is_none() {
+                        return Err(DcError::RootIndexInvalid);
+                    } else if x.is_negative() && index.is_some_and(|i| i % 2 == 0) {
+                        return Err(DcError::RootNegative);
+                    } else if x.is_zero() && n.is_negative() {
+                        return Err(DcError::DivideByZero);
                     }
-
-                    Ok(base.pow(exponent, scale))
-                })?;
-                if warn {
-                    // note: GNU dc doesn't emit any warning here.
-                    self.error(w, format_args!("warning: non-zero scale in exponent"));
+                    // have to print the warning outside the closure
+                    if !n.is_integer() {
+                        warnings.push(Warning::RootScale);
+                    }
+                    // the only other way it can fail
+                    x.nth_root_interruptible(n, scale, Interrupt::new(&stop))?
+                        .ok_or(DcError::ScaleTooBig)
+                });
+                self.warn_all(w, warnings);
+                result?;
+            }
+            Action::Round => match self.pop_top()? {
+                DcValue::Num(n) => {
+                    let x = n.round(self.scale, self.rounding_mode);
+                    self.push_result(x);
                 }
+                DcValue::Str(_) => return Err(DcError::RoundNonNumeric),
             }
-            Action::ModExp => {
-                if self.stack.len() >= 3 {
-                    for (i, value) in self.stack[self.stack.len() - 3..].iter().enumerate() {
-                        match value {
-                            DcValue::Num(n) => {
-                                if i == 1 && n.is_negative() {
-                                    return Err(DcError::NegativeExponent);
-                                } else if i == 2 && n.is_zero() {
-                                    return Err(DcError::RemainderByZero);
-                                }
-                            },
-                            DcValue::Str(_) => return Err(DcError::NonNumericValue)
-                        }
-                    }
-                } else {
+            Action::Floor | Action::Ceil | Action::Trunc => match self.pop_top()? {
+                DcValue::Num(n) => {
+                    let x = match action {
+                        Action::Floor => n.floor(),
+                        Action::Ceil => n.ceil(),
+                        _ => n.trunc(),
+                    };
+                    self.push_result(x);
+                }
+                DcValue::Str(_) => return Err(DcError::NonNumericValue),
+            }
+            // When they're equal, the one pushed first is kept.
+            Action::Min => self.binary_operator(|a, b| Ok(if b < a { b } else { a }.clone()))?,
+            Action::Max => self.binary_operator(|a, b| Ok(if b > a { b } else { a }.clone()))?,
+            Action::Gcd => self.binary_operator(|a, b| a.gcd(b).ok_or(DcError::NonInteger))?,
+            Action::Lcm => self.binary_operator(|a, b| a.lcm(b).ok_or(DcError::NonInteger))?,
+            Action::BitAnd =>
+                self.binary_operator(|a, b| a.bitand(b).ok_or(DcError::BitwiseInvalid))?,
+            Action::BitOr =>
+                self.binary_operator(|a, b| a.bitor(b).ok_or(DcError::BitwiseInvalid))?,
+            Action::BitXor =>
+                self.binary_operator(|a, b| a.bitxor(b).ok_or(DcError::BitwiseInvalid))?,
+            Action::StoreRecord(registers) => {
+                // A stack too short for the whole record is left alone. A bad index is popped,
+                // but the values are left on the stack, and nothing is stored.
+                if self.stack.len() <= registers.len() {
                     return Err(DcError::StackEmpty);
                 }
-
-                let unwrap_int = |value| match value {
-                    DcValue::Num(n) => n,
-                    DcValue::Str(_) => unreachable!(), // already checked above
-                };
-                let modulus = self.stack.pop().map(unwrap_int).unwrap();
-                let exponent = self.stack.pop().map(unwrap_int).unwrap();
-                let base = self.stack.pop().map(unwrap_int).unwrap();
-
-                if !base.is_integer() {
-                    self.error(w, format_args!("warning: non-zero scale in base"));
+                let key = self.pop_top()?;
+                let key = self.array_index(key)?;
+                for &register in registers.iter().rev() {
+                    let value = self.pop_top()?;
+                    self.note_register_write(register);
+                    self.registers.get_mut(register).array_store(key.clone(), value);
+                    self.note_array_entries(register);
                 }
-                if !exponent.is_integer() {
-                    self.error(w, format_args!("warning: non-zero scale in exponent"));
+            }
+            Action::LoadRecord(registers) => {
+                let key = self.pop_top()?;
+                let key = self.array_index(key)?;
+                for register in registers {
+                    self.note_register_read(register);
+                    let value = self.registers.get(register).array_load(&key).as_ref().clone();
+                    self.stack.push(value);
                 }
-                if !modulus.is_integer() {
-                    self.error(w, format_args!("warning: non-zero scale in modulus"));
+            }
+            Action::Random => {
+                let bound = match self.stack.last() {
+                    Some(DcValue::Num(n)) if n.has_fraction() || n.is_negative() || n.is_zero() =>
+                        return Err(DcError::RandomBoundInvalid),
+                    Some(DcValue::Num(n)) => n.to_int(),
+                    Some(DcValue::Str(_)) => return Err(DcError::NonNumericValue),
+                    None => return Err(DcError::StackEmpty),
+                };
+                let n = self.rng.below(&bound);
+                self.stack.pop();
+                self.push_result(BigReal::from(n));
+            }
+            Action::ModInverse => self.binary_operator(|value, modulus| {
+                if [value, modulus].iter().any(|n| !n.is_positive() || n.has_fraction()) {
+                    return Err(DcError::ModInverseInvalid);
                 }
-
-                let result = BigReal::modexp(&base, &exponent, &modulus, self.scale).unwrap();
-                self.stack.push(DcValue::Num(result));
+                value.modinv(modulus).ok_or(DcError::ModInverseNone)
+            })?,
+            Action::ShiftLeft | Action::ShiftRight => {
+                let shift: fn(&BigReal, u32) -> Option<BigReal> = match action {
+                    Action::ShiftLeft => BigReal::shl,
+                    _ => BigReal::shr,
+                };
+                self.binary_operator(|value, count| {
+                    if count.is_negative() || count.has_fraction() {
+                        return Err(DcError::ShiftCountInvalid);
+                    }
+                    let count = count.to_int().to_u32().ok_or(DcError::ShiftCountTooBig)?;
+                    shift(value, count).ok_or(DcError::NonInteger)
+                })?;
+            }
+            Action::Factorial => {
+                let n = match self.stack.last() {
+                    Some(DcValue::Num(n)) if n.is_negative() || n.has_fraction() =>
+                        return Err(DcError::FactorialInvalid),
+                    Some(DcValue::Num(n)) => n.to_u64().ok_or(DcError::FactorialTooBig)?,
+                    Some(DcValue::Str(_)) => return Err(DcError::NonNumericValue),
+                    None => return Err(DcError::StackEmpty),
+                };
+                let stop = || self.cancel.is_cancelled();
+                let result = BigReal::factorial(n, Interrupt::new(&stop))?;
+                self.stack.pop();
+                self.push_result(result);
             }
-            Action::Sqrt => match self.pop_top()? {
+            Action::Abs => match self.pop_top()? {
+                DcValue::Num(n) => self.push_result(n.abs()),
+                DcValue::Str(_) => return Err(DcError::NonNumericValue),
+            }
+            Action::SetRoundingMode => match self.pop_top()? {
                 DcValue::Num(n) => {
-                    if n.is_negative() {
-                        return Err(DcError::SqrtNegative);
-                    } else if n.is_zero() {
-                        self.stack.push(DcValue::Num(n));
-                    } else {
-                        let x = n.sqrt(self.scale).unwrap();
-                        self.stack.push(DcValue::Num(x));
-                    }
+                    self.rounding_mode = match n.to_u32() {
+                        Some(0) if !n.has_fraction() => RoundingMode::Truncate,
+                        Some(1) if !n.has_fraction() => RoundingMode::HalfUp,
+                        Some(2) if !n.has_fraction() => RoundingMode::HalfEven,
+                        _ => return Err(DcError::RoundingModeInvalid),
+                    };
                 }
-                DcValue::Str(_) => return Err(DcError::SqrtNonNumeric),
+                DcValue::Str(_) => return Err(DcError::RoundingModeInvalid),
             }
             Action::ClearStack => self.stack.clear(),
-            Action::Dup => if let Some(value) = self.stack.last().cloned() {
-                self.stack.push(value);
+            Action::Dup => match self.stack.last().cloned() {
+                Some(value) => {
+                    let tag = self.stack.tag(self.stack.len() - 1).cloned();
+                    self.stack.push_tagged(value, tag);
+                }
+                None => return Err(DcError::StackEmpty),
             }
             Action::Swap => {
                 if self.stack.len() >= 2 {
@@ -409,34 +1412,44 @@ impl Dc4State {
             Action::LoadOutputRadix => self.stack.push(DcValue::Num(BigReal::from(self.oradix))),
             Action::LoadPrecision => self.stack.push(DcValue::Num(BigReal::from(self.scale))),
             Action::Asciify => match self.pop_top()? {
-                DcValue::Str(mut s) => {
-                    s.truncate(1);
-                    self.stack.push(DcValue::Str(s));
+                DcValue::Str(s) => {
+                    self.stack.push(DcValue::Str(s.slice(0 .. s.len().min(1))));
                 }
                 DcValue::Num(n) => {
                     let (_sign, bytes) = n.to_int().to_bytes_le();
-                    self.stack.push(DcValue::Str(format!("{}", bytes[0] as char).into_bytes()));
+                    self.stack.push(DcValue::Str(format!("{}", bytes[0] as char).into()));
                 }
             }
-            Action::ExecuteMacro => match self.pop_top()? {
-                DcValue::Str(text) => return Ok(DcResult::Macro(text)),
-                num @ DcValue::Num(_) => self.stack.push(num),
+            Action::ExecuteMacro => match self.pop_top_tagged()? {
+                (DcValue::Str(text), tag) => {
+                    self.next_macro_register = tag.and_then(|tag| tag.loaded_from);
+                    return Ok(DcResult::Macro(text));
+                }
+                (num @ DcValue::Num(_), tag) => self.stack.push_tagged(num, tag),
             }
             Action::Input => {
                 let mut line = vec![];
-                let stdin = io::stdin();
-                let mut handle = stdin.lock();
-                if let Err(e) = handle.read_until(b'\n', &mut line) {
-                    writeln!(w, "warning: error reading input: {e}").unwrap();
+                let result = match self.line_reader.as_mut() {
+                    Some(reader) => reader.read_until(b'\n', &mut line),
+                    None => io::stdin().lock().read_until(b'\n', &mut line),
+                };
+                if let Err(e) = result {
+                    self.diagnostic(w, Severity::Warning, "input_error",
+                        format!("error reading input: {e}"));
                 }
-                return Ok(DcResult::Macro(line));
+                return Ok(DcResult::Macro(line.into()));
             }
             Action::Quit => return Ok(DcResult::Terminate(2)),
             Action::QuitLevels => match self.pop_top()? {
                 DcValue::Num(n) if n.is_positive() => {
-                    return n.to_u32()
-                        .map(DcResult::QuitLevels)
-                        .ok_or(DcError::QuitTooBig);
+                    let levels = n.to_u32().ok_or(DcError::QuitTooBig)?;
+                    let running = self.macro_depth as u64 + self.tail_call_depth;
+                    if u64::from(levels) > running
+                        && self.flavor.quit_overflow() == QuitOverflow::Error
+                    {
+                        return Err(DcError::QuitTooDeep);
+                    }
+                    return Ok(DcResult::QuitLevels(levels));
                 }
                 DcValue::Num(_) | DcValue::Str(_) =>
                     return Err(DcError::QuitInvalid),
@@ -460,8 +1473,81 @@ impl Dc4State {
                 let ver = env!("CARGO_PKG_VERSION_MAJOR").parse::<u64>().unwrap() << 24
                         | env!("CARGO_PKG_VERSION_MINOR").parse::<u64>().unwrap() << 16
                         | env!("CARGO_PKG_VERSION_PATCH").parse::<u64>().unwrap();
+                self.stack.push(DcValue::Str(crate::features_for(self.flavor).into()));
                 self.stack.push(DcValue::Num(BigReal::from(ver)));
-                self.stack.push(DcValue::Str(b"dc4".to_vec()));
+                self.stack.push(DcValue::Str(b"dc4".into()));
+            }
+            Action::IfElse(action, register, else_register) => {
+                return self.cond_macro(&action, register, Some(else_register));
+            }
+            Action::CompareEq => {
+                let equal = self.binary_lambda(|a, b| Ok(a == b))?;
+                self.stack.push(DcValue::Num(BigReal::from(u32::from(equal))));
+            }
+            Action::NaturalExp => match self.stack.last() {
+                Some(DcValue::Num(n)) => {
+                    let stop = || self.cancel.is_cancelled();
+                    let x = n.exp_interruptible(self.scale, Interrupt::new(&stop))?;
+                    self.stack.pop();
+                    self.push_result(x);
+                }
+                Some(DcValue::Str(_)) => {
+                    self.stack.pop();
+                    return Err(DcError::ExpNonNumeric);
+                }
+                None => return Err(DcError::StackEmpty),
+            }
+            Action::NaturalLog => match self.stack.last() {
+                Some(DcValue::Num(n)) => {
+                    let stop = || self.cancel.is_cancelled();
+                    match n.ln_interruptible(self.scale, Interrupt::new(&stop))? {
+                        Some(x) => {
+                            self.stack.pop();
+                            self.push_result(x);
+                        }
+                        None => return Err(DcError::LogNonPositive),
+                    }
+                }
+                Some(DcValue::Str(_)) => {
+                    self.stack.pop();
+                    return Err(DcError::LogNonNumeric);
+                }
+                None => return Err(DcError::StackEmpty),
+            }
+            Action::Arctan => match self.stack.last() {
+                Some(DcValue::Num(n)) => {
+                    let stop = || self.cancel.is_cancelled();
+                    let x = n.atan_interruptible(self.scale, Interrupt::new(&stop))?;
+                    self.stack.pop();
+                    self.push_result(x);
+                }
+                Some(DcValue::Str(_)) => {
+                    self.stack.pop();
+                    return Err(DcError::ArctanNonNumeric);
+                }
+                None => return Err(DcError::StackEmpty),
+            }
+            Action::Log => {
+                let scale = self.scale;
+                let cancel = self.cancel.clone();
+                let stop = || cancel.is_cancelled();
+                self.binary_operator(|x, base| {
+                    if !base.is_positive() || *base == BigReal::one() {
+                        Err(DcError::LogBaseInvalid)
+                    } else {
+                        x.log_interruptible(base, scale, Interrupt::new(&stop))?
+                            .ok_or(DcError::LogNonPositive)
+                    }
+                })?
+            }
+            Action::Not => match self.stack.last() {
+                Some(DcValue::Num(n)) => {
+                    let zero = n.is_zero();
+                    self.stack.pop();
+                    self.stack.push(DcValue::Num(BigReal::from(u32::from(zero))));
+                }
+                Some(DcValue::Str(_)) => return Err(DcError::NonNumericValue),
+                None => return Err(DcError::StackEmpty),
             }
             Action::Eof => (), // nothing to do
             Action::Unimplemented(c) => {
@@ -474,17 +1560,14 @@ impl Dc4State {
         Ok(DcResult::Continue)
     }
 
-    fn print_elem(&self, elem: &DcValue, w: &mut impl Write) {
-        match elem {
-            DcValue::Num(n) => if n.is_zero() {
-                // dc special-cases zero and ignores the scale, opting to not print the extra zero
-                // digits.
-                write!(w, "0")
-            } else {
-                write!(w, "{}", n.to_str_radix(self.oradix).to_uppercase())
-            }
-            DcValue::Str(s) => w.write_all(s),
-        }.unwrap();
+    pub fn print_elem(&self, elem: &DcValue, w: &mut impl Write) {
+        self.print_format().write(elem, w);
+    }
+
+    /// How values are printed right now. Print commands take this once, before printing
+    /// anything, so everything one command prints is formatted the same way.
+    fn print_format(&self) -> PrintFormat {
+        PrintFormat { radix: self.oradix, notation: self.notation }
     }
 
     fn get_two_ints(&self) -> Result<(&BigReal, &BigReal), DcError> {
@@ -508,11 +1591,58 @@ impl Dc4State {
         Ok((a, b))
     }
 
+    /// Perform the given action, like [`action`](Self::action), and also return what it did.
+    pub fn action_traced(&mut self, action: Action, w: &mut impl Write)
+        -> (Result<DcResult, DcError>, ActionEffect)
+    {
+        self.stack.start_recording();
+        self.register_effects = Some((vec![], vec![]));
+        let result = self.action(action, w);
+        let (popped, pushed) = self.stack.finish_recording();
+        let (registers_read, registers_written) = self.register_effects.take().unwrap();
+        let effect = ActionEffect {
+            popped,
+            pushed,
+            registers_read,
+            registers_written,
+            output: false,
+        };
+        (result, effect)
+    }
+
+    fn note_register_read(&mut self, register: u8) {
+        if let Some((read, _)) = self.register_effects.as_mut() {
+            read.push(register);
+        }
+    }
+
+    fn note_register_write(&mut self, register: u8) {
+        if let Some((_, written)) = self.register_effects.as_mut() {
+            written.push(register);
+        }
+    }
+
+    /// Pop the top of the stack, or return `None` if it's empty.
+    pub fn pop(&mut self) -> Option<DcValue> {
+        self.stack.pop()
+    }
+
+    /// Pop the top of the stack.
+    ///
+    /// Like GNU dc, commands which pop their operands before validating them (`i`, `o`, `k`, `Q`,
+    /// `v`, `:`, `;`) consume them even when they turn out to be invalid, while the arithmetic
+    /// operators and comparisons check their operands in place and leave the stack untouched if
+    /// they're invalid.
     fn pop_top(&mut self) -> Result<DcValue, DcError> {
         self.stack.pop()
             .ok_or(DcError::StackEmpty)
     }
 
+    fn pop_top_tagged(&mut self) -> Result<(DcValue, Option<Tag>), DcError> {
+        self.stack.pop_tagged()
+            .ok_or(DcError::StackEmpty)
+    }
+
     fn binary_lambda<T, F>(&mut self, mut f: F) -> Result<T, DcError>
         where F: FnMut(&BigReal, &BigReal) -> Result<T, DcError>
     {
@@ -530,48 +1660,279 @@ impl Dc4State {
         where F: FnMut(&BigReal, &BigReal) -> Result<BigReal, DcError>
     {
         let n = self.binary_lambda(|a, b| f(a, b))?;
-        self.stack.push(DcValue::Num(n));
+        self.push_result(n);
         Ok(())
     }
 
-    fn cond_macro<F>(&mut self, register: u8, f: F) -> Result<DcResult, DcError>
-        where F: Fn(&BigReal, &BigReal) -> bool
+    /// Push the result of an arithmetic operation.
+    fn push_result(&mut self, mut n: BigReal) {
+        if self.auto_simplify {
+            n.simplify();
+        }
+        self.stack.push(DcValue::Num(n));
+    }
+
+    /// Run a conditional macro command: compare the top two values, and run `register` if the
+    /// comparison is true, or `else_register` (if any) if it's false.
+    fn cond_macro(&mut self, action: &RegisterAction, register: u8, else_register: Option<u8>)
+        -> Result<DcResult, DcError>
+    {
+        let f: fn(&BigReal, &BigReal) -> bool = match action {
+            RegisterAction::Gt => |a, b| b > a,
+            RegisterAction::Le => |a, b| b <= a,
+            RegisterAction::Lt => |a, b| b < a,
+            RegisterAction::Ge => |a, b| b >= a,
+            RegisterAction::Eq => |a, b| b == a,
+            RegisterAction::Ne => |a, b| b != a,
+            // Only from an action made by hand, not by the parser.
+            _ => return Err(DcError::InvalidActionSequence),
+        };
+        let matched = match self.binary_lambda(|a, b| Ok(f(a, b))) {
+            Err(DcError::NonNumericValue) if self.verbose_errors => {
+                return Err(DcError::ComparisonNonNumeric(action.command(), register));
+            }
+            result => result?,
+        };
+        let register = match (matched, else_register) {
+            (true, _) => register,
+            (false, Some(else_register)) => else_register,
+            (false, None) => return Ok(DcResult::Continue),
+        };
+        self.note_register_read(register);
+        let text = match self.registers.get(register).value() {
+            Some(DcValue::Str(s)) => s.clone(),
+            Some(DcValue::Num(_)) => return Ok(DcResult::Continue),
+            None => return Err(DcError::RegisterEmpty(register)),
+        };
+        self.next_macro_register = Some(register);
+        Ok(DcResult::Macro(text))
+    }
+
+    pub(crate) fn report_error(&mut self, w: &mut impl Write, e: &DcError) {
+        self.report_error_at(w, e, None);
+    }
+
+    /// Report an error, with an excerpt of the macro it happened in if verbose errors are on.
+    fn report_error_at(&mut self, w: &mut impl Write, e: &DcError, excerpt: Option<Excerpt>) {
+        // Let the error saying output was cut off through, or there'd be no telling why.
+        let exempt = matches!(e, DcError::OutputLimit);
+        self.output_budget.set_exempt(exempt);
+        let excerpt = excerpt.filter(|_| self.verbose_errors);
+        self.diagnostic_with_excerpt(w, Severity::Error, e.code(), e.to_string(), excerpt);
+        self.output_budget.set_exempt(false);
+    }
+
+    /// Send a diagnostic to the handler if there is one, otherwise write it to the output.
+    fn diagnostic(&mut self, w: &mut impl Write, severity: Severity, code: &'static str,
+        message: String)
+    {
+        self.diagnostic_with_excerpt(w, severity, code, message, None);
+    }
+
+    fn diagnostic_with_excerpt(&mut self, w: &mut impl Write, severity: Severity,
+        code: &'static str, message: String, excerpt: Option<Excerpt>)
     {
-        if self.binary_lambda(|a, b| Ok(f(a, b)))? {
-            let text = match self.registers.get(register).value() {
-                Some(DcValue::Str(s)) => s.to_owned(),
-                Some(DcValue::Num(_)) => return Ok(DcResult::Continue),
-                None => return Err(DcError::RegisterEmpty(register)),
+        let origin = self.origin().cloned();
+        if self.diagnostic_sink.is_some() || self.diagnostic_handler.is_some() {
+            let diagnostic = Diagnostic {
+                severity,
+                code,
+                message,
+                // the position is only meaningful in the current input
+                position: if origin == self.input_name { self.position } else { None },
+                input: origin.map(|name| name.to_string()),
+                excerpt,
             };
-            Ok(DcResult::Macro(text))
+            if let Some(sink) = self.diagnostic_sink.as_mut() {
+                sink.push(diagnostic);
+            } else if let Some(handler) = self.diagnostic_handler.as_mut() {
+                handler(&diagnostic);
+            }
+            return;
+        }
+        let prefix = match origin {
+            Some(name) => format!("{}: {name}", self.program_name),
+            None => self.program_name.clone(),
+        };
+        match severity {
+            Severity::Error => writeln!(w, "{prefix}: {message}"),
+            Severity::Warning => writeln!(w, "{prefix}: warning: {message}"),
+        }.unwrap();
+        if let Some(excerpt) = excerpt {
+            for line in excerpt.to_string().lines() {
+                writeln!(w, "    {line}").unwrap();
+            }
+        }
+    }
+
+    /// Emit a warning, unless warnings are only to be emitted once and this one already has been,
+    /// in which case it just gets counted.
+    fn warn(&mut self, w: &mut impl Write, warning: Warning) {
+        if let Some(count) = self.warnings.get_mut(&warning) {
+            if self.warn_once {
+                *count += 1;
+                return;
+            }
         } else {
-            Ok(DcResult::Continue)
+            self.warnings.insert(warning, 0);
+        }
+        self.diagnostic(w, Severity::Warning, warning.code(), warning.to_string());
+    }
+
+    /// Emit the warnings an action collected about its operands, which must be in the order the
+    /// operands were pushed. They have to come before the action's error, if any, which is
+    /// reported after the action returns; see [`Diagnostic`](crate::diagnostic::Diagnostic) for
+    /// the order promised.
+    fn warn_all(&mut self, w: &mut impl Write, warnings: Vec<Warning>) {
+        for warning in warnings {
+            self.warn(w, warning);
+        }
+    }
+
+    /// Pop the levels pushed by `t` since there were `depth` of them, most recent first.
+    fn restore_scoped_registers(&mut self, depth: usize) {
+        while self.scoped_registers.len() > depth {
+            let register = self.scoped_registers.pop().unwrap();
+            self.registers.get_mut(register).drop_level();
+        }
+    }
+
+    /// Called at the end of each input: emit a count for any warnings which were suppressed, and
+    /// start afresh.
+    pub fn end_of_input(&mut self, w: &mut impl Write) {
+        // The input is the outermost macro level, so anything saved at its top level goes back.
+        self.restore_scoped_registers(0);
+        self.position = None;
+        for (warning, count) in std::mem::take(&mut self.warnings) {
+            if count > 0 {
+                self.diagnostic(w, Severity::Warning, warning.code(),
+                    format!("{warning} (repeated {count} times)"));
+            }
+        }
+    }
+}
+
+/// The settings values are printed with, as of the start of a print command.
+#[derive(Debug, Clone, Copy)]
+struct PrintFormat {
+    radix: u32,
+    notation: Notation,
+}
+
+impl PrintFormat {
+    /// Whether numbers are written with every digit, as [`BigReal::to_str_radix`] gives them.
+    fn is_plain(&self) -> bool {
+        self.radix != 10 || self.notation == Notation::Plain
+    }
+
+    fn write(&self, elem: &DcValue, w: &mut impl Write) {
+        let scientific = match self.notation {
+            _ if self.radix != 10 => None,
+            Notation::Plain => None,
+            Notation::Scientific(digits) => Some((digits, false)),
+            Notation::Engineering(digits) => Some((digits, true)),
+        };
+        match elem {
+            DcValue::Num(n) => if let Some((digits, engineering)) = scientific {
+                w.write_all(n.to_scientific(digits, engineering).as_bytes())
+            } else if n.is_zero() {
+                // dc special-cases zero and ignores the scale, opting to not print the extra zero
+                // digits.
+                write!(w, "0")
+            } else {
+                n.write_radix(self.radix, w)
+            }
+            DcValue::Str(s) => w.write_all(s),
+        }.unwrap();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(clippy::enum_variant_names)] // so far, they're all about scale
+enum Warning {
+    BaseScale,
+    ExponentFraction,
+    ExponentScale,
+    ModulusScale,
+    RootScale,
+}
+
+impl Warning {
+    fn code(&self) -> &'static str {
+        match self {
+            Warning::BaseScale => "base_scale",
+            Warning::ExponentFraction => "exponent_fraction",
+            Warning::ExponentScale => "exponent_scale",
+            Warning::ModulusScale => "modulus_scale",
+            Warning::RootScale => "root_scale",
         }
     }
+}
 
-    pub(crate) fn error(&self, w: &mut impl Write, args: fmt::Arguments<'_>) {
-        writeln!(w, "{}: {}", self.program_name, fmt::format(args)).unwrap();
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Warning::BaseScale => "non-zero scale in base",
+            Warning::ExponentFraction => "non-zero fractional part in exponent",
+            Warning::ExponentScale => "non-zero scale in exponent",
+            Warning::ModulusScale => "non-zero scale in modulus",
+            Warning::RootScale => "non-zero scale in root index",
+        })
     }
 }
 
 // A number in the process of being built up from input.
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct Number {
     int: BigInt,
+    // Decimal digits not yet added to `int`. Adding digits one at a time takes time quadratic in
+    // the number of digits, so in the common case of decimal input they are collected here and
+    // converted all at once instead.
+    digits: Vec<u8>,
     shift: Option<u32>,
     neg: bool,
+    num_digits: usize,
+    // The first digit which was too big for the input radix, if any.
+    invalid_digit: Option<u8>,
+    // The power of ten after an 'e', in scientific notation, and its sign. It's always decimal.
+    exponent: Option<u32>,
+    exponent_neg: bool,
+    // Whether the exponent has a sign or digits yet, after which a sign can't come.
+    exponent_started: bool,
+    // Whether the exponent had too many digits to fit.
+    exponent_too_big: bool,
 }
 
 impl Number {
-    pub fn push(&mut self, c: u8, iradix: u32) -> Result<(), DcError> {
+    /// Add a character to the number. An 'e' starts an exponent only with `scientific`, and
+    /// after that only a sign, then decimal digits, can follow, as the parser reads them.
+    pub fn push(&mut self, c: u8, iradix: u32, scientific: bool) -> Result<(), DcError> {
         match c {
+            b'e' if scientific && self.exponent.is_none() => { self.exponent = Some(0); }
+            b'_' | b'-' if self.exponent.is_some() && !self.exponent_started => {
+                self.exponent_neg = true;
+                self.exponent_started = true;
+            }
+            b'0' ..= b'9' if self.exponent.is_some() => {
+                let exponent = self.exponent.as_mut().unwrap();
+                match exponent.checked_mul(10).and_then(|e| e.checked_add(u32::from(c - b'0'))) {
+                    Some(e) => *exponent = e,
+                    None => self.exponent_too_big = true,
+                }
+                self.exponent_started = true;
+            }
+            _ if self.exponent.is_some() => return Err(DcError::UnexpectedNumberChar(c)),
             b'_' => { self.neg = true; }
+            b'0' ..= b'9' if iradix == 10 => {
+                self.digits.push(c);
+                self.count_digit();
+            }
             b'0' ..= b'9' | b'A' ..= b'F' => {
-                self.int *= iradix;
-                self.int += (c as char).to_digit(16).unwrap();
-                if let Some(shift) = self.shift.as_mut() {
-                    *shift += 1;
+                if self.invalid_digit.is_none() && (c as char).to_digit(16).unwrap() >= iradix {
+                    self.invalid_digit = Some(c);
                 }
+                self.flush_digits();
+                self.push_digit(c, iradix);
             }
             b'.' => { self.shift = Some(0); }
             _ => return Err(DcError::UnexpectedNumberChar(c)),
@@ -579,16 +1940,70 @@ impl Number {
         Ok(())
     }
 
-    pub fn finish(mut self, iradix: u32) -> DcValue {
-        if self.neg {
-            self.int *= -1;
+    fn push_digit(&mut self, c: u8, iradix: u32) {
+        self.int *= iradix;
+        self.int += (c as char).to_digit(16).unwrap();
+        self.count_digit();
+    }
+
+    fn count_digit(&mut self) {
+        self.num_digits += 1;
+        if let Some(shift) = self.shift.as_mut() {
+            *shift += 1;
+        }
+    }
+
+    /// Add any buffered decimal digits to `int`.
+    fn flush_digits(&mut self) {
+        if self.digits.is_empty() {
+            return;
+        }
+        let value = BigInt::parse_bytes(&self.digits, 10).expect("invalid decimal digits");
+        if self.int.is_zero() {
+            self.int = value;
+        } else {
+            self.int = &self.int * BigInt::from(10u32).pow(self.digits.len() as u32) + value;
+        }
+        self.digits.clear();
+    }
+
+    /// Get the value of the number. With `strict_digits`, it's an error for it to have a digit
+    /// which is too big for the input radix, unless that's its only digit. With
+    /// `exact_fractions`, fractions in other radixes get as many decimal digits as they need; see
+    /// [`Dc4::set_exact_fractions`](crate::Dc4::set_exact_fractions). It's always an error for
+    /// the exponent not to fit into 32 bits.
+    pub fn finish(mut self, iradix: u32, strict_digits: bool, exact_fractions: bool)
+        -> Result<DcValue, DcError>
+    {
+        if self.exponent_too_big {
+            return Err(DcError::ExponentTooBig);
+        }
+        if let Some(c) = self.invalid_digit {
+            if strict_digits && self.num_digits > 1 {
+                return Err(DcError::DigitInvalid(c, iradix));
+            }
         }
-        let mut real = BigReal::from(self.int);
+        let mut real = if self.int.is_zero() && self.digits.len() < 19 {
+            // Short decimal numbers are the most common kind, and fit in an i64 without needing a
+            // BigInt at all.
+            let n = self.digits.iter().fold(0i64, |n, c| n * 10 + i64::from(c - b'0'));
+            BigReal::from(if self.neg { -n } else { n })
+        } else {
+            self.flush_digits();
+            if self.neg {
+                self.int *= -1;
+            }
+            BigReal::from(self.int)
+        };
         if let Some(shift) = self.shift {
             if iradix == 10 {
                 // shortcut: shift is a number of decimal digits. The input was given in
                 // decimal, so just set the shift directly.
                 real.set_shift(shift);
+            } else if exact_fractions {
+                let scale = shift.saturating_mul(fraction_digits(iradix));
+                real = real.div(&BigReal::from(BigInt::from(iradix).pow(shift)), scale);
+                real.trim();
             } else {
                 // Otherwise, we have to repeatedly divide by iradix to get the right
                 // value. NOTE: the value 'shift' is the number of digits of input in
@@ -602,6 +2017,324 @@ impl Number {
                 }
             }
         }
-        DcValue::Num(real)
+        if let Some(exponent) = self.exponent {
+            let places = i64::from(exponent);
+            real = real.shift_decimal(if self.exponent_neg { -places } else { places });
+        }
+        Ok(DcValue::Num(real))
+    }
+}
+
+/// How many decimal digits each fractional digit in the given radix can need, when fractions are
+/// exact. A radix made up of only twos and fives, 2^a × 5^b, needs max(a, b) for the value to be
+/// exact. Other radixes have fractions like a third which never end in decimal, so those are
+/// truncated after as many digits as it takes to write the radix's biggest digit in decimal.
+fn fraction_digits(radix: u32) -> u32 {
+    let (mut rest, mut twos, mut fives) = (radix, 0, 0);
+    while rest % 2 == 0 {
+        rest /= 2;
+        twos += 1;
+    }
+    while rest % 5 == 0 {
+        rest /= 5;
+        fives += 1;
+    }
+    if rest == 1 {
+        twos.max(fives)
+    } else {
+        (radix - 1).ilog10() + 1
+    }
+}
+
+/// The value of a number literal read in the given input radix, allowing digits too big for it,
+/// or `None` if its exponent is too big or it isn't a number at all.
+pub(crate) fn literal_value(text: &[u8], iradix: u32) -> Option<BigReal> {
+    let mut num = Number::default();
+    for &c in text {
+        // The parser only puts an 'e' in a literal when reading scientific notation.
+        num.push(c, iradix, true).ok()?;
+    }
+    match num.finish(iradix, false, false) {
+        Ok(DcValue::Num(n)) => Some(n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::SliceParser;
+    use crate::reader_parser::ReaderParser;
+
+    fn parse(input: &[u8], iradix: u32, buffered: bool) -> String {
+        let mut num = Number::default();
+        for &c in input {
+            match c {
+                b'0' ..= b'9' | b'A' ..= b'F' if !buffered => num.push_digit(c, iradix),
+                _ => num.push(c, iradix, true).unwrap(),
+            }
+        }
+        match num.finish(iradix, false, false) {
+            Ok(DcValue::Num(n)) => format!("{n:?}"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_print_format_snapshot() {
+        // Nothing can run in the middle of a print command yet, so change the radix between
+        // taking the format and printing with it, as something run from inside one could.
+        let mut state = Dc4State::new("test".to_owned(), Flavor::Gnu);
+        let values = [255, 16].map(|n| DcValue::Num(BigReal::from(n)));
+        let format = state.print_format();
+        let mut out = vec![];
+        for value in &values {
+            format.write(value, &mut out);
+            state.oradix = 16;
+            out.push(b' ');
+        }
+        assert_eq!(out, b"255 16 ");
+
+        let mut out = vec![];
+        state.print_elem(&values[0], &mut out);
+        assert_eq!(out, b"FF");
+    }
+
+    #[test]
+    fn test_number_buffered_digits() {
+        let long = "1234567890".repeat(8);
+        let inputs = [
+            "0", "7", "000", "00012", "_123.456", ".000", "1.", "123.4500",
+            "1A", "1A.5", "A1", "9F9.F0", "_FFF", "12.3A45",
+            "12345678901234567890123456789.987654321",
+            &long,
+            &format!("{long}.{long}"),
+            &format!("{long}F{long}"),
+            &format!("_{long}.{long}B"),
+        ];
+        for input in inputs {
+            for iradix in [2, 10, 16] {
+                assert_eq!(parse(input.as_bytes(), iradix, true),
+                    parse(input.as_bytes(), iradix, false),
+                    "input {input:?} in radix {iradix}");
+            }
+        }
+    }
+
+    /// A reader which hands out its data in chunks of the given sizes, over and over.
+    struct Chunked<'a> {
+        data: &'a [u8],
+        sizes: Vec<usize>,
+        next: usize,
+    }
+
+    impl io::Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let available = io::BufRead::fill_buf(self)?;
+            let n = available.len().min(buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            io::BufRead::consume(self, n);
+            Ok(n)
+        }
+    }
+
+    impl io::BufRead for Chunked<'_> {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            let size = self.sizes[self.next % self.sizes.len()];
+            Ok(&self.data[.. size.min(self.data.len())])
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.data = &self.data[amt ..];
+            self.next += 1;
+        }
+    }
+
+    /// The actions parsed from a program, with literals coalesced, by each way of parsing.
+    fn slice_actions(text: &[u8], flavor: Flavor) -> Vec<String> {
+        let mut parser = SliceParser::with_flavor(text, flavor);
+        parser.set_coalesce_literals(true);
+        parser.map(|(_offset, action)| format!("{action:?}")).collect()
+    }
+
+    fn reader_actions(text: &[u8], sizes: Vec<usize>, flavor: Flavor) -> Vec<String> {
+        let mut parser = ReaderParser::new(Chunked { data: text, sizes, next: 0 }, flavor);
+        parser.set_coalesce_literals(true);
+        parser.map(|action| format!("{action:?}")).collect()
+    }
+
+    fn text_actions(text: &[u8], flavor: Flavor) -> Vec<String> {
+        let mut source = Source::text(DcString::from(text), Parser::new(flavor));
+        if let Source::Text { parser, .. } = &mut source {
+            parser.set_coalesce_literals(true);
+        }
+        std::iter::from_fn(|| source.next(true, &mut None))
+            .map(|action| format!("{action:?}"))
+            .collect()
+    }
+
+    /// Which byte offsets of a program fall between commands, rather than partway through one.
+    fn command_boundaries(text: &[u8], flavor: Flavor) -> Vec<bool> {
+        let mut parser = Parser::new(flavor);
+        let mut boundaries = vec![true];
+        for &c in text {
+            let mut input = Some(c);
+            while input.is_some() {
+                parser.step(&mut input);
+            }
+            boundaries.push(parser.between_commands());
+        }
+        boundaries
+    }
+
+    /// Programs with the trickiest places to stop and start again: literals, nested and unclosed
+    /// strings, comments, two- and three-character commands, and characters which end a number
+    /// and then have to be parsed again.
+    const TRICKY: &[&str] = &[
+        "16ff", "[partial", "1234", "1234s", "1234#\n5678f", "12_.5 [a[b]]p 3[x", "_1.2.3",
+        "[[]]]", "! ls\n1p", "s", "<", "!", "!>", "!<a=b", "1 2 !=xex", "1 2 >ab 3 <a", "=a",
+        "AB.CD_E", "# comment\r\n1p", "#", "[a\nb]n", "1 2+p[sum]P", "lx;y:z Lq Sr", "?q Q",
+        "1.", ".", "_", "1 G 2 N", "@ Y J", "\u{7f}\u{ff}", "[x]sx lxx [[y]x]x", "9z3Z2X",
+    ];
+
+    /// Programs made of random dc characters.
+    fn random_programs() -> Vec<Vec<u8>> {
+        let alphabet = b"0123456789ABF._ [[]]]sSlL<>=!#\nxpfq:;e ";
+        let mut rng = Rng::from_seed(0x2545_f491_4f6c_dd1d);
+        (0 .. 300).map(|_| {
+            let len = (rng.next_u64() % 40) as usize;
+            (0 .. len).map(|_| alphabet[(rng.next_u64() % alphabet.len() as u64) as usize])
+                .collect()
+        }).collect()
+    }
+
+    fn programs() -> Vec<Vec<u8>> {
+        TRICKY.iter().map(|p| p.as_bytes().to_vec()).chain(random_programs()).collect()
+    }
+
+    const FLAVORS: [Flavor; 3] = [Flavor::Gnu, Flavor::Bsd, Flavor::Gavin];
+
+    #[test]
+    fn test_chunking_whole() {
+        // Text run as a macro parses just like text in memory, and like a stream read all at once
+        // or a byte at a time.
+        for text in programs() {
+            for flavor in FLAVORS {
+                let expected = slice_actions(&text, flavor);
+                let context = format!("{:?} in {flavor:?}", String::from_utf8_lossy(&text));
+                assert_eq!(text_actions(&text, flavor), expected, "{context}");
+                assert_eq!(reader_actions(&text, vec![usize::MAX], flavor), expected,
+                    "{context}");
+                assert_eq!(reader_actions(&text, vec![1], flavor), expected, "{context}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunking_random() {
+        let mut rng = Rng::from_seed(0x9e37_79b9_7f4a_7c15);
+        for text in programs() {
+            for flavor in FLAVORS {
+                let sizes = (0 .. 8).map(|_| (rng.next_u64() % 7) as usize + 1)
+                    .collect::<Vec<_>>();
+                assert_eq!(reader_actions(&text, sizes.clone(), flavor),
+                    slice_actions(&text, flavor),
+                    "{:?} in {flavor:?}, in chunks of {sizes:?}", String::from_utf8_lossy(&text));
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunking_split_inputs() {
+        // Splitting a program into two inputs only makes a difference where the split is partway
+        // through a command: the end of the first input finishes it off. Everything before that
+        // is the same.
+        for text in programs() {
+            for flavor in FLAVORS {
+                let whole = slice_actions(&text, flavor);
+                let boundaries = command_boundaries(&text, flavor);
+                for split in 0 ..= text.len() {
+                    let first = text_actions(&text[.. split], flavor);
+                    let second = text_actions(&text[split ..], flavor);
+                    let context = format!("{:?} + {:?} in {flavor:?}",
+                        String::from_utf8_lossy(&text[.. split]),
+                        String::from_utf8_lossy(&text[split ..]));
+                    if boundaries[split] {
+                        assert_eq!([first, second].concat(), whole, "{context}");
+                    } else {
+                        let before = &first[.. first.len().saturating_sub(1)];
+                        assert!(whole.starts_with(before), "{context}: {first:?} vs {whole:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_arbitrary_actions() {
+        // Every command there is, with registers where they take them.
+        let mut commands = vec![];
+        for flavor in FLAVORS {
+            for c in 0 ..= 255u8 {
+                let text = [c, b'a', b'e', b'b', b'.'];
+                if let Some((_, action)) = SliceParser::with_flavor(&text, flavor).next() {
+                    commands.push(action);
+                }
+            }
+        }
+        let registers = [RegisterAction::Store, RegisterAction::Load, RegisterAction::PushRegStack,
+            RegisterAction::PopRegStack, RegisterAction::Gt, RegisterAction::Le, RegisterAction::Lt,
+            RegisterAction::Ge, RegisterAction::Eq, RegisterAction::Ne,
+            RegisterAction::StoreRegArray, RegisterAction::LoadRegArray,
+            RegisterAction::SaveScoped];
+
+        let mut rng = Rng::from_seed(0x2545_f491_4f6c_dd1d);
+        let mut next = move |bound: usize| (rng.next_u64() % bound as u64) as usize;
+        let bytes = |next: &mut dyn FnMut(usize) -> usize, alphabet: &[u8]| {
+            (0 .. next(4)).map(|_| alphabet[next(alphabet.len())]).collect::<Vec<u8>>()
+        };
+        const NUMBER: &[u8] = b"0123456789AF._-e!";
+        const STRING: &[u8] = b"019.+-*/pfzc[]sla";
+
+        for _ in 0 .. 2000 {
+            let mut dc = crate::Dc4::new("dc4 cargo test".to_string());
+            dc.set_line_reader(io::empty());
+            if next(2) == 0 {
+                dc.set_echo(io::sink());
+            }
+            dc.set_track_provenance(next(2) == 0);
+            let mut out = Vec::<u8>::new();
+            for _ in 0 .. 20 {
+                let action = match next(10) {
+                    0 => Action::NumberChar(NUMBER[next(NUMBER.len())]),
+                    1 => Action::PushNumber,
+                    2 => Action::StringChar(STRING[next(STRING.len())]),
+                    3 => Action::PushString,
+                    4 => Action::NumberLiteral(bytes(&mut next, NUMBER)),
+                    5 => Action::StringLiteral(bytes(&mut next, STRING)),
+                    6 => Action::Register(registers[next(registers.len())], b"ab"[next(2)]),
+                    7 => Action::IfElse(registers[next(registers.len())], b'a', b'b'),
+                    _ => commands[next(commands.len())].clone(),
+                };
+                // These can take a long time on big enough numbers, which random programs soon
+                // make, so finish any number being read and give them small ones to work on.
+                if matches!(action, Action::Exp | Action::ModExp | Action::Factorial
+                    | Action::SetPrecision | Action::ShiftLeft | Action::ShiftRight
+                    | Action::NaturalExp)
+                {
+                    let _ = dc.action(Action::PushNumber, &mut out);
+                    for _ in 0 .. 3 {
+                        let digits = (0 ..= next(2)).map(|_| b'0' + next(10) as u8).collect();
+                        let _ = dc.action(Action::NumberLiteral(digits), &mut out);
+                    }
+                }
+                // Whatever happens, it's an error at worst, never a panic.
+                let _ = if next(2) == 0 {
+                    dc.action(action, &mut out)
+                } else {
+                    dc.action_traced(action, &mut out).0
+                };
+            }
+        }
     }
 }
@@ -0,0 +1,144 @@
+//
+// dc4 :: library of macros
+//
+// dc has no standard library, so everyone writes their own factorial. These are some common
+// macros, loaded into registers by name with `Dc4::load_stdlib` or `--stdlib`.
+//
+// The macros only use commands which GNU dc has too, so a program using them can be run
+// elsewhere by pasting in their definitions.
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+/// A macro in a [`Package`], and the register it's loaded into.
+pub struct Macro {
+    /// The register holding the macro.
+    pub register: u8,
+    /// What it's called, for documentation.
+    pub name: &'static str,
+    /// What it does, in terms of the stack. Helpers, which are only run by the package's other
+    /// macros, say which macro they help.
+    pub description: &'static str,
+    /// The macro itself.
+    pub text: &'static [u8],
+}
+
+/// A set of macros loaded together.
+pub struct Package {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub macros: &'static [Macro],
+    /// Registers used for data, or as scratch space, but not loaded with a macro.
+    pub scratch: &'static [u8],
+}
+
+impl Package {
+    /// Every register the package uses, in order: the ones its macros are loaded into, then its
+    /// scratch registers. Loading the package replaces the values of the first ones, and running
+    /// its macros may change the values of any of them, so a program using the package shouldn't
+    /// keep its own values in them.
+    ///
+    /// The macros use the stack of their own register for temporary values, so they don't
+    /// disturb values further down the stacks of these registers.
+    pub fn registers(&self) -> Vec<u8> {
+        self.macros.iter().map(|m| m.register).chain(self.scratch.iter().copied()).collect()
+    }
+}
+
+const fn entry(register: u8, name: &'static str, description: &'static str, text: &'static [u8])
+    -> Macro
+{
+    Macro { register, name, description, text }
+}
+
+/// The packages, by name. All their registers are capital letters other than A to F, and no
+/// two packages use the same register for a macro, so any of them can be loaded together.
+/// Register `V` is a scratch register shared by packages, for throwing away values.
+pub const PACKAGES: &[Package] = &[
+    Package {
+        name: "math",
+        description: "factorial, Fibonacci numbers, and greatest common divisor",
+        macros: &[
+            entry(b'N', "factorial", "pop a nonnegative integer n and push n!",
+                b"1r d0<P +"),
+            entry(b'P', "factorial loop", "helper for N",
+                b"dSP * LP 1- d0<P"),
+            entry(b'I', "fibonacci", "pop a nonnegative integer n and push the n-th Fibonacci \
+                number (0, 1, 1, 2, ...)",
+                b"0r1r d0<Q + 0*+"),
+            entry(b'Q', "fibonacci loop", "helper for I",
+                b"SQ dSQ + LQ r LQ 1- d0<Q"),
+            entry(b'G', "gcd", "pop two integers and push their greatest common divisor, which \
+                is never negative",
+                b"K SG 0k d0!=R + d*v LG k"),
+            entry(b'R', "gcd loop", "helper for G",
+                b"dSR % LR r d0!=R"),
+        ],
+        scratch: b"",
+    },
+    Package {
+        name: "stack",
+        description: "stack manipulation",
+        macros: &[
+            entry(b'U', "drop", "pop a value and throw it away (into register V)",
+                b"sV"),
+            entry(b'O', "over", "push a copy of the second value on the stack",
+                b"SO d LO r"),
+            entry(b'T', "rot", "move the third value on the stack to the top",
+                b"ST r LT r"),
+        ],
+        scratch: b"V",
+    },
+    Package {
+        name: "array",
+        description: "sorting the array in register Y",
+        macros: &[
+            entry(b'S', "sort", "pop n and sort entries 0 to n-1 of the array in register Y \
+                into ascending order",
+                b"SS 1 d lS >H sV LS sV"),
+            entry(b'H', "sort outer loop", "helper for S",
+                b"d;Y SH d1- lWx 1+ LH r :Y 1+ d lS >H"),
+            entry(b'W', "sort inner loop", "helper for S",
+                b"d0!>X"),
+            entry(b'X', "sort comparison", "helper for S",
+                b"d;Y lH <Z"),
+            entry(b'Z', "sort shift", "helper for S",
+                b"d;Y r dSZ 1+ :Y LZ 1- lWx"),
+        ],
+        scratch: b"YV",
+    },
+];
+
+/// Look up a package by name.
+pub fn package(name: &str) -> Option<&'static Package> {
+    PACKAGES.iter().find(|p| p.name == name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_registers_dont_clash() {
+        let mut seen = vec![];
+        for package in PACKAGES {
+            for m in package.macros {
+                assert!(!seen.contains(&m.register), "register {:?} used twice",
+                    m.register as char);
+                assert!(!package.scratch.contains(&m.register));
+                seen.push(m.register);
+            }
+        }
+        for package in PACKAGES {
+            for r in package.scratch {
+                assert!(!seen.contains(r), "scratch register {:?} holds a macro", *r as char);
+            }
+        }
+    }
+
+    #[test]
+    fn test_package() {
+        assert_eq!(package("stack").unwrap().registers(), b"UOTV");
+        assert!(package("nope").is_none());
+    }
+}
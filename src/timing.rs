@@ -0,0 +1,167 @@
+//
+// dc4 phase timing
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::DcTimings;
+use crate::parser::{Action, RegisterAction};
+
+/// What the time is being spent on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Parsing,
+    Arithmetic,
+    Registers,
+    Printing,
+    Macros,
+    Other,
+    Output,
+}
+
+impl Phase {
+    /// Which phase running the given action counts towards.
+    pub fn of(action: &Action) -> Phase {
+        match action {
+            Action::NumberChar(_) | Action::StringChar(_) | Action::PushNumber
+                | Action::PushString | Action::NumberLiteral(_)
+                | Action::StringLiteral(_) => Phase::Parsing,
+            Action::Register(RegisterAction::Store, _)
+                | Action::Register(RegisterAction::Load, _)
+                | Action::Register(RegisterAction::PushRegStack, _)
+                | Action::Register(RegisterAction::PopRegStack, _)
+                | Action::Register(RegisterAction::StoreRegArray, _)
+                | Action::Register(RegisterAction::LoadRegArray, _)
+                | Action::Register(RegisterAction::SaveScoped, _)
+                | Action::StoreRecord(_) | Action::LoadRecord(_) => Phase::Registers,
+            // The comparisons' real work is the macro they run.
+            Action::Register(..) | Action::IfElse(..) | Action::ExecuteMacro | Action::Quit
+                | Action::QuitLevels => Phase::Macros,
+            Action::Print | Action::PrintNoNewline | Action::PrintNoNewlinePop
+                | Action::PrintBytesPop | Action::PrintStack | Action::PrintStackIndexed
+                => Phase::Printing,
+            Action::Add | Action::Sub | Action::Mul | Action::Div | Action::Rem | Action::DivRem
+                | Action::Exp | Action::ModExp | Action::Sqrt | Action::Root | Action::Round
+                | Action::Floor | Action::Ceil | Action::Trunc | Action::Abs | Action::Min
+                | Action::Max | Action::Gcd | Action::Lcm | Action::Factorial | Action::ShiftLeft
+                | Action::ShiftRight | Action::Random | Action::ModInverse | Action::NaturalExp
+                | Action::NaturalLog | Action::Log | Action::Arctan | Action::CompareEq
+                | Action::Not | Action::BitAnd | Action::BitOr | Action::BitXor
+                | Action::NumDigits | Action::NumFrxDigits
+                => Phase::Arithmetic,
+            _ => Phase::Other,
+        }
+    }
+}
+
+/// Attributes wall time to phases. The time between one call to [`switch`](Self::switch) and the
+/// next goes to the phase which was current, so the phases always add up to the total.
+///
+/// Only one timestamp is taken per change of phase, so the cost is per command run, not per byte
+/// of input.
+#[derive(Debug)]
+pub struct Timer {
+    /// When the current run started, if one is being timed.
+    started: Cell<Option<Instant>>,
+    mark: Cell<Instant>,
+    current: Cell<Phase>,
+    timings: RefCell<DcTimings>,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            started: Cell::new(None),
+            mark: Cell::new(now),
+            current: Cell::new(Phase::Other),
+            timings: RefCell::new(DcTimings::default()),
+        }
+    }
+
+    /// Start timing a run, which begins with parsing.
+    pub fn start(&self) {
+        let now = Instant::now();
+        self.started.set(Some(now));
+        self.mark.set(now);
+        self.current.set(Phase::Parsing);
+    }
+
+    /// Stop timing a run, adding its time to the total.
+    pub fn stop(&self) {
+        self.switch(Phase::Other);
+        if let Some(started) = self.started.take() {
+            self.timings.borrow_mut().total += self.mark.get() - started;
+        }
+    }
+
+    /// Charge the time since the last switch to the current phase, and change to a new one.
+    /// Returns the phase which was current. Outside of a run, this does nothing.
+    pub fn switch(&self, phase: Phase) -> Phase {
+        let current = self.current.get();
+        if phase == current || self.started.get().is_none() {
+            return current;
+        }
+        let now = Instant::now();
+        let elapsed = now - self.mark.replace(now);
+        let mut timings = self.timings.borrow_mut();
+        let slot = match current {
+            Phase::Parsing => &mut timings.parsing,
+            Phase::Arithmetic => &mut timings.arithmetic,
+            Phase::Registers => &mut timings.registers,
+            Phase::Printing => &mut timings.printing,
+            Phase::Macros => &mut timings.macros,
+            Phase::Other => &mut timings.other,
+            Phase::Output => &mut timings.output,
+        };
+        *slot += elapsed;
+        self.current.replace(phase)
+    }
+
+    pub fn timings(&self) -> DcTimings {
+        self.timings.borrow().clone()
+    }
+}
+
+/// Passes writes through to another writer, charging the time taken to output.
+pub(crate) struct TimedWrite<'a, W> {
+    inner: &'a mut W,
+    timer: Option<Rc<Timer>>,
+}
+
+impl<'a, W: Write> TimedWrite<'a, W> {
+    pub fn new(inner: &'a mut W, timer: Option<Rc<Timer>>) -> Self {
+        Self { inner, timer }
+    }
+
+    fn timed<T>(&mut self, f: impl FnOnce(&mut W) -> T) -> T {
+        match &self.timer {
+            Some(timer) => {
+                let outer = timer.switch(Phase::Output);
+                let result = f(self.inner);
+                timer.switch(outer);
+                result
+            }
+            None => f(self.inner),
+        }
+    }
+}
+
+impl<W: Write> Write for TimedWrite<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.timed(|w| w.write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.timed(|w| w.write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.timed(|w| w.flush())
+    }
+}
@@ -0,0 +1,69 @@
+//
+// dc4 :: changes to the state made all at once, or not at all
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+use std::io::Write;
+
+use crate::parser::Action;
+use crate::{Dc4, DcError, DcResult, DcString};
+
+/// A handle for making changes to the state as part of a transaction. See
+/// [`Dc4::transaction`].
+///
+/// The methods here are the same as the ones of the same name on [`Dc4`].
+pub struct Transaction<'a> {
+    dc: &'a mut Dc4,
+    aborted: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(dc: &'a mut Dc4) -> Self {
+        Self { dc, aborted: false }
+    }
+
+    /// Whether text run in the transaction stopped on an error, under
+    /// [`ErrorPolicy::AbortProgram`](crate::ErrorPolicy::AbortProgram).
+    pub(crate) fn aborted(&self) -> bool {
+        self.aborted
+    }
+
+    /// See [`Dc4::push_number`].
+    pub fn push_number(&mut self, input: impl AsRef<[u8]>) -> Result<(), DcError> {
+        self.dc.push_number(input)
+    }
+
+    /// See [`Dc4::push_scaled_int`].
+    pub fn push_scaled_int(&mut self, value: i128, scale: u32) {
+        self.dc.push_scaled_int(value, scale)
+    }
+
+    /// See [`Dc4::push_string`].
+    pub fn push_string(&mut self, string: impl Into<DcString>) {
+        self.dc.push_string(string)
+    }
+
+    /// See [`Dc4::action`].
+    pub fn action(&mut self, action: Action, w: &mut impl Write) -> Result<DcResult, DcError> {
+        self.dc.action(action, w)
+    }
+
+    /// See [`Dc4::actions`].
+    pub fn actions(&mut self, actions: impl Iterator<Item = Action>, w: &mut impl Write)
+        -> Result<DcResult, DcError>
+    {
+        self.dc.actions(actions, w)
+    }
+
+    /// See [`Dc4::text`]. If it stops on an error, because of
+    /// [`ErrorPolicy::AbortProgram`](crate::ErrorPolicy::AbortProgram), the whole transaction is
+    /// rolled back, whatever the closure returns.
+    pub fn text(&mut self, text: impl Into<DcString>, w: &mut impl Write) -> DcResult {
+        let result = self.dc.text(text, w);
+        if let DcResult::Aborted = result {
+            self.aborted = true;
+        }
+        result
+    }
+}
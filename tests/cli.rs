@@ -0,0 +1,558 @@
+//
+// dc4 command line tests
+//
+// Copyright (c) 2024 by William R. Fraser
+//
+
+#![deny(rust_2018_idioms)]
+
+use std::io::Write;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::process::{Command, Output, Stdio};
+
+fn dc4(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dc4"))
+        .args(args)
+        .env_remove("HOME") // don't pick up the user's config file
+        .output()
+        .expect("failed to run dc4")
+}
+
+fn dc4_stdin(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dc4"))
+        .args(args)
+        .env_remove("HOME")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run dc4");
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// A file in a temporary directory of its own, which is removed when this is dropped.
+struct ProgramFile {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl Deref for ProgramFile {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ProgramFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Write a program to a file in a new temporary directory.
+fn program_file(name: &str, contents: &[u8]) -> ProgramFile {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("dc4-cli-test-{}-{}",
+        std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+    let path = dir.join(name);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, contents).unwrap();
+    ProgramFile { dir, path }
+}
+
+#[test]
+fn test_diagnostics_json() {
+    let path = program_file("two_errors.dc", b"1 2 + p\n[foo] 3 *\n  lz p\n");
+    let path_str = path.to_str().unwrap();
+    let out = dc4(&["--diagnostics=json", "-f", path_str]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "3\n3\n");
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let diags = stderr.lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(diags, vec![
+        serde_json::json!({
+            "version": 1,
+            "severity": "error",
+            "code": "non_numeric_value",
+            "message": "non-numeric value",
+            "input": path_str,
+            "offset": 16,
+            "line": 2,
+            "column": 9,
+        }),
+        serde_json::json!({
+            "version": 1,
+            "severity": "error",
+            "code": "register_empty",
+            "message": "register 'z' (0172) is empty",
+            "input": path_str,
+            "offset": 21,
+            "line": 3,
+            "column": 4,
+        }),
+    ]);
+}
+
+#[test]
+fn test_diagnostics_text() {
+    let out = dc4(&["-e", "1 2 + p [foo] 3 *"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+        "3\ndc4: -e expression #1: non-numeric value\n");
+    assert!(out.stderr.is_empty());
+}
+
+#[test]
+fn test_diagnostics_json_expression() {
+    let out = dc4(&["--flavor=bsd", "--diagnostics=json", "-e", "2 .5 ^ p"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1\n");
+    let diag: serde_json::Value = serde_json::from_slice(&out.stderr).unwrap();
+    assert_eq!(diag["severity"], "warning");
+    assert_eq!(diag["code"], "exponent_fraction");
+    assert_eq!(diag["input"], "-e expression #1");
+    assert_eq!(diag["offset"], 5);
+}
+
+#[test]
+fn test_file_names_in_errors() {
+    let first = program_file("first.dc", b"[first]p [ + ]sa");
+    let second = program_file("second.dc", b"[second]p\n1 2 3 ++p\nc 1 lax *");
+    let out = dc4(&["-e", "1", first.to_str().unwrap(), second.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), format!(concat!(
+        "first\n",
+        "second\n",
+        "6\n",
+        "dc4: {}: stack empty\n",
+        "dc4: {}: stack empty\n",
+    ), first.display(), second.display()));
+}
+
+#[test]
+fn test_flavor_option() {
+    let out = dc4(&["--flavor=gavin", "-e", "2 2G p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1\n");
+
+    let out = dc4(&["--flavor=nope", "-e", "2 2G p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+        "invalid flavor \"nope\": must be 'gnu', 'bsd', or 'gavin'.\n");
+}
+
+#[test]
+fn test_input_from_program_stdin() {
+    // When the program is on standard input, '?' reads the line after the one it's on, and the
+    // program carries on after that.
+    let out = dc4_stdin(&[], b"? p\n5 6 +\n[after]p\n");
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "11\nafter\n");
+
+    // Including from inside macros, and several times.
+    let out = dc4_stdin(&[], b"[? 1 +]sx lxx lxx * p\n41\n1\n[end]p\n");
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "84\nend\n");
+
+    // Reading past the end of the input gets an empty line.
+    let out = dc4_stdin(&[], b"1 ? 2 + p");
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "3\n");
+}
+
+#[test]
+fn test_input_from_stdin_with_program_elsewhere() {
+    let out = dc4_stdin(&["-e", "? ? * p"], b"6\n7\n");
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "42\n");
+
+    // The same, followed by reading a program from standard input.
+    let out = dc4_stdin(&["-e", "? 2 *", "-"], b"21\np\n");
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "42\n");
+}
+
+#[test]
+fn test_config_file() {
+    let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/config.toml");
+
+    // The fixture picks the BSD flavor, JSON diagnostics, and verbose errors.
+    let out = dc4(&["--config", fixture, "-e", "[foo] 1 =x"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "");
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let diags = stderr.lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(diags.len(), 2);
+    assert_eq!(diags[0]["code"], "config_warning");
+    assert_eq!(diags[0]["message"], "line 12: unknown key 'line_length'");
+    assert_eq!(diags[0]["input"], fixture);
+    assert_eq!(diags[1]["message"], "non-numeric value in '=' comparison for register 'x' (0170)");
+
+    // Command line options take precedence.
+    let out = dc4(&[&format!("--config={fixture}"), "--diagnostics=text", "--flavor=gavin",
+        "-e", "2 2G p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), format!(
+        "dc4: {fixture}: warning: line 12: unknown key 'line_length'\n1\n"));
+}
+
+#[test]
+fn test_config_file_errors() {
+    let path = program_file("bad_config.toml", b"flavor = \"gnu\"\nwarn_once = 1\n");
+    let out = dc4(&["--config", path.to_str().unwrap(), "-e", "1p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), format!(
+        "dc4: {}: line 2: 'warn_once' must be a boolean, not an integer\n", path.display()));
+
+    // A missing config file is only an error if it was asked for.
+    let out = dc4(&["--config", "/nonexistent/dc4.toml", "-e", "1p"]);
+    assert!(String::from_utf8(out.stdout).unwrap()
+        .starts_with("dc4: /nonexistent/dc4.toml: can't read config file: "));
+}
+
+#[test]
+fn test_config_file_default_path() {
+    let config = program_file("home/.config/dc4/config.toml", b"flavor = 'gavin'\n");
+    let home = config.ancestors().nth(3).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_dc4"))
+        .args(["-e", "2 2G p"])
+        .env("HOME", home)
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1\n");
+
+    let out = Command::new(env!("CARGO_BIN_EXE_dc4"))
+        .args(["-e", "2 2G p"])
+        .env("HOME", home.join("nowhere"))
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "dc4: -e expression #1: 'G' (0107) unimplemented\n2\n");
+}
+
+#[test]
+fn test_echo() {
+    let out = dc4_stdin(&["--echo"], b"4 5 +\np\n");
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "9\n");
+    assert_eq!(String::from_utf8(out.stderr).unwrap(), "> 4\n> 5\n> +\n> p\n");
+}
+
+#[test]
+fn test_strict() {
+    let file = program_file("after_error.dc", b"[file ran]p\n");
+    let file = file.to_str().unwrap();
+
+    let out = dc4(&["--strict", "-e", "1p + 2p", file]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1\ndc4: -e expression #1: stack empty\n");
+
+    // Without it, everything runs.
+    let out = dc4(&["-e", "1p + 2p", file]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+        "1\ndc4: -e expression #1: stack empty\n2\nfile ran\n");
+
+    // Piped standard input is strict too, and a strict run without errors succeeds.
+    let out = dc4_stdin(&["--strict"], b"1p\nlx\n2p\n");
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1\ndc4: <stdin>: register 'x' (0170) is empty\n");
+    let out = dc4(&["--strict", "-e", "1p", file]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1\nfile ran\n");
+
+    // It can be just for some kinds of input.
+    let out = dc4_stdin(&["--strict=expressions,files", "-e", "1p", "-"], b"lx\n2p\n");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+        "1\ndc4: <stdin>: register 'x' (0170) is empty\n2\n");
+    let out = dc4(&["--strict=stdin", "-e", "1p + 2p", file]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+        "1\ndc4: -e expression #1: stack empty\n2\nfile ran\n");
+    let out = dc4(&["--strict=files", "-e", "1p", "-e", "lx", "/nonexistent.dc", file]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(!String::from_utf8(out.stdout).unwrap().contains("file ran"));
+    let out = dc4(&["--strict=expressions,bogus", "-e", "1p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "invalid strict inputs \
+        \"expressions,bogus\": must be a comma-separated list of 'expressions', 'files', and \
+        'stdin'.\n");
+}
+
+#[test]
+fn test_timings() {
+    let out = dc4(&["--timings", "-e", "2 100^ p"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+        "1267650600228229401496703205376\n");
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let names = stderr.lines()
+        .map(|line| line.split_whitespace().next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["phase", "parsing", "arithmetic", "registers", "printing", "macros",
+        "other", "output", "total"]);
+
+    // Still written when the program quits.
+    let out = dc4(&["--timings", "-e", "q", "-e", "1p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "");
+    assert_eq!(String::from_utf8(out.stderr).unwrap().lines().count(), 9);
+}
+
+#[test]
+fn test_stats() {
+    let out = dc4(&["--stats", "-e", "2 1000^ 1 5:a 2 7:a [xyz] f"]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let lines = stderr.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4, "{stderr}");
+    assert!(lines[0].starts_with("largest number") && lines[0].ends_with(" 302 digits"));
+    assert!(lines[1].starts_with("longest string") && lines[1].ends_with(" 3 bytes"));
+    assert!(lines[2].starts_with("stack memory"));
+    assert!(lines[3].starts_with("array a") && lines[3].ends_with(" 2 entries"));
+}
+
+#[test]
+fn test_strict_digits() {
+    let out = dc4(&["--strict-digits", "-e", "1A p 16i 1A p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+        "dc4: -e expression #1: digit 'A' is not valid in input radix 10\ndc4: -e expression #1: stack empty\n26\n");
+    let out = dc4(&["-e", "1A p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "20\n");
+}
+
+#[test]
+fn test_exact_fractions() {
+    let out = dc4(&["--exact-fractions", "-e", "16i 1.F p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1.9375\n");
+    let out = dc4(&["-e", "16i 1.F p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1.9\n");
+}
+
+#[test]
+fn test_scientific_notation() {
+    let out = dc4(&["--scientific-notation", "-e", "6.022e23 1e-3 * p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "602200000000000000000.000\n");
+}
+
+#[test]
+fn test_color() {
+    let run = |args: &[&str], no_color: Option<&str>, force_color: Option<&str>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_dc4"));
+        command.args(args).env_remove("HOME").env_remove("NO_COLOR").env_remove("FORCE_COLOR");
+        if let Some(value) = no_color {
+            command.env("NO_COLOR", value);
+        }
+        if let Some(value) = force_color {
+            command.env("FORCE_COLOR", value);
+        }
+        String::from_utf8(command.output().unwrap().stdout).unwrap()
+    };
+    let colored = "\x1b[31mdc4:\x1b[0m \x1b[2m-e expression #1:\x1b[0m stack empty\n1\n";
+    let plain = "dc4: -e expression #1: stack empty\n1\n";
+    let program = ["-e", "+ 1p"];
+    // Output here isn't a terminal, so there's no color unless it's asked for.
+    assert_eq!(run(&program, None, None), plain);
+    assert_eq!(run(&program, None, Some("1")), colored);
+    assert_eq!(run(&[&program[..], &["--color=always"]].concat(), Some("1"), None), colored);
+    assert_eq!(run(&[&program[..], &["--color=never"]].concat(), None, Some("1")), plain);
+    assert_eq!(run(&[&program[..], &["--color=auto"]].concat(), Some("1"), None), plain);
+}
+
+#[test]
+fn test_max_output() {
+    let out = dc4(&["--max-output=5", "--strict", "-e", "[1nlxx]dsxx", "-e", "2p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+        "11111dc4: -e expression #1: output limit reached; further output suppressed\n");
+    assert_eq!(out.status.code(), Some(1));
+    let out = dc4(&["--max-output=lots"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+        "invalid output limit \"lots\": must be a number of bytes.\n");
+}
+
+#[test]
+fn test_self_test() {
+    let out = dc4(&["--self-test"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "\
+arithmetic: PASS (6 checks)
+radix conversion: PASS (6 checks)
+macros: PASS (4 checks)
+quit levels: PASS (3 checks)
+");
+}
+
+#[test]
+fn test_quit_across_inputs() {
+    let cases: &[(&[&str], &str)] = &[
+        (&["-e", "1p q 2p", "-e", "9p"], "1\n"),
+        (&["-e", "[1p q]x 2p", "-e", "9p"], "1\n"),
+        (&["-e", "1p 1Q 2p", "-e", "9p"], "1\n2\n9\n"),
+        (&["-e", "[3Q 1p]x 2p", "-e", "9p"], "2\n9\n"),
+        (&["--flavor=bsd", "-e", "[3Q 1p]x 2p", "-e", "9p"],
+            "dc4: -e expression #1: Q command argument exceeded string execution depth\n1\n2\n9\n"),
+    ];
+    for (args, expected) in cases {
+        let out = dc4(args);
+        assert!(out.status.success(), "{args:?}");
+        assert_eq!(String::from_utf8(out.stdout).unwrap(), *expected, "{args:?}");
+    }
+}
+
+#[test]
+fn test_dump_state() {
+    let out = dc4(&["--dump-state", "-e", "3k 1 2 [x] sa 4 0:b p", "-e", "5 q", "-e", "6"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "2\n");
+    assert_eq!(String::from_utf8(out.stderr).unwrap(),
+        "scale 3, input radix 10, output radix 10\n\
+        stack: 3 values, top first\n\
+        \x20 5\n\
+        \x20 2\n\
+        \x20 1\n\
+        register 'a' (0141): [x], stack depth 1, 0 array entries\n\
+        register 'b' (0142): no value, stack depth 1, 1 array entry\n");
+}
+
+// Panics on demand are only in debug builds.
+#[cfg(debug_assertions)]
+#[test]
+fn test_internal_error() {
+    let out = Command::new(env!("CARGO_BIN_EXE_dc4"))
+        .args(["-e", "1n [still buffered]n", "-e", "boom", "-e", "2p"])
+        .env_remove("HOME")
+        .env("DC4_TEST_PANIC", "boom")
+        .output()
+        .expect("failed to run dc4");
+    assert_eq!(out.status.code(), Some(70));
+    // Output from before the panic isn't lost, and nothing runs after it.
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1still buffered");
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.starts_with("dc4: internal error: test panic on \"boom\" at src/main.rs:"),
+        "{stderr}");
+    assert!(stderr.ends_with("dc4: this is a bug in dc4; please report it\n"), "{stderr}");
+}
+
+#[test]
+fn test_file_preamble() {
+    // A byte order mark is skipped, in files and expressions.
+    let file = program_file("bom.dc", b"\xEF\xBB\xBF1p\n");
+    let out = dc4(&["-e", "\u{feff}1p", "-f", file.to_str().unwrap()]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1\n1\n");
+
+    // Carriage returns are whitespace, even where they end a comment.
+    let file = program_file("crlf.dc", b"#!/usr/bin/dc4\r\n1 2\r\n+p # sum\r\n[a\r\nb]P\r\n");
+    let out = dc4(&[file.to_str().unwrap()]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "3\na\r\nb");
+
+    // A lone carriage return doesn't end a comment, unless it's a #! line and --permissive says
+    // to skip it.
+    let file = program_file("cr.dc", b"#!/usr/bin/dc4\r1p\r2p\r");
+    let file = file.to_str().unwrap();
+    assert_eq!(String::from_utf8(dc4(&[file]).stdout).unwrap(), "");
+    let out = dc4(&["--permissive", file]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "1\n2\n");
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mmap() {
+    // A mapped file runs just the same as one that's read, errors and all.
+    let file = program_file("mmap.dc", b"#!/usr/bin/dc4\r1p\n2 [a]+\n[done]p");
+    let file = file.to_str().unwrap();
+    let read = String::from_utf8(dc4(&["--permissive", file]).stdout).unwrap();
+    let mapped = String::from_utf8(dc4(&["--permissive", "--mmap", file]).stdout).unwrap();
+    assert_eq!(mapped, read);
+    assert_eq!(mapped, format!("1\ndc4: {file}: non-numeric value\ndone\n"));
+
+    // Things which can't be mapped are read instead.
+    #[cfg(unix)]
+    {
+        let out = dc4_stdin(&["--mmap", "/dev/stdin"], b"3p");
+        assert_eq!(String::from_utf8(out.stdout).unwrap(), "3\n");
+        let empty = program_file("empty.dc", b"");
+        let out = dc4(&["--mmap", empty.to_str().unwrap(), "-e", "4p"]);
+        assert_eq!(String::from_utf8(out.stdout).unwrap(), "4\n");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_shebang_script() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // The #! line can't end in a carriage return, or the kernel takes it as part of the
+    // interpreter's arguments, but the rest can.
+    let script = format!("#!{}\n[hello from a script]p\r\n", env!("CARGO_BIN_EXE_dc4"));
+    let path = program_file("script.dc", script.as_bytes());
+    std::fs::set_permissions(&*path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let out = Command::new(&*path).env_remove("HOME").output().expect("failed to run script");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "hello from a script\n");
+}
+
+#[test]
+fn test_stdlib() {
+    let out = dc4(&["--stdlib=math,array", "-e", "10lNxp 3 0:Y 1 1:Y 2 2:Y 3lSx 0;Yn 1;Yn 2;Yp"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "3628800\n123\n");
+
+    let out = dc4(&["--stdlib=maths", "-e", "1p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+        "invalid library package \"maths\": must be one of 'math', 'stack', 'array'.\n");
+}
+
+#[test]
+fn test_binary_output() {
+    // Output which isn't UTF-8, with a character split across two writes, comes out unchanged
+    // when redirected, on any platform.
+    let out = dc4_stdin(&[], b"[ab\xfe\x00\xffcd]P 226P 130P 172P [\xe2]P 10P");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"ab\xfe\x00\xffcd\xe2\x82\xac\xe2\n");
+}
+
+#[test]
+fn test_check() {
+    let fixture = |name| format!("{}/tests/fixtures/lint/{name}", env!("CARGO_MANIFEST_DIR"));
+    let (clean, issues, bom_crlf) = (fixture("clean.dc"), fixture("issues.dc"),
+        fixture("bom_crlf.dc"));
+
+    let out = dc4(&["--check", &clean]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "dc4: no problems found in 1 input\n");
+
+    let out = dc4(&["--check", &clean, &issues, &bom_crlf]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), format!("\
+        {issues}:2:5: '&' (046) unimplemented\n\
+        {issues}:4:10: running shell commands is not supported\n\
+        {issues}:6:1: unterminated string\n\
+        {bom_crlf}:1:1: '&' (046) unimplemented\n\
+        {bom_crlf}:2:3: 'G' (0107) unimplemented\n\
+        dc4: 5 problems found in 2 of 3 inputs\n"));
+    // Nothing was run.
+    assert!(out.stderr.is_empty());
+
+    // The flavor decides what's a command, and --strict-digits adds digits too big for the radix.
+    let out = dc4(&["--check", "--flavor=gavin", "--strict-digits", &bom_crlf, &issues]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), format!("\
+        {issues}:3:1: digit 'A' is not valid in input radix 10; the number is read as 20\n\
+        {issues}:4:10: running shell commands is not supported\n\
+        {issues}:6:1: unterminated string\n\
+        dc4: 3 problems found in 1 of 2 inputs\n"));
+
+    // So does --scientific-notation, for exponents.
+    let out = dc4(&["--check", "-e", "1e5 p"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "\
+        -e expression #1:1:2: 'e' (0145) unimplemented\n\
+        dc4: 1 problem found in 1 of 1 input\n");
+    let out = dc4(&["--scientific-notation", "--check", "-e", "1e5 p"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "dc4: no problems found in 1 input\n");
+
+    // Expressions and standard input are checked too, and an unreadable file is worse than
+    // problems.
+    let out = dc4_stdin(&["--check", "-e", "1 2 & p", &fixture("missing.dc"), "-"], b"[abc");
+    assert_eq!(out.status.code(), Some(2));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), format!("\
+        -e expression #1:1:5: '&' (046) unimplemented\n\
+        dc4: {}: reading failed: No such file or directory (os error 2)\n\
+        <stdin>:1:5: unterminated string\n\
+        dc4: 2 problems found in 2 of 3 inputs\n", fixture("missing.dc")));
+}
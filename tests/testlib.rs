@@ -16,9 +16,26 @@ fn dc4_run_bytes(expr: &[u8]) -> Vec<u8> {
 
     dc.text(expr.to_vec(), &mut out);
 
+    assert_eq!(out, dc4_run_compiled(expr, dc4::parser::Flavor::default()),
+        "pre-parsed program gave different output: {}", String::from_utf8_lossy(expr));
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut slice_out = Vec::<u8>::new();
+    dc.run_slice(expr, &mut slice_out);
+    assert_eq!(out, slice_out,
+        "program run from a slice gave different output: {}", String::from_utf8_lossy(expr));
     out
 }
 
+fn dc4_run_tco(expr: &[u8], tail_call_optimization: bool) -> String {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.set_tail_call_optimization(tail_call_optimization);
+    let mut out = Vec::<u8>::new();
+
+    dc.text(expr.to_vec(), &mut out);
+
+    String::from_utf8(out).unwrap()
+}
+
 fn dc4_run_two(expr1: &[u8], expr2: &[u8]) -> String {
     let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
     let mut out = Vec::<u8>::new();
@@ -39,10 +56,44 @@ fn test_at() {
     let ver = env!("CARGO_PKG_VERSION_MAJOR").parse::<u64>().unwrap() << 24
             | env!("CARGO_PKG_VERSION_MINOR").parse::<u64>().unwrap() << 16
             | env!("CARGO_PKG_VERSION_PATCH").parse::<u64>().unwrap();
-    assert_eq!(dc4_run(b"@f"), format!("dc4\n{ver}\n"));
+    let features = dc4::features();
+    assert_eq!(dc4_run(b"@f"), format!("dc4\n{ver}\n{features}\n"));
     assert_eq!(dc4_run(b"@r0+"), ""); // ensure the version is a number
 }
 
+#[test]
+fn test_features() {
+    // Names are only ever added to the end of the list, so that programs which have checked for
+    // it keep working. When adding one, add to this; never change what's already here.
+    use dc4::parser::Flavor;
+    let known = "print-indexed,print-no-newline,scoped-registers,root,round,floor-ceil-trunc,abs,\
+        min-max,gcd,lcm,factorial,shift,random,mod-inverse";
+    assert!(dc4::features().starts_with(known), "{}", dc4::features());
+    assert!(!dc4::features().contains(' '));
+    // Each flavor lists the extensions it has, in the same order.
+    assert_eq!(dc4::features(), dc4::features_for(Flavor::Gnu));
+    assert_eq!(dc4::features_for(Flavor::Bsd), dc4::features_for(Flavor::Gnu));
+    let known = "print-indexed,print-no-newline,scoped-registers,shift,exp,ln,log,arctan,bitops,\
+        records";
+    let gavin = dc4::features_for(Flavor::Gavin);
+    assert!(gavin.starts_with(known), "{gavin}");
+}
+
+#[test]
+fn test_feature_probe() {
+    use dc4::parser::Flavor;
+    // The idiom suggested for Action::Version: on dc4, @ pushes three values, so d runs.
+    let probe = "[sn sv sf 1sh]sd 0sh zsz @ z lz- 3=d lhp";
+    for flavor in [Flavor::Gnu, Flavor::Bsd, Flavor::Gavin] {
+        let out = dc4_run_flavor(format!("{probe} lfp lnp").as_bytes(), flavor);
+        assert_eq!(out, format!("1\n{}\ndc4\n", dc4::features_for(flavor)), "{flavor:?}");
+    }
+    // Other dcs don't have @, which is like any other unknown command to them, so simulate that
+    // with one dc4 doesn't have either.
+    let out = dc4_run(probe.replace('@', "\x01").as_bytes());
+    assert!(out.ends_with("unimplemented\n0\n"), "{out}");
+}
+
 #[test]
 fn test_f() {
     assert_eq!(dc4_run(b"1 2 3 f"), "3\n2\n1\n");
@@ -65,6 +116,67 @@ fn test_weird_overflow() {
     assert_eq!(dc4_run(b"12A3 f"), "1303\n");
 }
 
+#[test]
+fn test_strict_digits() {
+    let run = |expr: &[u8], strict: bool| {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        dc.set_strict_digits(strict);
+        let mut out = Vec::<u8>::new();
+        dc.text(expr.to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+
+    assert_eq!(run(b"12A3 f", false), "1303\n");
+    assert_eq!(run(b"12A3 f", true), "dc4 cargo test: digit 'A' is not valid in input radix 10\n");
+    // The whole number is skipped, and nothing else.
+    assert_eq!(run(b"1 2F.F 3 f", false), "3\n36.5\n1\n");
+    assert_eq!(run(b"1 2F.F 3 f", true), "dc4 cargo test: digit 'F' is not valid in input radix 10\n3\n1\n");
+    assert_eq!(run(b"2i 12 f", true), "dc4 cargo test: digit '2' is not valid in input radix 2\n");
+
+    // A single digit is always allowed, so this gets back to decimal.
+    assert_eq!(run(b"2i Ai 10 F f", true), "15\n10\n");
+
+    // Hex digits are fine in hex.
+    assert_eq!(run(b"16i 12A3 FF.F f", true), "255.9\n4771\n");
+
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.set_strict_digits(true);
+    assert!(matches!(dc.push_number("1A"), Err(dc4::DcError::DigitInvalid(b'A', 10))));
+    assert!(matches!(dc.take_result(), Err(dc4::DcError::StackDepth(0))));
+}
+
+#[test]
+fn test_skip_bom() {
+    let run = |input: &[u8], skip: bool, stream: bool| {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        dc.set_skip_bom(skip);
+        let mut out = Vec::<u8>::new();
+        if stream {
+            dc.stream(&mut &input[..], &mut out);
+        } else {
+            dc.text(input.to_vec(), &mut out);
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    };
+
+    for stream in [false, true] {
+        assert_eq!(run(b"\xEF\xBB\xBF1p", true, stream), "1\n");
+        assert_eq!(run(b"\xEF\xBB\xBF1p", false, stream), concat!(
+            "dc4 cargo test: '\u{ef}' (0357) unimplemented\n",
+            "dc4 cargo test: '\u{bb}' (0273) unimplemented\n",
+            "dc4 cargo test: '\u{bf}' (0277) unimplemented\n",
+            "1\n"));
+        // Only one, only at the start, and only a whole one.
+        assert_eq!(run(b"\xEF\xBB\xBF\xEF\xBB\xBF1p", true, stream).lines().count(), 4);
+        assert_eq!(run(b"1p\xEF\xBB\xBF", true, stream).lines().count(), 4);
+        assert_eq!(run(b"\xEF\xBB1p", true, stream).lines().count(), 3);
+        assert_eq!(run(b"", true, stream), "");
+    }
+
+    // Strings run as macros aren't inputs.
+    assert_eq!(run(b"[\xEF\xBB\xBF2p]x", true, false).lines().count(), 4);
+}
+
 #[test]
 fn test_p() {
     assert_eq!(dc4_run(b"1 2 3 p"), "3\n");
@@ -79,6 +191,27 @@ fn test_n() {
     assert_eq!(dc4_run(b"n"), "dc4 cargo test: stack empty\n");
 }
 
+#[test]
+fn test_dup_swap() {
+    use dc4::parser::Flavor;
+    // Like GNU dc, these report an empty stack and leave it as it was.
+    let cases: &[(&[u8], &str)] = &[
+        (b"d f", "stack empty\n"),
+        (b"r f", "stack empty\n"),
+        (b"1 r f", "stack empty\n1\n"),
+        (b"1 d f", "1\n1\n"),
+        (b"1 2 r f", "1\n2\n"),
+        (b"d r 1 d r f", "stack empty\nstack empty\n1\n1\n"),
+        (b"1 r d r f", "stack empty\n1\n1\n"),
+        (b"c d 2 r d f", "stack empty\nstack empty\n2\n2\n"),
+    ];
+    for (program, expected) in cases {
+        let expected = expected.replace("stack empty", "dc4 cargo test: stack empty");
+        assert_eq!(dc4_run_flavor(program, Flavor::Gnu), expected,
+            "{}", String::from_utf8_lossy(program));
+    }
+}
+
 #[test]
 fn test_string_basic() {
     assert_eq!(dc4_run(b"[Hello, World!]f"), "Hello, World!\n");
@@ -143,7 +276,125 @@ fn test_invalid_arithmetic() {
     assert_eq!(dc4_run(b"3 0 /f"), "dc4 cargo test: divide by zero\n0\n3\n");
     assert_eq!(dc4_run(b"3 0 %f"), "dc4 cargo test: remainder by zero\n0\n3\n");
     assert_eq!(dc4_run(b"3 0 ~f"), "dc4 cargo test: divide by zero\n0\n3\n");
-    assert_eq!(dc4_run(b"3 2.5 ^f"), "dc4 cargo test: warning: non-zero scale in exponent\n9\n");
+}
+
+#[test]
+fn test_fractional_exponent() {
+    use dc4::parser::Flavor;
+    // GNU dc truncates the exponent without saying anything, BSD dc warns if the fractional part
+    // isn't zero, and Gavin's dc refuses.
+    assert_eq!(dc4_run_flavor(b"3 2.5 ^f", Flavor::Gnu), "9\n");
+    assert_eq!(dc4_run_flavor(b"3 2.5 ^f", Flavor::Bsd),
+        "dc4 cargo test: warning: non-zero fractional part in exponent\n9\n");
+    assert_eq!(dc4_run_flavor(b"3 2.5 ^f", Flavor::Gavin),
+        "dc4 cargo test: non-integer number\n2.5\n3\n");
+    for flavor in [Flavor::Gnu, Flavor::Bsd, Flavor::Gavin] {
+        assert_eq!(dc4_run_flavor(b"3 2.0 ^f", flavor), "9\n", "{flavor}");
+    }
+}
+
+#[test]
+fn test_error_operands() {
+    // What's left on the stack after an error: commands that pop and then validate consume their
+    // operands; arithmetic and comparisons restore them, in their original order.
+    let cases: &[(&[u8], &str)] = &[
+        (b"1 [bad]i f", "input base must be a number between 2 and 16 (inclusive)\n1\n"),
+        (b"1 17i f", "input base must be a number between 2 and 16 (inclusive)\n1\n"),
+        (b"1 [bad]o f", "output base must be a number between 2 and 16 (inclusive)\n1\n"),
+        (b"1 1o f", "output base must be a number between 2 and 16 (inclusive)\n1\n"),
+        (b"1 [bad]k f", "scale must be a nonnegative integer\n1\n"),
+        (b"1 _1k f", "scale must be a nonnegative integer\n1\n"),
+        (b"1 [bad]Q f", "Q command requires a number >= 1\n1\n"),
+        (b"1 0Q f", "Q command requires a number >= 1\n1\n"),
+        (b"1 2 [bad]:x f", "array index must be a nonnegative integer, not a string\n1\n"),
+        (b"1 2 _1:x f", "array index must be a nonnegative integer\n1\n"),
+        (b"1 :x f", "stack empty\n"),
+        (b"1 [bad];x f", "array index must be a nonnegative integer, not a string\n1\n"),
+        (b"1 [bad]v f", "square root of nonnumeric attempted\n1\n"),
+        (b"1 _4v f", "square root of negative number\n1\n"),
+        (b"1 [bad]2+ f", "non-numeric value\n2\nbad\n1\n"),
+        (b"1 2 0/ f", "divide by zero\n0\n2\n1\n"),
+        (b"1 2 0~ f", "divide by zero\n0\n2\n1\n"),
+        (b"7 + f", "stack empty\n7\n"),
+        (b"7 r f", "stack empty\n7\n"),
+        (b"1 [bad]2 3| f", "non-numeric value\n3\n2\nbad\n1\n"),
+        (b"2 _1 [bad]| f", "non-numeric value\nbad\n-1\n2\n"),
+        (b"2 _1 0| f", "remainder by zero\n0\n-1\n2\n"),
+        (b"1 [bad]2>x f", "non-numeric value\n2\nbad\n1\n"),
+        (b"1 2 3>x f", "register 'x' (0170) is empty\n1\n"),
+        (b"1 lx f", "register 'x' (0170) is empty\n1\n"),
+        (b"1 Lx f", "stack register 'x' (0170) is empty\n1\n"),
+    ];
+    for (program, expected) in cases {
+        assert_eq!(dc4_run(program), format!("dc4 cargo test: {expected}"),
+            "program: {}", String::from_utf8_lossy(program));
+    }
+}
+
+#[test]
+fn test_gnu_string_operands() {
+    // What each of GNU dc's commands does with a string where it wants a number, or a number
+    // where it wants a string: the program, what it prints, and the stack it leaves (as printed
+    // by 'f'). A few commands accept either; everything else is an error, and a string that
+    // looks like a number is never converted to one.
+    let run = |program: &[u8]| {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        let mut stack = Vec::<u8>::new();
+        dc.text(b"f".to_vec(), &mut stack);
+        (String::from_utf8(out).unwrap(), String::from_utf8(stack).unwrap())
+    };
+    let err = |msg: &str| format!("dc4 cargo test: {msg}\n");
+    let non_numeric = err("non-numeric value");
+    let cases: &[(&[u8], String, &str)] = &[
+        // Commands which take either.
+        (b"[abc]Z", String::new(), "3\n"),
+        (b"[]Z", String::new(), "0\n"),
+        (b"[abc]X", String::new(), "0\n"),
+        (b"[1.25]X", String::new(), "0\n"),
+        (b"[abc]a", String::new(), "a\n"),
+        (b"65a", String::new(), "A\n"),
+        (b"5x", String::new(), "5\n"),
+        (b"[abc]p", "abc\n".to_owned(), "abc\n"),
+        (b"[abc]n", "abc".to_owned(), ""),
+        (b"[abc]P", "abc".to_owned(), ""),
+        (b"[abc]d", String::new(), "abc\nabc\n"),
+        (b"1[abc]r", String::new(), "1\nabc\n"),
+        (b"[abc]z", String::new(), "1\nabc\n"),
+        (b"[abc]sx lx", String::new(), "abc\n"),
+        (b"[abc]Sx Lx", String::new(), "abc\n"),
+        (b"[abc]0:x 0;x", String::new(), "abc\n"),
+        (b"[abc]c", String::new(), ""),
+        // Commands which pop a number and check it: the string is consumed.
+        (b"[abc]i", err("input base must be a number between 2 and 16 (inclusive)"), ""),
+        (b"[3]i 11", err("input base must be a number between 2 and 16 (inclusive)"), "11\n"),
+        (b"[abc]o", err("output base must be a number between 2 and 16 (inclusive)"), ""),
+        (b"[abc]k", err("scale must be a nonnegative integer"), ""),
+        (b"[2]k 1 3/", err("scale must be a nonnegative integer"), "0\n"),
+        (b"[abc]Q", err("Q command requires a number >= 1"), ""),
+        (b"[abc]v", err("square root of nonnumeric attempted"), ""),
+        (b"[4]v", err("square root of nonnumeric attempted"), ""),
+        (b"[abc];x", err("array index must be a nonnegative integer, not a string"), ""),
+        (b"1 2[0];x", err("array index must be a nonnegative integer, not a string"), "2\n1\n"),
+        (b"1 2[0]:x", err("array index must be a nonnegative integer, not a string"), "1\n"),
+        // Arithmetic and comparisons: the operands are left as they were.
+        (b"1[abc]+", non_numeric.clone(), "abc\n1\n"),
+        (b"[2]3+", non_numeric.clone(), "3\n2\n"),
+        (b"[abc]1-", non_numeric.clone(), "1\nabc\n"),
+        (b"[abc]1*", non_numeric.clone(), "1\nabc\n"),
+        (b"[abc]1/", non_numeric.clone(), "1\nabc\n"),
+        (b"[abc]1%", non_numeric.clone(), "1\nabc\n"),
+        (b"[abc]1~", non_numeric.clone(), "1\nabc\n"),
+        (b"2[abc]^", non_numeric.clone(), "abc\n2\n"),
+        (b"[abc]2 3|", non_numeric.clone(), "3\n2\nabc\n"),
+        (b"[[ran]p]sx [abc]1<x", non_numeric.clone(), "1\nabc\n"),
+        (b"[[ran]p]sx 1[abc]>x", non_numeric.clone(), "abc\n1\n"),
+    ];
+    for (program, output, stack) in cases {
+        assert_eq!(run(program), (output.clone(), stack.to_string()),
+            "program: {}", String::from_utf8_lossy(program));
+    }
 }
 
 #[test]
@@ -198,12 +449,68 @@ fn test_conditional_macro() {
 #[test]
 fn test_array() {
     assert_eq!(dc4_run(b"7 [hello] 42:x f c 42;x f"), "7\nhello\n");
-    assert_eq!(dc4_run(b"7 [hello] [bogus] :x f"), "dc4 cargo test: array index must be a nonnegative integer\n7\n");
+    assert_eq!(dc4_run(b"7 [hello] [bogus] :x f"), "dc4 cargo test: array index must be a nonnegative integer, not a string\n7\n");
     assert_eq!(dc4_run(b"42 ;x f"), "0\n");
     assert_eq!(dc4_run(b";x f"), "dc4 cargo test: stack empty\n");
-    assert_eq!(dc4_run(b"[bogus];x f"), "dc4 cargo test: array index must be a nonnegative integer\n");
+    assert_eq!(dc4_run(b"[bogus];x f"), "dc4 cargo test: array index must be a nonnegative integer, not a string\n");
+
+    // Strings aren't indexes even if they look like numbers, as in GNU dc.
+    for index in ["[42]", "[]", "[_1]"] {
+        let err = "dc4 cargo test: array index must be a nonnegative integer, not a string\n";
+        assert_eq!(dc4_run(format!("7 [hello] {index}:x f").as_bytes()), format!("{err}7\n"));
+        assert_eq!(dc4_run(format!("7 {index};x f").as_bytes()), format!("{err}7\n"));
+    }
+    assert_eq!(dc4_run(b"7 _1;x f"),
+        "dc4 cargo test: array index must be a nonnegative integer\n7\n");
+    // Indexes from Z and z are numbers.
+    assert_eq!(dc4_run(b"[a] [abc]Z:x [b] z:x 3;x 1;x f"), "b\na\n");
 
     assert_eq!(dc4_run(b"1 0:a 0Sa 2 0:a La 0;a f"), "1\n0\n");
+
+    // Indexes are truncated to integers.
+    assert_eq!(dc4_run(b"[a] 1.5:x 1;x 1.99;x [b] 1.000:x 1.5;x f"), "b\na\na\n");
+}
+
+#[test]
+fn test_register_array_levels() {
+    // Each level of a register has its own value and array, as in GNU dc. See DcRegisterStack
+    // for the whole model.
+    let cases: &[(&[u8], &str)] = &[
+        // s keeps the array, whether it was set before or after.
+        (b"1 0:x 5sx 0;x lx f", "5\n1\n"),
+        (b"5sx 1 0:x 6sx 0;x lx f", "6\n1\n"),
+        // S starts a new, empty array, and L brings back the one underneath.
+        (b"1 0:x 5Sx 0;x Lx 0;x f", "1\n5\n0\n"),
+        (b"5Sx 1 0:x Lx 0;x f", "0\n5\n"),
+        (b"5sx 1 0:x 6Sx 2 0:x 7sx 0;x Lx 0;x lx f", "5\n1\n7\n2\n"),
+        // A level made by : has no value, so L fails and leaves it alone, as does l.
+        (b"1 0:x Lx 0;x f", "dc4 cargo test: stack register 'x' (0170) is empty\n1\n"),
+        (b"1 0:x lx 0;x f", "dc4 cargo test: register 'x' (0170) is empty\n1\n"),
+        (b"1 0:x 5Sx Lx Lx 0;x f", "dc4 cargo test: stack register 'x' (0170) is empty\n1\n5\n"),
+        // A macro and an array can share a register.
+        (b"[[hi]p]sx 7 0:x lxx 0;x p", "hi\n7\n"),
+        (b"[[hi]p]sx 7 0:x [[bye]p]Sx lxx 0;x p Lx lxx 0;x p", "bye\n0\nhi\n7\n"),
+        (b"[dd:x1-d0<x]sx 3lxx 0;x 1;x 2;x 3;x f", "3\n2\n1\n0\n0\n"),
+        // t saves the whole level, array included.
+        (b"1 0:x [tx 2 0:x 9sx 0;x p]x 0;x p lx",
+            "2\n1\ndc4 cargo test: register 'x' (0170) is empty\n"),
+    ];
+    for (program, expected) in cases {
+        assert_eq!(dc4_run(program), *expected, "program: {}", String::from_utf8_lossy(program));
+    }
+}
+
+#[test]
+fn test_print_newline_pop() {
+    use dc4::parser::Flavor;
+    // p and J leave the value on the stack; n pops it. Only p adds a newline, for strings as well
+    // as numbers, in every flavor: the BSD man page says p prints "with a newline" too.
+    for flavor in [Flavor::Gnu, Flavor::Bsd, Flavor::Gavin] {
+        assert_eq!(dc4_run_flavor(b"[hi]p 42p f", flavor), "hi\n42\n42\nhi\n");
+        assert_eq!(dc4_run_flavor(b"[hi]J 42J f", flavor), "hi4242\nhi\n");
+        assert_eq!(dc4_run_flavor(b"[hi]n 42n f", flavor), "hi42");
+        assert_eq!(dc4_run_flavor(b"J", flavor), "dc4 cargo test: stack empty\n");
+    }
 }
 
 #[test]
@@ -241,6 +548,7 @@ fn test_quitlevels() {
     // frame, and without precautions, the 2Q will quit the main frame as well.
 
     assert_eq!(dc4_run(program.as_bytes()), "43done\n");
+    assert_eq!(dc4_run_tco(program.as_bytes(), false), "43done\n");
 }
 
 #[test]
@@ -262,12 +570,148 @@ fn test_quitlevels2() {
     // level exits.
 
     assert_eq!(dc4_run(program.as_bytes()), "82\n");
+    assert_eq!(dc4_run_tco(program.as_bytes(), false), "82\n");
 }
 
 #[test]
 fn test_quitlevels3() {
-    assert_eq!(dc4_run(b"[[[[q]x1p]x2p]x3p]x4p"), "2\n3\n4\n");
-    assert_eq!(dc4_run(b"[q]s1 [l1x]s2 [l2x]s3 l3x [three]p l2x [two]p l1x [one]p"), "three\ntwo\n");
+    for tco in [true, false] {
+        assert_eq!(dc4_run_tco(b"[[[[q]x1p]x2p]x3p]x4p", tco), "2\n3\n4\n");
+        assert_eq!(dc4_run_tco(b"[q]s1 [l1x]s2 [l2x]s3 l3x [three]p l2x [two]p l1x [one]p", tco),
+            "three\ntwo\n");
+    }
+}
+
+#[test]
+fn test_quitlevels_tail_calls() {
+    // Quitting through a chain of tail calls (x -> y -> q) counts each of them as a level, the
+    // same as it would if they weren't elided.
+    let cases: &[(&[u8], &str)] = &[
+        (b"[1Q]sq [lqx]sy [lyx]sx [lxx [one]p]x [main]p", "one\nmain\n"),
+        (b"[2Q]sq [lqx]sy [lyx [y]p]sx [lxx [one]p]x [main]p", "y\none\nmain\n"),
+        (b"[3Q]sq [lqx]sy [lyx]sx [[lxx [two]p]x [one]p]x [main]p", "two\none\nmain\n"),
+        (b"[4Q]sq [lqx]sy [lyx]sx [[lxx [two]p]x [one]p]x [main]p", "one\nmain\n"),
+        (b"[2Q]sq [0 0=q]sy [0 0=y]sx [[0 0=x [two]p]x [one]p]x [main]p", "two\none\nmain\n"),
+        (b"[q]sq [lqx]sy [lyx]sx [lxx [one]p]x [main]p", "one\nmain\n"),
+    ];
+    for (program, expected) in cases {
+        assert_eq!(dc4_run_tco(program, true), *expected);
+        assert_eq!(dc4_run_tco(program, false), *expected);
+    }
+}
+
+#[test]
+fn test_quitlevels_if_else() {
+    use dc4::DcResult;
+    use dc4::parser::Flavor;
+
+    // BSD's '=xey' runs one of two macros as a single level, whichever branch is taken, exactly
+    // like a plain '=x'. These mirror the quit-level tests above, taking each branch in turn.
+    // (program, output, whether the program ends)
+    let cases: &[(&str, &str, bool)] = &[
+        ("[q]sq []sn 1 1 =qen 3p", "", true),
+        ("[q]sq []sn 1 2 =neq 3p", "", true),
+        ("[q]sq []sn [1 1 =qen 4p]x 3p", "3\n", false),
+        ("[q]sq []sn [1 2 =neq 4p]x 3p", "3\n", false),
+        ("[2Q]sq []sn [[1 1 =qen [two]p]x [one]p]x [main]p", "one\nmain\n", false),
+        ("[2Q]sq []sn [[1 2 =neq [two]p]x [one]p]x [main]p", "one\nmain\n", false),
+        ("[3Q]sq []sn [[[1 2 =neq [three]p]x [two]p]x [one]p]x [main]p", "one\nmain\n", false),
+        // In tail position, the chosen macro replaces the one running it, and quitting still
+        // counts each of them as a level.
+        ("[2Q]sq []sn [1 2=neq]sy [1 1=yen]sx [[1 2=nex [two]p]x [one]p]x [main]p",
+            "two\none\nmain\n", false),
+        ("[3Q]sq []sn [1 2=neq]sy [1 1=yen]sx [[1 2=nex [two]p]x [one]p]x [main]p",
+            "two\none\nmain\n", false),
+        ("[4Q]sq []sn [1 2=neq]sy [1 1=yen]sx [[1 2=nex [two]p]x [one]p]x [main]p",
+            "one\nmain\n", false),
+        ("[q]sq []sn [1 1=qen]sy [1 2=ney]sx [lxx [one]p]x [main]p", "one\nmain\n", false),
+        ("[4Q]sq []sn [1 1=qen]sy [1 2=ney]sx [1 1=xen [one]p]x [main]p", "main\n", false),
+    ];
+    for (program, expected, ends) in cases {
+        assert_eq!(dc4_run_flavor(program.as_bytes(), Flavor::Bsd), *expected, "{program:?}");
+        for tco in [true, false] {
+            for stream in [false, true] {
+                let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), Flavor::Bsd);
+                dc.set_tail_call_optimization(tco);
+                let mut out = Vec::<u8>::new();
+                let result = if stream {
+                    dc.stream(&mut program.as_bytes(), &mut out)
+                } else {
+                    dc.text(program.as_bytes().to_vec(), &mut out)
+                };
+                let context = format!("{program:?} tco {tco} stream {stream}");
+                assert_eq!(String::from_utf8(out).unwrap(), *expected, "{context}");
+                assert_eq!(matches!(result, DcResult::Terminate(_)), *ends, "{context}: {result:?}");
+            }
+        }
+    }
+
+    // An if-else at the end of a macro is elided as a tail call, whichever branch it takes. The
+    // last loop ends by running 'n' as well.
+    let cases: &[(&[u8], u64)] = &[
+        (b"[1-d0<x]sx 10 lxx", 10),
+        (b"[]sn [1-d0<xen]sx 10 lxx", 11),
+        (b"[]sn [1-d0>nex]sx 10 lxx", 12),
+    ];
+    for (program, elided) in cases {
+        let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), Flavor::Bsd);
+        dc.text(program.to_vec(), &mut Vec::new());
+        assert_eq!(dc.stats().tail_calls_elided, *elided, "{}", String::from_utf8_lossy(program));
+    }
+}
+
+#[test]
+fn test_quit_at_top_level() {
+    use dc4::DcResult;
+    use dc4::parser::Flavor;
+
+    // (program, flavor, output, whether the program ends)
+    let too_deep = "dc4 cargo test: Q command argument exceeded string execution depth\n";
+    let cases: &[(&str, &[Flavor], String, bool)] = &[
+        // 'q' ends the program from the top level, or from one macro deep.
+        ("1p q 2p", &[Flavor::Gnu, Flavor::Bsd, Flavor::Gavin], "1\n".into(), true),
+        ("[1p q 2p]x 3p", &[Flavor::Gnu, Flavor::Bsd, Flavor::Gavin], "1\n".into(), true),
+        ("[[q]x 1p]x 2p", &[Flavor::Gnu, Flavor::Bsd, Flavor::Gavin], "2\n".into(), false),
+        // 'Q' never quits the input itself.
+        ("1Q 2p", &[Flavor::Gnu, Flavor::Gavin], "2\n".into(), false),
+        ("[3Q 1p]x 2p", &[Flavor::Gnu, Flavor::Gavin], "2\n".into(), false),
+        ("[[3Q 1p]x 2p]x 3p", &[Flavor::Gnu, Flavor::Gavin], "3\n".into(), false),
+        // In BSD, quitting too many levels doesn't quit any.
+        ("1Q 2p", &[Flavor::Bsd], format!("{too_deep}2\n"), false),
+        ("[3Q 1p]x 2p", &[Flavor::Bsd], format!("{too_deep}1\n2\n"), false),
+        ("[[2Q 1p]x 2p]x 3p", &[Flavor::Bsd], "3\n".into(), false),
+    ];
+    for (program, flavors, expected, ends) in cases {
+        for &flavor in *flavors {
+            for tco in [true, false] {
+                for stream in [false, true] {
+                    let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), flavor);
+                    dc.set_tail_call_optimization(tco);
+                    let mut out = Vec::<u8>::new();
+                    let result = if stream {
+                        dc.stream(&mut program.as_bytes(), &mut out)
+                    } else {
+                        dc.text(program.as_bytes().to_vec(), &mut out)
+                    };
+                    let context = format!("{program:?} flavor {flavor} tco {tco} stream {stream}");
+                    assert_eq!(String::from_utf8(out).unwrap(), *expected, "{context}");
+                    assert_eq!(matches!(result, DcResult::Terminate(_)), *ends, "{context}: {result:?}");
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_tail_call_stats() {
+    // The first call is also a tail call: it's at the end of the program text.
+    let program = b"[1-d0<x]sx 10 lxx";
+    for (tco, elided) in [(true, 10), (false, 0)] {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        dc.set_tail_call_optimization(tco);
+        dc.text(program.to_vec(), &mut Vec::new());
+        assert_eq!(dc.stats().tail_calls_elided, elided);
+    }
 }
 
 #[test]
@@ -321,6 +765,77 @@ fn test_decimal() {
     assert_eq!(dc4_run(b"1.2 1.002 +f"), "2.202\n");
 }
 
+#[test]
+fn test_fractional_exponents() {
+    let run = |program: &[u8], flavor| {
+        let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), flavor);
+        dc.set_fractional_exponents(true);
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+    use dc4::parser::Flavor;
+    assert_eq!(run(b"10k 2 0.5 ^ p", Flavor::Gnu), "1.4142135623\n");
+    assert_eq!(run(b"20k 3 2.5 ^ p 10 _1.5 ^ p .5 .5 ^ p", Flavor::Gnu),
+        "15.58845726811989564174\n.03162277660168379331\n.70710678118654752440\n");
+    assert_eq!(run(b"30k 1000 .25 ^ p", Flavor::Gnu), "5.623413251903490803949510397764\n");
+    assert_eq!(run(b"5k 2 100.5 ^ p", Flavor::Gnu), "1792728671193156477399422023278.66149\n");
+    // Exact results come out exact, and the base's scale counts.
+    assert_eq!(run(b"4 .5 ^ p 16 .25 ^ p 100 1.5 ^ p 9.00 .5 ^ p", Flavor::Gnu),
+        "2\n2\n1000\n3.00\n");
+    // Without a fractional part, nothing changes.
+    assert_eq!(run(b"3 2.0 ^ p 2 _2 ^ p", Flavor::Gnu), "9\n0\n");
+    // Every flavor does the same, with no warnings or errors about the fraction.
+    assert_eq!(run(b"10k 2 0.5 ^ p", Flavor::Bsd), "1.4142135623\n");
+    assert_eq!(run(b"10k 2 0.5 ^ p", Flavor::Gavin), "1.4142135623\n");
+
+    assert_eq!(run(b"0 .5 ^ p", Flavor::Gnu), "0\n");
+    assert_eq!(run(b"0 _.5 ^ f", Flavor::Gnu), "dc4 cargo test: divide by zero\n-.5\n0\n");
+    assert_eq!(run(b"_4 .5 ^ f", Flavor::Gnu),
+        "dc4 cargo test: negative base with fractional exponent\n.5\n-4\n");
+
+    // It's opt-in: normally the fraction is dropped.
+    assert_eq!(dc4_run(b"10k 2 0.5 ^ p"), "1\n");
+}
+
+#[test]
+fn test_exact_fractions() {
+    let run = |program: &[u8], exact: bool| {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        dc.set_exact_fractions(exact);
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+    // (program, normally, exactly)
+    let cases: &[(&[u8], &str, &str)] = &[
+        (b"16i 1.8 p", "1.5\n", "1.5\n"),
+        (b"16i 1.F p", "1.9\n", "1.9375\n"),
+        (b"16i 1.F0 p", "1.93\n", "1.9375\n"),
+        (b"16i _.1 p", "0\n", "-.0625\n"),
+        (b"16i 1.F8 p", "1.96\n", "1.96875\n"),
+        (b"2i 1.1 p", "1.5\n", "1.5\n"),
+        (b"2i .011 p", ".375\n", ".375\n"),
+        (b"2i .0000001 p", ".0078125\n", ".0078125\n"),
+        (b"8i .7 p", ".8\n", ".875\n"),
+        // As few decimal places as the value needs, even fewer than were written.
+        (b"16i 1.0 X p", "1\n", "0\n"),
+        // Thirds and twelfths never end in decimal, so they're cut short: one decimal place for
+        // each digit in radix 3, and two in radix 12 (whose biggest digit, 11, takes two).
+        (b"3i .1 p", ".3\n", ".3\n"),
+        (b"3i .11 p", ".44\n", ".44\n"),
+        (b"Ci .1 p", "0\n", ".08\n"),
+        (b"Ci .6 p", ".5\n", ".5\n"),
+        // Decimal is the same either way.
+        (b"1.250 p", "1.250\n", "1.250\n"),
+    ];
+    for (program, normal, exact) in cases {
+        let program_str = String::from_utf8_lossy(program);
+        assert_eq!(run(program, false), *normal, "{program_str}");
+        assert_eq!(run(program, true), *exact, "{program_str} (exact)");
+    }
+}
+
 #[test]
 fn test_utf8() {
     assert_eq!(dc4_run("[Ā‡🎅]f sa f la f".as_bytes()), "Ā‡🎅\nĀ‡🎅\n");
@@ -339,12 +854,28 @@ fn test_utf8() {
 fn test_modexp() {
     assert_eq!(dc4_run(b"4 13 497 |f"), "445\n");
     assert_eq!(dc4_run(b"4 _13 497 |f"), "dc4 cargo test: negative exponent\n497\n-13\n4\n");
-    assert_eq!(dc4_run(b"4 13.9 497 |f"), "dc4 cargo test: warning: non-zero scale in exponent\n445\n");
     assert_eq!(dc4_run(b"4 13 0 |f"), "dc4 cargo test: remainder by zero\n0\n13\n4\n");
     assert_eq!(dc4_run(b"16o 16i 2946288212CAA2D5B80E1C661006807F 3285C3432ACBCB0F4D0232282ECC73DB 267D2F2E51C216A7DA752EAD48D22D89 |f"),
         "DDC404D916005967425A8D8A066CA56\n");
 }
 
+#[test]
+fn test_modexp_fractional() {
+    use dc4::parser::Flavor;
+    // GNU and BSD dc warn about any fractional digits, even zeros; Gavin's dc refuses non-zero
+    // ones.
+    for flavor in [Flavor::Gnu, Flavor::Bsd] {
+        assert_eq!(dc4_run_flavor(b"4 13.9 497 |f", flavor),
+            "dc4 cargo test: warning: non-zero scale in exponent\n445\n", "{flavor}");
+        assert_eq!(dc4_run_flavor(b"4.0 13 497.0 |f", flavor),
+            "dc4 cargo test: warning: non-zero scale in base\n\
+            dc4 cargo test: warning: non-zero scale in modulus\n445.0000000000000\n", "{flavor}");
+    }
+    assert_eq!(dc4_run_flavor(b"4 13.9 497 |f", Flavor::Gavin),
+        "dc4 cargo test: non-integer number\n497\n13.9\n4\n");
+    assert_eq!(dc4_run_flavor(b"4.0 13 497.0 |f", Flavor::Gavin), "445.0000000000000\n");
+}
+
 #[test]
 fn test_sqrt() {
     assert_eq!(dc4_run(b"[foo] vf"), "dc4 cargo test: square root of nonnumeric attempted\n");
@@ -440,6 +971,11 @@ fn test_digit_count() {
     assert_eq!(dc4_run(b"123.000450 10000000* Zf"), "16\n");
     assert_eq!(dc4_run(b"[spoopadoop]Zf"), "10\n");
     assert_eq!(dc4_run(b"Zf"), "dc4 cargo test: stack empty\n");
+    // The minus sign isn't counted.
+    assert_eq!(dc4_run(b"_123Zp 123Zp"), "3\n3\n");
+    assert_eq!(dc4_run(b"_0.5Zp 0.5Zp _0Zp"), "1\n1\n1\n");
+    assert_eq!(dc4_run(b"_123.000450Zp _.000450Zp"), "9\n3\n");
+    assert_eq!(dc4_run(b"_15000Zp"), "5\n");
 }
 
 #[test]
@@ -470,3 +1006,2113 @@ fn test_zero_print() {
     // but the scale didn't actually change:
     assert_eq!(dc4_run(b"12.345 .345- 12- .1+ f"), ".100\n");
 }
+
+#[test]
+fn test_push_scaled_int() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+
+    dc.push_scaled_int(123456, 2);
+    dc.text(b"p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8_lossy(&out), "1234.56\n");
+    out.clear();
+
+    // The scale is kept through arithmetic.
+    dc.push_scaled_int(-5, 0);
+    dc.text(b"* p X p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8_lossy(&out), "-6172.80\n2\n");
+    out.clear();
+
+    // The input radix doesn't matter.
+    dc.text(b"16i c".to_vec(), &mut out);
+    dc.push_scaled_int(10, 0);
+    dc.text(b"p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8_lossy(&out), "10\n");
+}
+
+#[test]
+fn test_scientific_notation() {
+    use dc4::parser::Flavor;
+    let run = |program: &[u8], flavor| {
+        let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), flavor);
+        dc.set_scientific_notation(true);
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(run(b"1.5e3 p", Flavor::Gnu), "1500\n");
+    assert_eq!(run(b"2e_2 p", Flavor::Gnu), ".02\n");
+    assert_eq!(run(b"2e-2 p X p", Flavor::Gnu), ".02\n2\n");
+    assert_eq!(run(b"_6.022e23 p", Flavor::Gnu), "-602200000000000000000000\n");
+    assert_eq!(run(b"1.25e1 p X p", Flavor::Gnu), "12.5\n1\n");
+    assert_eq!(run(b"5e p 7e_ p", Flavor::Gnu), "5\n7\n");
+    // The exponent is a power of ten in any radix.
+    assert_eq!(run(b"16i 1Ae2 p", Flavor::Gnu), "2600\n");
+    // Macros are read the same way.
+    assert_eq!(run(b"[3e2 p]x", Flavor::Gnu), "300\n");
+    // The BSD if/else syntax still works.
+    assert_eq!(run(b"[[yes]p]sx [[no]p]sy 1 1 =xey 1 2 =xey", Flavor::Bsd), "yes\nno\n");
+    // The exponent has to fit into 32 bits, or the number isn't pushed.
+    assert_eq!(run(b"5e_4294967295 X p", Flavor::Gnu), "4294967295\n");
+    assert_eq!(run(b"1e99999999999 z p 5e_9999999999 z p", Flavor::Gnu),
+        "dc4 cargo test: exponent out of range (must fit into 32 bits)\n0\n\
+        dc4 cargo test: exponent out of range (must fit into 32 bits)\n1\n");
+    assert_eq!(run(b"1e4294967296 7p", Flavor::Gnu),
+        "dc4 cargo test: exponent out of range (must fit into 32 bits)\n7\n");
+
+    // It's off by default.
+    assert_eq!(dc4_run(b"1.5e3 p"), "dc4 cargo test: 'e' (0145) unimplemented\n3\n");
+
+    // Numbers pushed through the API follow the setting too, and after an exponent has started,
+    // only a sign and then digits can follow.
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    assert!(matches!(dc.push_number("1e5"), Err(dc4::DcError::UnexpectedNumberChar(b'e'))));
+    dc.set_scientific_notation(true);
+    dc.push_number("1e5").unwrap();
+    dc.push_number("-2.5e-1").unwrap();
+    for (bad, c) in [("1e5e3", b'e'), ("1e5.5", b'.'), ("1e5A", b'A'), ("1e5_", b'_'),
+        ("1e__5", b'_')]
+    {
+        let result = dc.push_number(bad);
+        assert!(matches!(result, Err(dc4::DcError::UnexpectedNumberChar(x)) if x == c), "{bad}");
+    }
+    let mut out = Vec::<u8>::new();
+    dc.text("f", &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "-.25\n100000\n");
+}
+
+#[test]
+fn test_notation() {
+    use dc4::Notation;
+    let run = |program: &[u8], notation| {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        dc.set_notation(notation);
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+    let sci = Notation::Scientific(4);
+    let eng = Notation::Engineering(4);
+    assert_eq!(run(b"2 100^ p", sci), "1.268e+30\n");
+    assert_eq!(run(b"2 100^ p", eng), "1.268e+30\n");
+    assert_eq!(run(b"2 101^ p", eng), "2.535e+30\n");
+    assert_eq!(run(b"2 102^ p", eng), "5.071e+30\n");
+    assert_eq!(run(b"2 103^ p", eng), "10.14e+30\n");
+    assert_eq!(run(b"20k 1 10 20^/ p", sci), "1.000e-20\n");
+    assert_eq!(run(b"5k _2 3/ p", sci), "-6.667e-01\n");
+    assert_eq!(run(b"0 p 0.000 p", sci), "0.000e+00\n0.000e+00\n");
+    // n and f too, but not P, and the values themselves are unchanged.
+    assert_eq!(run(b"1234567 d n [ ]n f 10 % p", sci), "1.235e+06 1.235e+06\n7.000e+00\n");
+    assert_eq!(run(b"65 P", sci), "A");
+    // Other output radixes are printed as usual.
+    assert_eq!(run(b"16o 1234567 p", sci), "12D687\n");
+    assert_eq!(run(b"2 100^ p", Notation::Plain), "1267650600228229401496703205376\n");
+}
+
+#[test]
+fn test_push_f64() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+
+    dc.push_f64(0.1).unwrap();
+    dc.push_f64(0.2).unwrap();
+    dc.text(b"+ p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8_lossy(&out), ".3\n");
+    assert_eq!(f64::try_from(dc.take_result().unwrap()).unwrap(), 0.3);
+
+    for f in [1e300, -6.02214076e23, 5e-324] {
+        dc.push_f64(f).unwrap();
+        assert_eq!(f64::try_from(dc.take_result().unwrap()).unwrap(), f);
+    }
+
+    assert!(matches!(dc.push_f64(f64::NAN), Err(dc4::DcError::FloatNotFinite)));
+    assert!(matches!(dc.push_f64(f64::NEG_INFINITY), Err(dc4::DcError::FloatNotFinite)));
+    assert!(matches!(dc.take_result(), Err(dc4::DcError::StackDepth(0))));
+}
+
+#[cfg(feature = "num")]
+#[test]
+fn test_push_big() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+
+    let n: dc4::num_bigint::BigInt = "123456789012345678901234567890".parse().unwrap();
+    dc.push_big(n, 20);
+    dc.text(b"p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8_lossy(&out), "1234567890.12345678901234567890\n");
+}
+
+#[test]
+fn test_counts() {
+    assert_eq!(dc4_run(b"1 2 3 zp"), "3\n");
+    assert_eq!(dc4_run(b"KIOf"), "10\n10\n0\n");
+    assert_eq!(dc4_run(b"4294967296 Zp"), "10\n");
+    assert_eq!(dc4_run(b"1.00000000000000000000 Xp"), "20\n");
+
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    dc.push_string(vec![b'x'; 10 << 20]);
+    dc.text("Z [x]Z f", &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "1\n10485760\n");
+}
+
+#[test]
+fn test_warn_once() {
+    use dc4::parser::Flavor;
+    let program = b"0 sc [2 2.5^ s_ lc1+d sc 1000>l]dslx";
+
+    let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), Flavor::Bsd);
+    dc.set_warn_once(true);
+    let mut out = Vec::<u8>::new();
+    dc.text(program.to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(),
+        "dc4 cargo test: warning: non-zero fractional part in exponent\n\
+        dc4 cargo test: warning: non-zero fractional part in exponent (repeated 999 times)\n");
+
+    // Off by default.
+    let out = dc4_run_flavor(program, Flavor::Bsd);
+    assert_eq!(out.lines().count(), 1000);
+    assert!(out.lines()
+        .all(|line| line == "dc4 cargo test: warning: non-zero fractional part in exponent"));
+}
+
+#[test]
+fn test_exp_scale() {
+    // The scale of a^b is min(scale(a) * b, max(k, scale(a))), the scale POSIX gives bc, and the
+    // exact power is truncated to it.
+    let cases: &[(&str, &str, u32)] = &[
+        ("0k 1.5 2^", "2.2", 1),
+        ("2k 1.5 2^", "2.25", 2),
+        ("10k 1.5 2^", "2.25", 2),
+        ("0k 1.5 3^", "3.3", 1),
+        ("2k 1.5 3^", "3.37", 2),
+        ("10k 1.5 3^", "3.375", 3),
+        ("0k 1.5 30^", "191751.0", 1),
+        ("2k 1.5 30^", "191751.05", 2),
+        ("10k 1.5 30^", "191751.0592328840", 10),
+        ("0k 2.25 2^", "5.06", 2),
+        ("10k 2.25 2^", "5.0625", 4),
+        ("0k 2.25 30^", "36768468716.93", 2),
+        ("10k 2.25 30^", "36768468716.9330215400", 10),
+        ("0k _0.5 3^", "-.1", 1),
+        ("2k _0.5 3^", "-.12", 2),
+        ("10k _0.5 3^", "-.125", 3),
+        ("2k _0.5 30^", "0", 2),
+        ("10k _0.5 30^", ".0000000009", 10),
+        ("0k 1.05 30^", "4.32", 2),
+        ("10k 1.05 30^", "4.3219423751", 10),
+        ("5k 2 10^", "1024", 0),
+        ("5k 1.5 0^", "1", 0),
+        ("3k 1.5 _2^", ".444", 3),
+    ];
+    for (program, expected, scale) in cases {
+        assert_eq!(dc4_run(format!("{program} p X p").as_bytes()), format!("{expected}\n{scale}\n"),
+            "{program}");
+    }
+    // Zero to a negative power is one divided by zero.
+    assert_eq!(dc4_run(b"0 _1^ 0.00 _3^ f"),
+        "dc4 cargo test: divide by zero\ndc4 cargo test: divide by zero\n-3\n0\n-1\n0\n");
+}
+
+#[test]
+fn test_mul_scale() {
+    // The scale of a*b is min(scale(a) + scale(b), max(k, scale(a), scale(b))), truncated.
+    let cases: &[(&str, &str, u32)] = &[
+        ("0k .5 .5*", ".2", 1),
+        ("5k .5 .5*", ".25", 2),
+        ("0k 1.5 1.5*", "2.2", 1),
+        ("1k 1.25 1.25*", "1.56", 2),
+        ("3k 1.25 1.25*", "1.562", 3),
+        ("10k 1.25 1.25*", "1.5625", 4),
+        ("0k _1.5 1.5*", "-2.2", 1),
+        ("0k 1.50 2*", "3.00", 2),
+        ("0k 100 .01*", "1.00", 2),
+        ("0k 3 4*", "12", 0),
+        ("2k 1.001 1.001*", "1.002", 3),
+        ("2k 1.234 5.678*", "7.006", 3),
+    ];
+    for (program, expected, scale) in cases {
+        assert_eq!(dc4_run(format!("{program} p X p").as_bytes()), format!("{expected}\n{scale}\n"),
+            "{program}");
+    }
+
+    // A property test over every combination of operand scales and k from 0 to 5: the printed
+    // product and its X follow the rule, worked out independently with integers.
+    for scale_a in 0 .. 6 {
+        for scale_b in 0 .. 6 {
+            for k in 0 .. 6 {
+                let a = format!("12.{}", &"98765"[.. scale_a]);
+                let b = format!("_3.{}", &"14159"[.. scale_b]);
+                let digits = |s: &str| s.replace(['.', '_'], "").parse::<i128>().unwrap();
+                let full_scale = scale_a + scale_b;
+                let scale = full_scale.min(k.max(scale_a).max(scale_b));
+                let product = digits(&a) * digits(&b) / 10i128.pow((full_scale - scale) as u32);
+                let pow = 10i128.pow(scale as u32);
+                let mut expected = format!("-{}", product / pow);
+                if scale > 0 {
+                    expected += &format!(".{:0width$}", product % pow, width = scale);
+                }
+                let program = format!("{k}k {a} {b}* p X p");
+                assert_eq!(dc4_run(program.as_bytes()), format!("{expected}\n{scale}\n"),
+                    "{program}");
+            }
+        }
+    }
+
+    // Squaring a fraction 10,000 times doesn't keep doubling its digits.
+    assert_eq!(dc4_run(b"0si 1.1 [d* .9* li1+dsi 10000>L]dsLx Xp"), "1\n");
+}
+
+#[test]
+fn test_output_reuse() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+
+    let mut buf = Vec::<u8>::new();
+    dc.text_into(b"1p".to_vec(), &mut buf);
+    dc.text_into(b"2p".to_vec(), &mut buf);
+    assert_eq!(buf, b"1\n2\n");
+    buf.clear();
+    dc.text_into(b"3p".to_vec(), &mut buf);
+    assert_eq!(buf, b"3\n");
+
+    let mut sink = dc4::OutputSink::new();
+    for i in 0 .. 100 {
+        sink.reset();
+        dc.text(format!("c {i} 10* p").into_bytes(), &mut sink);
+        assert_eq!(sink.output(), format!("{}\n", i * 10).as_bytes());
+    }
+    assert_eq!(sink.into_output(), b"990\n");
+
+    // Running into a sink collects the diagnostics there too, and reset clears them.
+    let mut sink = dc4::OutputSink::new();
+    for i in 0 .. 10 {
+        sink.reset();
+        dc.text_to_sink(format!("c {i}p +").into_bytes(), &mut sink);
+        assert_eq!(sink.output(), format!("{i}\n").as_bytes());
+        let codes = sink.diagnostics().iter().map(|diag| diag.code).collect::<Vec<_>>();
+        assert_eq!(codes, ["stack_empty"]);
+    }
+    // Afterward, they go back to the output.
+    let mut out = Vec::<u8>::new();
+    dc.text(b"c +".to_vec(), &mut out);
+    assert_eq!(out, b"dc4 cargo test: stack empty\n");
+}
+
+#[test]
+fn test_comparison_errors() {
+    // GNU dc doesn't say which comparison it was.
+    assert_eq!(dc4_run(b"[foo] 7 >x f"), "dc4 cargo test: non-numeric value\n7\nfoo\n");
+
+    let run_verbose = |program: &[u8]| {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        dc.set_verbose_errors(true);
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+
+    for op in [">", "!>", "<", "!<", "=", "!="] {
+        assert_eq!(run_verbose(format!("[foo] 7 {op}x f").as_bytes()),
+            format!("dc4 cargo test: non-numeric value in '{op}' comparison for register 'x' (0170)\n7\nfoo\n"));
+        assert_eq!(run_verbose(format!("7 [foo] {op}y f").as_bytes()),
+            format!("dc4 cargo test: non-numeric value in '{op}' comparison for register 'y' (0171)\nfoo\n7\n"));
+    }
+
+    // Other errors are unchanged.
+    assert_eq!(run_verbose(b"7 >x"), "dc4 cargo test: stack empty\n");
+    assert_eq!(run_verbose(b"[foo] 7 *"), "dc4 cargo test: non-numeric value\n");
+}
+
+#[test]
+fn test_string_sharing() {
+    // Strings share storage between copies and slices; none of that is observable.
+    assert_eq!(dc4_run(b"[hello] d a p s. p"), "h\nhello\n");
+    assert_eq!(dc4_run(b"[] a Z p"), "0\n");
+    assert_eq!(dc4_run(b"[hello] sx lx lx a p s. p Lx a p"), "h\nhello\nh\n");
+    assert_eq!(dc4_run(b"[world] 1:a 1;a a p 1;a p"), "w\nworld\n");
+    // Macros run from a shared string, including via tail recursion.
+    assert_eq!(dc4_run(b"[d p 1 - d 0 <x] sx 3 lx x"), "3\n2\n1\n");
+}
+
+#[test]
+fn test_decimal_literals() {
+    // Long decimal literals are converted all at once; make sure the result is the same as adding
+    // up the digits one at a time, including with the hex digits dc accepts in any radix.
+    assert_eq!(dc4_run(b"1A.5 p 9F9.F0 p _12.3A45 p"), "20.5\n1060.50\n-12.4045\n");
+    let digits = "9876543210".repeat(1_000);
+    let out = dc4_run(format!("{digits}.{digits} d Z p s. d X p s. p").as_bytes());
+    let mut lines = out.lines();
+    assert_eq!(lines.next(), Some("20000"));
+    assert_eq!(lines.next(), Some("10000"));
+    assert_eq!(lines.collect::<String>().replace('\\', ""), format!("{digits}.{digits}"));
+}
+
+#[test]
+fn test_diagnostic_handler() {
+    use dc4::diagnostic::{Diagnostic, Position, Severity};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let diags = Rc::new(RefCell::new(Vec::<Diagnostic>::new()));
+    let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), dc4::parser::Flavor::Bsd);
+    dc.set_warn_once(true);
+    let sink = Rc::clone(&diags);
+    dc.set_diagnostic_handler(move |diag| sink.borrow_mut().push(diag.clone()));
+
+    let mut out = Vec::<u8>::new();
+    // Errors inside a macro are reported at the position the macro was run from.
+    dc.text_named("test", b"1 p\n[2 .5 ^ 2 .5 ^ * * *] sm\n lm x p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "1\n1\n");
+
+    let at = |offset, line, column| Some(Position { offset, line, column });
+    let diag = |severity, code: &'static str, message: &str, position| Diagnostic {
+        severity, code, message: message.to_owned(), position, input: Some("test".to_owned()),
+        excerpt: None,
+    };
+    assert_eq!(*diags.borrow(), vec![
+        diag(Severity::Warning, "exponent_fraction", "non-zero fractional part in exponent",
+            at(33, 3, 5)),
+        diag(Severity::Error, "stack_empty", "stack empty", at(33, 3, 5)),
+        diag(Severity::Warning, "exponent_fraction",
+            "non-zero fractional part in exponent (repeated 1 times)", None),
+    ]);
+
+    // Without a handler, they're written to the output as usual.
+    dc.clear_diagnostic_handler();
+    let mut out = Vec::<u8>::new();
+    dc.text(b"*".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "dc4 cargo test: stack empty\n");
+}
+
+#[test]
+fn test_diagnostic_excerpt() {
+    use dc4::diagnostic::{Diagnostic, Excerpt};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let run = |program: &[u8], verbose: bool| {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        dc.set_verbose_errors(verbose);
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+
+    // The error is in the middle of the macro, at the second '*'.
+    let program = b"[1 2 * * 3 + p]x";
+    assert_eq!(run(program, false), "dc4 cargo test: stack empty\n5\n");
+    assert_eq!(run(program, true), concat!(
+        "dc4 cargo test: stack empty\n",
+        "    1 2 * * 3 + p\n",
+        "          ^\n",
+        "5\n",
+    ));
+
+    // Long macros are cut short, and control characters escaped. Errors in the input itself have
+    // a position instead.
+    let program = b"[0 1 2 3 4 5 6 7 8 9\t[a] 1 + 0 1 2 3 4 5 6 7 8 9 10 11 12 13]x 5 [b] +";
+    assert_eq!(run(program, true), concat!(
+        "dc4 cargo test: non-numeric value\n",
+        "    ...3 4 5 6 7 8 9\\t[a] 1 + 0 1 2 3 4 5 6 7 8 9...\n",
+        "                            ^\n",
+        "dc4 cargo test: non-numeric value\n",
+    ));
+
+    // The diagnostic handler gets the excerpt too, including for macros run by tail calls.
+    let diags = Rc::new(RefCell::new(Vec::<Diagnostic>::new()));
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.set_verbose_errors(true);
+    let sink = Rc::clone(&diags);
+    dc.set_diagnostic_handler(move |diag| sink.borrow_mut().push(diag.clone()));
+    dc.text(b"* [2 sb lb lc]x".to_vec(), &mut Vec::new());
+    let excerpts = diags.borrow().iter().map(|diag| diag.excerpt.clone()).collect::<Vec<_>>();
+    assert_eq!(excerpts, vec![
+        None,
+        Some(Excerpt { text: "2 sb lb lc".to_owned(), caret: 9 }),
+    ]);
+}
+
+#[test]
+fn test_input_names() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    dc.stream_named("a.dc", &mut &b"[*]sm [1 2 3 + + p]sg lgx"[..], &mut out);
+    dc.stream_named("b.dc", &mut &b"lgx lmx lmx\n*"[..], &mut out);
+    dc.text(b"c lmx".to_vec(), &mut out);
+    dc.text_named("c.dc", b"[[in c]p lmx]x".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), concat!(
+        "6\n",
+        "6\n",
+        // the macro was defined in a.dc, so that's where the error is
+        "dc4 cargo test: a.dc: stack empty\n",
+        // errors directly in this input
+        "dc4 cargo test: b.dc: stack empty\n",
+        // ...and macros called without a name still know where they came from
+        "dc4 cargo test: a.dc: stack empty\n",
+        "in c\n",
+        "dc4 cargo test: a.dc: stack empty\n",
+    ));
+}
+
+#[test]
+fn test_flavor_switch() {
+    use dc4::parser::Flavor;
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    assert_eq!(dc.flavor(), Flavor::Gnu);
+    dc.set_flavor(Flavor::Gavin);
+    assert_eq!(dc.flavor(), Flavor::Gavin);
+    assert_eq!(dc4::Dc4::with_flavor("dc4 cargo test".to_string(), Flavor::Bsd).flavor(),
+        Flavor::Bsd);
+}
+
+fn dc4_run_flavor(expr: &[u8], flavor: dc4::parser::Flavor) -> String {
+    let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), flavor);
+    let mut out = Vec::<u8>::new();
+    dc.text(expr.to_vec(), &mut out);
+    assert_eq!(out, dc4_run_compiled(expr, flavor),
+        "pre-parsed program gave different output: {}", String::from_utf8_lossy(expr));
+    String::from_utf8(out).unwrap()
+}
+
+/// Run a program with `Program::compile` and `run_program` instead of `text`, which should always
+/// give the same output.
+fn dc4_run_compiled(expr: &[u8], flavor: dc4::parser::Flavor) -> Vec<u8> {
+    let program = dc4::Program::compile(expr, flavor);
+    let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), flavor);
+    let mut out = Vec::<u8>::new();
+    dc.run_program(&program, &mut out);
+    out
+}
+
+#[test]
+fn test_flavor_if_else() {
+    use dc4::parser::Flavor;
+    let program = b"[1]sy [0]sn 1 2 >yen 2 1 >yen 1 1 !=yen 3 3 =y f";
+    for flavor in [Flavor::Bsd, Flavor::Gavin] {
+        assert_eq!(dc4_run_flavor(program, flavor), "1\n0\n0\n1\n", "{flavor}");
+    }
+    // GNU dc doesn't have an 'e' command, and 'n' means something else there.
+    let gnu = dc4_run_flavor(program, Flavor::Gnu);
+    assert_eq!(gnu.matches("dc4 cargo test: 'e' (0145) unimplemented\n").count(), 3, "{gnu}");
+
+    // It works in macros too, and the flavor can be changed between inputs.
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    dc.text(b"[[yes]p]sy [[no]p]sn [1 2 <yen]sm".to_vec(), &mut out);
+    dc.set_flavor(Flavor::Bsd);
+    assert_eq!(dc.flavor(), Flavor::Bsd);
+    dc.text(b"lmx".to_vec(), &mut out);
+    dc.stream(&mut &b"2 1 <yen"[..], &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "no\nyes\n");
+}
+
+#[test]
+fn test_flavor_gavin_commands() {
+    use dc4::parser::Flavor;
+    // Macros are parsed with the flavor too, and it can be changed between inputs.
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    dc.text(b"[1 1G p]sm lmx".to_vec(), &mut out);
+    dc.set_flavor(Flavor::Gavin);
+    dc.text(b"lmx".to_vec(), &mut out);
+    dc.stream(&mut &b"0N p"[..], &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "dc4 cargo test: 'G' (0107) unimplemented\n\
+        1\n1\n1\n");
+
+    assert_eq!(dc4_run_flavor(b"1 1G p 1 2G p 0N p 5N p [x]N f", Flavor::Gavin),
+        "1\n0\n1\n0\ndc4 cargo test: non-numeric value\nx\n0\n1\n0\n1\n");
+    assert_eq!(dc4_run_flavor(b"1 1G", Flavor::Gnu),
+        "dc4 cargo test: 'G' (0107) unimplemented\n");
+    assert_eq!(dc4_run_flavor(b"0N", Flavor::Bsd),
+        "dc4 cargo test: 'N' (0116) unimplemented\n");
+}
+
+#[test]
+fn test_natural_exp() {
+    use dc4::parser::Flavor;
+    let run = |program: &[u8]| dc4_run_flavor(program, Flavor::Gavin);
+    assert_eq!(run(b"10k 1j p"), "2.7182818284\n");
+    assert_eq!(run(b"50k 1j p"), "2.71828182845904523536028747135266249775724709369995\n");
+    assert_eq!(run(b"10k 0j p 2.5j p"), "1.0000000000\n12.1824939607\n");
+    // Truncated to the scale, like v, and at least as many digits as the argument has.
+    assert_eq!(run(b"1j p 10j p 1.000j p"), "2\n22026\n2.718\n");
+
+    // Large and negative arguments.
+    assert_eq!(run(b"5k 100j p"), "26881171418161354484126255515800135873611118.77374\n");
+    assert_eq!(run(b"10k 1000j Z p"), "445\n");
+    assert_eq!(run(b"10k _1j p _20j p"), ".3678794411\n.0000000020\n");
+    // Far enough below zero, the result is zero at the scale, without working out e^-x.
+    assert_eq!(run(b"10k _1000000j p _99999999999999999999j p 5k _11j p _12j p"),
+        "0\n0\n.00001\n0\n");
+
+    assert_eq!(run(b"[x]j f"), "dc4 cargo test: exponential of nonnumeric attempted\n");
+    assert_eq!(run(b"j"), "dc4 cargo test: stack empty\n");
+    assert_eq!(dc4_run_flavor(b"1j f", Flavor::Gnu),
+        "dc4 cargo test: 'j' (0152) unimplemented\n1\n");
+}
+
+#[test]
+fn test_natural_log() {
+    use dc4::parser::Flavor;
+    let run = |program: &[u8]| dc4_run_flavor(program, Flavor::Gavin);
+    assert_eq!(run(b"50k 2g p"), ".69314718055994530941723212145817656807550013436025\n");
+    assert_eq!(run(b"50k 10g p"), "2.30258509299404568401799145468436420760110148862877\n");
+    assert_eq!(run(b"20k .5g p"), "-.69314718055994530941\n");
+    assert_eq!(run(b"30k 10 30^ g p"), "69.077552789821370520539743640530\n");
+    assert_eq!(run(b"30k 1 10 30^ / g p"), "-69.077552789821370520539743640530\n");
+    // Truncated to the scale, like v, and at least as many digits as the argument has.
+    assert_eq!(run(b"1000g p 3.000g p 1g p"), "6\n1.098\n0\n");
+    // It undoes j.
+    assert_eq!(run(b"20k 2.5j 25k g p"), "2.4999999999999999999999855\n");
+
+    assert_eq!(run(b"0g f"), "dc4 cargo test: logarithm of nonpositive number attempted\n0\n");
+    assert_eq!(run(b"_1g f"), "dc4 cargo test: logarithm of nonpositive number attempted\n-1\n");
+    // A string is popped, like with j, u, and v.
+    assert_eq!(run(b"1 [a] gf"), "dc4 cargo test: logarithm of nonnumeric attempted\n1\n");
+    assert_eq!(run(b"[a] gf"), "dc4 cargo test: logarithm of nonnumeric attempted\n");
+    assert_eq!(run(b"g"), "dc4 cargo test: stack empty\n");
+    assert_eq!(dc4_run_flavor(b"1g f", Flavor::Gnu),
+        "dc4 cargo test: 'g' (0147) unimplemented\n1\n");
+}
+
+#[test]
+fn test_root() {
+    assert_eq!(dc4_run(b"27 3V p _27 3V p 20k _8 3V p"), "3\n-3\n-2.00000000000000000000\n");
+    assert_eq!(dc4_run(b"20k 2 3V p"), "1.25992104989487316476\n");
+    assert_eq!(dc4_run(b"20k 2 2V p 2v p"), "1.41421356237309504880\n1.41421356237309504880\n");
+    // Negative roots are reciprocals.
+    assert_eq!(dc4_run(b"20k 2 _2V p 1000 _3V p"),
+        ".70710678118654752440\n.10000000000000000000\n");
+    assert_eq!(dc4_run(b"0 3V p 100 1V p 1.0000 2V p"), "0\n100\n1.0000\n");
+    // Big indexes.
+    assert_eq!(dc4_run(b"10k 2 1000V p 0k 3 1000^ 1000V p"), "1.0006933874\n3\n");
+
+    assert_eq!(dc4_run(b"10k 64 3.5V p"),
+        "dc4 cargo test: warning: non-zero scale in root index\n4.0000000000\n");
+    assert_eq!(dc4_run(b"_4 2V f"), "dc4 cargo test: even root of negative number\n2\n-4\n");
+    assert_eq!(dc4_run(b"4 0V f 4 .5V f"),
+        "dc4 cargo test: root index must be nonzero and fit into 32 bits\n0\n4\n\
+         dc4 cargo test: root index must be nonzero and fit into 32 bits\n.5\n4\n0\n4\n");
+    assert_eq!(dc4_run(b"0 _2V"), "dc4 cargo test: divide by zero\n");
+
+    // Gavin's dc has a command of its own on 'V'.
+    assert_eq!(dc4_run_flavor(b"8 3V f", dc4::parser::Flavor::Gavin),
+        "dc4 cargo test: 'V' (0126) unimplemented\n3\n8\n");
+}
+
+#[test]
+fn test_round() {
+    // Half up by default, which is away from zero for negative numbers.
+    assert_eq!(dc4_run(b"2k 2.005w p _2.005w p 2.004999w p 1.5w p"), "2.01\n-2.01\n2.00\n1.5\n");
+    assert_eq!(dc4_run(b"2.5w p _2.5w p 3.5w p 2.4w p"), "3\n-3\n4\n2\n");
+    assert_eq!(dc4_run(b"2W 2.5w p _2.5w p 3.5w p _3.5w p 2.51w p"), "2\n-2\n4\n-4\n3\n");
+    assert_eq!(dc4_run(b"0W 2k 2.999w p _2.999w p 1W 2.999w p"), "2.99\n-2.99\n3.00\n");
+
+    assert_eq!(dc4_run(b"[a]w"), "dc4 cargo test: rounding of nonnumeric attempted\n");
+    assert_eq!(dc4_run(b"3W 1.5W [a]W 2.5w p"), concat!(
+        "dc4 cargo test: rounding mode must be 0 (truncate), 1 (half up), or 2 (half even)\n",
+        "dc4 cargo test: rounding mode must be 0 (truncate), 1 (half up), or 2 (half even)\n",
+        "dc4 cargo test: rounding mode must be 0 (truncate), 1 (half up), or 2 (half even)\n",
+        "3\n",
+    ));
+
+    // Gavin's dc has a command of its own on 'W', but not on 'w'.
+    assert_eq!(dc4_run_flavor(b"2W 2.5w p", dc4::parser::Flavor::Gavin),
+        "dc4 cargo test: 'W' (0127) unimplemented\n3\n");
+}
+
+#[test]
+fn test_floor_ceil_trunc() {
+    assert_eq!(dc4_run(b"1.5{p _1.5{p 0{p 7{p _7{p"), "1\n-2\n0\n7\n-7\n");
+    assert_eq!(dc4_run(b"1.5}p _1.5}p 0}p 7}p _7}p"), "2\n-1\n0\n7\n-7\n");
+    assert_eq!(dc4_run(b"1.5$p _1.5$p 0$p 7$p _7$p"), "1\n-1\n0\n7\n-7\n");
+    // The result is always an integer, even when nothing was dropped.
+    assert_eq!(dc4_run(b"2.00{p 2.00}p _2.00$p 16i 1.8{p"), "2\n2\n-2\n1\n");
+
+    assert_eq!(dc4_run(b"[a]{ [a]} [a]$"), concat!(
+        "dc4 cargo test: non-numeric value\n",
+        "dc4 cargo test: non-numeric value\n",
+        "dc4 cargo test: non-numeric value\n",
+    ));
+
+    // Gavin's dc has commands of its own on '{' and '}', but truncates with '$' like here.
+    use dc4::parser::Flavor;
+    assert_eq!(dc4_run_flavor(b"1.5 2.5{}f", Flavor::Gavin), concat!(
+        "dc4 cargo test: '{' (0173) unimplemented\n",
+        "dc4 cargo test: '}' (0175) unimplemented\n",
+        "2.5\n1.5\n",
+    ));
+    assert_eq!(dc4_run_flavor(b"_1.5$p", Flavor::Gavin), "-1\n");
+    assert_eq!(dc4_run_flavor(b"_1.5{p", Flavor::Bsd), "-2\n");
+}
+
+#[test]
+fn test_abs() {
+    assert_eq!(dc4_run(b"_1.250m p 1.250m p 0m p _0.0m p _7m p 7m p"),
+        "1.250\n1.250\n0\n0\n7\n7\n");
+    assert_eq!(dc4_run(b"16o _255m p"), "FF\n");
+    assert_eq!(dc4_run(b"[a]m m"), concat!(
+        "dc4 cargo test: non-numeric value\n",
+        "dc4 cargo test: stack empty\n",
+    ));
+
+    // Gavin's dc has a command of its own on 'm'.
+    assert_eq!(dc4_run_flavor(b"_1m f", dc4::parser::Flavor::Gavin),
+        "dc4 cargo test: 'm' (0155) unimplemented\n-1\n");
+}
+
+#[test]
+fn test_min_max() {
+    assert_eq!(dc4_run(b"3 5( p 5 3( p 3 5) p 5 3) p"), "3\n3\n5\n5\n");
+    assert_eq!(dc4_run(b"_3 2( p _3 2) p _1.5 _1.25( p _1.5 _1.25) p"),
+        "-3\n2\n-1.5\n-1.25\n");
+    // Equal values with different scales compare equal, and the first one is kept.
+    assert_eq!(dc4_run(b"1.50 1.5( p 1.5 1.50) p 0 _0( p"), "1.50\n1.5\n0\n");
+    assert_eq!(dc4_run(b".1 .09( p .1 .09) p"), ".09\n.1\n");
+
+    // Strings are an error, and leave the stack alone.
+    assert_eq!(dc4_run(b"1 [a]( f"), "dc4 cargo test: non-numeric value\na\n1\n");
+    assert_eq!(dc4_run(b"[a] 1) f"), "dc4 cargo test: non-numeric value\n1\na\n");
+    assert_eq!(dc4_run(b"1)"), "dc4 cargo test: stack empty\n");
+
+    // Gavin's dc has commands of its own on '(' and ')'.
+    assert_eq!(dc4_run_flavor(b"1 2 (p", dc4::parser::Flavor::Gavin),
+        "dc4 cargo test: '(' (050) unimplemented\n2\n");
+    assert_eq!(dc4_run_flavor(b"1 2 )p", dc4::parser::Flavor::Gavin),
+        "dc4 cargo test: ')' (051) unimplemented\n2\n");
+}
+
+#[test]
+fn test_gcd() {
+    assert_eq!(dc4_run(b"12 18y p _12 18y p 12 _18y p 0 _7y p 7 0y p 0 0y p"),
+        "6\n6\n6\n7\n7\n0\n");
+    // Integers with zeros after the point are fine.
+    assert_eq!(dc4_run(b"12.0 18.00y p"), "6\n");
+    // 2^200 3^50 and 2^150 3^80 share 2^150 3^50.
+    assert_eq!(dc4_run(b"2 200^ 3 50^* 2 150^ 3 80^* y 2 150^ 3 50^* / p"), "1\n");
+    assert_eq!(dc4_run(b"2 200^ 3 50^* 2 150^ 3 80^* y p"),
+        "1024618246531448192529486101931556275808450117982966277666337116389376\n");
+
+    assert_eq!(dc4_run(b"1.5 3y f"), "dc4 cargo test: non-integer number\n3\n1.5\n");
+    assert_eq!(dc4_run(b"[a] 3y f"), "dc4 cargo test: non-numeric value\n3\na\n");
+
+    // Gavin's dc has a command of its own on 'y'.
+    assert_eq!(dc4_run_flavor(b"4 6y f", dc4::parser::Flavor::Gavin),
+        "dc4 cargo test: 'y' (0171) unimplemented\n6\n4\n");
+}
+
+#[test]
+fn test_lcm() {
+    assert_eq!(dc4_run(b"4 6M p _4 6M p 0 5M p 5 0M p 0 0M p 4.0 6.00M p"),
+        "12\n12\n0\n0\n0\n12\n");
+    // The least common multiple of 1 through 20.
+    assert_eq!(dc4_run(b"1 [d lm M sm 1+ d 20!<x]sx 1sm lxx lmp"), "232792560\n");
+    // Operands too big for any machine integer.
+    assert_eq!(dc4_run(b"2 100^ 3* 2 90^ 5* M 2 100^ 15* - p"), "0\n");
+
+    assert_eq!(dc4_run(b"1.5 3M f"), "dc4 cargo test: non-integer number\n3\n1.5\n");
+    assert_eq!(dc4_run(b"[a] 3M f"), "dc4 cargo test: non-numeric value\n3\na\n");
+
+    // Gavin's dc has a command of its own on 'M'.
+    assert_eq!(dc4_run_flavor(b"4 6M f", dc4::parser::Flavor::Gavin),
+        "dc4 cargo test: 'M' (0115) unimplemented\n6\n4\n");
+}
+
+#[test]
+fn test_factorial() {
+    assert_eq!(dc4_run(b"0T p 1T p 5T p 10.00T p"), "1\n1\n120\n3628800\n");
+    let hundred = concat!(
+        "93326215443944152681699238856266700490715968264381621468592963895217",
+        "59999322991560894146397615651828625369792082722375825118521091686400",
+        "0000000000000000000000\n");
+    assert_eq!(dc4_run(b"100T p"), hundred);
+    assert_eq!(dc4_run(b"100T Z p"), "158\n");
+
+    // Errors leave the operand on the stack.
+    let invalid = "dc4 cargo test: factorial of negative or non-integer number\n";
+    assert_eq!(dc4_run(b"_5T f"), format!("{invalid}-5\n"));
+    assert_eq!(dc4_run(b"2.5T f"), format!("{invalid}2.5\n"));
+    assert_eq!(dc4_run(b"2 64^T f"),
+        "dc4 cargo test: factorial argument too big\n18446744073709551616\n");
+    assert_eq!(dc4_run(b"[a]T f"), "dc4 cargo test: non-numeric value\na\n");
+
+    // Gavin's dc has a command of its own on 'T'.
+    assert_eq!(dc4_run_flavor(b"5T f", dc4::parser::Flavor::Gavin),
+        "dc4 cargo test: 'T' (0124) unimplemented\n5\n");
+}
+
+#[test]
+fn test_arctan() {
+    use dc4::parser::Flavor;
+    let run = |program: &[u8]| dc4_run_flavor(program, Flavor::Gavin);
+    // These are the digits bc -l gives for a(x) with scale=30.
+    let cases = [
+        ("1", ".785398163397448309615660845819"),
+        (".5", ".463647609000806116214256231461"),
+        ("_.5", "-.463647609000806116214256231461"),
+        ("2", "1.107148717794090503017065460178"),
+        ("10", "1.471127674303734591852875571761"),
+        ("_3", "-1.249045772398254425829917077281"),
+        (".01", ".009999666686665238206340116209"),
+        ("1000000", "1.570795326794896619564655024972"),
+        ("0", "0"),
+    ];
+    for (x, expected) in cases {
+        assert_eq!(run(format!("30k {x}u p").as_bytes()), format!("{expected}\n"), "{x}");
+    }
+    // The classic way to get π, as 4*a(1) in bc.
+    assert_eq!(run(b"30k 1u 4*p"), "3.141592653589793238462643383276\n");
+    assert_eq!(run(b"60k 1u 4*p"),
+        "3.141592653589793238462643383279502884197169399375105820974944\n");
+    // Truncated to the scale, like v, and at least as many digits as the argument has.
+    assert_eq!(run(b"1u p 1.000u p"), "0\n.785\n");
+
+    assert_eq!(run(b"[x]u f"), "dc4 cargo test: arctangent of nonnumeric attempted\n");
+    assert_eq!(run(b"u"), "dc4 cargo test: stack empty\n");
+    assert_eq!(dc4_run_flavor(b"1u f", Flavor::Gnu),
+        "dc4 cargo test: 'u' (0165) unimplemented\n1\n");
+}
+
+#[test]
+fn test_log() {
+    use dc4::parser::Flavor;
+    let run = |program: &[u8]| dc4_run_flavor(program, Flavor::Gavin);
+    assert_eq!(run(b"20k 2 10b p"), ".30102999566398119521\n");
+    assert_eq!(run(b"20k 10 2b p"), "3.32192809488736234787\n");
+    // Exact results stay exact, even though they're computed from inexact logarithms.
+    assert_eq!(run(b"1024 2b p 1000 10b p 10 100^ 10b p"), "10\n3\n100\n");
+    assert_eq!(run(b"10k 1000 10b p .001 10b p 2 .5b p"),
+        "3.0000000000\n-3.0000000000\n-1.0000000000\n");
+    // How many hex digits a number needs.
+    assert_eq!(run(b"255 16b 1+p 256 16b 1+p"), "2\n3\n");
+
+    let base_error = "dc4 cargo test: logarithm base must be positive and not 1\n";
+    assert_eq!(run(b"5 1b f"), format!("{base_error}1\n5\n"));
+    assert_eq!(run(b"5 0b f"), format!("{base_error}0\n5\n"));
+    assert_eq!(run(b"5 _2b f"), format!("{base_error}-2\n5\n"));
+    assert_eq!(run(b"0 2b f"),
+        "dc4 cargo test: logarithm of nonpositive number attempted\n2\n0\n");
+    assert_eq!(run(b"[a] 2b f"), "dc4 cargo test: non-numeric value\n2\na\n");
+    assert_eq!(run(b"2b f"), "dc4 cargo test: stack empty\n2\n");
+}
+
+#[test]
+fn test_action_traced() {
+    use dc4::parser::{Action, RegisterAction};
+    use dc4::{ActionEffect, DcValue};
+
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    dc.text(b"[abc] 1 2 3".to_vec(), &mut out);
+
+    // There is no public constructor for numbers, so get them by loading them from a register.
+    let num = |s: &str| {
+        let mut dc = dc4::Dc4::new(String::new());
+        let mut out = Vec::<u8>::new();
+        dc.text(format!("{s} sx").into_bytes(), &mut out);
+        let (_, effect) = dc.action_traced(Action::Register(RegisterAction::Load, b'x'), &mut out);
+        effect.pushed.into_iter().next().unwrap()
+    };
+    let string = |s: &str| DcValue::Str(s.into());
+
+    let mut traced = |action| {
+        let (result, effect) = dc.action_traced(action, &mut out);
+        result.unwrap();
+        effect
+    };
+
+    assert_eq!(traced(Action::Add), ActionEffect {
+        popped: vec![num("2"), num("3")],
+        pushed: vec![num("5")],
+        ..Default::default()
+    });
+    assert_eq!(traced(Action::Swap), ActionEffect {
+        popped: vec![num("1"), num("5")],
+        pushed: vec![num("5"), num("1")],
+        ..Default::default()
+    });
+    assert_eq!(traced(Action::Dup), ActionEffect {
+        pushed: vec![num("1")],
+        ..Default::default()
+    });
+    assert_eq!(traced(Action::Print), ActionEffect { output: true, ..Default::default() });
+    assert_eq!(traced(Action::Register(RegisterAction::Store, b'a')), ActionEffect {
+        popped: vec![num("1")],
+        registers_written: vec![b'a'],
+        ..Default::default()
+    });
+    assert_eq!(traced(Action::Register(RegisterAction::Load, b'a')), ActionEffect {
+        pushed: vec![num("1")],
+        registers_read: vec![b'a'],
+        ..Default::default()
+    });
+    // stack is now: [abc] 5 1 1
+    assert_eq!(traced(Action::Register(RegisterAction::StoreRegArray, b'r')), ActionEffect {
+        popped: vec![num("1"), num("1")],
+        registers_written: vec![b'r'],
+        ..Default::default()
+    });
+    assert_eq!(traced(Action::NumberChar(b'1')), ActionEffect::default());
+    assert_eq!(traced(Action::PushNumber), ActionEffect {
+        pushed: vec![num("1")],
+        ..Default::default()
+    });
+    assert_eq!(traced(Action::Register(RegisterAction::LoadRegArray, b'r')), ActionEffect {
+        popped: vec![num("1")],
+        pushed: vec![num("1")],
+        registers_read: vec![b'r'],
+        ..Default::default()
+    });
+    assert_eq!(traced(Action::ClearStack), ActionEffect {
+        popped: vec![string("abc"), num("5"), num("1")],
+        ..Default::default()
+    });
+
+    // Errors leave the stack alone.
+    let (result, effect) = dc.action_traced(Action::Add, &mut out);
+    assert!(result.is_err());
+    assert_eq!(effect, ActionEffect::default());
+    assert_eq!(String::from_utf8(out).unwrap(), "1\n");
+}
+
+#[test]
+fn test_fold_number() {
+    use dc4::parser::Action;
+    use dc4::DcError;
+
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let print_result = |dc: &mut dc4::Dc4| {
+        let mut out = Vec::<u8>::new();
+        let value = dc.take_result().unwrap();
+        dc.print_value(&value, &mut out);
+        String::from_utf8(out).unwrap()
+    };
+
+    for n in ["1", "2.50", "_10"] {
+        dc.fold_number(n, Action::Add).unwrap();
+    }
+    assert_eq!(print_result(&mut dc), "-6.50");
+
+    // Errors leave the running value alone.
+    dc.fold_number("6", Action::Mul).unwrap();
+    assert!(matches!(dc.fold_number("garbage", Action::Mul), Err(DcError::UnexpectedNumberChar(b'g'))));
+    assert!(matches!(dc.fold_number("0", Action::Div), Err(DcError::DivideByZero)));
+    dc.fold_number("7", Action::Mul).unwrap();
+    assert_eq!(print_result(&mut dc), "42");
+
+    // The stack must hold only the running value, before and after.
+    assert!(matches!(dc.take_result(), Err(DcError::StackDepth(0))));
+    dc.text(b"1 2".to_vec(), &mut Vec::new());
+    assert!(matches!(dc.fold_number("3", Action::Add), Err(DcError::StackDepth(2))));
+    assert!(matches!(dc.take_result(), Err(DcError::StackDepth(2))));
+    dc.text(b"+".to_vec(), &mut Vec::new());
+    assert_eq!(print_result(&mut dc), "3");
+
+    // Only binary operators are allowed, and anything else leaves the running value alone.
+    dc.fold_number("5", Action::Add).unwrap();
+    for op in [Action::DivRem, Action::Sqrt, Action::SetPrecision, Action::PrintStack,
+        Action::Quit, Action::Register(dc4::parser::RegisterAction::Load, b'x')]
+    {
+        assert!(matches!(dc.fold_number("4", op), Err(DcError::InvalidActionSequence)));
+    }
+    dc.fold_number("2", Action::Max).unwrap();
+    dc.fold_number("5", Action::CompareEq).unwrap();
+    assert_eq!(print_result(&mut dc), "1");
+}
+
+#[test]
+fn test_drain_results() {
+    use dc4::{ConversionError, DcValue};
+
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::new();
+    dc.text(b"1 _2 [three] 4.5 5.000 99999999999999999999 6", &mut out);
+    let results = dc.drain_results::<i64>().collect::<Vec<_>>();
+    assert_eq!(results.len(), 7);
+    assert_eq!(results[0], Ok(6));
+    assert_eq!(results[1].as_ref().unwrap_err().to_string(),
+        "can't convert 99999999999999999999 to i64");
+    assert_eq!(results[2], Ok(5));
+    // Failed values aren't lost: they come back in the error.
+    let err = results[3].clone().unwrap_err();
+    assert_eq!(err.to_string(), "can't convert 4.5 to i64");
+    assert_eq!(err.wanted, "i64");
+    let mut printed = Vec::new();
+    dc.print_value(&err.value, &mut printed);
+    assert_eq!(printed, b"4.5");
+    assert_eq!(results[4].as_ref().unwrap_err().value, DcValue::Str("three".into()));
+    assert_eq!(results[5..], [Ok(-2), Ok(1)]);
+    assert!(dc.drain_results::<i64>().next().is_none());
+    assert!(out.is_empty());
+
+    // Stopping early leaves the rest.
+    dc.text(b"1 2 3 4", &mut out);
+    assert_eq!(dc.drain_results::<u64>().take(2).collect::<Vec<_>>(), [Ok(4), Ok(3)]);
+    dc.text(b"f", &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "2\n1\n");
+
+    dc.text(b"c 2.5 _1 [a\xff] [hi] 1", &mut Vec::new());
+    let results = dc.drain_results::<String>().collect::<Vec<_>>();
+    assert_eq!(results[0].as_ref().unwrap_err().to_string(), "can't convert 1 to String");
+    assert_eq!(results[1], Ok("hi".to_owned()));
+    assert_eq!(results[2].as_ref().unwrap_err().value, DcValue::Str(b"a\xff".into()));
+    dc.text(b"2.5 _1 [x]", &mut Vec::new());
+    let results = dc.drain_results::<f64>().map(|r| r.map_err(|e| e.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(results, [Err("can't convert a string to f64".to_owned()), Ok(-1.0), Ok(2.5)]);
+
+    // Getting the values as they are can't fail.
+    dc.text(b"1 [a]", &mut Vec::new());
+    let values = dc.drain_results::<DcValue>().collect::<Result<Vec<_>, ConversionError>>();
+    assert_eq!(values.unwrap(), [DcValue::Str("a".into()), DcValue::Num(1.into())]);
+}
+
+#[test]
+fn test_echo() {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let echo = SharedBuf::default();
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.set_echo(echo.clone());
+    let mut out = Vec::<u8>::new();
+    dc.text(b"4 5 +p\n[d 1+ d3>x]sx 0 lxx f _.5 [[nested] str]P".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "9\n3\n2\n1\n0\n9\n[nested] str");
+    assert_eq!(String::from_utf8(echo.0.take()).unwrap(), concat!(
+        "> 4\n", "> 5\n", "> +\n", "> p\n",
+        "> [d 1+ d3>x]\n", "> sx\n", "> 0\n", "> lx\n", "> x\n",
+        ">> d\n", ">> 1\n", ">> +\n", ">> d\n", ">> 3\n", ">> >x\n",
+        ">> d\n", ">> 1\n", ">> +\n", ">> d\n", ">> 3\n", ">> >x\n",
+        ">> d\n", ">> 1\n", ">> +\n", ">> d\n", ">> 3\n", ">> >x\n",
+        "> f\n", "> _.5\n", "> [[nested] str]\n", "> P\n",
+    ));
+
+    dc.clear_echo();
+    dc.text(b"1 2 +".to_vec(), &mut Vec::new());
+    assert!(echo.0.borrow().is_empty());
+
+    // Failing to write the echo doesn't stop the program.
+    struct Closed;
+    impl Write for Closed {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    dc.set_echo(Closed);
+    let mut out = Vec::<u8>::new();
+    dc.text(b"c 4 5 +p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "9\n");
+}
+
+#[test]
+fn test_print_stack_indexed() {
+    // It works whether or not commands are being echoed, so a program which found
+    // print-indexed in the features list can use it.
+    assert_eq!(dc4_run(b"1 [a] Y f"), "0: a\n1: 1\na\n1\n");
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.set_echo(std::io::sink());
+    let mut out = Vec::<u8>::new();
+    dc.text(b"1 Y".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "0: 1\n");
+
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    dc.text(b"16o 42 [hello] _3 255 [] 5 6 7 8 9 10 Y c Y".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), concat!(
+        "0: A\n", "1: 9\n", "2: 8\n", "3: 7\n", "4: 6\n", "5: 5\n", "6: \n", "7: FF\n",
+        "8: -3\n", "9: hello\n", "10: 2A\n",
+    ));
+}
+
+#[test]
+fn test_provenance() {
+    use dc4::Provenance;
+
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.set_track_provenance(true);
+    dc.push_number("42").unwrap();
+    let mut out = Vec::<u8>::new();
+    dc.text_named("prog", b"sa 1 la d\n[5 2 3+]sm lmx 1 1=m".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "");
+
+    let at = |line, column, offset, macro_register| Some(Provenance::Program {
+        input: Some("prog".to_owned()),
+        position: Some(dc4::diagnostic::Position { offset, line, column }),
+        macro_register,
+    });
+    // The 42 went through 'a' and was duplicated, and is still the one pushed through the API.
+    assert_eq!(dc.provenance(6), at(1, 4, 3, None));
+    assert_eq!(dc.provenance(5), Some(Provenance::Api));
+    assert_eq!(dc.provenance(4), Some(Provenance::Api));
+    // Values made in a macro are at the top-level command which ran it, and note the register
+    // it was run from, whether by 'x' or a conditional. Arithmetic makes a new value.
+    assert_eq!(dc.provenance(3), at(2, 14, 23, Some(b'm')));
+    assert_eq!(dc.provenance(2), at(2, 14, 23, Some(b'm')));
+    assert_eq!(dc.provenance(0), at(2, 20, 29, Some(b'm')));
+    assert_eq!(dc.provenance(7), None);
+
+    let mut dump = Vec::<u8>::new();
+    dc.dump_state(&mut dump).unwrap();
+    let dump = String::from_utf8(dump).unwrap();
+    assert!(dump.contains("  5 (from prog:2:20 in macro 'm')\n"), "{dump}");
+    assert!(dump.contains("  42 (from API)\n"), "{dump}");
+    assert!(dump.contains("register 'a' (0141): 42 (from API), "), "{dump}");
+
+    dc.set_echo(std::io::sink());
+    let mut out = Vec::<u8>::new();
+    dc.text(b"c 9 Y".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "0: 9 (from 1:3)\n");
+
+    // Without tracking, nothing is known.
+    dc.set_track_provenance(false);
+    assert_eq!(dc.provenance(0), None);
+}
+
+#[test]
+fn test_output_ended_with_newline() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    assert_eq!(dc.output_ended_with_newline(), None);
+    let mut out = Vec::<u8>::new();
+    dc.text(b"1 2".to_vec(), &mut out);
+    assert_eq!(dc.output_ended_with_newline(), None);
+
+    for (program, newline) in [
+        (&b"p"[..], true),
+        (b"n", false),
+        (b"[abc]P", false),
+        (b"[abc\n]P", true),
+        (b"1f", true),
+        (b"3J", false),
+        (b"a", false),
+        (b"[x]p", true),
+    ] {
+        dc.text(program.to_vec(), &mut out);
+        assert_eq!(dc.output_ended_with_newline(), Some(newline),
+            "{}", String::from_utf8_lossy(program));
+    }
+
+    // Running something which writes nothing leaves it as it was.
+    dc.text(b"5 sa".to_vec(), &mut out);
+    assert_eq!(dc.output_ended_with_newline(), Some(true));
+    dc.action(dc4::parser::Action::PrintNoNewlinePop, &mut out).unwrap();
+    assert_eq!(dc.output_ended_with_newline(), Some(false));
+
+    // Error messages written to the output count.
+    dc.text(b"c 3n d".to_vec(), &mut out);
+    assert_eq!(dc.output_ended_with_newline(), Some(true));
+    dc.stream(&mut &b"4n"[..], &mut out);
+    assert_eq!(dc.output_ended_with_newline(), Some(false));
+    dc.stream(&mut &b"c+"[..], &mut out);
+    assert_eq!(dc.output_ended_with_newline(), Some(true));
+}
+
+#[test]
+fn test_string_interning() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    // A macro of 80 bytes, which is big enough to be interned.
+    let body = format!("{} c [done]p", "1 ".repeat(35));
+    assert_eq!(body.len(), 80);
+    dc.text(format!("[{body}]sa [{body}]sb").into_bytes(), &mut out);
+    let stats = dc.stats();
+    assert_eq!((stats.intern_hits, stats.intern_misses, stats.interned_bytes), (1, 1, 80));
+
+    // Defining it over and over in a loop doesn't take any more memory. The loop's own macro is
+    // interned too, taking 100 bytes.
+    dc.text(format!("[[{body}]sc li 1+ d si 10>l]sl 0si llx").into_bytes(), &mut out);
+    let stats = dc.stats();
+    assert_eq!((stats.intern_hits, stats.intern_misses, stats.interned_bytes), (11, 2, 80 + 100));
+
+    // Changing a copy leaves the others alone.
+    dc.text(b"la".to_vec(), &mut out);
+    let mut changed: Vec<u8> = dc.take_result().unwrap().try_into().map(dc4::DcString::into_vec)
+        .unwrap();
+    changed.splice(0 .. 0, b"[changed]p ".iter().cloned());
+    dc.push_string(changed);
+    dc.text(b"sa lax lbx lcx".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "changed\ndone\ndone\ndone\n");
+
+    // Memory is freed as usual once nothing uses a string.
+    let mut out = Vec::<u8>::new();
+    dc.text(b"0sb 0sc 0sl".to_vec(), &mut out);
+    assert_eq!(dc.stats().interned_bytes, 0);
+
+    // Small strings aren't interned.
+    dc.text(b"[1 2 +]sa [1 2 +]sb".to_vec(), &mut out);
+    assert_eq!((dc.stats().intern_hits, dc.stats().intern_misses), (11, 2));
+}
+
+#[test]
+fn test_max_output_bytes() {
+    use dc4::{DcResult, ErrorPolicy};
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.set_error_policy(ErrorPolicy::AbortProgram);
+    dc.set_max_output_bytes(Some(10));
+
+    // An endless print loop is cut off at the limit, and the error only reported once.
+    let mut out = Vec::<u8>::new();
+    let result = dc.text(b"[1nlxx]dsxx".to_vec(), &mut out);
+    assert!(matches!(result, DcResult::Aborted), "{result:?}");
+    assert_eq!(String::from_utf8(out).unwrap(),
+        "1111111111dc4 cargo test: output limit reached; further output suppressed\n");
+    assert_eq!(dc.stats().output_bytes, 10);
+
+    // Carrying on past it writes nothing more, and reports nothing more.
+    dc.set_error_policy(ErrorPolicy::Continue);
+    let mut out = Vec::<u8>::new();
+    dc.text(b"c 1p 2p d+".to_vec(), &mut out);
+    assert_eq!(out, b"");
+
+    // Setting the limit again starts the count over.
+    dc.set_max_output_bytes(Some(10));
+    let mut out = Vec::<u8>::new();
+    dc.text(b"c 12p 34p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "12\n34\n");
+    assert_eq!(dc.stats().output_bytes, 6);
+
+    // A single write can go over, and is cut off partway.
+    let mut out = Vec::<u8>::new();
+    dc.text(b"12345p 6p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(),
+        "1234dc4 cargo test: output limit reached; further output suppressed\n");
+
+    dc.set_max_output_bytes(None);
+    let mut out = Vec::<u8>::new();
+    dc.text(b"c 12345p".to_vec(), &mut out);
+    assert_eq!(out, b"12345\n");
+}
+
+#[test]
+fn test_cancel() {
+    use dc4::{Dc4, DcResult};
+    use std::time::{Duration, Instant};
+
+    // Run a program which would take a long time, cancelling it from another thread shortly
+    // after it starts.
+    fn run_cancelled(dc: &mut Dc4, program: &[u8]) -> String {
+        let handle = dc.cancel_handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            handle.cancel();
+        });
+        let start = Instant::now();
+        let mut out = Vec::<u8>::new();
+        let result = dc.text(program.to_vec(), &mut out);
+        let elapsed = start.elapsed();
+        canceller.join().unwrap();
+        assert!(matches!(result, DcResult::Aborted), "{result:?}");
+        // Each of these takes well over a minute unless stopped.
+        assert!(elapsed < Duration::from_secs(15), "took {elapsed:?}");
+        String::from_utf8(out).unwrap()
+    }
+    fn run(dc: &mut Dc4, program: &[u8]) -> String {
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    }
+    let mut dc = Dc4::new("dc4 cargo test".to_string());
+
+    // Part way through v, ^, and |, the program stops even though the error policy is to carry
+    // on, and the operands are left as they were.
+    assert_eq!(run_cancelled(&mut dc, b"100000k 2v 9p"), "dc4 cargo test: cancelled\n");
+    assert_eq!(run(&mut dc, b"f c 0k"), "2\n");
+    assert_eq!(run_cancelled(&mut dc, b"2 10000000000^ 9p"), "dc4 cargo test: cancelled\n");
+    assert_eq!(run(&mut dc, b"f c"), "10000000000\n2\n");
+    assert_eq!(run_cancelled(&mut dc, b"3 2 100000^ 10 1000^| 9p"), "dc4 cargo test: cancelled\n");
+    assert_eq!(run(&mut dc, b"Z r Z r f c"), "1001\n30103\n3\n");
+    // Between commands, including in macros.
+    assert_eq!(run_cancelled(&mut dc, b"[lxx]dsxx 9p"), "dc4 cargo test: cancelled\n");
+    // Part way through V, and Gavin's j, g, u, and b.
+    assert_eq!(run_cancelled(&mut dc, b"c 300000k 2 7V 9p"), "dc4 cargo test: cancelled\n");
+    assert_eq!(run(&mut dc, b"f c 0k"), "7\n2\n");
+    dc.set_flavor(dc4::parser::Flavor::Gavin);
+    assert_eq!(run_cancelled(&mut dc, b"1000000j 9p"), "dc4 cargo test: cancelled\n");
+    assert_eq!(run(&mut dc, b"f c 100000k"), "1000000\n");
+    assert_eq!(run_cancelled(&mut dc, b"2g 9p"), "dc4 cargo test: cancelled\n");
+    assert_eq!(run_cancelled(&mut dc, b"u 9p"), "dc4 cargo test: cancelled\n");
+    assert_eq!(run_cancelled(&mut dc, b"3b 9p"), "dc4 cargo test: cancelled\n");
+    assert_eq!(run(&mut dc, b"f c 0k"), "3\n2\n");
+    dc.set_flavor(dc4::parser::Flavor::Gnu);
+
+    // The interpreter carries on afterwards.
+    assert_eq!(run(&mut dc, b"2 3+p 2k 2vp"), "5\n1.41\n");
+}
+
+#[test]
+fn test_auto_simplify() {
+    let run = |program: &[u8], simplify: bool| {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        dc.set_auto_simplify(simplify);
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+
+    // Results are stored without their trailing zeros, but nothing visible changes.
+    let programs: &[&[u8]] = &[
+        b"100k 1 2/ d p X p Z p",
+        b"20k 1 8/ d* p X p",
+        b"20k 1 4/ 3 + 1.5 - p X p",
+        b"10k 10 4 ~ f X f Z f",
+        b"10k 7.50 2.5 % p X p",
+        b"30k 1 4/ 3^ p X p 0k 1 4/ p",
+        b"20k 1 4/ 2^ X p 0k 1.50 2^ p X p",
+        b"40k 1 4/ v p X p",
+        b"30k 16o 1 2/ p 2o 1 8/ p",
+        b"20k 1 2/ 5 * 2 * X p 2.5 =x [equal]p",
+        b"20k 3 2/ 2 * d 3 =y 0k 2 / p X p",
+        b"10k 1 4/ 2 * 2 7 | p 1 2/ Z p",
+        b"10k _1 4/ p _2 3/ p X p",
+    ];
+    for program in programs {
+        assert_eq!(run(program, true), run(program, false),
+            "program: {}", String::from_utf8_lossy(program));
+    }
+    assert_eq!(run(b"100k 1 2/ X p", true), "100\n");
+    assert_eq!(run(b"10k 1 2/ p", true), ".5000000000\n");
+}
+
+#[test]
+fn test_error_policy() {
+    use dc4::{DcResult, ErrorPolicy};
+
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.set_error_policy(ErrorPolicy::AbortProgram);
+    let mut out = Vec::<u8>::new();
+    // The error inside the macro stops both it and the input.
+    let result = dc.text(b"[1p + 2p]x 3p".to_vec(), &mut out);
+    assert!(matches!(result, DcResult::Aborted));
+    assert_eq!(String::from_utf8(out).unwrap(), "1\ndc4 cargo test: stack empty\n");
+
+    let mut out = Vec::<u8>::new();
+    let result = dc.stream(&mut &b"4p lz 5p"[..], &mut out);
+    assert!(matches!(result, DcResult::Aborted));
+    assert_eq!(String::from_utf8(out).unwrap(), "4\ndc4 cargo test: register 'z' (0172) is empty\n");
+
+    dc.set_error_policy(ErrorPolicy::Continue);
+    let mut out = Vec::<u8>::new();
+    let result = dc.text(b"c [1p + 2p]x 3p".to_vec(), &mut out);
+    assert!(matches!(result, DcResult::Continue));
+    assert_eq!(String::from_utf8(out).unwrap(), "1\ndc4 cargo test: stack empty\n2\n3\n");
+}
+
+#[test]
+fn test_save_scoped() {
+    use dc4::{DcResult, ErrorPolicy};
+
+    // The register's value and array are back the way they were when the macro ends.
+    assert_eq!(dc4_run(b"1sa 7 0:a [ta 5sa la p 9 0:a]x la p 0;a p"), "5\n1\n7\n");
+    assert_eq!(dc4_run(b"[tb 3sb]x lb"), "dc4 cargo test: register 'b' (0142) is empty\n");
+    // Until then, the value is the same as before.
+    assert_eq!(dc4_run(b"1sa [ta la p]x"), "1\n");
+
+    // Quitting out of several levels puts back what each of them saved.
+    assert_eq!(dc4_run(b"1sa [ta 2sa [ta 3sa 2Q 4p]x 5p]x la p"), "1\n");
+    // A macro which was tail called ends along with its caller, either way.
+    for tco in [true, false] {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        dc.set_tail_call_optimization(tco);
+        let mut out = Vec::<u8>::new();
+        dc.text(b"1sa [ta 2sa [ta 3sa la p]x]x la p".to_vec(), &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "3\n1\n");
+    }
+
+    // Errors which abort the macro put them back too.
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.set_error_policy(ErrorPolicy::AbortProgram);
+    let mut out = Vec::<u8>::new();
+    let result = dc.text(b"1sa [ta 2sa [ta 3sa + 4p]x]x".to_vec(), &mut out);
+    assert!(matches!(result, DcResult::Aborted));
+    dc.text(b"la p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "dc4 cargo test: stack empty\n1\n");
+
+    // At the top level, the input is the macro.
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    dc.text(b"1sa".to_vec(), &mut out);
+    dc.stream(&mut &b"ta 2sa la p"[..], &mut out);
+    dc.text(b"la p".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "2\n1\n");
+}
+
+#[test]
+fn test_import_export_array() {
+    use dc4::DcValue;
+
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let run = |dc: &mut dc4::Dc4, expr: &str| {
+        let mut out = Vec::<u8>::new();
+        dc.text(expr.as_bytes().to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+    assert!(dc.export_array(b'a').is_empty());
+
+    let entries = (0 .. 1000u64).map(|i| (i, DcValue::Num((i as i32 * 3).into())));
+    dc.import_array(b'a', entries).unwrap();
+    assert_eq!(run(&mut dc, "0;ap 999;ap 1000;ap"), "0\n2997\n0\n");
+
+    // A later entry for the same index wins.
+    dc.import_array(b'b', [(1, DcValue::Num(1.into())), (1, DcValue::Str(b"one".to_vec().into()))])
+        .unwrap();
+    assert_eq!(run(&mut dc, "1;bp"), "one\n");
+
+    // Imports go into the top level of the register, and go away with it.
+    dc.import_array(b'b', [(5, DcValue::Num(50.into()))]).unwrap();
+    assert_eq!(run(&mut dc, "1;bp 5;bp"), "0\n50\n");
+    assert_eq!(run(&mut dc, "7Sb"), "");
+    dc.import_array(b'b', [(5, DcValue::Num(55.into()))]).unwrap();
+    assert_eq!(run(&mut dc, "5;bp Lbp 5;bp"), "55\n7\n50\n");
+
+    // Exports come out in index order, including entries stored by the program.
+    assert_eq!(run(&mut dc, "[x]2:b 3 1.5:b"), "");
+    assert_eq!(dc.export_array(b'b'), vec![
+        (1, DcValue::Num(3.into())),
+        (2, DcValue::Str(b"x".to_vec().into())),
+        (5, DcValue::Num(50.into())),
+    ]);
+}
+
+#[test]
+fn test_timings() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.text(b"1p".to_vec(), &mut Vec::new());
+    assert_eq!(dc.stats().timings, None);
+
+    dc.set_timings(true);
+    let mut out = Vec::<u8>::new();
+    dc.text(b"3 20000^ d sx Z p [lxp]x".to_vec(), &mut out);
+    dc.stream(&mut &b"lx 2/ sx 1 2 3 f c"[..], &mut out);
+    let timings = dc.stats().timings.unwrap();
+    let sum = timings.parsing + timings.arithmetic + timings.registers + timings.printing
+        + timings.macros + timings.other + timings.output;
+    assert!(sum <= timings.total);
+    assert!(timings.total - sum <= timings.total / 100, "{timings:?}");
+    assert!(timings.arithmetic > timings.registers, "{timings:?}");
+    assert!(!timings.printing.is_zero(), "{timings:?}");
+    assert!(!timings.output.is_zero(), "{timings:?}");
+
+    dc.set_timings(false);
+    assert_eq!(dc.stats().timings, None);
+}
+
+#[test]
+fn test_high_water_marks() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.text(b"1p".to_vec(), &mut Vec::new());
+    assert_eq!(dc.stats().high_water, None);
+
+    dc.set_high_water_marks(true);
+    let mut out = Vec::<u8>::new();
+    // 7^591 has 500 digits, and is gone by the end.
+    dc.text(b"7 591^ 7 590^ / p [hello] sa 1 0:x 2 1:x 3 1:x 4 9:y c 123.45".to_vec(), &mut out);
+    assert_eq!(out, b"7\n");
+    let marks = dc.stats().high_water.unwrap();
+    assert_eq!(marks.max_digits, 500);
+    assert_eq!(marks.max_string_bytes, 5);
+    assert_eq!(marks.max_array_entries, [(b'x', 2), (b'y', 1)].into_iter().collect());
+    // Both big powers were on the stack at once.
+    assert!(marks.max_stack_bytes > 2 * 200, "{marks:?}");
+
+    // Smaller values don't lower the marks.
+    dc.text(b"c 1 [x]".to_vec(), &mut out);
+    assert_eq!(dc.stats().high_water.unwrap(), marks);
+
+    dc.set_high_water_marks(false);
+    assert_eq!(dc.stats().high_water, None);
+}
+
+#[test]
+fn test_dump_state() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.text(b"16o 255 [a\nb] 5 s: 6 S: 9 ;: 7 3 :q".to_vec(), &mut Vec::new());
+    let mut out = Vec::<u8>::new();
+    dc.dump_state(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+        "scale 0, input radix 10, output radix 16\n\
+        stack: 3 values, top first\n\
+        \x20 0\n\
+        \x20 [a\\nb]\n\
+        \x20 FF\n\
+        register ':' (072): 6, stack depth 2, 0 array entries\n\
+        register 'q' (0161): no value, stack depth 1, 1 array entry\n");
+
+    // A huge state is cut down to size.
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.text(b"1 [d 1+ d 1000>x]dsxx 7 1000^ sy".to_vec(), &mut Vec::new());
+    let mut out = Vec::<u8>::new();
+    dc.dump_state(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 25, "{out}");
+    assert_eq!(lines[1], "stack: 1000 values, top first");
+    assert_eq!(lines[2], "  1000");
+    assert_eq!(lines[22], "  (980 more)");
+    assert_eq!(lines[23], "register 'x' (0170): [d 1+ d 1000>x], stack depth 1, 0 array entries");
+    assert_eq!(lines[24], format!("register 'y' (0171): {}... (846 characters), stack depth 1, \
+        0 array entries", &dc4_run(b"7 1000^ p")[..60]));
+
+    // Long strings and fractions in other radixes are cut short the same way.
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    dc.text(format!("100k 16o _1 3/ [{}]", "ab\n".repeat(30)).into_bytes(), &mut Vec::new());
+    let mut out = Vec::<u8>::new();
+    dc.dump_state(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines[2], format!("  [{}]... (90 characters)", "ab\\n".repeat(20)));
+    assert_eq!(lines[3], format!("  -.{}... (86 characters)", "5".repeat(58)));
+}
+
+#[test]
+fn test_array_index_limit() {
+    use dc4::{DcError, DcValue};
+    // Up to the limit, even a huge index is fine: only the entries stored take up space.
+    assert_eq!(dc4_run(b"4294967295;ap c 7 4294967295:a 4294967295;ap c 4294967295.9;ap"),
+        "0\n7\n7\n");
+    assert_eq!(dc4_run(b"1 2 4294967296:a f"),
+        "dc4 cargo test: array index out of range (must be at most 4294967295)\n1\n");
+    assert_eq!(dc4_run(b"1 1000000000000000000000000000000;a f"),
+        "dc4 cargo test: array index out of range (must be at most 4294967295)\n1\n");
+
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let run = |dc: &mut dc4::Dc4, expr: &str| {
+        let mut out = Vec::<u8>::new();
+        dc.text(expr.as_bytes().to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+    dc.set_max_array_index(10);
+    assert_eq!(run(&mut dc, "1 10:a 10;ap c 11;a"),
+        "1\ndc4 cargo test: array index out of range (must be at most 10)\n");
+    let entries = [(2, DcValue::Num(2.into())), (11, DcValue::Num(11.into()))];
+    assert!(matches!(dc.import_array(b'a', entries), Err(DcError::ArrayIndexTooBig(10))));
+    assert_eq!(run(&mut dc, "2;ap 10;ap"), "0\n1\n");
+
+    dc.set_max_array_index(u64::MAX);
+    let max = u64::MAX;
+    assert_eq!(run(&mut dc, &format!("{max};ap c 2 {max}:a {max};ap")), "0\n2\n");
+    assert_eq!(dc.export_array(b'a').last(), Some(&(u64::MAX, DcValue::Num(2.into()))));
+}
+
+#[test]
+fn test_eval_once() {
+    use dc4::diagnostic::Severity;
+    assert_eq!(dc4::eval_once("2 100^p").unwrap(), "1267650600228229401496703205376\n");
+    assert_eq!(dc4::eval_once("").unwrap(), "");
+    // '?' doesn't wait for standard input.
+    assert_eq!(dc4::eval_once("? zp").unwrap(), "0\n");
+    assert_eq!(dc4::eval_once("255P").unwrap(), "\u{fffd}");
+
+    // Any error fails the whole thing, even if the program carries on.
+    let err = dc4::eval_once("1p * 2p [x]3+").unwrap_err();
+    assert_eq!(err.output, "1\n2\n");
+    assert_eq!(err.diagnostics.iter().map(|d| d.code).collect::<Vec<_>>(),
+        ["stack_empty", "non_numeric_value"]);
+    assert_eq!(err.to_string(), "stack empty (and 1 more)");
+
+    // Warnings fail it too, unless tolerated.
+    let err = dc4::eval_once("4 13.9 497 |p").unwrap_err();
+    assert_eq!(err.diagnostics[0].severity, Severity::Warning);
+    assert_eq!(err.to_string(), "warning: non-zero scale in exponent");
+    assert_eq!(err.output, "445\n");
+    assert_eq!(dc4::eval_once_tolerating_warnings("4 13.9 497 |p").unwrap(), "445\n");
+    assert!(dc4::eval_once_tolerating_warnings("4 13.9 497 |p *").is_err());
+}
+
+#[test]
+fn test_run_program() {
+    use dc4::diagnostic::Diagnostic;
+    use dc4::parser::Flavor;
+    use dc4::Program;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // One program, many inputs.
+    let program = Program::compile("d * 1 + p", Flavor::Gnu);
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    for i in 0 .. 4 {
+        dc.push_scaled_int(i, 0);
+        dc.run_program(&program, &mut out);
+    }
+    assert_eq!(String::from_utf8(out).unwrap(), "1\n2\n5\n10\n");
+
+    // Numbers are read in the input radix at the time, and strings can still be run as macros.
+    let program = Program::compile("[2 *]sd 10 ldx p c", Flavor::Gnu);
+    let mut out = Vec::<u8>::new();
+    dc.run_program(&program, &mut out);
+    dc.text(b"16i".to_vec(), &mut out);
+    dc.run_program(&program, &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "20\n32\n");
+
+    // Diagnostics have the same positions, and quitting works the same, as when running text.
+    let text = "1 p\n[2 .5 ^ 2 .5 ^ * * *] sm\n lm x p [q]x 3p";
+    let diags = |compiled: bool| {
+        let diags = Rc::new(RefCell::new(Vec::<Diagnostic>::new()));
+        let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), Flavor::Bsd);
+        let sink = Rc::clone(&diags);
+        dc.set_diagnostic_handler(move |diag| sink.borrow_mut().push(diag.clone()));
+        let mut out = Vec::<u8>::new();
+        let result = if compiled {
+            dc.run_program(&Program::compile(text, Flavor::Bsd), &mut out)
+        } else {
+            dc.text(text.as_bytes().to_vec(), &mut out)
+        };
+        assert!(matches!(result, dc4::DcResult::Terminate(0)));
+        assert_eq!(String::from_utf8(out).unwrap(), "1\n1\n");
+        diags.take()
+    };
+    assert_eq!(diags(true).len(), 3);
+    assert_eq!(diags(true), diags(false));
+
+    // A program compiled in another flavor doesn't run at all.
+    let program = Program::compile("1 2 3 G p", Flavor::Gavin);
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    assert!(matches!(dc.run_program(&program, &mut out), dc4::DcResult::Continue));
+    assert_eq!(String::from_utf8(out).unwrap(), "dc4 cargo test: program was compiled for the \
+        gavin flavor, but this is the gnu flavor\n");
+    assert!(dc.take_result().is_err());
+    dc.set_error_policy(dc4::ErrorPolicy::AbortProgram);
+    assert!(matches!(dc.run_program(&program, &mut Vec::new()), dc4::DcResult::Aborted));
+    dc.set_flavor(Flavor::Gavin);
+    let mut out = Vec::<u8>::new();
+    dc.run_program(&program, &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "0\n");
+
+    // A program can be shared between threads.
+    let program = Program::compile("2 100^ p", Flavor::Gnu);
+    let threads = (0 .. 2).map(|_| {
+        let program = program.clone();
+        std::thread::spawn(move || {
+            let mut out = Vec::<u8>::new();
+            dc4::Dc4::new("dc4 cargo test".to_string()).run_program(&program, &mut out);
+            out
+        })
+    }).collect::<Vec<_>>();
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), b"1267650600228229401496703205376\n");
+    }
+}
+
+#[test]
+fn test_coalesced_literals() {
+    use dc4::parser::{Action, SliceParser};
+    // Running the whole literals gives exactly the same output as running a character at a time.
+    let programs: &[&[u8]] = &[
+        b"1 2+p [hello]p _1.25 3*p",
+        b"16i FF.8 Ai p [a[b]c]P 10P",
+        b"[lx 1+ d sx 5>m]sm 0sx lmx lxp",
+        b"7 [unterminated",
+        b"2 3.",
+        b"[\xfe\x00]P 255P",
+    ];
+    for program in programs {
+        let run = |coalesce: bool| {
+            let mut parser = SliceParser::new(program);
+            parser.set_coalesce_literals(coalesce);
+            let actions = parser.map(|(_offset, action)| action).collect::<Vec<_>>();
+            assert_eq!(coalesce, actions.iter()
+                .any(|a| matches!(a, Action::NumberLiteral(_) | Action::StringLiteral(_))));
+            let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+            let mut out = Vec::<u8>::new();
+            dc.actions(actions.into_iter(), &mut out).unwrap();
+            dc.action(Action::PrintStack, &mut out).unwrap();
+            out
+        };
+        let expected = run(false);
+        assert_eq!(run(true), expected, "{}", String::from_utf8_lossy(program));
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        dc.text(b"f".to_vec(), &mut out);
+        assert_eq!(out, expected, "{}", String::from_utf8_lossy(program));
+    }
+}
+
+fn dc4_run_stdlib(packages: &[&str], expr: &[u8], flavor: dc4::parser::Flavor) -> String {
+    let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), flavor);
+    dc.load_stdlib(packages).unwrap();
+    let mut out = Vec::<u8>::new();
+    dc.text(expr.to_vec(), &mut out);
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_stdlib() {
+    use dc4::parser::Flavor;
+    let cases: &[(&str, &[u8], &str)] = &[
+        ("math", b"0lNxp 1lNxp 5lNxp 20lNxp", "1\n1\n120\n2432902008176640000\n"),
+        ("math", b"0lIxp 1lIxp 2lIxp 10lIxp 100lIxp", "0\n1\n1\n55\n354224848179261915075\n"),
+        ("math", b"12 18lGxp 7 0lGxp 0 7lGxp _12 18lGxp 17 5lGxp", "6\n7\n7\n6\n1\n"),
+        // The scale is left as it was.
+        ("math", b"5k 12 18lGxp Kp", "6\n5\n"),
+        ("stack", b"1 2 3 lUx f", "2\n1\n"),
+        ("stack", b"1 2 lOx f", "1\n2\n1\n"),
+        ("stack", b"1 2 3 lTx f", "1\n3\n2\n"),
+        ("array", b"5 0:Y 3 1:Y 9 2:Y 1 3:Y 3 4:Y _2 5:Y 6lSx 0;Yn 1;Yn 2;Yn 3;Yn 4;Yn 5;Yp zp",
+            "-213359\n1\n"),
+        ("array", b"7 0:Y 0lSx 1lSx 0;Yp zp", "7\n1\n"),
+    ];
+    for flavor in [Flavor::Gnu, Flavor::Bsd, Flavor::Gavin] {
+        for &(package, program, expected) in cases {
+            assert_eq!(dc4_run_stdlib(&[package], program, flavor), expected,
+                "{package}: {} ({flavor:?})", String::from_utf8_lossy(program));
+        }
+    }
+}
+
+#[test]
+fn test_stdlib_unknown() {
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let err = dc.load_stdlib(&["math", "nope"]).unwrap_err();
+    assert_eq!(err.to_string(), "no such library package: \"nope\"");
+    // Nothing is loaded.
+    let mut out = Vec::<u8>::new();
+    dc.text(b"lNp".to_vec(), &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(),
+        "dc4 cargo test: register 'N' (0116) is empty\ndc4 cargo test: stack empty\n");
+
+    let all = dc4::stdlib::PACKAGES.iter().map(|p| p.name).collect::<Vec<_>>();
+    dc.load_stdlib(&all).unwrap();
+    for package in dc4::stdlib::PACKAGES {
+        assert!(package.registers().iter().all(|r| !r.is_ascii_lowercase()));
+    }
+}
+
+#[test]
+fn test_bitwise() {
+    use dc4::parser::Flavor;
+    let run = |program: &[u8]| dc4_run_flavor(program, Flavor::Gavin);
+    assert_eq!(run(b"16o 16i FF F0& p FF00 F0U p FF0 F0R p"), "F0\nFFF0\nF00\n");
+    assert_eq!(run(b"12 10& p 12 10U p 12 10R p 0 0U p 6.00 3& p"), "8\n14\n6\n0\n2\n");
+    // Operands too big for any machine integer.
+    assert_eq!(run(b"2 100^ 1+ 2 100^ 2+ & 2 100^ - p"), "0\n");
+    assert_eq!(run(b"2 100^ 1 R 2 100^ - p"), "1\n");
+
+    assert_eq!(run(b"_1 3& f"), "dc4 cargo test: bitwise operation on negative or non-integer \
+        number\n3\n-1\n");
+    assert_eq!(run(b"1.5 3U f"), "dc4 cargo test: bitwise operation on negative or non-integer \
+        number\n3\n1.5\n");
+    assert_eq!(run(b"[a] 3R f"), "dc4 cargo test: non-numeric value\n3\na\n");
+    assert_eq!(dc4_run_flavor(b"1 1& f", Flavor::Gnu),
+        "dc4 cargo test: '&' (046) unimplemented\n1\n1\n");
+}
+
+#[test]
+fn test_records() {
+    use dc4::parser::Flavor;
+    let run = |program: &[u8]| dc4_run_flavor(program, Flavor::Gavin);
+    // Two records of name, age, and city, in parallel arrays n, a, and c.
+    let store = b"[Bob] 42 [NY] 0 \\:nac [Al] 7 [LA] 1 \\:nac ";
+    assert_eq!(run(&[&store[..], b"z p 0;n p 1;a p 1;c p"].concat()), "0\nBob\n7\nLA\n");
+    // Loading pushes the fields in the order listed, so the stack is as it was before storing.
+    assert_eq!(run(&[&store[..], b"0 \\;nac f c 1 \\;cn f"].concat()),
+        "NY\n42\nBob\nAl\nLA\n");
+    // The list ends at any whitespace, or the end of the program.
+    assert_eq!(run(b"5 6 0 \\:xy\t0 \\;yx\nf"), "5\n6\n");
+    assert_eq!(run(b"5 0 \\:x 0 \\;x"), "");
+    // A bad index is popped, but nothing is stored and the values stay on the stack.
+    assert_eq!(run(&[&store[..], b"[Cy] 9 [SF] _1 \\:nac f 0;n p"].concat()),
+        "dc4 cargo test: array index must be a nonnegative integer\nSF\n9\nCy\nBob\n");
+    // Too few values for the record changes nothing.
+    assert_eq!(run(b"5 0 \\:nac f"), "dc4 cargo test: stack empty\n0\n5\n");
+    // Only Gavin's flavor has them.
+    assert_eq!(dc4_run(b"5 0 \\:nac"), concat!(
+        "dc4 cargo test: '\\\\' (0134) unimplemented\n",
+        "dc4 cargo test: stack empty\n"));
+}
+
+#[test]
+fn test_mod_inverse() {
+    assert_eq!(dc4_run(b"3 7, p 10 7, p 1 1, p"), "5\n5\n0\n");
+    // An RSA private exponent.
+    assert_eq!(dc4_run(b"65537 61 1- 53 1- * , p"), "2753\n");
+    // Numbers too big for any machine integer: the inverse times the number is 1, modulo m.
+    assert_eq!(dc4_run(b"2 89^ 1- sa 2 127^ 1- sm la lm, la * lm % p"), "1\n");
+
+    assert_eq!(dc4_run(b"4 8, f"),
+        "dc4 cargo test: no modular inverse: the number and modulus have a common factor\n8\n4\n");
+    for program in [&b"0 7, f"[..], b"3 _7, f", b"3.5 7, f", b"3 7.5, f"] {
+        let out = dc4_run(program);
+        assert!(out.starts_with("dc4 cargo test: modular inverse needs a positive integer and \
+            modulus\n"), "{out}");
+        assert_eq!(out.lines().count(), 3, "operands left on the stack: {out}");
+    }
+    assert_eq!(dc4_run(b"[a] 7, f"), "dc4 cargo test: non-numeric value\n7\na\n");
+    assert_eq!(dc4_run(b"7, f"), "dc4 cargo test: stack empty\n7\n");
+
+    // Gavin's dc uses ',' for something else.
+    assert_eq!(dc4_run_flavor(b"3 7, f", dc4::parser::Flavor::Gavin),
+        "dc4 cargo test: ',' (054) unimplemented\n7\n3\n");
+}
+
+#[test]
+fn test_diagnostic_order() {
+    use dc4::diagnostic::Diagnostic;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let codes = |program: &[u8]| {
+        let diags = Rc::new(RefCell::new(Vec::<Diagnostic>::new()));
+        // BSD dc warns about fractional exponents for ^ as well as |.
+        let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(),
+            dc4::parser::Flavor::Bsd);
+        let sink = Rc::clone(&diags);
+        dc.set_diagnostic_handler(move |diag| sink.borrow_mut().push(diag.clone()));
+        dc.text(program.to_vec(), &mut Vec::new());
+        let codes = diags.borrow().iter()
+            .map(|diag| format!("{}:{}", diag.severity, diag.code))
+            .collect::<Vec<_>>();
+        codes
+    };
+    // Warnings in operand order, however the operands are given.
+    assert_eq!(codes(b"4.0 13.0 497.0 |"),
+        ["warning:base_scale", "warning:exponent_scale", "warning:modulus_scale"]);
+    assert_eq!(codes(b"4 13.0 497.0 |"), ["warning:exponent_scale", "warning:modulus_scale"]);
+    assert_eq!(codes(b"4.0 13 497.0 |"), ["warning:base_scale", "warning:modulus_scale"]);
+    // Operands which are rejected aren't warned about.
+    assert_eq!(codes(b"4.0 13.0 0.0 |"), ["error:remainder_by_zero"]);
+    assert_eq!(codes(b"4.0 _13.0 497.0 |"), ["error:negative_exponent"]);
+    assert_eq!(codes(b"4.0 13.0 [x] |"), ["error:non_numeric_value"]);
+    assert_eq!(codes(b"4 0.5V"), ["error:root_index_invalid"]);
+    assert_eq!(codes(b"_4 2.0V"), ["error:root_negative"]);
+    assert_eq!(codes(b"4 2.0V"), ["warning:root_scale"]);
+    assert_eq!(codes(b"2 3.5^"), ["warning:exponent_fraction"]);
+    assert_eq!(codes(b"[x] 3.5^"), ["error:non_numeric_value"]);
+
+    // The same order in text output.
+    assert_eq!(dc4_run(b"4.0 13.0 497.0 |p"), "\
+        dc4 cargo test: warning: non-zero scale in base\n\
+        dc4 cargo test: warning: non-zero scale in exponent\n\
+        dc4 cargo test: warning: non-zero scale in modulus\n\
+        445.0000000000000\n");
+}
+
+#[test]
+fn test_shift() {
+    assert_eq!(dc4_run(b"5 3H p 40 3h p 1 100H p 2 100^ 100h p"),
+        "40\n5\n1267650600228229401496703205376\n1\n");
+    // Right shifts round down, so negative numbers go away from zero.
+    assert_eq!(dc4_run(b"5 1h p _5 1h p _1 100h p _5 1H p 0 1000H p"), "2\n-3\n-1\n-10\n0\n");
+    // They pair with the bitwise commands.
+    assert_eq!(dc4_run_flavor(b"16o 16i FF 4h F& p 1 10H 1- 3h p", dc4::parser::Flavor::Gavin),
+        "F\n1FFF\n");
+    // Integers with zeros after the point are fine.
+    assert_eq!(dc4_run(b"5.00 1H p"), "10\n");
+
+    assert_eq!(dc4_run(b"5 _1H f"), "dc4 cargo test: shift count must be a nonnegative integer\n\
+        -1\n5\n");
+    assert_eq!(dc4_run(b"5 1.5h f"), "dc4 cargo test: shift count must be a nonnegative integer\n\
+        1.5\n5\n");
+    assert_eq!(dc4_run(b"5 2 32^H f"),
+        "dc4 cargo test: shift count out of range (must fit into 32 bits)\n4294967296\n5\n");
+    assert_eq!(dc4_run(b"5.5 1H f"), "dc4 cargo test: non-integer number\n1\n5.5\n");
+    assert_eq!(dc4_run(b"[a] 1h f"), "dc4 cargo test: non-numeric value\n1\na\n");
+}
+
+#[test]
+fn test_stream_until_quit() {
+    use dc4::parser::Flavor;
+    use std::io::{Cursor, Read};
+
+    // Returns the output, the length of the program, how far the input was read, and the rest.
+    let run = |input: &[u8], flavor: Flavor, skip_bom: bool| {
+        let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), flavor);
+        dc.set_skip_bom(skip_bom);
+        let mut cursor = Cursor::new(input);
+        let mut out = Vec::<u8>::new();
+        let (result, len) = dc.stream_until_quit(&mut cursor, &mut out);
+        assert!(matches!(result, dc4::DcResult::Terminate(_)), "{result:?}");
+        let read = cursor.position();
+        cursor.set_position(len);
+        let mut rest = vec![];
+        cursor.read_to_end(&mut rest).unwrap();
+        (String::from_utf8(out).unwrap(), len, read, String::from_utf8(rest).unwrap())
+    };
+
+    assert_eq!(run(b"1p q LEFTOVER", Flavor::Gnu, false),
+        ("1\n".to_owned(), 4, 4, " LEFTOVER".to_owned()));
+    // The q ends the number, and is used right away.
+    assert_eq!(run(b"2p 5q\nLEFTOVER", Flavor::Gnu, false),
+        ("2\n".to_owned(), 5, 5, "\nLEFTOVER".to_owned()));
+    assert_eq!(run(b"[3pq]x LEFTOVER", Flavor::Gnu, false),
+        ("3\n".to_owned(), 6, 6, " LEFTOVER".to_owned()));
+    // A conditional which might have an else register is the one case of reading a byte ahead.
+    assert_eq!(run(b"[4pq]sa 1 1=a LEFTOVER", Flavor::Bsd, false),
+        ("4\n".to_owned(), 13, 14, " LEFTOVER".to_owned()));
+    // A byte order mark which was skipped is part of the program.
+    assert_eq!(run(b"\xEF\xBB\xBF5p q LEFTOVER", Flavor::Gnu, true),
+        ("5\n".to_owned(), 7, 7, " LEFTOVER".to_owned()));
+
+    // Without quitting, the whole stream is the program.
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    let (result, len) = dc.stream_until_quit(&mut &b"6p 7p"[..], &mut out);
+    assert!(matches!(result, dc4::DcResult::Continue));
+    assert_eq!((len, &out[..]), (5, &b"6\n7\n"[..]));
+}
+
+#[test]
+fn test_stream_poll() {
+    use dc4::parser::Flavor;
+    use dc4::StreamPoll;
+    use std::io::{self, BufRead, Read};
+
+    /// A non-blocking reader, which often has nothing ready, and is sometimes interrupted.
+    struct NotReady<'a> {
+        data: &'a [u8],
+        calls: usize,
+    }
+
+    impl Read for NotReady<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let available = self.fill_buf()?;
+            let n = available.len().min(buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            self.consume(n);
+            Ok(n)
+        }
+    }
+
+    impl BufRead for NotReady<'_> {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            self.calls += 1;
+            match self.calls % 7 {
+                0 | 3 => Err(io::ErrorKind::WouldBlock.into()),
+                5 => Err(io::ErrorKind::Interrupted.into()),
+                n => Ok(&self.data[.. (n + 2).min(self.data.len())]),
+            }
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.data = &self.data[amt ..];
+        }
+    }
+
+    let programs: &[(&[u8], Flavor)] = &[
+        (b"\xEF\xBB\xBF12.5 3*p [a [nested] string]p 100000000000000000000 1+p", Flavor::Gnu),
+        (b"1 2 3 sa sb sc lalblc f [x]sx 5 5=x 5 6=xey [y]sy 1 1=yex", Flavor::Bsd),
+        (b"1 0/ [oops] 4+ _3 2^p z", Flavor::Gnu),
+        (b"[3 4*p]dsxx 7 0:a 0;ap", Flavor::Gavin),
+    ];
+    for (program, flavor) in programs {
+        let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), *flavor);
+        dc.set_skip_bom(true);
+        let mut expected = Vec::<u8>::new();
+        dc.stream(&mut &program[..], &mut expected);
+
+        let mut dc = dc4::Dc4::with_flavor("dc4 cargo test".to_string(), *flavor);
+        dc.set_skip_bom(true);
+        let mut reader = NotReady { data: program, calls: 0 };
+        let mut out = Vec::<u8>::new();
+        let mut pending = 0;
+        let result = loop {
+            match dc.stream_poll(&mut reader, &mut out) {
+                StreamPoll::Pending => pending += 1,
+                StreamPoll::Done(result) => break result,
+            }
+        };
+        assert!(matches!(result, dc4::DcResult::Continue), "{result:?}");
+        assert!(pending > 0);
+        assert_eq!(String::from_utf8(out).unwrap(), String::from_utf8(expected).unwrap());
+    }
+
+    // Quitting finishes the program, and the next call starts another with the rest.
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut reader = NotReady { data: b"1p 2 q 3p", calls: 0 };
+    let mut out = Vec::<u8>::new();
+    let mut results = vec![];
+    while results.len() < 2 {
+        if let StreamPoll::Done(result) = dc.stream_poll(&mut reader, &mut out) {
+            results.push(format!("{result:?}"));
+        }
+    }
+    assert_eq!(results, ["Terminate(2)", "Continue"]);
+    assert_eq!(String::from_utf8(out).unwrap(), "1\n3\n");
+}
+
+#[test]
+fn test_random() {
+    let run = |seed: u64, program: &[u8]| {
+        let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+        dc.seed_rng(seed);
+        let mut out = Vec::<u8>::new();
+        dc.text(program.to_vec(), &mut out);
+        String::from_utf8(out).unwrap()
+    };
+    // The same seed gives the same numbers.
+    let program = b"1000000' p 6' p 2 200^' p";
+    assert_eq!(run(7, program), run(7, program));
+    assert_ne!(run(7, program), run(8, program));
+    assert_eq!(run(7, b"1' p 1' p"), "0\n0\n");
+
+    // Roll a die 6000 times, counting each face in array c: each should come up about 1000
+    // times.
+    let counts = run(1, b"[6' d;c 1+ r:c li1+ dsi 6000>L]sL 0si lLx \
+        0;cp 1;cp 2;cp 3;cp 4;cp 5;cp 6;cp");
+    let counts = counts.lines().map(|line| line.parse::<u32>().unwrap()).collect::<Vec<_>>();
+    assert_eq!(counts.iter().sum::<u32>(), 6000, "{counts:?}");
+    assert!(counts[.. 6].iter().all(|&n| (850 .. 1150).contains(&n)), "{counts:?}");
+    assert_eq!(counts[6], 0);
+    // Bounds too big for a machine integer.
+    assert_eq!(run(3, b"[[bad]p]sb [2 100^ d' !<b li1+ dsi 50>y]sy 0si lyx [ok]p"), "ok\n");
+
+    // Unseeded, it gives different numbers each time.
+    let unseeded = || {
+        let mut out = Vec::<u8>::new();
+        dc4::Dc4::new("dc4 cargo test".to_string()).text(b"2 64^' p".to_vec(), &mut out);
+        out
+    };
+    assert_ne!(unseeded(), unseeded());
+
+    assert_eq!(dc4_run(b"0' f"), "dc4 cargo test: random bound must be a positive integer\n0\n");
+    assert_eq!(dc4_run(b"_5' f"), "dc4 cargo test: random bound must be a positive integer\n-5\n");
+    assert_eq!(dc4_run(b"2.5' f"), "dc4 cargo test: random bound must be a positive integer\n2.5\n");
+    assert_eq!(dc4_run(b"[a]' f"), "dc4 cargo test: non-numeric value\na\n");
+    assert_eq!(dc4_run(b"'"), "dc4 cargo test: stack empty\n");
+
+    // Gavin's dc has a command of its own on '\''.
+    assert_eq!(dc4_run_flavor(b"5' f", dc4::parser::Flavor::Gavin),
+        "dc4 cargo test: '\\'' (047) unimplemented\n5\n");
+}
+
+#[test]
+fn test_lint_path() {
+    use dc4::parser::{Flavor, ParseIssueKind};
+    use dc4::{LintIssue, LintOptions};
+    let fixture = |name| format!("{}/tests/fixtures/lint/{name}", env!("CARGO_MANIFEST_DIR"));
+    let lint = |name, flavor, options| dc4::lint_path(fixture(name), flavor, options).unwrap()
+        .iter()
+        .map(LintIssue::to_string)
+        .collect::<Vec<_>>();
+    let options = LintOptions::default();
+    assert!(lint("clean.dc", Flavor::Gnu, options).is_empty());
+    assert_eq!(lint("issues.dc", Flavor::Gnu, options), [
+        "2:5: '&' (046) unimplemented",
+        "4:10: running shell commands is not supported",
+        "6:1: unterminated string",
+    ]);
+    assert_eq!(lint("issues.dc", Flavor::Gavin,
+        LintOptions { strict_digits: true, unterminated_strings: false, ..options }), [
+        "3:1: digit 'A' is not valid in input radix 10; the number is read as 20",
+        "4:10: running shell commands is not supported",
+    ]);
+
+    // The byte order mark isn't counted, and a carriage return is just the end of a line.
+    let issues = dc4::lint_path(fixture("bom_crlf.dc"), Flavor::Gnu, options).unwrap();
+    assert_eq!(issues.iter().map(|issue| (issue.position.offset, issue.position.line,
+        issue.position.column, issue.kind.clone())).collect::<Vec<_>>(), [
+        (0, 1, 1, ParseIssueKind::Unimplemented(b'&')),
+        (12, 2, 3, ParseIssueKind::Unimplemented(b'G')),
+    ]);
+
+    assert!(dc4::lint_path(fixture("missing.dc"), Flavor::Gnu, options).is_err());
+}
+
+#[test]
+fn test_transaction() {
+    use dc4::parser::{Action, RegisterAction};
+    use dc4::{DcError, ErrorPolicy};
+    let mut dc = dc4::Dc4::new("dc4 cargo test".to_string());
+    let mut out = Vec::<u8>::new();
+    dc.text("1 2 [a]sx 5 0:y 6 1:y [b]Sz", &mut out);
+    let state = |dc: &mut dc4::Dc4| {
+        let mut out = Vec::<u8>::new();
+        dc.dump_state(&mut out).unwrap();
+        format!("{}{:?}", String::from_utf8(out).unwrap(), dc.export_array(b'y'))
+    };
+    let before = state(&mut dc);
+
+    // Changes to the stack, registers, arrays, scale, and radixes are all undone.
+    let result = dc.transaction(|txn| {
+        txn.text("c 3 [c]sx 7 0:y 8 2:y Lz 5k 16i", &mut out);
+        txn.push_number("42")?;
+        txn.action(Action::Sqrt, &mut out)?;
+        txn.push_string("x");
+        txn.action(Action::Add, &mut out)
+    });
+    assert!(matches!(result, Err(DcError::NonNumericValue)));
+    assert_eq!(state(&mut dc), before);
+
+    // Any error will do, as long as dc's errors convert to it.
+    let result: Result<(), Box<dyn std::error::Error>> = dc.transaction(|txn| {
+        txn.push_number("3")?;
+        txn.text("Ls", &mut out);
+        Err("changed my mind".into())
+    });
+    assert_eq!(result.unwrap_err().to_string(), "changed my mind");
+    assert_eq!(state(&mut dc), before);
+
+    // On success, the changes are kept.
+    let result = dc.transaction(|txn| {
+        txn.push_scaled_int(314, 2);
+        txn.text("2k [c]sx", &mut out);
+        Ok::<_, DcError>(7)
+    });
+    assert!(matches!(result, Ok(7)));
+    let mut out = Vec::<u8>::new();
+    dc.text("f K p lx p", &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "3.14\n2\n1\n2\nc\n");
+    let mut out = Vec::<u8>::new();
+    dc.text("c 1 2", &mut out);
+
+    // Output can't be taken back.
+    let mut out = Vec::<u8>::new();
+    let result = dc.transaction(|txn| {
+        txn.text("[hello]n 0", &mut out);
+        txn.action(Action::Div, &mut out)
+    });
+    assert!(matches!(result, Err(DcError::DivideByZero)));
+    assert_eq!(out, b"hello");
+
+    // Under the strict policy, text which stops on an error rolls back the transaction, even if
+    // the closure carries on.
+    let before = state(&mut dc);
+    dc.set_error_policy(ErrorPolicy::AbortProgram);
+    let mut out = Vec::<u8>::new();
+    let result = dc.transaction(|txn| {
+        txn.text("9 sx 0 0/ 10", &mut out);
+        Ok::<_, DcError>(())
+    });
+    assert!(matches!(result, Err(DcError::TransactionAborted)));
+    assert_eq!(String::from_utf8(out).unwrap(), "dc4 cargo test: divide by zero\n");
+    assert_eq!(state(&mut dc), before);
+    dc.set_error_policy(ErrorPolicy::Continue);
+
+    // Saving a register with t is undone too, so it isn't restored again when the input ends.
+    let mut out = Vec::<u8>::new();
+    dc.text("c 1 sa 2 Sa", &mut out);
+    let result = dc.transaction(|txn| {
+        txn.action(Action::Register(RegisterAction::SaveScoped, b'a'), &mut out)?;
+        Err::<(), _>(DcError::StackEmpty)
+    });
+    assert!(matches!(result, Err(DcError::StackEmpty)));
+    dc.text("", &mut out);
+    dc.text("la p La p La p", &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "2\n2\n1\n");
+
+    // So is a number or string part way through being read.
+    let mut out = Vec::<u8>::new();
+    let result = dc.transaction(|txn| {
+        txn.action(Action::NumberChar(b'7'), &mut out)?;
+        txn.action(Action::StringChar(b'x'), &mut out)?;
+        Err::<(), _>(DcError::StackEmpty)
+    });
+    assert!(matches!(result, Err(DcError::StackEmpty)));
+    dc.text("c 3 p [y]p", &mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "3\ny\n");
+}